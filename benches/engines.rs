@@ -0,0 +1,77 @@
+//! Compare the copy engines on a generated tree: `cargo bench --bench engines` (add `--features uring` to include
+//! the io_uring engine). The tree is generated once in the temporary directory, its shape can be changed with
+//! `BENCH_DIRS`, `BENCH_BIG_FILES`, `BENCH_BIG_SIZE`, `BENCH_SMALL_FILES` and `BENCH_SMALL_SIZE`.
+//! The source is read from the page cache after the first run, so the numbers compare the engines, not the disks.
+//! The streaming engine also runs with `--pipeline-depth 1` (read and write in turns) and 8. Its read-ahead shows on
+//! a slow destination, set it with `BENCH_DESTINATION` (an sshfs mount, a throttled loop device...)
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+/// Every engine copies the tree this number of times and the fastest run is reported
+const RUNS: usize = 3;
+
+fn setting(name: &str, default: u64) -> u64 {
+    std::env::var(name).ok().and_then(|value| value.parse().ok()).unwrap_or(default)
+}
+
+/// Generate the source tree unless it is already there with the same shape
+fn generate(base: &Path) -> (PathBuf, u64) {
+    let dirs = setting("BENCH_DIRS", 8);
+    let big_files = setting("BENCH_BIG_FILES", 4);
+    let big_size = setting("BENCH_BIG_SIZE", 16 * 1024 * 1024);
+    let small_files = setting("BENCH_SMALL_FILES", 64);
+    let small_size = setting("BENCH_SMALL_SIZE", 16 * 1024);
+    let source = base.join(format!("source-{dirs}-{big_files}x{big_size}-{small_files}x{small_size}"));
+    let total = dirs * (big_files * big_size + small_files * small_size);
+    if source.exists() {
+        return (source, total);
+    }
+    let partial = base.join("partial");
+    let _ = std::fs::remove_dir_all(&partial);
+    for dir in 0..dirs {
+        let dir = partial.join(format!("dir{dir}"));
+        std::fs::create_dir_all(&dir).unwrap();
+        let files = (0..big_files).map(|file| (format!("big{file}"), big_size))
+            .chain((0..small_files).map(|file| (format!("small{file}"), small_size)));
+        for (name, size) in files {
+            let content: Vec<u8> = (0..size).map(|byte| (byte % 251) as u8).collect();
+            std::fs::write(dir.join(name), content).unwrap();
+        }
+    }
+    std::fs::rename(&partial, &source).unwrap();
+    (source, total)
+}
+
+/// Copy the tree with the engine and return how long it took
+fn run(source: &Path, dest: &Path, engine: &str, depth: &str) -> Duration {
+    let _ = std::fs::remove_dir_all(dest);
+    let started = Instant::now();
+    let status = Command::new(env!("CARGO_BIN_EXE_rs-copier"))
+        .args(["--source".as_ref(), source.as_os_str(), "--destination".as_ref(), dest.as_os_str()])
+        .args(["--copy-engine", engine, "--pipeline-depth", depth])
+        .stdout(std::process::Stdio::null())
+        .status()
+        .unwrap();
+    let elapsed = started.elapsed();
+    assert!(status.success(), "The {engine} engine failed");
+    elapsed
+}
+
+fn main() {
+    let base = std::env::temp_dir().join("rs-copier-bench");
+    let (source, total) = generate(&base);
+    let dest = std::env::var_os("BENCH_DESTINATION").map_or_else(|| base.join("dest"), |dest| PathBuf::from(dest).join("rs-copier-bench"));
+    let mut engines = vec![("auto", "2"), ("stream", "1"), ("stream", "2"), ("stream", "8")];
+    if cfg!(all(feature = "uring", target_os = "linux")) {
+        engines.push(("uring", "2"));
+    }
+    println!("{} MiB in {:?} to {:?}", total / (1024 * 1024), source, dest);
+    for (engine, depth) in engines {
+        let best = (0..RUNS).map(|_| run(&source, &dest, engine, depth)).min().unwrap();
+        let name = if engine == "stream" { format!("{engine}/{depth}") } else { engine.to_owned() };
+        println!("{name:>8}: {:>8.3} s {:>10.1} MiB/s", best.as_secs_f64(), total as f64 / (1024.0 * 1024.0) / best.as_secs_f64());
+    }
+    let _ = std::fs::remove_dir_all(&dest);
+}
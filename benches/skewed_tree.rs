@@ -0,0 +1,61 @@
+//! Copy a skewed tree, a huge directory next to many small ones, and report how busy the workers were:
+//! `cargo bench --bench skewed_tree`. The huge directory has `BENCH_SKEWED_FILES` files (200k by default) and there
+//! are `BENCH_SKEWED_DIRS` other directories (1000) of 10 files; it is generated once in the temporary directory
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Instant;
+
+fn setting(name: &str, default: u64) -> u64 {
+    std::env::var(name).ok().and_then(|value| value.parse().ok()).unwrap_or(default)
+}
+
+/// Generate the source tree unless it is already there with the same shape
+fn generate(base: &Path) -> PathBuf {
+    let files = setting("BENCH_SKEWED_FILES", 200_000);
+    let dirs = setting("BENCH_SKEWED_DIRS", 1000);
+    let source = base.join(format!("skewed-{files}-{dirs}"));
+    if source.exists() {
+        return source;
+    }
+    let partial = base.join("partial");
+    let _ = std::fs::remove_dir_all(&partial);
+    let content = vec![b'x'; 4096];
+    std::fs::create_dir_all(partial.join("huge")).unwrap();
+    for file in 0..files {
+        std::fs::write(partial.join("huge").join(format!("file{file}")), &content).unwrap();
+    }
+    for dir in 0..dirs {
+        let dir = partial.join(format!("small{dir}"));
+        std::fs::create_dir_all(&dir).unwrap();
+        for file in 0..10 {
+            std::fs::write(dir.join(format!("file{file}")), &content).unwrap();
+        }
+    }
+    std::fs::rename(&partial, &source).unwrap();
+    source
+}
+
+fn main() {
+    let base = std::env::temp_dir().join("rs-copier-bench");
+    let source = generate(&base);
+    let dest = base.join("skewed-dest");
+    let _ = std::fs::remove_dir_all(&dest);
+    println!("{:?}", source);
+    let started = Instant::now();
+    let output = Command::new(env!("CARGO_BIN_EXE_rs-copier"))
+        .args(["--source".as_ref(), source.as_os_str(), "--destination".as_ref(), dest.as_os_str()])
+        .args(["--concurrency", &setting("BENCH_SKEWED_CONCURRENCY", 8).to_string()])
+        .output()
+        .unwrap();
+    let elapsed = started.elapsed();
+    assert!(output.status.success(), "The copy failed");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let busy = stdout.lines()
+        .filter(|line| line.contains("workers were busy"))
+        .find_map(|line| line.rsplit_once("] ").map(|(_, busy)| busy))
+        .unwrap_or("not reported");
+    println!("   elapsed: {:>8.3} s", elapsed.as_secs_f64());
+    println!("   {}", busy);
+    let _ = std::fs::remove_dir_all(&dest);
+}
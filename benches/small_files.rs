@@ -0,0 +1,61 @@
+//! Compare the copy of a tree of tiny files with and without the batches of `--small-file-threshold`:
+//! `cargo bench --bench small_files`. The tree has `BENCH_TINY_FILES` files (1M by default) of `BENCH_TINY_SIZE`
+//! bytes, 1000 per directory; it is generated once in the temporary directory
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+fn setting(name: &str, default: u64) -> u64 {
+    std::env::var(name).ok().and_then(|value| value.parse().ok()).unwrap_or(default)
+}
+
+/// Generate the source tree unless it is already there with the same shape
+fn generate(base: &Path) -> PathBuf {
+    let files = setting("BENCH_TINY_FILES", 1_000_000);
+    let size = setting("BENCH_TINY_SIZE", 1024);
+    let source = base.join(format!("source-{files}x{size}"));
+    if source.exists() {
+        return source;
+    }
+    let partial = base.join("partial");
+    let _ = std::fs::remove_dir_all(&partial);
+    let content = vec![b'x'; size as usize];
+    for file in 0..files {
+        let dir = partial.join(format!("dir{}", file / 1000));
+        if file % 1000 == 0 {
+            std::fs::create_dir_all(&dir).unwrap();
+        }
+        std::fs::write(dir.join(format!("file{file}")), &content).unwrap();
+    }
+    std::fs::rename(&partial, &source).unwrap();
+    source
+}
+
+/// Copy the tree with the threshold and return how long it took
+fn run(source: &Path, dest: &Path, threshold: &str) -> Duration {
+    let _ = std::fs::remove_dir_all(dest);
+    let started = Instant::now();
+    let status = Command::new(env!("CARGO_BIN_EXE_rs-copier"))
+        .args(["--source".as_ref(), source.as_os_str(), "--destination".as_ref(), dest.as_os_str()])
+        .args(["--small-file-threshold", threshold])
+        .stdout(std::process::Stdio::null())
+        .status()
+        .unwrap();
+    let elapsed = started.elapsed();
+    assert!(status.success(), "The copy with --small-file-threshold {threshold} failed");
+    elapsed
+}
+
+fn main() {
+    let base = std::env::temp_dir().join("rs-copier-bench");
+    let source = generate(&base);
+    let dest = base.join("dest");
+    println!("{:?}", source);
+    let unbatched = run(&source, &dest, "0");
+    let batched = run(&source, &dest, "64KiB");
+    println!("one task per file: {:>8.3} s", unbatched.as_secs_f64());
+    println!("   batched files: {:>8.3} s", batched.as_secs_f64());
+    println!("         speedup: {:>8.2}x", unbatched.as_secs_f64() / batched.as_secs_f64());
+    let _ = std::fs::remove_dir_all(&dest);
+}
@@ -0,0 +1,353 @@
+//! `--concurrency auto`: the number of copies in flight is adjusted while copying instead of guessed.
+//! It starts low and an AIMD controller moves it from what the copies take: one more copy at a time while the
+//! throughput improves, and a cut when the latency spikes or the system runs out of resources (EAGAIN, ENFILE)
+
+use std::io;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use log::debug;
+use crate::limit::CopyPermits;
+
+/// Copies in flight when the automatic concurrency starts
+pub const AUTO_START: usize = 4;
+/// The most copies in flight of the automatic concurrency
+pub const AUTO_MAX: usize = 256;
+/// Directories listed at the same time with the automatic concurrency
+pub const AUTO_DIRECTORIES: usize = 16;
+/// Directories listed at the same time per copy of a fixed concurrency, listing is cheaper than copying
+pub const DIRECTORIES_PER_COPY: usize = 2;
+/// Copies per core of the default concurrency, the copies mostly wait for the disks
+pub const COPIES_PER_CORE: usize = 2;
+/// Bounds of the default concurrency: a small board still overlaps a few copies, a big server (or a container that
+/// reports the cores of its host) does not flood the disks
+pub const DEFAULT_MIN: usize = 4;
+pub const DEFAULT_MAX: usize = 64;
+
+/// Shortest time between two adjustments
+const WINDOW: Duration = Duration::from_millis(500);
+/// Copies finished in a window before it can tell anything
+const MIN_SAMPLES: u64 = 8;
+/// The throughput of a window must beat the previous one by this factor to keep increasing
+const IMPROVEMENT: f64 = 1.02;
+/// A mean latency this many times the best one seen is a spike
+const LATENCY_SPIKE: f64 = 3.0;
+
+/// The `--concurrency` value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Concurrency {
+    /// Adjusted while copying
+    Auto,
+    /// Always this number of copies in flight
+    Fixed(usize),
+}
+
+impl Concurrency {
+    /// Copies in flight that the permits can reach
+    pub fn max_copies(self) -> usize {
+        match self {
+            Concurrency::Auto => AUTO_MAX,
+            Concurrency::Fixed(copies) => copies,
+        }
+    }
+
+    /// Directories listed (or pre-scanned) at the same time unless --list-concurrency
+    pub fn directories(self) -> usize {
+        match self {
+            Concurrency::Auto => AUTO_DIRECTORIES,
+            Concurrency::Fixed(copies) => copies * DIRECTORIES_PER_COPY,
+        }
+    }
+}
+
+/// The copies of the default concurrency for a machine with `cores` cores
+pub fn default_copies(cores: usize) -> usize {
+    cores.saturating_mul(COPIES_PER_CORE).clamp(DEFAULT_MIN, DEFAULT_MAX)
+}
+
+/// The cores the process can use (the CPU quota of a container included), 1 when they are unknown
+pub fn available_cores() -> usize {
+    std::thread::available_parallelism().map_or(1, |cores| cores.get())
+}
+
+/// The concurrency when `--concurrency` is not given
+pub fn default_concurrency() -> Concurrency {
+    Concurrency::Fixed(default_copies(available_cores()))
+}
+
+/// Parse `auto` or a number of copies
+pub fn parse_concurrency(value: &str) -> Result<Concurrency, String> {
+    match value.trim() {
+        "auto" => Ok(Concurrency::Auto),
+        number => number.parse().map(Concurrency::Fixed)
+            .map_err(|_| format!("the concurrency must be a number or auto: {:?}", value)),
+    }
+}
+
+/// What was measured since the last adjustment
+#[derive(Debug)]
+struct Window {
+    started: Instant,
+    copies: u64,
+    latency: Duration,
+    overloaded: bool,
+    /// The totals of the stats when it started
+    bytes: u64,
+    files: u64,
+}
+
+/// The AIMD controller. It only does arithmetic on what it is given, so the timings can be injected
+#[derive(Debug)]
+pub struct Controller {
+    level: usize,
+    max: usize,
+    window: Window,
+    /// Bytes (or files when nothing is copied byte by byte, like renames) per second of the previous window
+    last_throughput: Option<f64>,
+    /// The lowest mean latency of a window
+    best_latency: Option<Duration>,
+}
+
+impl Controller {
+    pub fn new(level: usize, max: usize, now: Instant) -> Self {
+        let window = Window { started: now, copies: 0, latency: Duration::ZERO, overloaded: false, bytes: 0, files: 0 };
+        Self { level: level.clamp(1, max), max, window, last_throughput: None, best_latency: None }
+    }
+
+    pub fn level(&self) -> usize {
+        self.level
+    }
+
+    /// Record a finished copy: how long it took and whether it failed because the system is overloaded
+    pub fn record(&mut self, latency: Duration, overloaded: bool) {
+        self.window.copies += 1;
+        self.window.latency += latency;
+        self.window.overloaded |= overloaded;
+    }
+
+    /// Close the window when it is long enough and return the new level when it changes.
+    /// `bytes` and `files` are the totals copied so far
+    pub fn adjust(&mut self, now: Instant, bytes: u64, files: u64) -> Option<usize> {
+        let elapsed = now.saturating_duration_since(self.window.started);
+        if !self.window.overloaded && (elapsed < WINDOW || self.window.copies < MIN_SAMPLES) {
+            return None;
+        }
+        let window = std::mem::replace(&mut self.window, Window {
+            started: now,
+            copies: 0,
+            latency: Duration::ZERO,
+            overloaded: false,
+            bytes,
+            files,
+        });
+        let seconds = elapsed.as_secs_f64().max(f64::EPSILON);
+        let throughput = match bytes.saturating_sub(window.bytes) {
+            0 => files.saturating_sub(window.files) as f64 / seconds,
+            copied => copied as f64 / seconds,
+        };
+        let latency = window.latency / window.copies.max(1) as u32;
+        let best_latency = *self.best_latency.get_or_insert(latency);
+        self.best_latency = Some(best_latency.min(latency));
+
+        let level = if window.overloaded {
+            self.level / 2
+        } else if latency.as_secs_f64() > best_latency.as_secs_f64() * LATENCY_SPIKE {
+            self.level * 3 / 4
+        } else if self.last_throughput.is_none_or(|last| throughput > last * IMPROVEMENT) {
+            self.level + 1
+        } else {
+            self.level
+        };
+        // A cut starts over the comparison of the throughputs
+        self.last_throughput = (level >= self.level).then_some(throughput);
+        let level = level.clamp(1, self.max);
+        if level == self.level {
+            return None;
+        }
+        self.level = level;
+        Some(level)
+    }
+}
+
+/// The automatic concurrency of a run: the controller and the copy permits it resizes
+#[derive(Debug)]
+pub struct Adaptive {
+    controller: Mutex<Controller>,
+    permits: CopyPermits,
+    /// Permits that must be removed but were in use when the level went down
+    debt: Mutex<usize>,
+}
+
+impl Adaptive {
+    /// Control `permits`, which has room for AUTO_MAX copies and starts with AUTO_START of them
+    pub fn new(permits: CopyPermits) -> Self {
+        let controller = Controller::new(AUTO_START, permits.capacity(), Instant::now());
+        Self { controller: Mutex::new(controller), permits, debt: Mutex::new(0) }
+    }
+
+    pub fn level(&self) -> usize {
+        self.controller.lock().unwrap().level()
+    }
+
+    /// Account a finished copy and resize the permits when the controller asks for it
+    pub fn finished(&self, latency: Duration, overloaded: bool, bytes: u64, files: u64) {
+        let (previous, level) = {
+            let mut controller = self.controller.lock().unwrap();
+            let previous = controller.level();
+            controller.record(latency, overloaded);
+            match controller.adjust(Instant::now(), bytes, files) {
+                Some(level) => (previous, level),
+                None => (previous, previous),
+            }
+        };
+        let mut debt = self.debt.lock().unwrap();
+        if level > previous {
+            let added = level - previous;
+            let paid = added.min(*debt);
+            *debt -= paid;
+            self.permits.add(added - paid);
+        } else {
+            *debt += previous - level;
+        }
+        // The permits in use are removed once they are released
+        let forgotten = self.permits.forget(*debt);
+        *debt -= forgotten;
+        if level != previous {
+            debug!("The concurrency goes from {} to {} copies", previous, level);
+        }
+    }
+}
+
+/// Whether the copy failed because the system is out of some resource, the concurrency must go down
+pub fn is_overload(error: &anyhow::Error) -> bool {
+    error.chain().filter_map(|cause| cause.downcast_ref::<io::Error>()).any(|error| {
+        #[cfg(unix)]
+        if matches!(error.raw_os_error(), Some(libc::ENFILE | libc::EMFILE | libc::EAGAIN)) {
+            return true;
+        }
+        error.kind() == io::ErrorKind::WouldBlock
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use crate::limit::CopyPermits;
+    use super::{default_copies, is_overload, parse_concurrency, Adaptive, Concurrency, Controller, AUTO_START, DEFAULT_MAX, DEFAULT_MIN, WINDOW};
+
+    /// Run `windows` windows of a system that copies 1000 bytes in `latency(level)` with up to `limit` copies
+    /// at the same time, and return the levels
+    fn simulate(controller: &mut Controller, started: Instant, windows: u32, limit: usize, latency: impl Fn(usize) -> Duration) -> Vec<usize> {
+        let mut now = started;
+        let mut bytes = 0;
+        let mut levels = vec![];
+        for _ in 0..windows {
+            let level = controller.level();
+            let latency = latency(level);
+            let copies = (WINDOW.as_secs_f64() / latency.as_secs_f64() * level.min(limit) as f64) as u64;
+            for _ in 0..copies {
+                controller.record(latency, false);
+            }
+            now += WINDOW;
+            bytes += copies * 1000;
+            controller.adjust(now, bytes, 0);
+            levels.push(controller.level());
+        }
+        levels
+    }
+
+    #[test]
+    fn increases_while_the_throughput_improves() {
+        let started = Instant::now();
+        let mut controller = Controller::new(AUTO_START, 256, started);
+        // The disk does 16 copies at the same time, more only wait longer
+        let levels = simulate(&mut controller, started, 100, 16, |level| Duration::from_millis(10 * (level.max(16) / 16) as u64));
+        assert_eq!(levels[0], AUTO_START + 1);
+        let last = *levels.last().unwrap();
+        assert!((16..=18).contains(&last), "{:?}", levels);
+    }
+
+    #[test]
+    fn backs_off() {
+        let started = Instant::now();
+        let mut controller = Controller::new(20, 256, started);
+        simulate(&mut controller, started, 1, 256, |_| Duration::from_millis(10));
+        assert_eq!(controller.level(), 21);
+
+        // The latency spikes
+        let now = started + WINDOW;
+        simulate(&mut controller, now, 1, 256, |_| Duration::from_millis(50));
+        assert_eq!(controller.level(), 15);
+
+        // Out of file descriptors, the cut does not wait for the window
+        controller.record(Duration::from_millis(1), true);
+        assert_eq!(controller.adjust(now + WINDOW, 0, 0), Some(7));
+        controller.record(Duration::from_millis(1), true);
+        assert_eq!(controller.adjust(now + WINDOW, 0, 0), Some(3));
+        for _ in 0..3 {
+            controller.record(Duration::from_millis(1), true);
+            controller.adjust(now + WINDOW, 0, 0);
+        }
+        assert_eq!(controller.level(), 1);
+    }
+
+    #[test]
+    fn short_windows_wait() {
+        let started = Instant::now();
+        let mut controller = Controller::new(AUTO_START, 256, started);
+        for _ in 0..100 {
+            controller.record(Duration::from_millis(1), false);
+        }
+        assert_eq!(controller.adjust(started + WINDOW / 2, 1000, 0), None);
+        assert_eq!(controller.adjust(started + WINDOW, 1000, 0), Some(AUTO_START + 1));
+    }
+
+    #[tokio::test]
+    async fn resizes_the_permits() {
+        let permits = CopyPermits::limited(256, AUTO_START);
+        let adaptive = Adaptive::new(permits.clone());
+        let held: Vec<_> = hold(&permits, AUTO_START).await;
+        // A copy that ran out of file descriptors halves the level while every permit is in use
+        adaptive.finished(Duration::from_millis(1), true, 0, 0);
+        assert_eq!(adaptive.level(), AUTO_START / 2);
+        assert_eq!(permits.available(), 0);
+        drop(held);
+        // The permits in use are removed on the next adjustment
+        adaptive.finished(Duration::from_millis(1), false, 0, 0);
+        assert_eq!(permits.available(), AUTO_START / 2);
+    }
+
+    async fn hold(permits: &CopyPermits, count: usize) -> Vec<tokio::sync::OwnedSemaphorePermit> {
+        let mut held = vec![];
+        for _ in 0..count {
+            held.push(permits.acquire().await);
+        }
+        held
+    }
+
+    #[test]
+    fn overload_errors() {
+        let error = anyhow::Error::new(std::io::Error::from(std::io::ErrorKind::WouldBlock)).context("Cannot copy file");
+        assert!(is_overload(&error));
+        #[cfg(unix)]
+        assert!(is_overload(&anyhow::Error::new(std::io::Error::from_raw_os_error(libc::EMFILE))));
+        assert!(!is_overload(&anyhow::Error::new(std::io::Error::from(std::io::ErrorKind::NotFound))));
+    }
+
+    #[test]
+    fn concurrency_values() {
+        assert_eq!(parse_concurrency("auto"), Ok(Concurrency::Auto));
+        assert_eq!(parse_concurrency("12"), Ok(Concurrency::Fixed(12)));
+        assert!(parse_concurrency("fast").is_err());
+    }
+
+    #[test]
+    fn default_concurrency() {
+        assert_eq!(default_copies(1), DEFAULT_MIN);
+        assert_eq!(default_copies(0), DEFAULT_MIN);
+        assert_eq!(default_copies(4), 8);
+        assert_eq!(default_copies(24), 48);
+        assert_eq!(default_copies(64), DEFAULT_MAX);
+        assert_eq!(default_copies(usize::MAX), DEFAULT_MAX);
+        assert!(matches!(super::default_concurrency(), Concurrency::Fixed(copies) if (DEFAULT_MIN..=DEFAULT_MAX).contains(&copies)));
+    }
+}
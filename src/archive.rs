@@ -0,0 +1,395 @@
+//! Tar archives as destination (the source tree is packed into it) or as source (it is extracted).
+//! Writing a tar is serial: the files are read concurrently and funneled into a single writer thread
+
+use std::collections::BTreeSet;
+use std::fs::Metadata;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+use anyhow::{anyhow, Context, Result};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use log::{debug, info, warn};
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use crate::{extracts_over, report_file_error, report_file_result, report_path_error, CopyOptions};
+
+/// Files up to this size are read concurrently and sent to the writer in memory, the writer reads the bigger ones itself
+const IN_MEMORY_LIMIT: u64 = 8 * 1024 * 1024;
+
+/// Whether the path is a tar archive, based on its extension (`.tar`, `.tar.gz` or `.tgz`)
+pub fn is_tar(path: &Path) -> bool {
+    name(path).ends_with(".tar") || is_gzip(path)
+}
+
+pub fn is_gzip(path: &Path) -> bool {
+    let name = name(path);
+    name.ends_with(".tar.gz") || name.ends_with(".tgz")
+}
+
+fn name(path: &Path) -> String {
+    path.file_name().map(|name| name.to_string_lossy().to_lowercase()).unwrap_or_default()
+}
+
+enum Content {
+    Memory(Vec<u8>),
+    Disk(PathBuf),
+}
+
+/// An entry for the writer. Directories have no content
+struct Entry {
+    path: PathBuf,
+    metadata: Metadata,
+    content: Option<Content>,
+}
+
+/// The source entries that went into an archive, for --delete-source
+#[derive(Debug, Default)]
+pub struct Packed {
+    pub files: Vec<PathBuf>,
+    pub directories: Vec<PathBuf>,
+    /// The entries that are neither files nor directories, left out of the archive
+    pub skipped: u64,
+}
+
+/// Pack the tree of `source` into the tar `archive`. The paths in the archive are relative to the source.
+/// The files that could not be read are reported and left out
+pub async fn create(source: &Path, archive: &Path, options: &Arc<CopyOptions>) -> Result<Packed> {
+    info!("Packing {:?} into {:?}", source, archive);
+    if let Some(parent) = archive.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let (sender, receiver) = mpsc::channel(options.copy_permits.capacity());
+    let writer = {
+        let archive = archive.to_owned();
+        tokio::task::spawn_blocking(move || write_archive(&archive, receiver))
+    };
+    // The writer finishes when the walk drops the sender
+    let walked = walk(source, sender, options).await;
+    let written = writer.await?.with_context(|| format!("Cannot write archive: {:?}", archive));
+    let packed = walked?;
+    written?;
+    Ok(packed)
+}
+
+/// Send every directory and file of the tree to the writer. A directory that cannot be listed is reported like in a
+/// copy and the rest of the tree is still packed, but its entry is not removed with --delete-source
+async fn walk(source: &Path, sender: mpsc::Sender<Entry>, options: &Arc<CopyOptions>) -> Result<Packed> {
+    let mut packed = Packed::default();
+    let mut pending = vec![source.to_owned()];
+    let mut reads = JoinSet::new();
+    while let Some(dir) = pending.pop() {
+        let mut paths = match tokio::fs::read_dir(&dir).await {
+            Ok(paths) => paths,
+            Err(error) if dir == source => return Err(anyhow::Error::new(error).context(format!("Cannot read directory: {:?}", dir))),
+            Err(error) => {
+                listing_failed(&dir, error, &mut packed, options)?;
+                continue;
+            },
+        };
+        loop {
+            let entry = match paths.next_entry().await {
+                Ok(Some(entry)) => entry,
+                Ok(None) => break,
+                Err(error) => {
+                    listing_failed(&dir, error, &mut packed, options)?;
+                    break;
+                },
+            };
+            let path = entry.path();
+            let relative = path.strip_prefix(source)?.to_owned();
+            let metadata = match tokio::fs::symlink_metadata(&path).await {
+                Ok(metadata) => metadata,
+                Err(error) => {
+                    report_file_error(anyhow::Error::new(error).context(format!("Cannot get metadata: {:?}", path)), options)?;
+                    continue;
+                }
+            };
+            if metadata.is_dir() {
+                send(&sender, Entry { path: relative, metadata, content: None }).await?;
+                packed.directories.push(path.clone());
+                pending.push(path);
+            } else if metadata.is_file() {
+                let permit = options.copy_permits.acquire().await;
+                let sender = sender.clone();
+                let stats = options.stats.clone();
+                reads.spawn(async move {
+                    let size = metadata.len();
+                    let content = if size <= IN_MEMORY_LIMIT {
+                        Content::Memory(tokio::fs::read(&path).await.with_context(|| format!("Cannot read file: {:?}", path))?)
+                    } else {
+                        Content::Disk(path.clone())
+                    };
+                    send(&sender, Entry { path: relative, metadata, content: Some(content) }).await?;
+                    drop(permit);
+                    stats.copied(size, false);
+                    Ok(path)
+                });
+            } else {
+                warn!("Neither a file nor a directory, it is not archived: {:?}", path);
+                packed.skipped += 1;
+            }
+            while let Some(result) = reads.try_join_next() {
+                packed.files.extend(report_file_result(result, options)?);
+            }
+        }
+    }
+    while let Some(result) = reads.join_next().await {
+        packed.files.extend(report_file_result(result, options)?);
+    }
+    Ok(packed)
+}
+
+/// Report the directory `dir` that could not be listed (or not to its end), and keep it in the source
+fn listing_failed(dir: &Path, error: io::Error, packed: &mut Packed, options: &CopyOptions) -> Result<()> {
+    options.stats.directory_failed();
+    packed.directories.retain(|archived| archived != dir);
+    report_path_error(dir, anyhow::Error::new(error).context(format!("Cannot list directory: {:?}", dir)), options)
+}
+
+async fn send(sender: &mpsc::Sender<Entry>, entry: Entry) -> Result<()> {
+    sender.send(entry).await.map_err(|_| anyhow!("The archive writer stopped"))
+}
+
+fn write_archive(archive: &Path, entries: mpsc::Receiver<Entry>) -> Result<()> {
+    let file = std::fs::File::create(archive)?;
+    if is_gzip(archive) {
+        append_all(tar::Builder::new(GzEncoder::new(file, Compression::default())), entries)?.finish()?;
+    } else {
+        append_all(tar::Builder::new(file), entries)?.flush()?;
+    }
+    Ok(())
+}
+
+fn append_all<W: Write>(mut builder: tar::Builder<W>, mut entries: mpsc::Receiver<Entry>) -> Result<W> {
+    while let Some(entry) = entries.blocking_recv() {
+        let mut header = tar::Header::new_gnu();
+        header.set_metadata_in_mode(&entry.metadata, tar::HeaderMode::Complete);
+        match entry.content {
+            None => {
+                header.set_size(0);
+                builder.append_data(&mut header, &entry.path, io::empty())?;
+            },
+            Some(Content::Memory(bytes)) => {
+                header.set_size(bytes.len() as u64);
+                builder.append_data(&mut header, &entry.path, bytes.as_slice())?;
+            },
+            Some(Content::Disk(path)) => {
+                let file = std::fs::File::open(&path)?;
+                header.set_size(file.metadata()?.len());
+                builder.append_data(&mut header, &entry.path, file)?;
+            },
+        }
+    }
+    Ok(builder.into_inner()?)
+}
+
+/// Extract the tar `archive` into `dest`. The entries that cannot be extracted are reported and the others are still
+/// extracted, the files follow the time window of the filters and `--if-exists`. With `--fsync` the files and then
+/// their directories are flushed
+pub async fn extract(archive: &Path, dest: &Path, options: &Arc<CopyOptions>) -> Result<()> {
+    info!("Extracting {:?} into {:?}", archive, dest);
+    let (archive, dest, options) = (archive.to_owned(), dest.to_owned(), options.clone());
+    tokio::task::spawn_blocking(move || {
+        std::fs::create_dir_all(&dest).with_context(|| format!("Cannot create directory: {:?}", dest))?;
+        let file = std::fs::File::open(&archive).with_context(|| format!("Cannot open archive: {:?}", archive))?;
+        let unpacked = if is_gzip(&archive) {
+            unpack(tar::Archive::new(GzDecoder::new(file)), &dest, &options)
+        } else {
+            unpack(tar::Archive::new(file), &dest, &options)
+        };
+        unpacked.with_context(|| format!("Cannot read archive: {:?}", archive))
+    }).await?
+}
+
+/// Extract every entry. An error only when the archive cannot be read any further
+fn unpack<R: io::Read>(mut archive: tar::Archive<R>, dest: &Path, options: &CopyOptions) -> Result<()> {
+    // The directories get their time once their files are written, which changes it
+    let mut dirs = Vec::new();
+    let mut written_dirs = BTreeSet::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        let to = dest.join(&path);
+        let header = entry.header();
+        let (kind, size) = (header.entry_type(), entry.size());
+        let modified = header.mtime().ok().map(|seconds| UNIX_EPOCH + Duration::from_secs(seconds));
+        if kind.is_dir() {
+            dirs.extend(modified.map(|modified| (to.clone(), modified)));
+        } else {
+            if !options.filters.selects(modified) {
+                continue;
+            }
+            match extracts_over(&to, modified, options) {
+                Ok(true) => {},
+                Ok(false) => continue,
+                Err(error) => {
+                    report_path_error(&to, error, options)?;
+                    continue;
+                },
+            }
+        }
+        let extracted = match entry.unpack_in(dest) {
+            Ok(true) if kind.is_file() => flushed(&to, options),
+            Ok(true) => Ok(()),
+            Ok(false) => Err(anyhow!("The entry points outside of the destination")),
+            Err(error) => Err(error.into()),
+        };
+        if let Err(error) = extracted {
+            report_path_error(&to, error.context(format!("Cannot extract {:?}", path)), options)?;
+            continue;
+        }
+        if kind.is_file() {
+            options.stats.copied(size, false);
+            written_dirs.extend(to.parent().map(Path::to_owned));
+        }
+    }
+    for (dir, modified) in dirs.iter().rev() {
+        let set = std::fs::File::open(dir).and_then(|dir| dir.set_modified(*modified));
+        if let Err(error) = set {
+            debug!("Cannot set the time of directory {:?}: {}", dir, error);
+        }
+    }
+    if options.fsync {
+        for dir in written_dirs {
+            if let Err(error) = sync_directory(&dir) {
+                report_file_error(anyhow::Error::new(error).context(format!("Cannot sync directory: {:?}", dir)), options)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Flush an extracted file with --fsync
+fn flushed(file: &Path, options: &CopyOptions) -> Result<()> {
+    if options.fsync {
+        std::fs::File::open(file)?.sync_all().with_context(|| format!("Cannot sync file: {:?}", file))?;
+    }
+    Ok(())
+}
+
+/// Only Unix can open a directory to flush it, like `copy::sync_directory`
+fn sync_directory(dir: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    std::fs::File::open(dir)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::io::Read;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use super::{create, extract, is_tar};
+    use crate::test_support::init;
+    use crate::{CopyOptions, IfExists};
+
+    /// A tar with the `(name, content)` files. The names are written as they are, `..` included
+    fn tar_with(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for &(name, content) in files {
+            let mut header = tar::Header::new_gnu();
+            header.as_gnu_mut().unwrap().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_mtime(1_600_000_000);
+            header.set_cksum();
+            builder.append(&header, content).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn tar_extensions() {
+        assert!(is_tar("backup.tar".as_ref()));
+        assert!(is_tar("backup.TAR.GZ".as_ref()));
+        assert!(is_tar("backup.tgz".as_ref()));
+        assert!(!is_tar("backup".as_ref()));
+        assert!(!is_tar("backup.gz".as_ref()));
+    }
+
+    #[tokio::test]
+    async fn archive_tree() {
+        let base_dir = init("archive_tree").await;
+
+        let source = base_dir.join("source");
+        tokio::fs::create_dir_all(source.join("nested").join("empty")).await.unwrap();
+        tokio::fs::write(source.join("file1"), "one").await.unwrap();
+        tokio::fs::write(source.join("nested").join("file2"), "two").await.unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            tokio::fs::set_permissions(source.join("file1"), std::fs::Permissions::from_mode(0o751)).await.unwrap();
+        }
+
+        let archive = base_dir.join("out").join("archive.tar");
+        create(&source, &archive, &Arc::default()).await.unwrap();
+
+        let mut entries = BTreeMap::new();
+        let mut tar = tar::Archive::new(std::fs::File::open(&archive).unwrap());
+        for entry in tar.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let path = entry.path().unwrap().to_string_lossy().trim_end_matches('/').to_owned();
+            let mode = entry.header().mode().unwrap();
+            let mut content = String::new();
+            entry.read_to_string(&mut content).unwrap();
+            entries.insert(path, (content, mode));
+        }
+
+        let paths: Vec<_> = entries.keys().map(String::as_str).collect();
+        assert_eq!(paths, ["file1", "nested", "nested/empty", "nested/file2"]);
+        assert_eq!(entries["file1"].0, "one");
+        assert_eq!(entries["nested/file2"].0, "two");
+        #[cfg(unix)]
+        assert_eq!(entries["file1"].1 & 0o777, 0o751);
+    }
+
+    #[tokio::test]
+    async fn extract_entries() {
+        let base_dir = init("extract_entries").await;
+
+        let archive = base_dir.join("archive.tar");
+        tokio::fs::write(&archive, tar_with(&[
+            ("nested/file", b"one"),
+            ("../escape", b"evil"),
+            ("existing", b"archived"),
+            ("top", b"top"),
+        ])).await.unwrap();
+        let dest = base_dir.join("dest");
+        tokio::fs::create_dir(&dest).await.unwrap();
+        tokio::fs::write(dest.join("existing"), "kept").await.unwrap();
+        let options = Arc::new(CopyOptions { fsync: true, if_exists: IfExists::Skip, ..Default::default() });
+        extract(&archive, &dest, &options).await.unwrap();
+
+        // The entry going up fails, the others are extracted
+        assert!(!base_dir.join("escape").exists());
+        assert_eq!(tokio::fs::read_to_string(dest.join("nested/file")).await.unwrap(), "one");
+        assert_eq!(tokio::fs::read_to_string(dest.join("top")).await.unwrap(), "top");
+        assert_eq!(tokio::fs::read_to_string(dest.join("existing")).await.unwrap(), "kept");
+        assert_eq!(options.stats.summary(), "2 files copied, 0 files cloned, 0 files renamed, 6 bytes");
+        assert_eq!(options.stats.files_skipped.load(Ordering::Relaxed), 1);
+        assert_eq!(options.stats.files_failed.load(Ordering::Relaxed), 1);
+        let failures = options.failures.list();
+        assert!(failures[0].message.contains("Cannot extract"), "{:?}", failures);
+    }
+
+    #[tokio::test]
+    async fn extract_gzip_archive() {
+        let base_dir = init("extract_gzip_archive").await;
+
+        let source = base_dir.join("source");
+        tokio::fs::create_dir_all(source.join("nested")).await.unwrap();
+        tokio::fs::write(source.join("nested").join("file1"), "one").await.unwrap();
+        let archive = base_dir.join("archive.tar.gz");
+        create(&source, &archive, &Arc::default()).await.unwrap();
+
+        let dest = base_dir.join("dest");
+        extract(&archive, &dest, &Arc::default()).await.unwrap();
+
+        assert_eq!(tokio::fs::read_to_string(dest.join("nested").join("file1")).await.unwrap(), "one");
+    }
+}
@@ -0,0 +1,173 @@
+//! `--atomic`: every file is written under a hidden temporary name in its destination directory
+//! (`.rscopier-tmp.<name>.<run>-<n>`) and renamed to its name once it is complete, with its metadata (and flushed with
+//! `--fsync`), so a destination file is never a truncated copy. The rename stays in the directory, where it is
+//! atomic. A temporary file that is not renamed is removed; the ones left by a run that died are removed when a later
+//! run with `--atomic` goes through their directory
+
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::{anyhow, Context, Result};
+use log::{debug, info};
+use crate::IfExists;
+
+/// Prefix of the temporary files
+const PREFIX: &str = ".rscopier-tmp.";
+
+/// The longest name kept in a temporary name, the longer ones are replaced by a hash so it fits in the filesystem
+const MAX_NAME: usize = 160;
+
+/// Temporary files of this run
+static WRITTEN: AtomicU64 = AtomicU64::new(0);
+
+/// The id of this run in the names of its temporary files, the ones of other runs are leftovers
+fn run_id() -> &'static str {
+    static ID: OnceLock<String> = OnceLock::new();
+    ID.get_or_init(|| {
+        let started = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        format!("{:x}{:x}", std::process::id(), started.as_nanos() & 0xffff_ffff)
+    })
+}
+
+/// A temporary file of the destination `to`, removed when dropped before it is renamed (also when its copy is
+/// cancelled)
+#[derive(Debug)]
+pub struct TempFile {
+    path: PathBuf,
+    /// An existing destination is replaced
+    replaces: bool,
+    renamed: bool,
+}
+
+impl TempFile {
+    /// The temporary file of `to`. `replaces` when a destination that exists by the time of the rename is replaced
+    /// (`--if-exists overwrite`, or `newer` with an older destination)
+    pub fn new(to: &Path, replaces: bool) -> Self {
+        let name = to.file_name().unwrap_or(OsStr::new("file"));
+        let mut temp = OsString::from(PREFIX);
+        if name.len() > MAX_NAME {
+            temp.push(&blake3::hash(name.as_encoded_bytes()).to_hex()[..32]);
+        } else {
+            temp.push(name);
+        }
+        temp.push(format!(".{}-{}", run_id(), WRITTEN.fetch_add(1, Ordering::Relaxed)));
+        Self { path: to.with_file_name(temp), replaces, renamed: false }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Rename the complete file to `to`. False when `to` appeared in the meantime and is kept by `if_exists`, an error
+    /// with `--if-exists error`
+    pub async fn commit(mut self, to: &Path, if_exists: IfExists) -> Result<bool> {
+        if !self.replaces {
+            // A link fails when the destination exists, unlike a rename
+            match tokio::fs::hard_link(&self.path, to).await {
+                Ok(()) => {
+                    self.renamed = true;
+                    tokio::fs::remove_file(&self.path).await
+                        .with_context(|| format!("Cannot remove the temporary file: {:?}", self.path))?;
+                    return Ok(true);
+                },
+                Err(error) if error.kind() == io::ErrorKind::AlreadyExists => return match if_exists {
+                    IfExists::Error => Err(anyhow!("Destination already exists: {:?}", to)),
+                    _ => Ok(false),
+                },
+                // Without hard links (FAT, some network filesystems) the rename checks first
+                Err(_) if tokio::fs::symlink_metadata(to).await.is_ok() => return match if_exists {
+                    IfExists::Error => Err(anyhow!("Destination already exists: {:?}", to)),
+                    _ => Ok(false),
+                },
+                Err(error) => debug!("Cannot link {:?}, renamed instead: {}", self.path, error),
+            }
+        }
+        tokio::fs::rename(&self.path, to).await
+            .with_context(|| format!("Cannot rename {:?} to {:?}", self.path, to))?;
+        self.renamed = true;
+        Ok(true)
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.renamed {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Remove the temporary files that runs before this one left in `dir`
+pub async fn remove_leftovers(dir: &Path) -> Result<()> {
+    let mut entries = tokio::fs::read_dir(dir).await
+        .with_context(|| format!("Cannot list directory: {:?}", dir))?;
+    let current = format!(".{}-", run_id());
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name();
+        let Some(name) = name.to_str() else { continue };
+        if !name.starts_with(PREFIX) || !entry.file_type().await.is_ok_and(|file_type| file_type.is_file()) {
+            continue;
+        }
+        if name.rsplit_once('.').is_some_and(|(_, suffix)| format!(".{}", suffix).starts_with(&current)) {
+            continue;
+        }
+        tokio::fs::remove_file(entry.path()).await
+            .with_context(|| format!("Cannot remove the temporary file of an earlier run: {:?}", entry.path()))?;
+        info!("Removed the temporary file of an earlier run: {:?}", entry.path());
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::test_support::init;
+    use crate::IfExists;
+    use super::{remove_leftovers, TempFile, PREFIX};
+
+    #[tokio::test]
+    async fn temporary_files() {
+        let base_dir = init("temporary_files").await;
+
+        let to = base_dir.join("file");
+        let temp = TempFile::new(&to, false);
+        assert_eq!(temp.path().parent(), Some(base_dir.as_path()));
+        assert!(temp.path().file_name().unwrap().to_str().unwrap().starts_with(".rscopier-tmp.file."));
+        tokio::fs::write(temp.path(), "new").await.unwrap();
+        assert!(temp.commit(&to, IfExists::Skip).await.unwrap());
+        assert_eq!(tokio::fs::read_to_string(&to).await.unwrap(), "new");
+
+        // A destination that appeared while it was copied is kept, or replaced when it is overwritten
+        for (replaces, if_exists, content) in [(false, IfExists::Skip, "new"), (true, IfExists::Overwrite, "newer")] {
+            let temp = TempFile::new(&to, replaces);
+            tokio::fs::write(temp.path(), "newer").await.unwrap();
+            let path = temp.path().to_owned();
+            assert_eq!(temp.commit(&to, if_exists).await.unwrap(), replaces);
+            assert_eq!(tokio::fs::read_to_string(&to).await.unwrap(), content);
+            assert!(!path.exists());
+        }
+        let temp = TempFile::new(&to, false);
+        tokio::fs::write(temp.path(), "newer").await.unwrap();
+        assert!(temp.commit(&to, IfExists::Error).await.is_err());
+
+        // Dropped before the rename, like a cancelled copy
+        let temp = TempFile::new(&to, true);
+        tokio::fs::write(temp.path(), "part").await.unwrap();
+        let path = temp.path().to_owned();
+        drop(temp);
+        assert!(!path.exists());
+
+        // Only the files of other runs are leftovers
+        let current = TempFile::new(&base_dir.join("current"), true);
+        tokio::fs::write(current.path(), "part").await.unwrap();
+        let leftover = base_dir.join(format!("{}file.1f2e3d-7", PREFIX));
+        tokio::fs::write(&leftover, "part").await.unwrap();
+        remove_leftovers(&base_dir).await.unwrap();
+        assert!(!leftover.exists());
+        assert!(current.path().exists());
+        assert_eq!(std::fs::read_dir(&base_dir).unwrap().count(), 2);
+    }
+}
@@ -0,0 +1,132 @@
+//! Where the files are read and written. The walk and the copies only go through `Backend`: the local filesystem is the
+//! default, `sftp://` destinations are written through SFTP and the tests can walk and copy an in-memory tree
+
+use std::fmt::Debug;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::time::SystemTime;
+use anyhow::{Context, Result};
+use tokio::fs::ReadDir;
+use crate::{copy, CopyOptions};
+
+/// Whether the destination is an `sftp://` URL
+pub fn is_sftp(destination: &Path) -> bool {
+    destination.to_str().is_some_and(|destination| destination.starts_with("sftp://"))
+}
+
+pub type BackendFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T>> + Send + 'a>>;
+
+/// An entry of a directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub path: PathBuf,
+    /// A regular file, anything else is walked as a directory
+    pub is_file: bool,
+    /// A symbolic link, walked as a directory unless the links are preserved
+    pub is_symlink: bool,
+}
+
+/// What the copy needs to know about a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileInfo {
+    pub len: u64,
+    /// A directory or a link to one, which the copy can go into
+    pub is_dir: bool,
+    /// None where the platform has no modification time
+    pub modified: Option<SystemTime>,
+}
+
+/// The entries of a directory, read one by one
+pub trait DirEntries: Send {
+    /// The next entry, None at the end of the directory. After an error the next call goes on with the rest
+    fn next_entry(&mut self) -> BackendFuture<'_, Option<DirEntry>>;
+}
+
+/// The filesystem operations of a copy
+pub trait Backend: Debug + Send + Sync {
+    fn read_dir<'a>(&'a self, dir: &'a Path) -> BackendFuture<'a, Box<dyn DirEntries>>;
+    /// The metadata of a file (not followed when it is a link, only is_dir tells where it leads)
+    fn metadata<'a>(&'a self, path: &'a Path) -> BackendFuture<'a, FileInfo>;
+    /// Create the directory and its missing parents
+    fn create_dir_all<'a>(&'a self, dir: &'a Path) -> BackendFuture<'a, ()>;
+    /// Flush the entries of the directory
+    fn sync_directory<'a>(&'a self, dir: &'a Path) -> BackendFuture<'a, ()>;
+    /// Copy the file `from` (read from the local filesystem unless the backend is both the source and the destination)
+    /// to `to` and finish it: stats, metadata and removal of the source.
+    /// The source is returned when its removal must wait for the flush of the destination directory
+    fn copy<'a>(&'a self, from: &'a Path, to: &'a Path, options: &'a CopyOptions) -> BackendFuture<'a, Option<PathBuf>>;
+    fn remove_file<'a>(&'a self, path: &'a Path) -> BackendFuture<'a, ()>;
+    /// Remove a directory with everything in it
+    fn remove_dir_all<'a>(&'a self, dir: &'a Path) -> BackendFuture<'a, ()>;
+}
+
+/// The local filesystem through `tokio::fs`
+#[derive(Debug)]
+pub struct LocalBackend;
+
+struct LocalEntries {
+    dir: PathBuf,
+    entries: ReadDir,
+}
+
+impl DirEntries for LocalEntries {
+    fn next_entry(&mut self) -> BackendFuture<'_, Option<DirEntry>> {
+        Box::pin(async move {
+            let Some(entry) = self.entries.next_entry().await
+                .with_context(|| format!("Cannot read entry of {:?}", self.dir))? else {
+                return Ok(None);
+            };
+            let file_type = entry.file_type().await
+                .with_context(|| format!("Cannot get file type: {:?}", entry.path()))?;
+            Ok(Some(DirEntry { path: entry.path(), is_file: file_type.is_file(), is_symlink: file_type.is_symlink() }))
+        })
+    }
+}
+
+impl Backend for LocalBackend {
+    fn read_dir<'a>(&'a self, dir: &'a Path) -> BackendFuture<'a, Box<dyn DirEntries>> {
+        Box::pin(async move {
+            let entries = tokio::fs::read_dir(dir).await?;
+            Ok(Box::new(LocalEntries { dir: dir.to_owned(), entries }) as Box<dyn DirEntries>)
+        })
+    }
+
+    fn metadata<'a>(&'a self, path: &'a Path) -> BackendFuture<'a, FileInfo> {
+        Box::pin(async move {
+            let metadata = tokio::fs::symlink_metadata(path).await?;
+            let is_dir = metadata.is_dir()
+                || (metadata.is_symlink() && tokio::fs::metadata(path).await.is_ok_and(|target| target.is_dir()));
+            Ok(FileInfo { len: metadata.len(), is_dir, modified: metadata.modified().ok() })
+        })
+    }
+
+    fn create_dir_all<'a>(&'a self, dir: &'a Path) -> BackendFuture<'a, ()> {
+        Box::pin(async move {
+            tokio::fs::create_dir_all(dir).await
+                .with_context(|| format!("Cannot create directory: {:?}", dir))
+        })
+    }
+
+    fn sync_directory<'a>(&'a self, dir: &'a Path) -> BackendFuture<'a, ()> {
+        Box::pin(copy::sync_directory(dir))
+    }
+
+    fn copy<'a>(&'a self, from: &'a Path, to: &'a Path, options: &'a CopyOptions) -> BackendFuture<'a, Option<PathBuf>> {
+        Box::pin(crate::process_file(from, to, options))
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a Path) -> BackendFuture<'a, ()> {
+        Box::pin(async move {
+            tokio::fs::remove_file(path).await
+                .with_context(|| format!("Cannot remove file: {:?}", path))
+        })
+    }
+
+    fn remove_dir_all<'a>(&'a self, dir: &'a Path) -> BackendFuture<'a, ()> {
+        Box::pin(async move {
+            tokio::fs::remove_dir_all(dir).await
+                .with_context(|| format!("Cannot remove directory: {:?}", dir))
+        })
+    }
+}
@@ -0,0 +1,242 @@
+//! `rs-copier bench`: a synthetic tree copied into the destination with every configuration of a sweep (concurrency
+//! levels and buffer sizes) to choose them. Every run goes through the real copy, only the source is made up
+
+use std::fmt::Write;
+use std::path::Path;
+use std::time::Duration;
+use anyhow::{Context, Result};
+use tokio::io::AsyncWriteExt;
+use crate::size;
+
+/// Bytes written at once while generating the files
+const WRITE_CHUNK: usize = 1 << 20;
+
+/// The tree and the sweep of `rs-copier bench`
+#[derive(clap::Args, Debug, Clone)]
+pub struct BenchArgs {
+    /// Files of the synthetic tree
+    #[clap(long, value_parser, default_value = "1000")]
+    pub files: u64,
+    /// Files in every directory of the tree
+    #[clap(long, value_parser, default_value = "100")]
+    pub files_per_dir: u64,
+    /// Smallest file, the sizes are spread evenly on a log scale up to --max-size
+    #[clap(long, value_parser = size::parse_size, default_value = "4KiB")]
+    pub min_size: u64,
+    /// Largest file
+    #[clap(long, value_parser = size::parse_size, default_value = "1MiB")]
+    pub max_size: u64,
+    /// Comma separated concurrencies of the sweep
+    #[clap(long, value_parser, value_delimiter = ',', default_value = "1,2,4,8,16")]
+    pub concurrency_levels: Vec<usize>,
+    /// Comma separated buffer sizes of the sweep, only --buffer-size when not given
+    #[clap(long, value_parser = size::parse_size, value_delimiter = ',')]
+    pub buffer_sizes: Vec<u64>,
+    /// Print the results as JSON instead of a table
+    #[clap(long, value_parser)]
+    pub json: bool,
+}
+
+impl BenchArgs {
+    /// Reject the trees and sweeps that cannot be run
+    pub fn check(&self) -> Result<()> {
+        if self.files == 0 || self.files_per_dir == 0 {
+            return Err(anyhow::anyhow!("--files and --files-per-dir must be at least 1"));
+        }
+        if self.min_size > self.max_size {
+            return Err(anyhow::anyhow!("--min-size is bigger than --max-size"));
+        }
+        if self.concurrency_levels.is_empty() || self.concurrency_levels.contains(&0) {
+            return Err(anyhow::anyhow!("The concurrency levels must be at least 1"));
+        }
+        Ok(())
+    }
+
+    /// The largest concurrency of the sweep, the runtime is sized for it
+    pub fn max_concurrency(&self) -> usize {
+        self.concurrency_levels.iter().copied().max().unwrap_or(1)
+    }
+
+    /// The (concurrency, buffer size) of every run, `buffer_size` when no buffer size is swept
+    pub fn configurations(&self, buffer_size: u64) -> Vec<(usize, u64)> {
+        let buffer_sizes = if self.buffer_sizes.is_empty() { vec![buffer_size] } else { self.buffer_sizes.clone() };
+        buffer_sizes.iter()
+            .flat_map(|&buffer_size| self.concurrency_levels.iter().map(move |&concurrency| (concurrency, buffer_size)))
+            .collect()
+    }
+
+    /// The size of the `index`th file. The same arguments always give the same tree, so the runs compare
+    fn file_size(&self, index: u64) -> u64 {
+        let fraction = (splitmix64(index) >> 11) as f64 / (1u64 << 53) as f64;
+        let (min, max) = (self.min_size.max(1) as f64, self.max_size.max(1) as f64);
+        ((min * (max / min).powf(fraction)) as u64).clamp(self.min_size, self.max_size)
+    }
+}
+
+/// A step of splitmix64, enough to spread the sizes and fill the files
+fn splitmix64(seed: u64) -> u64 {
+    let mut value = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    value = (value ^ (value >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    value = (value ^ (value >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    value ^ (value >> 31)
+}
+
+/// The files and bytes of the synthetic tree
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tree {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+/// Write the synthetic tree into `dir`, which must not exist. The content is random so that no compression or
+/// deduplication of the destination helps
+pub async fn generate(dir: &Path, bench: &BenchArgs) -> Result<Tree> {
+    let content: Vec<u8> = (0..WRITE_CHUNK as u64 / 8).flat_map(|word| splitmix64(word).to_le_bytes()).collect();
+    let mut tree = Tree { files: 0, bytes: 0 };
+    for index in 0..bench.files {
+        let subdir = dir.join(format!("dir-{:04}", index / bench.files_per_dir));
+        if index % bench.files_per_dir == 0 {
+            tokio::fs::create_dir_all(&subdir).await
+                .with_context(|| format!("Cannot create directory: {:?}", subdir))?;
+        }
+        let path = subdir.join(format!("file-{:06}", index));
+        let mut file = tokio::fs::File::create(&path).await
+            .with_context(|| format!("Cannot create file: {:?}", path))?;
+        let size = bench.file_size(index);
+        let mut written = 0;
+        while written < size {
+            let chunk = (size - written).min(WRITE_CHUNK as u64);
+            file.write_all(&content[..chunk as usize]).await
+                .with_context(|| format!("Cannot write file: {:?}", path))?;
+            written += chunk;
+        }
+        tree.files += 1;
+        tree.bytes += size;
+    }
+    Ok(tree)
+}
+
+/// A run of the sweep
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Measure {
+    pub concurrency: usize,
+    pub buffer_size: u64,
+    pub elapsed: Duration,
+}
+
+impl Measure {
+    pub fn bytes_per_second(&self, tree: Tree) -> f64 {
+        tree.bytes as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+
+    pub fn files_per_second(&self, tree: Tree) -> f64 {
+        tree.files as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+/// One line per run, for people
+pub fn table(tree: Tree, measures: &[Measure]) -> String {
+    let mut table = format!("{} files, {} bytes\n", tree.files, tree.bytes);
+    let _ = writeln!(table, "{:>11} {:>11} {:>9} {:>10} {:>10}", "concurrency", "buffer", "seconds", "MiB/s", "files/s");
+    for measure in measures {
+        let _ = writeln!(
+            table,
+            "{:>11} {:>11} {:>9.3} {:>10.2} {:>10.1}",
+            measure.concurrency,
+            measure.buffer_size,
+            measure.elapsed.as_secs_f64(),
+            measure.bytes_per_second(tree) / (1024.0 * 1024.0),
+            measure.files_per_second(tree),
+        );
+    }
+    table
+}
+
+/// The tree and the runs as JSON, for scripts
+pub fn to_json(tree: Tree, measures: &[Measure]) -> String {
+    let mut json = String::from("{\n");
+    let _ = writeln!(json, "  \"files\": {},", tree.files);
+    let _ = writeln!(json, "  \"bytes\": {},", tree.bytes);
+    json.push_str("  \"runs\": [");
+    for (index, measure) in measures.iter().enumerate() {
+        let _ = write!(
+            json,
+            "{}\n    {{\"concurrency\": {}, \"buffer_size\": {}, \"elapsed_seconds\": {:.3}, \"bytes_per_second\": {:.0}, \"files_per_second\": {:.1}}}",
+            if index == 0 { "" } else { "," },
+            measure.concurrency,
+            measure.buffer_size,
+            measure.elapsed.as_secs_f64(),
+            measure.bytes_per_second(tree),
+            measure.files_per_second(tree),
+        );
+    }
+    json.push_str(if measures.is_empty() { "]\n}\n" } else { "\n  ]\n}\n" });
+    json
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use clap::Parser;
+    use crate::test_support::init;
+    use super::{generate, to_json, BenchArgs, Measure, Tree};
+
+    /// The bench arguments alone
+    #[derive(Parser)]
+    struct Command {
+        #[clap(flatten)]
+        bench: BenchArgs,
+    }
+
+    fn bench_args(args: &[&str]) -> BenchArgs {
+        Command::parse_from(["bench"].iter().chain(args)).bench
+    }
+
+    #[tokio::test]
+    async fn synthetic_tree() {
+        let base_dir = init("synthetic_tree").await;
+
+        let bench = bench_args(&["--files", "25", "--files-per-dir", "10", "--min-size", "1K", "--max-size", "64K"]);
+        bench.check().unwrap();
+        let tree = generate(&base_dir.join("source"), &bench).await.unwrap();
+        assert_eq!(tree.files, 25);
+        let mut bytes = 0;
+        for dir in ["dir-0000", "dir-0001", "dir-0002"] {
+            let mut entries = tokio::fs::read_dir(base_dir.join("source").join(dir)).await.unwrap();
+            while let Some(entry) = entries.next_entry().await.unwrap() {
+                let len = entry.metadata().await.unwrap().len();
+                assert!((1024..=65536).contains(&len), "{}", len);
+                bytes += len;
+            }
+        }
+        assert_eq!(tree.bytes, bytes);
+        // The sizes are spread, and the same every time
+        assert!((0..25).any(|index| bench.file_size(index) < 8192) && (0..25).any(|index| bench.file_size(index) > 8192));
+        assert_eq!(bench.file_size(7), bench_args(&["--min-size", "1K", "--max-size", "64K"]).file_size(7));
+    }
+
+    #[test]
+    fn sweep() {
+        let bench = bench_args(&["--concurrency-levels", "1,4", "--buffer-sizes", "64K,1M"]);
+        assert_eq!(bench.configurations(1 << 20), [(1, 64 << 10), (4, 64 << 10), (1, 1 << 20), (4, 1 << 20)]);
+        assert_eq!(bench.max_concurrency(), 4);
+        assert_eq!(bench_args(&[]).configurations(256 << 10).len(), 5);
+        assert!(bench_args(&["--concurrency-levels", "0,2"]).check().is_err());
+        assert!(bench_args(&["--min-size", "2M"]).check().is_err());
+    }
+
+    #[test]
+    fn json() {
+        let tree = Tree { files: 10, bytes: 2048 };
+        let measures = [
+            Measure { concurrency: 1, buffer_size: 1024, elapsed: Duration::from_secs(2) },
+            Measure { concurrency: 4, buffer_size: 1024, elapsed: Duration::from_millis(500) },
+        ];
+        let json = to_json(tree, &measures);
+        assert!(json.contains("\"files\": 10,\n"), "{}", json);
+        assert!(json.contains("{\"concurrency\": 1, \"buffer_size\": 1024, \"elapsed_seconds\": 2.000, \"bytes_per_second\": 1024, \"files_per_second\": 5.0},\n"), "{}", json);
+        assert!(json.contains("\"files_per_second\": 20.0}\n  ]\n}\n"), "{}", json);
+        assert_eq!(to_json(tree, &[]), "{\n  \"files\": 10,\n  \"bytes\": 2048,\n  \"runs\": []\n}\n");
+    }
+}
@@ -0,0 +1,154 @@
+//! Directories whose names differ only by their case (`Docs` and `docs`). A destination that ignores the case (the
+//! default of Windows and macOS) takes them as the same directory, mixing their files under one name or another.
+//! There the second one fails, and nothing below it is copied. `--case-fold-merge lower` (or `upper`) gives every
+//! directory of the destination its name in that case instead, so the variants are merged on purpose, with a warning
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use anyhow::{anyhow, Result};
+use log::warn;
+use crate::space;
+use crate::CaseFold;
+
+/// The destination directories of the run by their name in lower case, with the source directory that got them first
+#[derive(Debug, Default)]
+pub struct CaseFolds {
+    fold: Option<CaseFold>,
+    claimed: Mutex<HashMap<String, (PathBuf, PathBuf)>>,
+}
+
+impl CaseFolds {
+    /// Folding the names to `fold`, or failing the variants without it
+    pub fn new(fold: Option<CaseFold>) -> Self {
+        Self { fold, claimed: Mutex::default() }
+    }
+
+    /// Whether the names are folded, not only checked
+    pub fn merges(&self) -> bool {
+        self.fold.is_some()
+    }
+
+    /// The relative destination of a directory, with its names in the case of --case-fold-merge
+    pub fn fold<'a>(&self, relative: Cow<'a, Path>) -> Cow<'a, Path> {
+        let Some(fold) = self.fold else { return relative };
+        let folded: PathBuf = relative.iter()
+            .map(|name| match (name.to_str(), fold) {
+                (Some(name), CaseFold::Lower) => name.to_lowercase().into(),
+                (Some(name), CaseFold::Upper) => name.to_uppercase().into(),
+                // Kept as it is when it is not text
+                (None, _) => name.to_owned(),
+            })
+            .collect();
+        if folded == *relative {
+            relative
+        } else {
+            Cow::Owned(folded)
+        }
+    }
+
+    /// Record that the source directory `source` lands in `dest`. Another source that landed in `dest` with another
+    /// case is an error, and a warning when they are merged on purpose
+    pub fn claim(&self, source: &Path, dest: &Path) -> Result<()> {
+        let key = dest.to_string_lossy().to_lowercase();
+        let mut claimed = self.claimed.lock().unwrap();
+        let Some((first_source, first_dest)) = claimed.get(&key) else {
+            claimed.insert(key, (source.to_owned(), dest.to_owned()));
+            return Ok(());
+        };
+        if first_source == source {
+            return Ok(());
+        }
+        if first_dest != dest {
+            return Err(anyhow!(
+                "The directory {:?} has the name of {:?} but with another case, the destination takes them as the same \
+                directory (--case-fold-merge merges them)",
+                source, first_source,
+            ));
+        }
+        // Not only a --strip-components that lands them in the same place
+        if self.fold.is_some() && first_source.to_string_lossy().to_lowercase() == source.to_string_lossy().to_lowercase() {
+            warn!("The directories {:?} and {:?} are merged into {:?}", first_source, source, dest);
+        }
+        Ok(())
+    }
+}
+
+/// Whether the filesystem of `dest` (or of its nearest existing parent) ignores the case of the names: that directory
+/// is found with its name in another case too, and it is the same one. False when it cannot be told
+pub fn ignores_case(dest: &Path) -> bool {
+    let Some(existing) = space::existing_ancestor(dest).canonicalize().ok() else { return false };
+    let Some(dir) = existing.ancestors().find(|dir| dir.file_name().is_some_and(|name| swapped_case(name).is_some())) else {
+        return false;
+    };
+    let swapped = dir.with_file_name(swapped_case(dir.file_name().expect("it has a name")).expect("it has letters"));
+    match (std::fs::metadata(dir), std::fs::metadata(swapped)) {
+        (Ok(found), Ok(other)) => same_file(&found, &other),
+        _ => false,
+    }
+}
+
+/// `name` with the case of its letters swapped, None when that does not change it
+fn swapped_case(name: &std::ffi::OsStr) -> Option<String> {
+    let name = name.to_str()?;
+    let swapped: String = name.chars()
+        .map(|char| if char.is_lowercase() { char.to_ascii_uppercase() } else { char.to_ascii_lowercase() })
+        .collect();
+    (swapped != name).then_some(swapped)
+}
+
+#[cfg(unix)]
+fn same_file(first: &std::fs::Metadata, second: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    first.dev() == second.dev() && first.ino() == second.ino()
+}
+
+/// Elsewhere the case-insensitive filesystems are the usual ones
+#[cfg(not(unix))]
+fn same_file(_first: &std::fs::Metadata, _second: &std::fs::Metadata) -> bool {
+    true
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+    use std::path::Path;
+    use crate::test_support::init;
+    use crate::CaseFold;
+    use super::{ignores_case, CaseFolds};
+
+    #[test]
+    fn folded_names() {
+        let folds = CaseFolds::new(Some(CaseFold::Lower));
+        assert_eq!(folds.fold(Cow::Borrowed(Path::new("Docs/Naïve/ÉTÉ"))), Path::new("docs/naïve/été"));
+        assert!(matches!(folds.fold(Cow::Borrowed(Path::new("docs"))), Cow::Borrowed(_)));
+        assert_eq!(CaseFolds::new(Some(CaseFold::Upper)).fold(Cow::Borrowed(Path::new("Docs/a"))), Path::new("DOCS/A"));
+        assert_eq!(CaseFolds::new(None).fold(Cow::Borrowed(Path::new("Docs"))), Path::new("Docs"));
+
+        // Only the variants of a name collide
+        let folds = CaseFolds::new(None);
+        folds.claim(Path::new("/source/Docs"), Path::new("/dest/Docs")).unwrap();
+        folds.claim(Path::new("/source/Docs"), Path::new("/dest/Docs")).unwrap();
+        folds.claim(Path::new("/source/other/Docs"), Path::new("/dest/other/Docs")).unwrap();
+        let error = folds.claim(Path::new("/source/docs"), Path::new("/dest/docs")).unwrap_err();
+        assert!(error.to_string().starts_with("The directory \"/source/docs\" has the name of \"/source/Docs\" but with another case"), "{}", error);
+        let folds = CaseFolds::new(Some(CaseFold::Lower));
+        folds.claim(Path::new("/source/Docs"), Path::new("/dest/docs")).unwrap();
+        folds.claim(Path::new("/source/docs"), Path::new("/dest/docs")).unwrap();
+    }
+
+    #[tokio::test]
+    async fn case_sensitive_destination() {
+        let base_dir = init("case_sensitive_destination").await;
+
+        // The tests run on a filesystem that tells the case apart
+        if cfg!(target_os = "linux") {
+            assert!(!ignores_case(&base_dir.join("missing/dest")));
+            tokio::fs::create_dir(base_dir.join("Dest")).await.unwrap();
+            tokio::fs::create_dir(base_dir.join("dEST")).await.unwrap();
+            assert!(!ignores_case(&base_dir.join("Dest")));
+        }
+    }
+}
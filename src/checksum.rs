@@ -0,0 +1,174 @@
+//! Streaming checksums of the copied files
+
+use std::fmt;
+use std::path::Path;
+use anyhow::Result;
+use clap::ValueEnum;
+use serde::Deserialize;
+use tokio::io::AsyncReadExt;
+use crate::limit::Bandwidth;
+use crate::xxh3::Xxh3;
+
+/// Size of the buffer used to hash a file from disk
+const BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Supported checksum algorithms
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ChecksumAlgorithm {
+    Md5,
+    Sha1,
+    Sha256,
+    #[default]
+    Blake3,
+    /// Not cryptographic, the fastest to compare a copy with its source
+    Xxh3,
+}
+
+impl fmt::Display for ChecksumAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Md5 => "md5",
+            Self::Sha1 => "sha1",
+            Self::Sha256 => "sha256",
+            Self::Blake3 => "blake3",
+            Self::Xxh3 => "xxh3",
+        };
+        f.write_str(name)
+    }
+}
+
+impl ChecksumAlgorithm {
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::from_str(name, true).ok()
+    }
+}
+
+/// An incremental implementation of an algorithm
+trait Digester: Send {
+    fn update(&mut self, data: &[u8]);
+    fn finish(self: Box<Self>) -> Vec<u8>;
+}
+
+/// Algorithms implemented with the RustCrypto `Digest` trait
+struct RustCrypto<D>(D);
+
+impl<D: sha2::Digest + Send> Digester for RustCrypto<D> {
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    fn finish(self: Box<Self>) -> Vec<u8> {
+        self.0.finalize().to_vec()
+    }
+}
+
+impl Digester for blake3::Hasher {
+    fn update(&mut self, data: &[u8]) {
+        blake3::Hasher::update(self, data);
+    }
+
+    fn finish(self: Box<Self>) -> Vec<u8> {
+        self.finalize().as_bytes().to_vec()
+    }
+}
+
+impl Digester for Xxh3 {
+    fn update(&mut self, data: &[u8]) {
+        Xxh3::update(self, data);
+    }
+
+    fn finish(self: Box<Self>) -> Vec<u8> {
+        self.digest().to_be_bytes().to_vec()
+    }
+}
+
+/// Incremental hasher fed with the bytes as they are copied
+pub struct Hasher(Box<dyn Digester>);
+
+impl Hasher {
+    pub fn new(algorithm: ChecksumAlgorithm) -> Self {
+        match algorithm {
+            ChecksumAlgorithm::Md5 => Self(Box::new(RustCrypto(md5::Md5::default()))),
+            ChecksumAlgorithm::Sha1 => Self(Box::new(RustCrypto(sha1::Sha1::default()))),
+            ChecksumAlgorithm::Sha256 => Self(Box::new(RustCrypto(sha2::Sha256::default()))),
+            ChecksumAlgorithm::Blake3 => Self(Box::new(blake3::Hasher::new())),
+            ChecksumAlgorithm::Xxh3 => Self(Box::new(Xxh3::new())),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    /// Lowercase hexadecimal digest
+    pub fn finish(self) -> String {
+        self.0.finish().iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+}
+
+/// Hash the whole content of a file
+pub async fn hash_file(path: &Path, algorithm: ChecksumAlgorithm) -> Result<String> {
+    hash_reader(tokio::fs::File::open(path).await?, algorithm, None).await
+}
+
+/// Hash everything `file` has left to read, within the `bandwidth` limit when there is one
+pub async fn hash_reader(mut file: tokio::fs::File, algorithm: ChecksumAlgorithm, bandwidth: Option<&Bandwidth>) -> Result<String> {
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+    let mut hasher = Hasher::new(algorithm);
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        if let Some(bandwidth) = bandwidth {
+            bandwidth.acquire(read).await;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hasher.finish())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{hash_file, ChecksumAlgorithm, Hasher};
+    use crate::test_support::init;
+
+    #[test]
+    fn known_vectors() {
+        let vectors = [
+            (ChecksumAlgorithm::Md5, "900150983cd24fb0d6963f7d28e17f72"),
+            (ChecksumAlgorithm::Sha1, "a9993e364706816aba3e25717850c26c9cd0d89d"),
+            (ChecksumAlgorithm::Sha256, "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"),
+            (ChecksumAlgorithm::Blake3, "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"),
+            (ChecksumAlgorithm::Xxh3, "78af5f94892f3950"),
+        ];
+        for (algorithm, expected) in vectors {
+            let mut hasher = Hasher::new(algorithm);
+            hasher.update(b"abc");
+            assert_eq!(hasher.finish(), expected, "{}", algorithm);
+            assert_eq!(ChecksumAlgorithm::from_name(&algorithm.to_string()), Some(algorithm));
+        }
+    }
+
+    #[tokio::test]
+    async fn streaming_equals_whole_file() {
+        let base_dir = init("streaming_equals_whole_file").await;
+
+        let file = base_dir.join("file");
+        tokio::fs::write(&file, "text").await.unwrap();
+
+        for algorithm in [ChecksumAlgorithm::Md5, ChecksumAlgorithm::Sha1, ChecksumAlgorithm::Sha256, ChecksumAlgorithm::Blake3, ChecksumAlgorithm::Xxh3] {
+            let mut hasher = Hasher::new(algorithm);
+            hasher.update(b"te");
+            hasher.update(b"xt");
+            let digest = hasher.finish();
+
+            if algorithm == ChecksumAlgorithm::Sha256 {
+                assert_eq!(digest, "982d9e3eb996f559e633f4d194def3761d909f5a3b647d1a851fead67c32c9d1");
+            }
+            assert_eq!(hash_file(&file, algorithm).await.unwrap(), digest);
+        }
+    }
+}
@@ -0,0 +1,176 @@
+//! `--checksum-cache`: the checksums of the sources are kept in a file between runs, with the size and modification
+//! time of the file they were computed from. A source with the same size and time is not read again, any change of
+//! either hashes it again. The first line names the algorithm (`# algorithm: blake3`), a cache of another algorithm
+//! is discarded. Then every line has the format `digest  size  mtime  /absolute/path`, with the mtime in
+//! nanoseconds since the epoch
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+use anyhow::{anyhow, Context, Result};
+use log::info;
+use crate::checksum::{self, ChecksumAlgorithm};
+use crate::paths;
+
+/// Prefix of the line with the name of the algorithm
+const ALGORITHM_HEADER: &str = "# algorithm: ";
+
+/// The file a checksum was computed from
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Entry {
+    size: u64,
+    /// Nanoseconds since the epoch
+    modified: u128,
+    digest: String,
+}
+
+/// The cache of a run, loaded at the start and saved at the end
+#[derive(Debug)]
+pub struct ChecksumCache {
+    algorithm: ChecksumAlgorithm,
+    entries: Mutex<BTreeMap<PathBuf, Entry>>,
+    /// Files read to compute their checksum, the ones the cache did not have
+    hashed: AtomicU64,
+}
+
+impl ChecksumCache {
+    /// Load the cache file `path`, empty when it does not exist yet
+    pub async fn load(path: &Path, algorithm: ChecksumAlgorithm) -> Result<Self> {
+        let content = match tokio::fs::read_to_string(path).await {
+            Ok(content) => content,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(error) => return Err(error).with_context(|| format!("Cannot read the checksum cache: {:?}", path)),
+        };
+        let entries = parse(&content, algorithm).with_context(|| format!("Invalid checksum cache: {:?}", path))?;
+        Ok(Self { algorithm, entries: Mutex::new(entries), hashed: AtomicU64::new(0) })
+    }
+
+    /// The checksum of the file `path`, from the cache when the file did not change since it was computed
+    pub async fn hash_file(&self, path: &Path) -> Result<String> {
+        let metadata = tokio::fs::metadata(path).await?;
+        // Without a modification time a change cannot be told, the file is always read
+        let modified = metadata.modified().ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|modified| modified.as_nanos());
+        let key = std::path::absolute(path)?;
+        if let Some(modified) = modified {
+            let cached = self.entries.lock().unwrap().get(&key)
+                .filter(|entry| entry.size == metadata.len() && entry.modified == modified)
+                .map(|entry| entry.digest.clone());
+            if let Some(digest) = cached {
+                return Ok(digest);
+            }
+        }
+        self.hashed.fetch_add(1, Ordering::Relaxed);
+        let digest = checksum::hash_file(path, self.algorithm).await?;
+        let mut entries = self.entries.lock().unwrap();
+        match modified {
+            Some(modified) => entries.insert(key, Entry { size: metadata.len(), modified, digest: digest.clone() }),
+            None => entries.remove(&key),
+        };
+        Ok(digest)
+    }
+
+    /// Files that had to be read
+    pub fn hashed(&self) -> u64 {
+        self.hashed.load(Ordering::Relaxed)
+    }
+
+    /// Write the cache to `path`, replacing the file at once so an interrupted save keeps the previous one
+    pub async fn save(&self, path: &Path) -> Result<()> {
+        let mut content = format!("{}{}\n", ALGORITHM_HEADER, self.algorithm);
+        for (file, entry) in self.entries.lock().unwrap().iter() {
+            writeln!(content, "{}  {}  {}  {}", entry.digest, entry.size, entry.modified, paths::escape(file))?;
+        }
+        let mut next = path.as_os_str().to_owned();
+        next.push(".next");
+        tokio::fs::write(&next, content).await
+            .with_context(|| format!("Cannot write the checksum cache: {:?}", path))?;
+        tokio::fs::rename(&next, path).await
+            .with_context(|| format!("Cannot write the checksum cache: {:?}", path))
+    }
+}
+
+/// The entries of a cache file, none when it was written with another algorithm
+fn parse(content: &str, algorithm: ChecksumAlgorithm) -> Result<BTreeMap<PathBuf, Entry>> {
+    let mut entries = BTreeMap::new();
+    for (number, line) in content.lines().enumerate().filter(|(_, line)| !line.is_empty()) {
+        let invalid = || anyhow!("Invalid line {}: {:?}", number + 1, line);
+        if let Some(name) = line.strip_prefix(ALGORITHM_HEADER) {
+            if ChecksumAlgorithm::from_name(name.trim()).ok_or_else(invalid)? != algorithm {
+                info!("The checksum cache has {} checksums, it is started over with {}", name.trim(), algorithm);
+                return Ok(BTreeMap::new());
+            }
+            continue;
+        }
+        let mut fields = line.splitn(4, "  ");
+        let (Some(digest), Some(size), Some(modified), Some(file)) = (fields.next(), fields.next(), fields.next(), fields.next()) else {
+            return Err(invalid());
+        };
+        let entry = Entry {
+            size: size.parse().map_err(|_| invalid())?,
+            modified: modified.parse().map_err(|_| invalid())?,
+            digest: digest.to_owned(),
+        };
+        entries.insert(paths::unescape(file), entry);
+    }
+    Ok(entries)
+}
+
+/// The checksum of a source, through the cache when there is one
+pub async fn hash_source(path: &Path, algorithm: ChecksumAlgorithm, cache: Option<&ChecksumCache>) -> Result<String> {
+    match cache {
+        Some(cache) => cache.hash_file(path).await,
+        None => checksum::hash_file(path, algorithm).await,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::checksum::{self, ChecksumAlgorithm};
+    use crate::test_support::init;
+    use super::ChecksumCache;
+
+    #[tokio::test]
+    async fn cached_checksums() {
+        let base_dir = init("cached_checksums").await;
+
+        let (file, path) = (base_dir.join("file"), base_dir.join("cache"));
+        tokio::fs::write(&file, "content").await.unwrap();
+        let digest = checksum::hash_file(&file, ChecksumAlgorithm::Blake3).await.unwrap();
+
+        let cache = ChecksumCache::load(&path, ChecksumAlgorithm::Blake3).await.unwrap();
+        assert_eq!(cache.hash_file(&file).await.unwrap(), digest);
+        assert_eq!(cache.hash_file(&file).await.unwrap(), digest);
+        assert_eq!(cache.hashed(), 1);
+        cache.save(&path).await.unwrap();
+
+        // The next run does not read the unchanged file
+        let cache = ChecksumCache::load(&path, ChecksumAlgorithm::Blake3).await.unwrap();
+        assert_eq!(cache.hash_file(&file).await.unwrap(), digest);
+        assert_eq!(cache.hashed(), 0);
+
+        // Another size is read again
+        tokio::fs::write(&file, "other content").await.unwrap();
+        assert_eq!(cache.hash_file(&file).await.unwrap(), checksum::hash_file(&file, ChecksumAlgorithm::Blake3).await.unwrap());
+        assert_eq!(cache.hashed(), 1);
+        // And so is another time with the same size
+        let modified = std::fs::metadata(&file).unwrap().modified().unwrap();
+        tokio::fs::write(&file, "OTHER content").await.unwrap();
+        std::fs::File::options().write(true).open(&file).unwrap()
+            .set_modified(modified + std::time::Duration::from_secs(1)).unwrap();
+        assert_eq!(cache.hash_file(&file).await.unwrap(), checksum::hash_file(&file, ChecksumAlgorithm::Blake3).await.unwrap());
+        assert_eq!(cache.hashed(), 2);
+
+        // A cache of another algorithm starts over
+        cache.save(&path).await.unwrap();
+        let cache = ChecksumCache::load(&path, ChecksumAlgorithm::Sha256).await.unwrap();
+        cache.hash_file(&file).await.unwrap();
+        assert_eq!(cache.hashed(), 1);
+        assert!(ChecksumCache::load(&file, ChecksumAlgorithm::Blake3).await.is_err());
+    }
+}
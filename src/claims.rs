@@ -0,0 +1,29 @@
+//! Destinations written during the run.
+//! Flattening the tree (`--strip-components`) can map several sources to the same destination
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::sync::OwnedMutexGuard;
+
+#[derive(Debug, Default)]
+pub struct Claims(Mutex<HashMap<PathBuf, Arc<tokio::sync::Mutex<()>>>>);
+
+impl Claims {
+    /// Claim the destination `to`. Returns whether another source already claimed it and a guard
+    /// that keeps the copies to the same destination from overlapping
+    pub async fn claim(&self, to: &Path) -> (bool, OwnedMutexGuard<()>) {
+        let (claimed, lock) = {
+            let mut claims = self.0.lock().unwrap();
+            match claims.get(to) {
+                Some(lock) => (true, lock.clone()),
+                None => {
+                    let lock = Arc::new(tokio::sync::Mutex::new(()));
+                    claims.insert(to.to_owned(), lock.clone());
+                    (false, lock)
+                }
+            }
+        };
+        (claimed, lock.lock_owned().await)
+    }
+}
@@ -0,0 +1,420 @@
+//! `--config`: the options of a run loaded from a TOML file.
+//! The keys are the names of the `Args` fields (`delete_source`, `chunk_threshold`, ...).
+//! Precedence: the flags given in the command line, then the config file, then the defaults
+
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use anyhow::{Context, Result};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches};
+use serde::{de, Deserialize, Deserializer};
+use crate::adaptive::{self, Concurrency};
+use crate::checksum::ChecksumAlgorithm;
+use crate::hook::{self, HookCommand};
+use crate::metadata::PreserveFlags;
+use crate::priority::{self, IoNice};
+use crate::rename::{self, RenamePattern};
+use crate::{filter, metadata, size, Args, CaseFold, DanglingLinks, DedupeMethod, Engine, ErrorMode, IfExists, OnTypeConflict, Order, Reflink, SkipUnchanged, SourceBase, Volatile};
+
+/// Declare the config keys and how each one is merged into `Args`:
+/// `value` fields are replaced, `optional` fields are set to `Some`
+macro_rules! config {
+    ($($kind:ident $field:ident: $type:ty),* $(,)?) => {
+        /// Every key is optional, unknown keys are rejected
+        #[derive(Debug, Default, Deserialize)]
+        #[serde(deny_unknown_fields)]
+        pub struct Config {
+            $($field: Option<$type>,)*
+        }
+
+        impl Config {
+            /// Set the values of the file that were not given in the command line
+            fn merge_into(self, args: &mut Args, matches: &ArgMatches) {
+                $(
+                    if let Some(value) = self.$field {
+                        if !from_command_line(matches, stringify!($field)) {
+                            config!(@set $kind args.$field, value);
+                        }
+                    }
+                )*
+            }
+        }
+    };
+    (@set value $target:expr, $value:expr) => { $target = $value.into() };
+    (@set optional $target:expr, $value:expr) => { $target = Some($value.into()) };
+}
+
+config! {
+    optional source: PathBuf,
+    optional destination: PathBuf,
+    value delete_source: bool,
+    value force_source_cleanup: bool,
+    optional concurrency: ConcurrencyValue,
+    optional list_concurrency: usize,
+    optional runtime_threads: usize,
+    optional blocking_threads: usize,
+    optional workers_per_disk: usize,
+    value chunk_parallelism: usize,
+    value chunk_threshold: Size,
+    value verify_big_files: bool,
+    value resume: bool,
+    optional tempdir: PathBuf,
+    optional preserve: Preserve,
+    value archive: bool,
+    value preserve_attributes: bool,
+    value preserve_crtime: bool,
+    value preserve_acls: bool,
+    value relativize_links: bool,
+    value follow_top_level_links: bool,
+    value dangling_links: DanglingLinks,
+    optional manifest: PathBuf,
+    value dedupe_dest: bool,
+    value dedupe_method: DedupeMethod,
+    optional stats_file: PathBuf,
+    optional metrics_file: PathBuf,
+    optional error_report: PathBuf,
+    optional checksum_algorithm: ChecksumAlgorithm,
+    optional checksum_cache: PathBuf,
+    optional journal: PathBuf,
+    value journal_compact: bool,
+    optional verify_manifest: PathBuf,
+    value verify_after: bool,
+    value verify: bool,
+    value ignore_errors: bool,
+    value stop_on_error: bool,
+    optional error_mode: ErrorMode,
+    value max_errors: u64,
+    value grace_period: Period,
+    value no_fsync: bool,
+    value fsync: bool,
+    value fsync_batch: usize,
+    value atomic: bool,
+    value strip_components: usize,
+    value sanitize_names: bool,
+    optional case_fold_merge: CaseFold,
+    optional rename_pattern: Rename,
+    value if_exists: IfExists,
+    value modify_window: Period,
+    value on_type_conflict: OnTypeConflict,
+    optional skip_unchanged: SkipUnchanged,
+    optional as_name: PathBuf,
+    value source_base: SourceBase,
+    value reflink: Reflink,
+    value copy_engine: Engine,
+    value buffer_size: Size,
+    value pipeline_depth: usize,
+    optional bwlimit: Rate,
+    optional max_files_per_sec: u64,
+    value rate_limit_deletions: bool,
+    value list_only: bool,
+    value list_sizes: bool,
+    value deterministic: bool,
+    optional chmod: Mode,
+    optional chmod_dirs: Mode,
+    value preallocate: bool,
+    value direct_io: bool,
+    optional exclude_newer_than: Time,
+    optional exclude_older_than: Time,
+    value include_empty_dirs: bool,
+    value no_include_empty_dirs: bool,
+    value small_file_threshold: Size,
+    value prescan: bool,
+    value order: Order,
+    optional max_pending: usize,
+    value dereference_source: bool,
+    value no_prescan: bool,
+    optional prescan_timeout: Period,
+    value human_readable: bool,
+    value progress_json: bool,
+    optional checkpoint_every: u64,
+    optional files_from: PathBuf,
+    value null_separated: bool,
+    optional file_timeout: Period,
+    optional io_nice: IoNiceValue,
+    optional nice: i32,
+    optional timeout_per_gb: Period,
+    value retries: u32,
+    value retry_delay: Period,
+    value volatile: Volatile,
+    value detect_changes: bool,
+    value no_detect_changes: bool,
+    optional trash: PathBuf,
+    value interactive: bool,
+    value yes: bool,
+    value force: bool,
+    value no_space_check: bool,
+    optional min_free_space: Size,
+    optional post_copy_hook: Hook,
+    value hook_concurrency: usize,
+    value hook_must_succeed: bool,
+    optional hook_timeout: Period,
+}
+
+/// Whether the argument of the field was given in the command line
+fn from_command_line(matches: &ArgMatches, field: &str) -> bool {
+    matches.value_source(field.replace('_', "-")) == Some(ValueSource::CommandLine)
+}
+
+/// Parse the command line and merge it with the config file given with `--config`
+pub fn parse_args<I, T>(command_line: I) -> Result<Args>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString> + Clone,
+{
+    let matches = Args::command().get_matches_from(command_line);
+    let mut args = Args::from_arg_matches(&matches)?;
+    if let Some(path) = &args.config {
+        load(path)?.merge_into(&mut args, &matches);
+    }
+    Ok(args)
+}
+
+fn load(path: &Path) -> Result<Config> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Cannot read the config file: {:?}", path))?;
+    toml::from_str(&content).with_context(|| format!("Invalid config file: {:?}", path))
+}
+
+/// A number of copies or `"auto"`
+#[derive(Debug)]
+struct ConcurrencyValue(Concurrency);
+
+/// A time like `60s` (before now) or `2024-05-01 13:00:00`
+#[derive(Debug)]
+struct Time(SystemTime);
+
+/// A number of seconds or a duration like `5m`
+#[derive(Debug)]
+struct Period(Duration);
+
+/// A number of bytes or a size like `256MiB`
+#[derive(Debug)]
+struct Size(u64);
+
+/// A number of bytes per second or a rate like `50MB/s`
+#[derive(Debug)]
+struct Rate(u64);
+
+/// A list of what is preserved like `"timestamps,mode"`
+#[derive(Debug)]
+struct Preserve(PreserveFlags);
+
+/// A template or `"s/find/replace/"`
+#[derive(Debug)]
+struct Rename(RenamePattern);
+
+/// A command line like `"scan --quiet {dest}"`
+#[derive(Debug)]
+struct Hook(HookCommand);
+
+/// `"idle"` or `"best-effort:N"`
+#[derive(Debug)]
+struct IoNiceValue(IoNice);
+
+/// A mode: an octal string like `"644"` or a TOML octal integer like `0o644`
+#[derive(Debug)]
+struct Mode(u32);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum NumberOrText<N> {
+    Number(N),
+    Text(String),
+}
+
+fn number_or_text<'de, D, N>(deserializer: D, parse: fn(&str) -> Result<N, String>) -> Result<N, D::Error>
+where
+    D: Deserializer<'de>,
+    N: Deserialize<'de>,
+{
+    match NumberOrText::deserialize(deserializer)? {
+        NumberOrText::Number(number) => Ok(number),
+        NumberOrText::Text(text) => parse(&text).map_err(de::Error::custom),
+    }
+}
+
+impl<'de> Deserialize<'de> for Size {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        number_or_text(deserializer, size::parse_size).map(Self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Rate {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        number_or_text(deserializer, size::parse_rate).map(Self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Mode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        number_or_text(deserializer, metadata::parse_mode).map(Self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Preserve {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        metadata::parse_preserve(&text).map(Self).map_err(de::Error::custom)
+    }
+}
+
+impl<'de> Deserialize<'de> for Rename {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        rename::parse_rename_pattern(&text).map(Self).map_err(de::Error::custom)
+    }
+}
+
+impl<'de> Deserialize<'de> for Hook {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        hook::parse_hook_command(&text).map(Self).map_err(de::Error::custom)
+    }
+}
+
+impl<'de> Deserialize<'de> for IoNiceValue {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        priority::parse_io_nice(&text).map(Self).map_err(de::Error::custom)
+    }
+}
+
+impl<'de> Deserialize<'de> for Time {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        filter::parse_time(&text).map(Self).map_err(de::Error::custom)
+    }
+}
+
+impl<'de> Deserialize<'de> for Period {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match NumberOrText::<u64>::deserialize(deserializer)? {
+            NumberOrText::Number(seconds) => Ok(Self(Duration::from_secs(seconds))),
+            NumberOrText::Text(text) => size::parse_duration(&text).map(Self).map_err(de::Error::custom),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ConcurrencyValue {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match NumberOrText::<usize>::deserialize(deserializer)? {
+            NumberOrText::Number(copies) => Ok(Self(Concurrency::Fixed(copies))),
+            NumberOrText::Text(text) => adaptive::parse_concurrency(&text).map(Self).map_err(de::Error::custom),
+        }
+    }
+}
+
+impl From<ConcurrencyValue> for Concurrency {
+    fn from(value: ConcurrencyValue) -> Self {
+        value.0
+    }
+}
+
+impl From<Period> for Duration {
+    fn from(period: Period) -> Self {
+        period.0
+    }
+}
+
+impl From<Time> for SystemTime {
+    fn from(time: Time) -> Self {
+        time.0
+    }
+}
+
+impl From<Size> for u64 {
+    fn from(size: Size) -> Self {
+        size.0
+    }
+}
+
+impl From<Rate> for u64 {
+    fn from(rate: Rate) -> Self {
+        rate.0
+    }
+}
+
+impl From<Preserve> for PreserveFlags {
+    fn from(preserve: Preserve) -> Self {
+        preserve.0
+    }
+}
+
+impl From<IoNiceValue> for IoNice {
+    fn from(value: IoNiceValue) -> Self {
+        value.0
+    }
+}
+
+impl From<Rename> for RenamePattern {
+    fn from(rename: Rename) -> Self {
+        rename.0
+    }
+}
+
+impl From<Hook> for HookCommand {
+    fn from(hook: Hook) -> Self {
+        hook.0
+    }
+}
+
+impl From<Mode> for u32 {
+    fn from(mode: Mode) -> Self {
+        mode.0
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use super::parse_args;
+    use crate::adaptive::Concurrency;
+    use crate::IfExists;
+    use crate::test_support::init;
+
+    #[tokio::test]
+    async fn command_line_overrides_config() {
+        let base_dir = init("command_line_overrides_config").await;
+
+        let config = base_dir.join("config.toml");
+        tokio::fs::write(&config, r#"
+            source = "from_config"
+            destination = "from_config"
+            concurrency = 4
+            chunk_threshold = "1MiB"
+            if_exists = "skip"
+            chmod = "640"
+            chmod_dirs = 0o750
+            delete_source = true
+            as_name = "from_config"
+        "#).await.unwrap();
+
+        let config = config.to_str().unwrap();
+        let args = parse_args(["rs-copier", "--config", config, "--destination", "from_cli", "--concurrency", "8", "--as", "from_cli"]).unwrap();
+
+        assert_eq!(args.source.as_deref(), Some(Path::new("from_config")));
+        assert_eq!(args.destination.as_deref(), Some(Path::new("from_cli")));
+        assert_eq!(args.concurrency, Some(Concurrency::Fixed(8)));
+        assert_eq!(args.as_name.as_deref(), Some(Path::new("from_cli")));
+        assert_eq!(args.chunk_threshold, 1024 * 1024);
+        assert_eq!(args.if_exists, IfExists::Skip);
+        assert_eq!(args.chmod, Some(0o640));
+        assert_eq!(args.chmod_dirs, Some(0o750));
+        assert!(args.delete_source);
+        // Neither the command line nor the config set it
+        assert_eq!(args.buffer_size, 1024 * 1024);
+    }
+
+    #[tokio::test]
+    async fn unknown_config_keys() {
+        let base_dir = init("unknown_config_keys").await;
+
+        let config = base_dir.join("config.toml");
+        tokio::fs::write(&config, "concurency = 4\n").await.unwrap();
+
+        let error = parse_args(["rs-copier", "--config", config.to_str().unwrap()]).unwrap_err();
+        assert!(format!("{:#}", error).contains("concurency"), "{:#}", error);
+    }
+}
@@ -0,0 +1,42 @@
+//! `--interactive`: the deletions of `--delete-source` are confirmed on the terminal before anything is copied.
+//! `--yes` answers for the scripts, and nothing is asked when the standard input is not a terminal
+
+use std::io::{self, BufRead, Write};
+
+/// Ask `question` on `output` and read the answer from `input`. Only `y` or `yes` confirm, anything else (and the
+/// end of the input) does not
+pub fn ask(question: &str, input: &mut dyn BufRead, output: &mut dyn Write) -> io::Result<bool> {
+    write!(output, "{} [y/N] ", question)?;
+    output.flush()?;
+    let mut answer = String::new();
+    input.read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes"))
+}
+
+/// The question before deleting the sources, with the number of files when the pre-scan counted them
+pub fn deletion_question(files: Option<u64>) -> String {
+    match files {
+        Some(files) => format!("About to delete {} source files, continue?", files),
+        None => "About to delete the source files, continue?".to_owned(),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use super::{ask, deletion_question};
+
+    #[test]
+    fn answers() {
+        let question = deletion_question(Some(3));
+        let mut output = vec![];
+        assert!(ask(&question, &mut Cursor::new("y\n"), &mut output).unwrap());
+        assert_eq!(String::from_utf8(output).unwrap(), "About to delete 3 source files, continue? [y/N] ");
+        assert!(ask(&question, &mut Cursor::new(" YES \n"), &mut vec![]).unwrap());
+        assert!(!ask(&question, &mut Cursor::new("n\n"), &mut vec![]).unwrap());
+        assert!(!ask(&question, &mut Cursor::new("\n"), &mut vec![]).unwrap());
+        // Nothing to read is a no
+        assert!(!ask(&question, &mut Cursor::new(""), &mut vec![]).unwrap());
+    }
+}
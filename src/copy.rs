@@ -0,0 +1,913 @@
+//! Routines that copy a single file from the source to the destination
+
+use std::fs::File;
+use std::future::Future;
+use std::io;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use anyhow::{anyhow, Context, Result};
+use log::debug;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use crate::checksum::{self, Hasher};
+use crate::direct::{self, Direct};
+use crate::limit::Bandwidth;
+use crate::{checksum_cache, filter, reflink, resume, CopyOptions, Engine, IfExists, Reflink};
+
+/// Size of the buffer used by the streaming and chunked copies
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BufferSize(pub usize);
+
+impl Default for BufferSize {
+    /// Same value as the `--buffer-size` default
+    fn default() -> Self {
+        Self(1024 * 1024)
+    }
+}
+
+/// Buffers of the streamed copy that can be read ahead while the previous ones are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PipelineDepth(pub usize);
+
+impl Default for PipelineDepth {
+    /// Same value as the `--pipeline-depth` default
+    fn default() -> Self {
+        Self(2)
+    }
+}
+
+/// Most bytes asked to a single copy_file_range call, bigger files take several calls
+#[cfg(target_os = "linux")]
+const KERNEL_COPY_CHUNK: usize = if cfg!(test) { 64 * 1024 } else { 1 << 30 };
+
+/// A successful copy
+#[derive(Debug)]
+pub struct Copied {
+    /// Number of bytes copied
+    pub bytes: u64,
+    /// Checksum of the content, only computed when some feature needs it
+    pub digest: Option<String>,
+    /// The file was cloned with a reflink instead of copying its bytes
+    pub cloned: bool,
+}
+
+/// A successful move
+#[derive(Debug)]
+pub enum Moved {
+    /// Source and destination are in the same filesystem, the file was just renamed
+    Renamed,
+    /// The file was copied, the source is still there
+    Copied(Copied),
+}
+
+/// Move the file `from` to `to` with a rename. When they are in different filesystems
+/// the file is copied instead, into `written` (`to` or its temporary file), and the caller has to remove the source
+pub async fn move_file(from: &Path, to: &Path, written: &Path, options: &CopyOptions) -> Result<Moved> {
+    let renamed = tokio::fs::rename(from, to).await;
+    move_after_rename(from, written, options, renamed).await
+}
+
+async fn move_after_rename(from: &Path, written: &Path, options: &CopyOptions, renamed: io::Result<()>) -> Result<Moved> {
+    match renamed {
+        Ok(()) => Ok(Moved::Renamed),
+        Err(error) if is_cross_device(&error) => {
+            debug!("Cannot rename across filesystems, copy instead: {:?}", from);
+            Ok(Moved::Copied(copy_file(from, written, options).await?))
+        },
+        Err(error) => Err(error.into()),
+    }
+}
+
+/// Whether a rename failed because source and destination are in different filesystems
+#[cfg(unix)]
+pub fn is_cross_device(error: &io::Error) -> bool {
+    error.raw_os_error() == Some(libc::EXDEV)
+}
+
+#[cfg(not(unix))]
+pub fn is_cross_device(error: &io::Error) -> bool {
+    error.kind() == io::ErrorKind::CrossesDevices
+}
+
+/// Copies the bytes of a single file. The walk, the filters and the removal of the sources are the same whatever the
+/// engine, only how the content goes from `from` to `to` changes
+pub trait CopyEngine: Sync {
+    fn copy<'a>(&'a self, from: &'a Path, to: &'a Path, options: &'a CopyOptions) -> Pin<Box<dyn Future<Output = Result<Copied>> + Send + 'a>>;
+}
+
+/// The default engine: chunked, direct, streamed or system copies depending on the options
+pub struct Standard;
+
+impl CopyEngine for Standard {
+    fn copy<'a>(&'a self, from: &'a Path, to: &'a Path, options: &'a CopyOptions) -> Pin<Box<dyn Future<Output = Result<Copied>> + Send + 'a>> {
+        Box::pin(copy_bytes(from, to, options))
+    }
+}
+
+/// The implementation of the selected engine
+fn engine(engine: Engine) -> &'static dyn CopyEngine {
+    match engine {
+        #[cfg(all(feature = "uring", target_os = "linux"))]
+        Engine::Uring => &crate::uring::Uring,
+        // Builds without io_uring reject it when the options are parsed
+        _ => &Standard,
+    }
+}
+
+/// Copy the file `from` to `to`.
+/// It is cloned first when reflinks are enabled, the bytes are only copied (by the selected engine) if the filesystem
+/// cannot clone it and reflinks are not mandatory. With --resume the big files are copied through a `.part` instead
+pub async fn copy_file(from: &Path, to: &Path, options: &CopyOptions) -> Result<Copied> {
+    if options.reflink != Reflink::Never {
+        match reflink::clone_file(from, to).await {
+            Ok(bytes) => {
+                debug!("Cloned: {:?} to {:?}", from, to);
+                if options.fsync {
+                    sync_file(to).await.context("Cannot sync file")?;
+                }
+                let digest = source_digest(from, options).await?;
+                return Ok(Copied { bytes, digest, cloned: true });
+            },
+            Err(error) if options.reflink == Reflink::Auto && reflink::is_unsupported(&error) => {
+                debug!("Cannot clone, copy instead: {:?}: {}", from, error);
+            },
+            Err(error) => return Err(anyhow::Error::new(error).context("Cannot clone file")),
+        }
+    }
+    if options.resume {
+        let len = tokio::fs::metadata(from).await?.len();
+        if len > options.chunk_threshold {
+            debug!("Resumable copy: {:?} to {:?}", from, to);
+            return resume::copy_file(from, to, len, options).await;
+        }
+    }
+    engine(options.copy_engine).copy(from, to, options).await
+}
+
+/// Check that the copy `written` of `from` has all the bytes of its source: the count the copy returned and the size
+/// of `written` on the disk. A destination close to full can take fewer bytes without failing the writes
+pub async fn check_size(from: &Path, written: &Path, copied: u64) -> Result<()> {
+    let expected = tokio::fs::metadata(from).await
+        .with_context(|| format!("Cannot read metadata: {:?}", from))?.len();
+    let actual = tokio::fs::metadata(written).await
+        .with_context(|| format!("Cannot read metadata: {:?}", written))?.len();
+    if copied != expected || actual != expected {
+        return Err(anyhow!(
+            "The copy of {:?} has {} bytes ({} copied) instead of the {} of its source", from, actual, copied, expected,
+        ));
+    }
+    Ok(())
+}
+
+/// The checklist of a copy right before --delete-source deletes its source: the copy wrote all the bytes of the
+/// source (`copied`) and the destination `to` is there, with that size, after everything that followed the copy
+/// (the rename of --atomic, the metadata, a hook). The copies that --verify finds different or that --fsync cannot
+/// flush already failed before. A copy that is not confirmed keeps its source and fails
+pub async fn confirm_copy(from: &Path, to: &Path, copied: u64) -> Result<()> {
+    check_size(from, to, copied).await
+        .with_context(|| format!("The copy of {:?} is not confirmed, its source is kept", from))
+}
+
+/// Copy the bytes with the standard engine.
+/// Files bigger than the chunk threshold are copied in parallel ranges when chunk parallelism is enabled.
+/// With direct I/O the rest of files bypass the page cache, unless the filesystem does not support it.
+/// On Linux the auto engine copies the bytes inside the kernel with copy_file_range unless they must go through the
+/// process (digest, bandwidth), falling back to the other copies when the files cannot use it.
+/// The streaming copy is used when a digest is needed (the content is hashed while it is copied), when the bandwidth
+/// is limited, when the destination is preallocated or flushed (through the handle that wrote it) or when it is the
+/// selected engine
+async fn copy_bytes(from: &Path, to: &Path, options: &CopyOptions) -> Result<Copied> {
+    if options.chunk_parallelism > 1 {
+        let len = tokio::fs::metadata(from).await?.len();
+        if len > options.chunk_threshold {
+            let bytes = copy_chunked(from, to, len, options).await?;
+            // Ranges are copied out of order so the source has to be hashed on its own
+            let digest = source_digest(from, options).await?;
+            if options.verify_chunked {
+                let source_digest = match &digest {
+                    Some(digest) => digest.clone(),
+                    None => checksum_cache::hash_source(from, options.checksum_algorithm, options.checksum_cache.as_deref()).await?,
+                };
+                if checksum::hash_file(to, options.checksum_algorithm).await? != source_digest {
+                    return Err(anyhow!("The checksum of the chunked copy does not match the source"));
+                }
+            }
+            return Ok(Copied { bytes, digest, cloned: false });
+        }
+    }
+    if options.direct_io {
+        let settings = direct::Settings {
+            buffer_size: options.buffer_size,
+            bandwidth: options.bandwidth.clone(),
+            fsync: options.fsync,
+            hasher: options.needs_digest().then(|| Hasher::new(options.checksum_algorithm)),
+        };
+        let (source, dest) = (from.to_owned(), to.to_owned());
+        match tokio::task::spawn_blocking(move || direct::copy(&source, &dest, settings)).await?? {
+            Direct::Copied { bytes, digest } => {
+                debug!("Copied with direct I/O: {:?} to {:?}", from, to);
+                return Ok(Copied { bytes, digest, cloned: false });
+            },
+            Direct::Unsupported(error) => direct::warn_unsupported(&error),
+        }
+    }
+    #[cfg(target_os = "linux")]
+    if options.copy_engine == Engine::Auto && !options.needs_digest() && options.bandwidth.is_none() {
+        if let Some(bytes) = copy_in_kernel(from, to, options).await? {
+            debug!("Kernel copy (copy_file_range): {:?} to {:?}", from, to);
+            return Ok(Copied { bytes, digest: None, cloned: false });
+        }
+    }
+    let stream = options.needs_digest() || options.bandwidth.is_some() || options.preallocate || options.fsync;
+    if stream || options.copy_engine == Engine::Stream {
+        debug!("Streamed copy: {:?} to {:?}", from, to);
+        let mut hasher = options.needs_digest().then(|| Hasher::new(options.checksum_algorithm));
+        let bytes = copy_stream(from, to, options, hasher.as_mut()).await?;
+        return Ok(Copied { bytes, digest: hasher.map(Hasher::finish), cloned: false });
+    }
+    debug!("System copy: {:?} to {:?}", from, to);
+    Ok(Copied { bytes: tokio::fs::copy(from, to).await?, digest: None, cloned: false })
+}
+
+/// Copy (or move, with options.remove_source) a small file with plain blocking calls: a rename or the system copy,
+/// flushed with options.fsync. None when options.if_exists keeps the existing destination
+pub fn copy_small_file(from: &Path, to: &Path, options: &CopyOptions) -> Result<Option<Moved>> {
+    if options.if_exists != IfExists::Overwrite {
+        if let Ok(dest) = std::fs::symlink_metadata(to) {
+            match options.if_exists {
+                IfExists::Error => return Err(anyhow!("Destination already exists: {:?}", to)),
+                IfExists::Newer if filter::is_outdated(&std::fs::metadata(from)?, &dest, options.modify_window) => {},
+                _ => return Ok(None),
+            }
+        }
+    }
+    debug!("Small file copy: {:?} to {:?}", from, to);
+    if options.remove_source {
+        match std::fs::rename(from, to) {
+            Ok(()) => return Ok(Some(Moved::Renamed)),
+            Err(error) if is_cross_device(&error) => {},
+            Err(error) => return Err(anyhow::Error::new(error).context(format!("Cannot move file: {:?}", from))),
+        }
+    }
+    let bytes = std::fs::copy(from, to).with_context(|| format!("Cannot copy file: {:?}", from))?;
+    if options.fsync {
+        File::open(to).and_then(|file| file.sync_all()).with_context(|| format!("Cannot sync file: {:?}", to))?;
+    }
+    Ok(Some(Moved::Copied(Copied { bytes, digest: None, cloned: false })))
+}
+
+/// Hash the source when a digest is needed but it could not be computed while copying
+async fn source_digest(from: &Path, options: &CopyOptions) -> Result<Option<String>> {
+    if options.needs_digest() {
+        Ok(Some(checksum_cache::hash_source(from, options.checksum_algorithm, options.checksum_cache.as_deref()).await?))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Flush a file that was not written through a handle of ours (a clone) to the disk
+pub async fn sync_file(to: &Path) -> Result<()> {
+    tokio::fs::File::open(to).await?.sync_all().await?;
+    Ok(())
+}
+
+/// Flush a directory so the entries created in it are persisted.
+/// Only Unix can open a directory to flush it, elsewhere the entries are persisted with the files
+pub async fn sync_directory(dir: &Path) -> Result<()> {
+    #[cfg(unix)]
+    tokio::fs::File::open(dir).await?.sync_all().await?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+/// Create the link `to` pointing to `target`, like the source link `from`
+#[cfg(unix)]
+pub async fn symlink(target: &Path, _from: &Path, to: &Path) -> Result<()> {
+    tokio::fs::symlink(target, to).await?;
+    Ok(())
+}
+
+/// Windows links are created for a file or for a directory, like the one the source link points to
+#[cfg(windows)]
+pub async fn symlink(target: &Path, from: &Path, to: &Path) -> Result<()> {
+    if tokio::fs::metadata(from).await.is_ok_and(|metadata| metadata.is_dir()) {
+        tokio::fs::symlink_dir(target, to).await?;
+    } else {
+        tokio::fs::symlink_file(target, to).await?;
+    }
+    Ok(())
+}
+
+#[cfg(not(any(unix, windows)))]
+pub async fn symlink(_target: &Path, from: &Path, _to: &Path) -> Result<()> {
+    Err(anyhow::anyhow!("Links cannot be created in this platform: {:?}", from))
+}
+
+/// Copy the file reading and writing it with a buffer of `options.buffer_size` bytes.
+/// Up to options.pipeline_depth buffers are read ahead while the previous ones are written, so the source and the
+/// destination are busy at the same time.
+/// The bytes are fed to the hasher on the way and every chunk waits for the bandwidth limit before it is written.
+/// Errors report the offset where they happened
+async fn copy_stream(from: &Path, to: &Path, options: &CopyOptions, mut hasher: Option<&mut Hasher>) -> Result<u64> {
+    let mut source = tokio::fs::File::open(from).await?;
+    let metadata = source.metadata().await?;
+    let mut dest = tokio::fs::File::create(to).await?;
+    if options.preallocate {
+        let file = dest.try_clone().await?.into_std().await;
+        let len = metadata.len();
+        match tokio::task::spawn_blocking(move || preallocate(&file, len)).await? {
+            Ok(()) => {},
+            Err(error) if is_preallocation_unsupported(&error) => {
+                debug!("Cannot preallocate, copy anyway: {:?}: {}", to, error);
+            },
+            Err(error) => return Err(anyhow::Error::new(error).context("Cannot preallocate file")),
+        }
+    }
+    // The reader fills the free buffers while the writer empties the filled ones, in order
+    let depth = options.pipeline_depth.0.max(1);
+    let (filled_sender, mut filled) = tokio::sync::mpsc::channel::<(Vec<u8>, usize)>(depth);
+    let (free_sender, mut free) = tokio::sync::mpsc::channel(depth);
+    for _ in 0..depth {
+        free_sender.try_send(vec![0u8; options.buffer_size.0]).expect("the channel has room for every buffer");
+    }
+    let reader = async move {
+        let mut offset = 0;
+        while let Some(mut buffer) = free.recv().await {
+            // Short reads are fine, only 0 means the end of the file
+            let read = source.read(&mut buffer).await
+                .with_context(|| format!("Read error at offset {}", offset))?;
+            if read == 0 || filled_sender.send((buffer, read)).await.is_err() {
+                break;
+            }
+            offset += read as u64;
+        }
+        // Dropping the sender tells the writer that everything was read
+        Ok::<_, anyhow::Error>(())
+    };
+    let writer = async {
+        let mut copied = 0;
+        while let Some((buffer, read)) = filled.recv().await {
+            if let Some(hasher) = hasher.as_mut() {
+                hasher.update(&buffer[..read]);
+            }
+            if let Some(bandwidth) = &options.bandwidth {
+                bandwidth.acquire(read).await;
+            }
+            dest.write_all(&buffer[..read]).await
+                .with_context(|| format!("Write error at offset {}", copied))?;
+            copied += read as u64;
+            // The reader may be done already
+            let _ = free_sender.send(buffer).await;
+        }
+        Ok(copied)
+    };
+    // The first error cancels the other side
+    let ((), copied) = tokio::try_join!(reader, writer)?;
+    dest.flush().await
+        .with_context(|| format!("Write error at offset {}", copied))?;
+    if options.preallocate {
+        // The source may have shrunk since the space was reserved
+        dest.set_len(copied).await?;
+    }
+    if options.fsync {
+        dest.sync_all().await.context("Cannot sync file")?;
+    }
+    tokio::fs::set_permissions(to, metadata.permissions()).await?;
+    Ok(copied)
+}
+
+/// Copy `from` to `to` inside the kernel, preallocated and flushed like the streamed copy. None when the files cannot
+/// use copy_file_range (another filesystem in old kernels, filesystems without it, not a regular file), nothing
+/// was copied and the caller copies the bytes another way
+#[cfg(target_os = "linux")]
+async fn copy_in_kernel(from: &Path, to: &Path, options: &CopyOptions) -> Result<Option<u64>> {
+    let (source, dest) = (from.to_owned(), to.to_owned());
+    let (reserve, fsync) = (options.preallocate, options.fsync);
+    tokio::task::spawn_blocking(move || {
+        let from = File::open(&source)?;
+        let metadata = from.metadata()?;
+        if !metadata.is_file() {
+            return Ok(None);
+        }
+        let to = File::create(&dest)?;
+        if reserve {
+            match preallocate(&to, metadata.len()) {
+                Ok(()) => {},
+                Err(error) if is_preallocation_unsupported(&error) => {
+                    debug!("Cannot preallocate, copy anyway: {:?}: {}", dest, error);
+                },
+                Err(error) => return Err(anyhow::Error::new(error).context("Cannot preallocate file")),
+            }
+        }
+        let copied = match copy_file_range_all(&from, &to, metadata.len(), KERNEL_COPY_CHUNK)? {
+            KernelCopy::Copied(copied) => copied,
+            KernelCopy::Unsupported(error) => {
+                debug!("Cannot copy in the kernel, copy the bytes instead: {:?}: {}", source, error);
+                return Ok(None);
+            },
+        };
+        if reserve {
+            // The source may have shrunk since the space was reserved
+            to.set_len(copied)?;
+        }
+        if fsync {
+            to.sync_all().context("Cannot sync file")?;
+        }
+        std::fs::set_permissions(&dest, metadata.permissions())?;
+        Ok(Some(copied))
+    }).await?
+}
+
+#[cfg(target_os = "linux")]
+enum KernelCopy {
+    Copied(u64),
+    /// The first call failed, nothing was copied
+    Unsupported(io::Error),
+}
+
+/// Copy the rest of `from` (of `len` bytes) into `to` with copy_file_range calls of up to `chunk` bytes, from and to
+/// the offsets of the files. The calls can copy less than asked, the loop goes on until the end of the file
+#[cfg(target_os = "linux")]
+fn copy_file_range_all(from: &File, to: &File, len: u64, chunk: usize) -> io::Result<KernelCopy> {
+    use std::os::unix::io::AsRawFd;
+    let mut copied = 0;
+    loop {
+        // SAFETY: both descriptors are open during the call, the null offsets use (and move) the ones of the files
+        let result = unsafe {
+            libc::copy_file_range(from.as_raw_fd(), std::ptr::null_mut(), to.as_raw_fd(), std::ptr::null_mut(), chunk, 0)
+        };
+        if result > 0 {
+            copied += result as u64;
+            continue;
+        }
+        if result == 0 {
+            // Some filesystems (procfs, sysfs) tell the end of files that have content
+            if copied == 0 && len > 0 {
+                return Ok(KernelCopy::Unsupported(io::ErrorKind::Unsupported.into()));
+            }
+            return Ok(KernelCopy::Copied(copied));
+        }
+        let error = io::Error::last_os_error();
+        match error.raw_os_error() {
+            Some(libc::EINTR) => {},
+            Some(libc::EXDEV | libc::ENOSYS | libc::EOPNOTSUPP | libc::EINVAL | libc::EPERM | libc::EBADF) if copied == 0 => {
+                return Ok(KernelCopy::Unsupported(error));
+            },
+            _ => return Err(error),
+        }
+    }
+}
+
+/// Reserve `len` bytes for the file so it is not fragmented while it grows
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn preallocate(file: &File, len: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let len = libc::off_t::try_from(len).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    // SAFETY: the descriptor is valid while the file is alive. The error is returned, errno is not set
+    match unsafe { libc::posix_fallocate(file.as_raw_fd(), 0, len) } {
+        0 => Ok(()),
+        code => Err(io::Error::from_raw_os_error(code)),
+    }
+}
+
+#[cfg(target_os = "macos")]
+pub fn preallocate(file: &File, len: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let mut store = libc::fstore_t {
+        fst_flags: libc::F_ALLOCATEALL,
+        fst_posmode: libc::F_PEOFPOSMODE,
+        fst_offset: 0,
+        fst_length: libc::off_t::try_from(len).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?,
+        fst_bytesalloc: 0,
+    };
+    // SAFETY: the descriptor is valid while the file is alive and store outlives the call
+    if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_PREALLOCATE, &mut store) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+pub fn preallocate(file: &File, len: u64) -> io::Result<()> {
+    use std::os::windows::io::AsRawHandle;
+    use windows_sys::Win32::Storage::FileSystem::{FileAllocationInfo, SetFileInformationByHandle, FILE_ALLOCATION_INFO};
+    let info = FILE_ALLOCATION_INFO {
+        AllocationSize: i64::try_from(len).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?,
+    };
+    // SAFETY: the handle is valid while the file is alive and info outlives the call
+    let done = unsafe {
+        SetFileInformationByHandle(
+            file.as_raw_handle(),
+            FileAllocationInfo,
+            &info as *const FILE_ALLOCATION_INFO as *const _,
+            std::mem::size_of::<FILE_ALLOCATION_INFO>() as u32,
+        )
+    };
+    if done == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos", windows)))]
+pub fn preallocate(_file: &File, _len: u64) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Whether the preallocation failed because the filesystem (or the platform) does not support it
+pub fn is_preallocation_unsupported(error: &io::Error) -> bool {
+    #[cfg(unix)]
+    if let Some(code) = error.raw_os_error() {
+        return [libc::EOPNOTSUPP, libc::ENOTSUP, libc::EINVAL, libc::ENOSYS].contains(&code);
+    }
+    error.kind() == io::ErrorKind::Unsupported
+}
+
+/// Copy a file of `len` bytes splitting it in up to `options.chunk_parallelism` ranges. Every range is copied
+/// in the blocking pool using positional reads and writes into the pre-allocated destination.
+/// The streams count against the copy permits: the file already holds one and the extra streams only take the
+/// permits that are free, so small files are not starved and big files never wait for each other
+async fn copy_chunked(from: &Path, to: &Path, len: u64, options: &CopyOptions) -> Result<u64> {
+    let extra_permits = options.copy_permits.try_acquire_up_to(options.chunk_parallelism - 1);
+    let streams = extra_permits.len() + 1;
+    debug!("Chunked copy: {:?} to {:?} using {} ranges", from, to, streams);
+    let source = tokio::fs::File::open(from).await?;
+    let permissions = source.metadata().await?.permissions();
+    let source = Arc::new(source.into_std().await);
+    let dest = tokio::fs::File::create(to).await?;
+    dest.set_len(len).await?;
+    let dest = Arc::new(dest.into_std().await);
+
+    let buffer_size = options.buffer_size;
+    let handles: Vec<_> = chunk_ranges(len, streams)
+        .into_iter()
+        .map(|(offset, size)| {
+            let source = source.clone();
+            let dest = dest.clone();
+            let bandwidth = options.bandwidth.clone();
+            let handle = tokio::task::spawn_blocking(move || {
+                copy_range(&source, &dest, offset, size, buffer_size, bandwidth.as_deref())
+            });
+            (offset, size, handle)
+        })
+        .collect();
+
+    let mut copied = 0;
+    for (offset, size, handle) in handles {
+        let written = handle.await?
+            .with_context(|| format!("Range error at offset {}", offset))?;
+        if written != size {
+            return Err(anyhow!("The range at offset {} copied {} of {} bytes", offset, written, size));
+        }
+        copied += written;
+    }
+    drop(extra_permits);
+    if copied != len {
+        return Err(anyhow!("The chunked copy wrote {} of {} bytes", copied, len));
+    }
+    if options.fsync {
+        tokio::task::spawn_blocking(move || dest.sync_all()).await?.context("Cannot sync file")?;
+    }
+    tokio::fs::set_permissions(to, permissions).await?;
+    Ok(copied)
+}
+
+/// Split `len` bytes in at most `parts` contiguous `(offset, size)` ranges.
+/// The ranges cover the whole file and never overlap
+fn chunk_ranges(len: u64, parts: usize) -> Vec<(u64, u64)> {
+    let parts = (parts.max(1) as u64).min(len.max(1));
+    let base = len / parts;
+    let extra = len % parts;
+    let mut offset = 0;
+    (0..parts)
+        .map(|i| {
+            let size = if i < extra { base + 1 } else { base };
+            let range = (offset, size);
+            offset += size;
+            range
+        })
+        .collect()
+}
+
+/// Copy `size` bytes starting at `offset` from source to dest (same offset in both files)
+fn copy_range(
+    source: &File,
+    dest: &File,
+    offset: u64,
+    size: u64,
+    buffer_size: BufferSize,
+    bandwidth: Option<&Bandwidth>,
+) -> io::Result<u64> {
+    let mut buffer = vec![0u8; buffer_size.0.min(size as usize)];
+    let mut done = 0;
+    while done < size {
+        let want = (size - done).min(buffer.len() as u64) as usize;
+        let read = read_at(source, &mut buffer[..want], offset + done)?;
+        if read == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("source ended at offset {}", offset + done),
+            ));
+        }
+        if let Some(bandwidth) = bandwidth {
+            bandwidth.acquire_blocking(read);
+        }
+        write_all_at(dest, &buffer[..read], offset + done)?;
+        done += read as u64;
+    }
+    Ok(done)
+}
+
+#[cfg(unix)]
+pub fn read_at(file: &File, buffer: &mut [u8], offset: u64) -> io::Result<usize> {
+    use std::os::unix::fs::FileExt;
+    file.read_at(buffer, offset)
+}
+
+#[cfg(windows)]
+pub fn read_at(file: &File, buffer: &mut [u8], offset: u64) -> io::Result<usize> {
+    use std::os::windows::fs::FileExt;
+    file.seek_read(buffer, offset)
+}
+
+#[cfg(unix)]
+pub fn write_all_at(file: &File, buffer: &[u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.write_all_at(buffer, offset)
+}
+
+#[cfg(windows)]
+pub fn write_all_at(file: &File, mut buffer: &[u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buffer.is_empty() {
+        let written = file.seek_write(buffer, offset)?;
+        if written == 0 {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write whole buffer"));
+        }
+        buffer = &buffer[written..];
+        offset += written as u64;
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use super::{chunk_ranges, confirm_copy, copy_file, move_after_rename, sync_directory, sync_file, BufferSize, Moved, PipelineDepth};
+    #[cfg(target_os = "linux")]
+    use {std::fs::File, super::{copy_file_range_all, KernelCopy, KERNEL_COPY_CHUNK}};
+    use crate::limit::Bandwidth;
+    use crate::{CopyOptions, CopyPermits, Engine, Reflink};
+    use crate::test_support::init;
+
+    #[test]
+    fn ranges_cover_the_file() {
+        for (len, parts) in [(0, 4), (1, 4), (10, 3), (1000, 7), (1 << 20, 8)] {
+            let ranges = chunk_ranges(len, parts);
+            assert!(ranges.len() <= parts);
+            let mut expected_offset = 0;
+            for (offset, size) in ranges {
+                assert_eq!(offset, expected_offset);
+                expected_offset += size;
+            }
+            assert_eq!(expected_offset, len);
+        }
+    }
+
+    #[tokio::test]
+    async fn chunked_copy() {
+        let base_dir = init("chunked_copy").await;
+
+        let source = base_dir.join("big_file");
+        let dest = base_dir.join("big_file_copy");
+        let content: Vec<u8> = (0..(5 * 1024 * 1024 + 123)).map(|i| (i % 251) as u8).collect();
+        tokio::fs::write(&source, &content).await.unwrap();
+
+        let options = CopyOptions { chunk_parallelism: 4, chunk_threshold: 1024, verify_chunked: true, ..Default::default() };
+        let copied = copy_file(&source, &dest, &options).await.unwrap();
+
+        assert_eq!(copied.bytes, content.len() as u64);
+        assert_eq!(tokio::fs::read(&dest).await.unwrap(), content);
+        // The extra streams gave their permits back
+        assert_eq!(options.copy_permits.available(), 10);
+    }
+
+    #[tokio::test]
+    async fn chunked_copy_without_free_permits() {
+        let base_dir = init("chunked_copy_without_free_permits").await;
+
+        let source = base_dir.join("big_file");
+        let dest = base_dir.join("big_file_copy");
+        let content: Vec<u8> = (0..(1024 * 1024 + 7)).map(|i| (i % 251) as u8).collect();
+        tokio::fs::write(&source, &content).await.unwrap();
+
+        // The only permit is held by the file itself: a single stream and no waiting
+        let copy_permits = CopyPermits::new(1);
+        let _permit = copy_permits.acquire().await;
+        let options = CopyOptions { chunk_parallelism: 4, chunk_threshold: 1024, copy_permits, ..Default::default() };
+        let copied = copy_file(&source, &dest, &options).await.unwrap();
+
+        assert_eq!(copied.bytes, content.len() as u64);
+        assert_eq!(tokio::fs::read(&dest).await.unwrap(), content);
+    }
+
+    #[tokio::test]
+    async fn sync_missing_destination() {
+        let base_dir = init("sync_missing_destination").await;
+
+        assert!(sync_file(&base_dir.join("file")).await.is_err());
+        tokio::fs::write(base_dir.join("file"), "text").await.unwrap();
+        sync_file(&base_dir.join("file")).await.unwrap();
+        sync_directory(&base_dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn confirmed_copies() {
+        let base_dir = init("confirmed_copies").await;
+
+        let (source, dest) = (base_dir.join("source"), base_dir.join("dest"));
+        tokio::fs::write(&source, "some text").await.unwrap();
+        assert!(confirm_copy(&source, &dest, 9).await.is_err());
+        tokio::fs::write(&dest, "some text").await.unwrap();
+        confirm_copy(&source, &dest, 9).await.unwrap();
+        // A short count, or a destination that lost bytes
+        assert!(confirm_copy(&source, &dest, 4).await.is_err());
+        tokio::fs::write(&dest, "some").await.unwrap();
+        let error = confirm_copy(&source, &dest, 9).await.unwrap_err();
+        assert_eq!(format!("{:#}", error), format!(
+            "The copy of {:?} is not confirmed, its source is kept: The copy of {:?} has 4 bytes (9 copied) instead of the 9 of its source",
+            source, source,
+        ));
+    }
+
+    #[tokio::test]
+    async fn move_across_filesystems() {
+        let base_dir = init("move_across_filesystems").await;
+
+        let source = base_dir.join("file");
+        let dest = base_dir.join("moved");
+        tokio::fs::write(&source, "text").await.unwrap();
+
+        #[cfg(unix)]
+        let cross_device = io::Error::from_raw_os_error(libc::EXDEV);
+        #[cfg(not(unix))]
+        let cross_device = io::Error::from(io::ErrorKind::CrossesDevices);
+        let moved = move_after_rename(&source, &dest, &Default::default(), Err(cross_device)).await.unwrap();
+
+        assert!(matches!(moved, Moved::Copied(copied) if copied.bytes == 4));
+        assert_eq!(tokio::fs::read_to_string(&dest).await.unwrap(), "text");
+        // Removing the source is up to the caller
+        assert!(source.exists());
+
+        let denied = io::Error::from(io::ErrorKind::PermissionDenied);
+        assert!(move_after_rename(&source, &dest, &Default::default(), Err(denied)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn reflink_modes() {
+        let base_dir = init("reflink_modes").await;
+
+        let source = base_dir.join("file");
+        tokio::fs::write(&source, "text").await.unwrap();
+
+        // Whether the clone works depends on the filesystem of the test directory, auto always succeeds
+        let options = CopyOptions { reflink: Reflink::Auto, ..Default::default() };
+        let auto = copy_file(&source, &base_dir.join("auto"), &options).await.unwrap();
+        assert_eq!(auto.bytes, 4);
+        assert_eq!(tokio::fs::read_to_string(base_dir.join("auto")).await.unwrap(), "text");
+
+        let options = CopyOptions { reflink: Reflink::Always, ..Default::default() };
+        match copy_file(&source, &base_dir.join("always"), &options).await {
+            Ok(always) => {
+                assert!(always.cloned);
+                assert_eq!(tokio::fs::read_to_string(base_dir.join("always")).await.unwrap(), "text");
+            },
+            Err(_) => assert!(!auto.cloned),
+        }
+
+        let never = copy_file(&source, &base_dir.join("never"), &CopyOptions::default()).await.unwrap();
+        assert!(!never.cloned);
+    }
+
+    #[tokio::test]
+    async fn stream_copy() {
+        let base_dir = init("stream_copy").await;
+
+        let source = base_dir.join("file");
+        let dest = base_dir.join("copy");
+        let content: Vec<u8> = (0..10_007).map(|i| (i % 251) as u8).collect();
+        tokio::fs::write(&source, &content).await.unwrap();
+        // Leftovers of a bigger destination must not survive
+        tokio::fs::write(&dest, vec![0u8; 20_000]).await.unwrap();
+
+        for depth in [1, 2, 5] {
+            let options = CopyOptions {
+                copy_engine: Engine::Stream,
+                buffer_size: BufferSize(7),
+                pipeline_depth: PipelineDepth(depth),
+                ..Default::default()
+            };
+            let copied = copy_file(&source, &dest, &options).await.unwrap();
+
+            assert_eq!(copied.bytes, content.len() as u64);
+            assert_eq!(tokio::fs::read(&dest).await.unwrap(), content);
+        }
+    }
+
+    #[tokio::test]
+    async fn stream_error_offset() {
+        let base_dir = init("stream_error_offset").await;
+
+        // A directory can be opened but not read
+        let options = CopyOptions { copy_engine: Engine::Stream, ..Default::default() };
+        let error = copy_file(&base_dir, &base_dir.join("copy"), &options).await.unwrap_err();
+
+        assert!(format!("{:#}", error).contains("Read error at offset 0"));
+    }
+
+    #[tokio::test]
+    async fn bandwidth_limit() {
+        let base_dir = init("bandwidth_limit").await;
+
+        let source = base_dir.join("file");
+        let content: Vec<u8> = (0..300 * 1024).map(|i| (i % 251) as u8).collect();
+        tokio::fs::write(&source, &content).await.unwrap();
+
+        // 300 KiB at 1 MiB/s, the first 100 ms are the burst
+        let bandwidth = Some(Arc::new(Bandwidth::new(1024 * 1024)));
+        let options = CopyOptions { bandwidth, buffer_size: BufferSize(16 * 1024), ..Default::default() };
+        let started = Instant::now();
+        copy_file(&source, &base_dir.join("copy"), &options).await.unwrap();
+
+        assert!(started.elapsed() >= Duration::from_millis(150), "{:?}", started.elapsed());
+        assert_eq!(tokio::fs::read(base_dir.join("copy")).await.unwrap(), content);
+    }
+
+    #[tokio::test]
+    async fn preallocated_copy() {
+        let base_dir = init("preallocated_copy").await;
+
+        let source = base_dir.join("file");
+        let dest = base_dir.join("copy");
+        let content: Vec<u8> = (0..100_003).map(|i| (i % 251) as u8).collect();
+        tokio::fs::write(&source, &content).await.unwrap();
+
+        let options = CopyOptions { preallocate: true, buffer_size: BufferSize(4096), ..Default::default() };
+        let copied = copy_file(&source, &dest, &options).await.unwrap();
+
+        assert_eq!(copied.bytes, content.len() as u64);
+        assert_eq!(tokio::fs::metadata(&dest).await.unwrap().len(), content.len() as u64);
+        assert_eq!(tokio::fs::read(&dest).await.unwrap(), content);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn kernel_copy() {
+        let base_dir = init("kernel_copy").await;
+
+        let source = base_dir.join("file");
+        let dest = base_dir.join("copy");
+        // Several calls of KERNEL_COPY_CHUNK bytes and a shorter one
+        let content: Vec<u8> = (0..3 * KERNEL_COPY_CHUNK + 4321).map(|i| (i % 251) as u8).collect();
+        tokio::fs::write(&source, &content).await.unwrap();
+        tokio::fs::write(&dest, vec![0u8; 5 * KERNEL_COPY_CHUNK]).await.unwrap();
+
+        for options in [CopyOptions::default(), CopyOptions { fsync: true, preallocate: true, ..Default::default() }] {
+            let copied = copy_file(&source, &dest, &options).await.unwrap();
+            assert_eq!(copied.bytes, content.len() as u64);
+            assert_eq!(tokio::fs::read(&dest).await.unwrap(), content);
+        }
+
+        let (from, to) = (File::open(&source).unwrap(), File::create(&dest).unwrap());
+        match copy_file_range_all(&from, &to, content.len() as u64, 1000).unwrap() {
+            KernelCopy::Copied(copied) => {
+                assert_eq!(copied, content.len() as u64);
+                assert_eq!(tokio::fs::read(&dest).await.unwrap(), content);
+            },
+            // Without copy_file_range in this filesystem nothing is written
+            KernelCopy::Unsupported(_) => assert_eq!(tokio::fs::metadata(&dest).await.unwrap().len(), 0),
+        }
+    }
+
+    #[tokio::test]
+    async fn direct_io_copy() {
+        let base_dir = init("direct_io_copy").await;
+
+        let source = base_dir.join("file");
+        let dest = base_dir.join("copy");
+        // Several buffers and a tail that is not a whole block
+        let content: Vec<u8> = (0..3 * 8192 + 123).map(|i| (i % 251) as u8).collect();
+        tokio::fs::write(&source, &content).await.unwrap();
+
+        // Filesystems without direct I/O fall back to the other engines
+        let options = CopyOptions { direct_io: true, buffer_size: BufferSize(8192), ..Default::default() };
+        let copied = copy_file(&source, &dest, &options).await.unwrap();
+
+        assert_eq!(copied.bytes, content.len() as u64);
+        assert_eq!(tokio::fs::read(&dest).await.unwrap(), content);
+    }
+}
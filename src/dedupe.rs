@@ -0,0 +1,136 @@
+//! `--dedupe-dest`: the files whose content was already written in this run become hard links to the first copy
+//! instead of taking the space again. The copies are hashed while they are written (like for the manifest) and only
+//! the sources with the size of a copied file are hashed before copying. A match is compared byte by byte before
+//! linking, a collision of the checksums never links different files. With `--dedupe-method reflink` the duplicates are
+//! clones of the first copy instead: they share its blocks but not its metadata, and a change to one leaves the others
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use anyhow::{Context, Result};
+use crate::checksum::ChecksumAlgorithm;
+use crate::checksum_cache::{self, ChecksumCache};
+
+/// The files written in the run by size and then by checksum
+#[derive(Debug, Default)]
+pub struct Dedupe(Mutex<HashMap<u64, HashMap<String, PathBuf>>>);
+
+/// A file already written with the content of a source
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Original {
+    pub path: PathBuf,
+    pub digest: String,
+    pub len: u64,
+}
+
+impl Dedupe {
+    /// Record the destination `to` of `len` bytes, unless a file with the same content was recorded first
+    pub fn record(&self, to: &Path, len: u64, digest: String) {
+        if len > 0 {
+            self.0.lock().unwrap().entry(len).or_default().entry(digest).or_insert_with(|| to.to_owned());
+        }
+    }
+
+    /// The file already written with the content of the source `from`, None when there is none (and it must be copied)
+    pub async fn original(&self, from: &Path, algorithm: ChecksumAlgorithm, cache: Option<&ChecksumCache>) -> Result<Option<Original>> {
+        let len = tokio::fs::metadata(from).await?.len();
+        // Only a file of the same size can match, most sources are not hashed twice
+        let seen = self.0.lock().unwrap().get(&len).is_some_and(|digests| !digests.is_empty());
+        if !seen {
+            return Ok(None);
+        }
+        let digest = checksum_cache::hash_source(from, algorithm, cache).await
+            .with_context(|| format!("Cannot hash file: {:?}", from))?;
+        let Some(path) = self.0.lock().unwrap().get(&len).and_then(|digests| digests.get(&digest)).cloned() else {
+            return Ok(None);
+        };
+        let (source, original) = (from.to_owned(), path.clone());
+        if !tokio::task::spawn_blocking(move || same_bytes(&source, &original)).await?
+            .with_context(|| format!("Cannot compare {:?} with {:?}", from, path))? {
+            return Ok(None);
+        }
+        Ok(Some(Original { path, digest, len }))
+    }
+}
+
+/// Whether both files have the same bytes
+fn same_bytes(first: &Path, second: &Path) -> io::Result<bool> {
+    const BLOCK: usize = 64 * 1024;
+    let (mut first, mut second) = (File::open(first)?, File::open(second)?);
+    let (mut first_block, mut second_block) = (vec![0u8; BLOCK], vec![0u8; BLOCK]);
+    loop {
+        let read = read_block(&mut first, &mut first_block)?;
+        if read != read_block(&mut second, &mut second_block)? || first_block[..read] != second_block[..read] {
+            return Ok(false);
+        }
+        if read == 0 {
+            return Ok(true);
+        }
+    }
+}
+
+/// Fill the block unless the file ends first, the bytes read
+fn read_block(file: &mut File, block: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < block.len() {
+        match file.read(&mut block[filled..])? {
+            0 => break,
+            read => filled += read,
+        }
+    }
+    Ok(filled)
+}
+
+/// Fail when the filesystem of `dir` cannot have hard links (or reflinks with `reflink`), before copying anything
+pub async fn check_links(dir: &Path, reflink: bool) -> Result<()> {
+    let probe = dir.join(format!(".rs-copier-link-{}", std::process::id()));
+    let link = probe.with_extension("link");
+    tokio::fs::write(&probe, b"").await
+        .with_context(|| format!("Cannot write in the destination: {:?}", dir))?;
+    let linked = match reflink {
+        true => crate::reflink::clone_file(&probe, &link).await.map(|_| ()),
+        false => tokio::fs::hard_link(&probe, &link).await,
+    };
+    let _ = tokio::fs::remove_file(&link).await;
+    let _ = tokio::fs::remove_file(&probe).await;
+    let what = if reflink { "reflinks" } else { "hard links" };
+    linked.with_context(|| format!("--dedupe-dest needs {}, the destination cannot have them: {:?}", what, dir))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::checksum::{self, ChecksumAlgorithm};
+    use crate::test_support::init;
+    use super::{check_links, same_bytes, Dedupe};
+
+    #[tokio::test]
+    async fn finds_the_originals() {
+        let base_dir = init("finds_the_originals").await;
+
+        let algorithm = ChecksumAlgorithm::Sha256;
+        let (first, same, other) = (base_dir.join("first"), base_dir.join("same"), base_dir.join("other"));
+        tokio::fs::write(&first, "content").await.unwrap();
+        tokio::fs::write(&same, "content").await.unwrap();
+        tokio::fs::write(&other, "another").await.unwrap();
+
+        let dedupe = Dedupe::default();
+        assert_eq!(dedupe.original(&same, algorithm, None).await.unwrap(), None);
+        dedupe.record(&first, 7, checksum::hash_file(&first, algorithm).await.unwrap());
+        let original = dedupe.original(&same, algorithm, None).await.unwrap().unwrap();
+        assert_eq!((original.path, original.len), (first.clone(), 7));
+        // Same size, another content
+        assert_eq!(dedupe.original(&other, algorithm, None).await.unwrap(), None);
+
+        // A forged checksum is caught by the comparison of the bytes
+        let forged = Dedupe::default();
+        forged.record(&first, 7, checksum::hash_file(&other, algorithm).await.unwrap());
+        assert_eq!(forged.original(&other, algorithm, None).await.unwrap(), None);
+        assert!(same_bytes(&first, &same).unwrap() && !same_bytes(&first, &other).unwrap());
+
+        check_links(&base_dir, false).await.unwrap();
+        assert_eq!(std::fs::read_dir(&base_dir).unwrap().count(), 3);
+    }
+}
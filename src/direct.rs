@@ -0,0 +1,111 @@
+//! `--direct-io`: copies that bypass the page cache (`O_DIRECT` on Linux).
+//! The reads and writes use a buffer aligned to the block size of the filesystems and the tail of the file,
+//! which is not a whole block, is written without `O_DIRECT`
+
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Once};
+use anyhow::Result;
+use log::warn;
+use crate::checksum::Hasher;
+use crate::copy::BufferSize;
+use crate::limit::Bandwidth;
+
+/// The lack of direct I/O support is only reported once
+static DIRECT_UNSUPPORTED: Once = Once::new();
+
+/// What a direct copy needs from the options, it runs in the blocking pool
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+pub struct Settings {
+    pub buffer_size: BufferSize,
+    pub bandwidth: Option<Arc<Bandwidth>>,
+    pub fsync: bool,
+    pub hasher: Option<Hasher>,
+}
+
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+pub enum Direct {
+    Copied { bytes: u64, digest: Option<String> },
+    /// The platform or the filesystem cannot open the files with direct I/O, nothing was copied
+    Unsupported(io::Error),
+}
+
+/// Warn that the files are copied through the page cache
+pub fn warn_unsupported(error: &io::Error) {
+    DIRECT_UNSUPPORTED.call_once(|| {
+        warn!("Direct I/O is not supported, the files are copied through the page cache: {}. Only the first occurrence is reported", error);
+    });
+}
+
+#[cfg(target_os = "linux")]
+pub fn copy(from: &Path, to: &Path, mut settings: Settings) -> Result<Direct> {
+    use std::fs::OpenOptions;
+    use std::os::unix::fs::{FileExt, MetadataExt, OpenOptionsExt};
+    use anyhow::{anyhow, Context};
+
+    let source = match OpenOptions::new().read(true).custom_flags(libc::O_DIRECT).open(from) {
+        Ok(source) => source,
+        Err(error) if is_unsupported(&error) => return Ok(Direct::Unsupported(error)),
+        Err(error) => return Err(error.into()),
+    };
+    let metadata = source.metadata()?;
+    let dest = match OpenOptions::new().write(true).create(true).truncate(true).custom_flags(libc::O_DIRECT).open(to) {
+        Ok(dest) => dest,
+        Err(error) if is_unsupported(&error) => return Ok(Direct::Unsupported(error)),
+        Err(error) => return Err(error.into()),
+    };
+    let block = metadata.blksize().max(dest.metadata()?.blksize()).max(512) as usize;
+    let size = settings.buffer_size.0.div_ceil(block) * block;
+    let mut storage = vec![0u8; size + block];
+    let start = storage.as_ptr().align_offset(block);
+    let buffer = &mut storage[start..start + size];
+
+    let mut copied = 0;
+    loop {
+        // The offset is always a whole number of blocks here
+        let read = source.read_at(buffer, copied)
+            .with_context(|| format!("Read error at offset {}", copied))?;
+        if read == 0 {
+            break;
+        }
+        if let Some(hasher) = settings.hasher.as_mut() {
+            hasher.update(&buffer[..read]);
+        }
+        if let Some(bandwidth) = &settings.bandwidth {
+            bandwidth.acquire_blocking(read);
+        }
+        let aligned = read - read % block;
+        dest.write_all_at(&buffer[..aligned], copied)
+            .with_context(|| format!("Write error at offset {}", copied))?;
+        if aligned < read {
+            // Only the end of the file is not a whole block
+            let offset = copied + aligned as u64;
+            OpenOptions::new().write(true).open(to)?
+                .write_all_at(&buffer[aligned..read], offset)
+                .with_context(|| format!("Write error at offset {}", offset))?;
+            copied += read as u64;
+            break;
+        }
+        copied += read as u64;
+    }
+    if copied < metadata.len() {
+        return Err(anyhow!("Short read at offset {}", copied));
+    }
+    if settings.fsync {
+        dest.sync_all().context("Cannot sync file")?;
+    }
+    std::fs::set_permissions(to, metadata.permissions())?;
+    Ok(Direct::Copied { bytes: copied, digest: settings.hasher.map(Hasher::finish) })
+}
+
+/// Whether opening with `O_DIRECT` failed because the filesystem does not support it (tmpfs)
+#[cfg(target_os = "linux")]
+fn is_unsupported(error: &io::Error) -> bool {
+    [Some(libc::EINVAL), Some(libc::EOPNOTSUPP), Some(libc::ENOTSUP)].contains(&error.raw_os_error())
+}
+
+/// Only Linux copies with direct I/O
+#[cfg(not(target_os = "linux"))]
+pub fn copy(_from: &Path, _to: &Path, _settings: Settings) -> Result<Direct> {
+    Ok(Direct::Unsupported(io::ErrorKind::Unsupported.into()))
+}
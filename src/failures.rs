@@ -0,0 +1,216 @@
+//! The files that could not be copied, collected from all the tasks. The end of the run logs how many failed and
+//! the first ones, `--error-report` writes all of them, and the exit code tells the run apart from a complete one:
+//!
+//! - 0: every selected file was copied
+//! - 1: some files failed, the others were copied
+//! - 2: nothing was copied, every file failed or the run stopped before copying any (invalid command line or
+//!   options...)
+//! - 3: the free space of the destination dropped below `--min-free-space` and the run was stopped
+//! - 4, 5 and 6: the source does not exist, is neither a directory nor a file or cannot be read
+//! - 70: a task panicked, a bug: the run was stopped
+//! - 130: the run was interrupted (Ctrl-C, SIGTERM)
+
+use std::fmt::{self, Write};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use anyhow::{Context, Result};
+use tokio::task::JoinError;
+use crate::interrupt::Interrupted;
+use crate::paths;
+use crate::source::InvalidSource;
+use crate::space::OutOfSpace;
+
+/// The failures logged at the end of the run, the rest are only in the report
+pub const LOGGED_FAILURES: usize = 20;
+
+/// The exit codes, stable across versions
+pub const EXIT_SUCCESS: u8 = 0;
+pub const EXIT_PARTIAL: u8 = 1;
+pub const EXIT_NOTHING_COPIED: u8 = 2;
+pub const EXIT_NO_SPACE: u8 = 3;
+pub const EXIT_SOURCE_MISSING: u8 = 4;
+pub const EXIT_SOURCE_NOT_DIRECTORY: u8 = 5;
+pub const EXIT_SOURCE_UNREADABLE: u8 = 6;
+pub const EXIT_INTERNAL_ERROR: u8 = 70;
+pub const EXIT_INTERRUPTED: u8 = 130;
+
+/// The exit codes in `--help`
+pub const EXIT_CODES: &str = "Exit codes:
+    0  every selected file was copied
+    1  some files could not be copied, the others were (see --error-report)
+    2  nothing was copied: every file failed or the run stopped before copying any
+    3  the destination went below --min-free-space, the run was stopped
+    4  the source does not exist
+    5  the source is neither a directory nor a regular file
+    6  the source cannot be read
+    70  internal error, a bug stopped the run
+    130  the run was interrupted (Ctrl-C, SIGTERM), the copies in flight were finished or removed";
+
+/// A file (or directory) that could not be copied
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Failure {
+    /// None when the error is not about a single path, like a failed listing
+    pub path: Option<PathBuf>,
+    /// The kind of the I/O error that caused it, `Other` for the rest
+    pub kind: String,
+    pub message: String,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.path {
+            Some(path) => write!(f, "{:?} ({}): {}", path, self.kind, self.message),
+            None => write!(f, "({}) {}", self.kind, self.message),
+        }
+    }
+}
+
+/// The failures of the run, in the order they were reported
+#[derive(Debug, Default)]
+pub struct Failures(Mutex<Vec<Failure>>);
+
+impl Failures {
+    /// Record the `error` of the file `path`
+    pub fn record(&self, path: Option<&Path>, error: &anyhow::Error) {
+        let kind = error.chain().find_map(|cause| cause.downcast_ref::<io::Error>()).map(io::Error::kind);
+        let failure = Failure {
+            path: path.map(Path::to_owned),
+            kind: kind.map_or_else(|| "Other".to_owned(), |kind| format!("{:?}", kind)),
+            message: format!("{:#}", error),
+        };
+        self.0.lock().unwrap().push(failure);
+    }
+
+    pub fn list(&self) -> Vec<Failure> {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// The report of `--error-report`: a line per failure with the path (`-` when there is none), the kind of the
+    /// error and its message, separated by tabs
+    pub fn report(&self) -> String {
+        let mut report = String::new();
+        for failure in self.0.lock().unwrap().iter() {
+            let path = failure.path.as_ref().map_or_else(|| "-".to_owned(), |path| paths::escape(path).into_owned());
+            let _ = writeln!(report, "{}\t{}\t{}", path, failure.kind, failure.message.replace(['\t', '\n'], " "));
+        }
+        report
+    }
+
+    /// Write `report` into `path`, written next to it first and renamed like the stats file
+    pub async fn write_report(&self, path: &Path) -> Result<()> {
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".part");
+        tokio::fs::write(&partial, self.report()).await
+            .with_context(|| format!("Cannot write the error report: {:?}", partial))?;
+        tokio::fs::rename(&partial, path).await
+            .with_context(|| format!("Cannot write the error report: {:?}", path))
+    }
+}
+
+/// The error of a run that went through the tree but could not copy some files, or that stopped after copying some
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Incomplete {
+    pub copied: u64,
+    pub failed: u64,
+}
+
+impl fmt::Display for Incomplete {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "copied {} files, {} failed", self.copied, self.failed)
+    }
+}
+
+impl std::error::Error for Incomplete {}
+
+/// The error of a task that panicked. It is a bug: the run is stopped instead of skipping what the task had to do
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Panicked {
+    /// What the task was doing
+    pub task: String,
+    pub message: String,
+}
+
+impl Panicked {
+    /// The panic of `error`, None when the task was cancelled instead
+    pub fn of(error: JoinError, task: impl Into<String>) -> Option<Self> {
+        let payload = error.try_into_panic().ok()?;
+        let message = payload.downcast_ref::<&str>().map(|message| message.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "without a message".to_owned());
+        Some(Self { task: task.into(), message })
+    }
+}
+
+impl fmt::Display for Panicked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Internal error, {} panicked: {}. This is a bug, the run was stopped", self.task, self.message)
+    }
+}
+
+impl std::error::Error for Panicked {}
+
+/// The exit code of a run that ended with `error`
+pub fn exit_code(error: &anyhow::Error) -> u8 {
+    if error.downcast_ref::<Interrupted>().is_some() {
+        return EXIT_INTERRUPTED;
+    }
+    if error.downcast_ref::<OutOfSpace>().is_some() {
+        return EXIT_NO_SPACE;
+    }
+    if error.downcast_ref::<Panicked>().is_some() {
+        return EXIT_INTERNAL_ERROR;
+    }
+    if let Some(source) = error.downcast_ref::<InvalidSource>() {
+        return source.exit_code();
+    }
+    match error.downcast_ref::<Incomplete>() {
+        Some(incomplete) if incomplete.copied > 0 => EXIT_PARTIAL,
+        _ => EXIT_NOTHING_COPIED,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::path::Path;
+    use anyhow::anyhow;
+    use crate::interrupt::Interrupted;
+    use crate::space::OutOfSpace;
+    use super::{exit_code, Failures, Incomplete, Panicked, EXIT_INTERNAL_ERROR, EXIT_INTERRUPTED, EXIT_NOTHING_COPIED, EXIT_NO_SPACE, EXIT_PARTIAL};
+
+    #[test]
+    fn reports() {
+        let failures = Failures::default();
+        let denied = anyhow::Error::new(io::Error::from(io::ErrorKind::PermissionDenied)).context("Cannot copy file: \"a\"");
+        failures.record(Some(Path::new("dir/a")), &denied);
+        failures.record(None, &anyhow!("Cannot list\tdirectory"));
+        assert_eq!(failures.list()[0].kind, "PermissionDenied");
+        assert_eq!(failures.report(), "dir/a\tPermissionDenied\tCannot copy file: \"a\": permission denied\n-\tOther\tCannot list directory\n");
+
+        let partial = anyhow::Error::new(Incomplete { copied: 3, failed: 1 });
+        assert_eq!(exit_code(&partial), EXIT_PARTIAL);
+        assert_eq!(partial.to_string(), "copied 3 files, 1 failed");
+        // Also under the error that stopped the run
+        assert_eq!(exit_code(&anyhow!("Stopped").context(Incomplete { copied: 0, failed: 1 })), EXIT_NOTHING_COPIED);
+        assert_eq!(exit_code(&anyhow!("The source is required")), EXIT_NOTHING_COPIED);
+        let interrupted = anyhow::Error::new(Interrupted { abandoned: 2 }).context(Incomplete { copied: 3, failed: 0 });
+        assert_eq!(exit_code(&interrupted), EXIT_INTERRUPTED);
+        assert_eq!(format!("{:#}", interrupted), "copied 3 files, 0 failed: The copy was interrupted, 2 copies in flight were abandoned");
+        let full = OutOfSpace { dest: "dest".into(), available: 10, min: 100 };
+        assert_eq!(exit_code(&anyhow::Error::new(full).context(Incomplete { copied: 3, failed: 0 })), EXIT_NO_SPACE);
+    }
+
+    #[tokio::test]
+    async fn panics() {
+        let error = tokio::spawn(async { panic!("index out of bounds") }).await.unwrap_err();
+        let panicked = Panicked::of(error, "the listing of \"dir\"").unwrap();
+        assert_eq!(panicked.to_string(), "Internal error, the listing of \"dir\" panicked: index out of bounds. This is a bug, the run was stopped");
+        assert_eq!(exit_code(&anyhow::Error::new(panicked).context(Incomplete { copied: 3, failed: 0 })), EXIT_INTERNAL_ERROR);
+
+        let cancelled = tokio::spawn(std::future::pending::<()>());
+        cancelled.abort();
+        assert!(Panicked::of(cancelled.await.unwrap_err(), "nothing").is_none());
+    }
+}
@@ -0,0 +1,163 @@
+//! `--files-from`: the files to copy come from a list of paths relative to the source (one per line, or separated by
+//! NUL with `--null-separated`, like the output of `find -print0`) instead of walking the tree. The list is turned
+//! into a pre-scan with those files only and their parent directories, which the ordered copy goes through
+
+use std::collections::BTreeSet;
+use std::path::{Component, Path, PathBuf};
+use anyhow::{Context, Result};
+use tokio::io::AsyncReadExt;
+use log::warn;
+use crate::prescan::Scan;
+use crate::walk::{self, Links};
+use crate::DanglingLinks;
+
+/// The relative paths of the list, without the empty entries. The `.` components (what `find .` prints) are removed
+fn parse_list(content: &[u8], null_separated: bool) -> Vec<PathBuf> {
+    let separator = if null_separated { b'\0' } else { b'\n' };
+    content.split(|byte| *byte == separator)
+        .map(|entry| if null_separated { entry } else { entry.strip_suffix(b"\r").unwrap_or(entry) })
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| entry_path(entry).components().filter(|component| *component != Component::CurDir).collect::<PathBuf>())
+        .filter(|path| !path.as_os_str().is_empty())
+        .collect()
+}
+
+/// The path of an entry of the list, any bytes on Unix
+#[cfg(unix)]
+fn entry_path(bytes: &[u8]) -> PathBuf {
+    use std::os::unix::ffi::OsStrExt;
+    PathBuf::from(std::ffi::OsStr::from_bytes(bytes))
+}
+
+/// The path of an entry of the list, which must be UTF-8 elsewhere
+#[cfg(not(unix))]
+fn entry_path(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(bytes).into_owned())
+}
+
+/// Whether the path stays inside the source: relative and without `..`
+fn is_inside(path: &Path) -> bool {
+    path.components().all(|component| matches!(component, Component::Normal(_)))
+}
+
+/// The files of the list `list` (`-` for the standard input) found in `base_source`, with the directories between
+/// them and the source. The entries that are missing, outside the source or not files are warned about and left out
+pub async fn scan(base_source: &Path, list: &Path, null_separated: bool, links: Links) -> Result<Scan> {
+    let content = if list == Path::new("-") {
+        let mut content = vec![];
+        tokio::io::stdin().read_to_end(&mut content).await.map(|_| content)
+    } else {
+        tokio::fs::read(list).await
+    }.with_context(|| format!("Cannot read the list of files: {:?}", list))?;
+    let mut scan = Scan::default();
+    let mut dirs = BTreeSet::new();
+    let mut listed = BTreeSet::new();
+    for relative in parse_list(&content, null_separated) {
+        if !is_inside(&relative) {
+            warn!("{:?} is not inside the source, skipped from the list", relative);
+            continue;
+        }
+        if !listed.insert(relative.clone()) {
+            continue;
+        }
+        let path = base_source.join(&relative);
+        // None for a link copied as a link
+        let mut preserved = links.preserved(relative.components().count() - 1);
+        if let Some(target) = walk::dangling_target(&path).await.filter(|_| !preserved) {
+            match links.dangling {
+                DanglingLinks::Skip => {
+                    warn!("{:?} is a dangling symlink, skipped from the list: {:?}", path, target);
+                    continue;
+                },
+                DanglingLinks::Preserve => preserved = true,
+                DanglingLinks::Error => {
+                    scan.errors.push(walk::dangling_error(&path, &target));
+                    continue;
+                },
+            }
+        }
+        let metadata = match tokio::fs::symlink_metadata(&path).await {
+            Ok(metadata) if metadata.is_symlink() && preserved => Ok(None),
+            Ok(metadata) if metadata.is_symlink() => tokio::fs::metadata(&path).await.map(Some),
+            metadata => metadata.map(Some),
+        };
+        match metadata {
+            Ok(None) => {
+                scan.links.push(path.clone());
+                scan.totals.files += 1;
+            },
+            Ok(Some(metadata)) if metadata.is_file() => {
+                scan.files.push((path.clone(), metadata.len()));
+                scan.totals.files += 1;
+                scan.totals.bytes += metadata.len();
+            },
+            Ok(Some(_)) => {
+                warn!("{:?} is not a file, skipped from the list", path);
+                continue;
+            },
+            Err(_) => {
+                warn!("{:?} does not exist, skipped from the list", path);
+                continue;
+            },
+        }
+        dirs.extend(path.ancestors().skip(1).take_while(|dir| *dir != base_source).map(Path::to_owned));
+    }
+    scan.dirs = dirs.into_iter().collect();
+    Ok(scan)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use crate::test_support::init;
+    use crate::walk::Links;
+    use super::{parse_list, scan};
+
+    #[test]
+    fn lists() {
+        let paths = |names: &[&str]| names.iter().map(PathBuf::from).collect::<Vec<_>>();
+        assert_eq!(parse_list(b"a\n./b/c\r\n\nd\n", false), paths(&["a", "b/c", "d"]));
+        assert_eq!(parse_list(b"with\nnewline\0./other\0", true), paths(&["with\nnewline", "other"]));
+        assert_eq!(parse_list(b"./\n", false), paths(&[]));
+    }
+
+    #[tokio::test]
+    async fn listed_files() {
+        let base_dir = init("listed_files").await;
+
+        let source = base_dir.join("source");
+        tokio::fs::create_dir_all(source.join("a/b")).await.unwrap();
+        tokio::fs::write(source.join("a/b/file"), "12345").await.unwrap();
+        tokio::fs::write(source.join("top"), "12").await.unwrap();
+        let list = base_dir.join("list");
+        tokio::fs::write(&list, "a/b/file\nmissing\na\n../outside\ntop\ntop\n").await.unwrap();
+
+        let scan = scan(&source, &list, false, Links::default()).await.unwrap();
+        assert_eq!(scan.files, [(source.join("a/b/file"), 5), (source.join("top"), 2)]);
+        assert_eq!(scan.dirs, [source.join("a"), source.join("a/b")]);
+        assert_eq!((scan.totals.files, scan.totals.bytes), (2, 7));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn listed_dangling_links() {
+        use crate::DanglingLinks;
+
+        let base_dir = init("listed_dangling_links").await;
+
+        let source = base_dir.join("source");
+        tokio::fs::create_dir_all(&source).await.unwrap();
+        tokio::fs::symlink("missing", source.join("broken")).await.unwrap();
+        let list = base_dir.join("list");
+        tokio::fs::write(&list, "broken\n").await.unwrap();
+
+        let listed = |dangling| scan(&source, &list, false, Links { dangling, ..Default::default() });
+        let failed = listed(DanglingLinks::Error).await.unwrap();
+        assert_eq!(failed.errors.len(), 1);
+        assert!(failed.errors[0].to_string().contains("dangling symlink"), "{}", failed.errors[0]);
+        let skipped = listed(DanglingLinks::Skip).await.unwrap();
+        assert!(skipped.errors.is_empty() && skipped.links.is_empty());
+        assert_eq!(listed(DanglingLinks::Preserve).await.unwrap().links, [source.join("broken")]);
+    }
+}
@@ -0,0 +1,130 @@
+//! Filters that select which files of the source are copied. `--list-only` applies the same ones
+
+use std::fs::Metadata;
+use std::time::{Duration, SystemTime};
+use chrono::{Local, NaiveDate, NaiveDateTime, TimeZone};
+use crate::size;
+
+/// Inclusion window of modification times. A file is selected only when its mtime is not older than `oldest`
+/// (`--exclude-older-than`) and not newer than `newest` (`--exclude-newer-than`); both bounds are inclusive
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TimeWindow {
+    pub oldest: Option<SystemTime>,
+    pub newest: Option<SystemTime>,
+}
+
+impl TimeWindow {
+    pub fn is_unbounded(&self) -> bool {
+        self.oldest.is_none() && self.newest.is_none()
+    }
+
+    /// Whether no file can be selected
+    pub fn is_empty(&self) -> bool {
+        matches!((self.oldest, self.newest), (Some(oldest), Some(newest)) if oldest > newest)
+    }
+
+    pub fn contains(&self, mtime: SystemTime) -> bool {
+        self.oldest.is_none_or(|oldest| mtime >= oldest) && self.newest.is_none_or(|newest| mtime <= newest)
+    }
+}
+
+/// Whether the mtime `source` is newer than `dest` by more than `window` (`--modify-window`). The mtimes within the
+/// window are taken as the same one, a filesystem like FAT only keeps them to 2 seconds
+pub fn newer_than(source: SystemTime, dest: SystemTime, window: Duration) -> bool {
+    source.duration_since(dest).is_ok_and(|ahead| ahead > window)
+}
+
+/// Whether `--if-exists newer` replaces the destination: its source is newer, or either mtime is unknown
+pub fn is_outdated(source: &Metadata, dest: &Metadata, window: Duration) -> bool {
+    match (source.modified(), dest.modified()) {
+        (Ok(source), Ok(dest)) => newer_than(source, dest, window),
+        _ => true,
+    }
+}
+
+/// All the filters of a run
+#[derive(Debug, Clone, Default)]
+pub struct Filters {
+    pub time_window: TimeWindow,
+}
+
+impl Filters {
+    /// Whether any filter needs the metadata of the files
+    pub fn needs_metadata(&self) -> bool {
+        !self.time_window.is_unbounded()
+    }
+
+    /// Whether the file modified at `mtime` is copied. Platforms without mtime (None) select everything
+    pub fn selects(&self, mtime: Option<SystemTime>) -> bool {
+        mtime.is_none_or(|mtime| self.time_window.contains(mtime))
+    }
+}
+
+/// Parse a point in time: a duration before now (`90s`, `15m`, `2h`, `7d`, `1w`), an RFC 3339 timestamp,
+/// or a local date and time (`2024-05-01 13:00:00`, `2024-05-01T13:00:00` or just `2024-05-01`)
+pub fn parse_time(value: &str) -> Result<SystemTime, String> {
+    let value = value.trim();
+    if let Ok(duration) = size::parse_duration(value) {
+        return SystemTime::now().checked_sub(duration).ok_or_else(|| format!("duration too big: {:?}", value));
+    }
+    if let Ok(time) = chrono::DateTime::parse_from_rfc3339(value) {
+        return Ok(time.into());
+    }
+    let naive = ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(value, format).ok())
+        .or_else(|| NaiveDate::parse_from_str(value, "%Y-%m-%d").ok().and_then(|date| date.and_hms_opt(0, 0, 0)));
+    match naive.map(|naive| Local.from_local_datetime(&naive).earliest()) {
+        Some(Some(time)) => Ok(time.into()),
+        _ => Err(format!("not a duration or a timestamp: {:?}", value)),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use super::{newer_than, parse_time, TimeWindow};
+
+    #[test]
+    fn times() {
+        let ago = SystemTime::now().duration_since(parse_time("60s").unwrap()).unwrap();
+        assert!(ago > Duration::from_secs(59) && ago < Duration::from_secs(70), "{:?}", ago);
+        let ago = SystemTime::now().duration_since(parse_time("2d").unwrap()).unwrap();
+        assert!(ago > Duration::from_secs(2 * 24 * 3600 - 1));
+
+        let rfc3339 = parse_time("2024-05-01T13:00:00Z").unwrap();
+        assert_eq!(rfc3339.duration_since(UNIX_EPOCH).unwrap().as_secs(), 1_714_568_400);
+        assert!(parse_time("2024-05-01 13:00:00").is_ok());
+        assert!(parse_time("2024-05-01").is_ok());
+        assert!(parse_time("5x").is_err());
+        assert!(parse_time("yesterday").is_err());
+    }
+
+    #[test]
+    fn time_windows() {
+        let now = SystemTime::now();
+        let minute = Duration::from_secs(60);
+        let window = TimeWindow { oldest: Some(now - 10 * minute), newest: Some(now - minute) };
+        assert!(window.contains(now - 5 * minute));
+        assert!(!window.contains(now));
+        assert!(!window.contains(now - 20 * minute));
+        assert!(!window.is_empty());
+
+        assert!(TimeWindow { oldest: Some(now), newest: Some(now - minute) }.is_empty());
+        assert!(TimeWindow::default().contains(now));
+    }
+
+    #[test]
+    fn modify_window() {
+        let now = SystemTime::now();
+        let second = Duration::from_secs(1);
+        assert!(newer_than(now + second, now, Duration::ZERO));
+        assert!(!newer_than(now, now, Duration::ZERO));
+        assert!(!newer_than(now - second, now, Duration::ZERO));
+        // Within the window both ways
+        assert!(!newer_than(now + second, now, 2 * second));
+        assert!(!newer_than(now + 2 * second, now, 2 * second));
+        assert!(newer_than(now + 3 * second, now, 2 * second));
+    }
+}
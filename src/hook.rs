@@ -0,0 +1,212 @@
+//! `--post-copy-hook`: a command run after every file is copied, like a virus scan or the trigger of an upload. The
+//! command is split into words like a shell does (with `'` and `"` quotes and `\` escapes, but nothing is expanded)
+//! and run without a shell. `{dest}` and `{source}` in its words are replaced with the paths of the file, and without
+//! them the destination is added as the last argument. The paths are in `RS_COPIER_DEST` and `RS_COPIER_SOURCE` too.
+//!
+//! Up to `--hook-concurrency` hooks run at the same time, the copies wait for them. A hook that fails (with a nonzero
+//! exit or that cannot be started) is logged, and with `--hook-must-succeed` its file fails too: then `--delete-source`
+//! keeps its source, unless the file was moved with a rename. A hook still running after `--hook-timeout` (or
+//! `--file-timeout`) is killed and fails the same way, so it cannot hold its slot forever
+
+use std::ffi::OsString;
+use std::path::Path;
+use std::process::Stdio;
+use std::time::Duration;
+use anyhow::{anyhow, Result};
+use log::{debug, warn};
+use tokio::sync::Semaphore;
+
+/// The variables with the paths of the file
+const DEST_VARIABLE: &str = "RS_COPIER_DEST";
+const SOURCE_VARIABLE: &str = "RS_COPIER_SOURCE";
+
+/// A parsed `--post-copy-hook`: the program and its arguments
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HookCommand(Vec<String>);
+
+/// Parse a command line of `--post-copy-hook`
+pub fn parse_hook_command(text: &str) -> Result<HookCommand, String> {
+    let mut words = vec![];
+    let mut word: Option<String> = None;
+    let mut chars = text.chars();
+    while let Some(char) = chars.next() {
+        match char {
+            char if char.is_whitespace() => words.extend(word.take()),
+            '\'' => {
+                let quoted = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(char) => quoted.push(char),
+                        None => return Err(format!("Unterminated ' quote: {}", text)),
+                    }
+                }
+            },
+            '"' => {
+                let quoted = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(escaped @ ('"' | '\\')) => quoted.push(escaped),
+                            Some(char) => quoted.extend(['\\', char]),
+                            None => return Err(format!("Unterminated \" quote: {}", text)),
+                        },
+                        Some(char) => quoted.push(char),
+                        None => return Err(format!("Unterminated \" quote: {}", text)),
+                    }
+                }
+            },
+            '\\' => match chars.next() {
+                Some(escaped) => word.get_or_insert_with(String::new).push(escaped),
+                None => return Err(format!("Nothing to escape at the end: {}", text)),
+            },
+            char => word.get_or_insert_with(String::new).push(char),
+        }
+    }
+    words.extend(word);
+    if words.is_empty() {
+        return Err("The hook needs a command".to_owned());
+    }
+    Ok(HookCommand(words))
+}
+
+impl HookCommand {
+    /// The program and its arguments for the copy of `from` into `to`, with the paths as they are, also when they are
+    /// not UTF-8
+    fn words(&self, from: &Path, to: &Path) -> Vec<OsString> {
+        let placed = self.0.iter().any(|word| word.contains("{dest}") || word.contains("{source}"));
+        let mut words: Vec<OsString> = self.0.iter().map(|word| substitute(word, from, to)).collect();
+        if !placed {
+            words.push(to.into());
+        }
+        words
+    }
+}
+
+/// `word` with its `{dest}` and `{source}` replaced by the paths
+fn substitute(word: &str, from: &Path, to: &Path) -> OsString {
+    let mut substituted = OsString::new();
+    let mut rest = word;
+    while let Some(start) = rest.find('{') {
+        substituted.push(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(after) = rest.strip_prefix("{dest}") {
+            substituted.push(to);
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("{source}") {
+            substituted.push(from);
+            rest = after;
+        } else {
+            substituted.push("{");
+            rest = &rest[1..];
+        }
+    }
+    substituted.push(rest);
+    substituted
+}
+
+/// The hooks of a run
+#[derive(Debug)]
+pub struct Hooks {
+    command: HookCommand,
+    /// Bound of the hooks running at the same time
+    permits: Semaphore,
+    /// Whether a failed hook fails its file
+    must_succeed: bool,
+    /// After which a running hook is killed
+    timeout: Option<Duration>,
+}
+
+impl Hooks {
+    pub fn new(command: HookCommand, concurrency: usize, must_succeed: bool, timeout: Option<Duration>) -> Self {
+        Self { command, permits: Semaphore::new(concurrency), must_succeed, timeout }
+    }
+
+    /// Run the hook of the file copied from `from` into `to`. An error only when it failed with --hook-must-succeed
+    pub async fn run(&self, from: &Path, to: &Path) -> Result<()> {
+        let words = self.command.words(from, to);
+        let _permit = self.permits.acquire().await?;
+        debug!("Hook: {:?}", words);
+        let output = tokio::process::Command::new(&words[0])
+            .args(&words[1..])
+            .env(DEST_VARIABLE, to)
+            .env(SOURCE_VARIABLE, from)
+            .stdin(Stdio::null())
+            .kill_on_drop(true)
+            .output();
+        // Dropping the output on a timeout kills the hook
+        let output = match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, output).await.ok(),
+            None => Some(output.await),
+        };
+        let failure = match output {
+            Some(Ok(output)) if output.status.success() => return Ok(()),
+            Some(Ok(output)) => {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                match stderr.trim() {
+                    "" => output.status.to_string(),
+                    stderr => format!("{}: {}", output.status, stderr),
+                }
+            },
+            Some(Err(error)) => format!("cannot run {:?}: {}", words[0], error),
+            None => format!("killed after {:?}", self.timeout.unwrap_or_default()),
+        };
+        let error = anyhow!("The post-copy hook of {:?} failed, {}", to, failure);
+        if self.must_succeed {
+            return Err(error);
+        }
+        warn!("{:#}", error);
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use super::{parse_hook_command, HookCommand};
+
+    #[test]
+    fn hook_commands() {
+        let words = |text| parse_hook_command(text).unwrap().0;
+        assert_eq!(words("scan --quiet"), ["scan", "--quiet"]);
+        assert_eq!(words("  sh -c 'echo \"$RS_COPIER_DEST\"'  "), ["sh", "-c", "echo \"$RS_COPIER_DEST\""]);
+        assert_eq!(words(r#"notify "a \"b\" \c" d\ e ''"#), ["notify", r#"a "b" \c"#, "d e", ""]);
+        assert!(parse_hook_command("").is_err());
+        assert!(parse_hook_command("sh -c 'unterminated").is_err());
+
+        let command = HookCommand(vec!["upload".into(), "--to={dest}".into(), "{source}".into()]);
+        assert_eq!(command.words(Path::new("/src/a b"), Path::new("/dst/a b")), ["upload", "--to=/dst/a b", "/src/a b"]);
+        let command = HookCommand(vec!["scan".into()]);
+        assert_eq!(command.words(Path::new("/src/a"), Path::new("/dst/a")), ["scan", "/dst/a"]);
+        let command = HookCommand(vec!["{a}".into(), "{{dest}}".into(), "{source".into()]);
+        assert_eq!(command.words(Path::new("/src/a"), Path::new("/dst/a")), ["{a}", "{/dst/a}", "{source"]);
+
+        // The bytes of the paths are kept
+        #[cfg(unix)]
+        {
+            use std::ffi::OsStr;
+            use std::os::unix::ffi::{OsStrExt, OsStringExt};
+            let command = HookCommand(vec!["upload".into(), "--to={dest}".into()]);
+            let to = Path::new(OsStr::from_bytes(b"/dst/bad\xffname"));
+            let words = command.words(Path::new("/src/a"), to);
+            assert_eq!(words[1].clone().into_vec(), b"--to=/dst/bad\xffname");
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn hook_timeouts() {
+        use std::time::{Duration, Instant};
+        use super::Hooks;
+        let hooks = Hooks::new(parse_hook_command("sh -c 'exec sleep 30' {dest}").unwrap(), 1, true, Some(Duration::from_millis(100)));
+        let started = Instant::now();
+        let error = hooks.run(Path::new("/src/a"), Path::new("/dst/a")).await.unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert!(error.to_string().contains("killed after"), "{:#}", error);
+        // Its permit is given back
+        let hooks = Hooks { command: parse_hook_command("true").unwrap(), ..hooks };
+        hooks.run(Path::new("/src/a"), Path::new("/dst/a")).await.unwrap();
+    }
+}
@@ -0,0 +1,95 @@
+//! A graceful stop on Ctrl-C (and SIGTERM on Unix): no other copy is started, the copies in flight get up to
+//! `--grace-period` to finish, the partial destinations of the ones still running then are removed and the run ends
+//! with its summary and the exit code 130. The cleanup of the source of `--delete-source` never runs after it.
+//! A second signal exits at once
+
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use log::{error, warn};
+use tokio::sync::Notify;
+use crate::failures::EXIT_INTERRUPTED;
+
+#[derive(Debug)]
+pub struct Interrupt {
+    requested: AtomicBool,
+    notify: Notify,
+    /// How long the copies in flight can take to finish once it is requested
+    pub grace: Duration,
+}
+
+impl Interrupt {
+    pub fn new(grace: Duration) -> Self {
+        Self { requested: AtomicBool::new(false), notify: Notify::new(), grace }
+    }
+
+    /// An interrupt requested by the signals, from now on
+    pub fn install(grace: Duration) -> std::io::Result<Arc<Self>> {
+        let interrupt = Arc::new(Self::new(grace));
+        #[cfg(unix)]
+        let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+        let requested = interrupt.clone();
+        tokio::spawn(async move {
+            for signals in 0.. {
+                #[cfg(unix)]
+                tokio::select! {
+                    result = tokio::signal::ctrl_c() => if result.is_err() { break },
+                    received = terminate.recv() => if received.is_none() { break },
+                }
+                #[cfg(not(unix))]
+                if tokio::signal::ctrl_c().await.is_err() {
+                    break;
+                }
+                if signals > 0 {
+                    error!("Interrupted again, exiting now: the copies in flight are left as they are");
+                    std::process::exit(EXIT_INTERRUPTED.into());
+                }
+                warn!(
+                    "Interrupted: no other file is copied, the copies in flight have {:.0?} to finish. Interrupt again to exit now",
+                    requested.grace,
+                );
+                requested.request();
+            }
+        });
+        Ok(interrupt)
+    }
+
+    pub fn request(&self) {
+        self.requested.store(true, Ordering::Release);
+        self.notify.notify_waiters();
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::Acquire)
+    }
+
+    /// Wait until it is requested, returns at once when it already was
+    pub async fn requested(&self) {
+        let notified = self.notify.notified();
+        tokio::pin!(notified);
+        // Registered before the check, a request in between is not missed
+        notified.as_mut().enable();
+        if self.is_requested() {
+            return;
+        }
+        notified.await;
+    }
+}
+
+/// The error of an interrupted run, `abandoned` copies were still running at the end of the grace period
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interrupted {
+    pub abandoned: usize,
+}
+
+impl fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.abandoned {
+            0 => write!(f, "The copy was interrupted"),
+            abandoned => write!(f, "The copy was interrupted, {} copies in flight were abandoned", abandoned),
+        }
+    }
+}
+
+impl std::error::Error for Interrupted {}
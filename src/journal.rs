@@ -0,0 +1,239 @@
+//! `--journal`: every finished file is appended to a journal, and a run with the same journal skips the files it has
+//! as done without touching their destination, so a run that died resumes where it was instead of checking every
+//! destination again. The records are written in batches and flushed to the disk every second; a crash loses at most
+//! the last second, whose files are copied again.
+//! The first line is the version (`# rs-copier journal 1`), then every line is `status  size  mtime  path` separated
+//! by tabs: `done` or `failed`, the size and the modification time (nanoseconds since the epoch) of the source and its
+//! path relative to the source of the run. A source that changed since it was recorded is copied again. The records
+//! cut by a crash at the end of the file are dropped when it is opened, and the file is truncated before them.
+//! Sources whose path is not UTF-8 are not recorded
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{Seek, SeekFrom, Write as _};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use anyhow::{anyhow, Context, Result};
+use log::warn;
+use crate::backend::FileInfo;
+use crate::paths;
+
+/// The first line of the journals of this version
+const HEADER: &str = "# rs-copier journal 1";
+
+/// How often the records are flushed to the disk
+const SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The source of a file that is not done yet, as it is recorded once finished
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    path: String,
+    size: u64,
+    modified: u128,
+}
+
+/// The size and modification time of the sources done, by relative path
+type DoneFiles = HashMap<String, (u64, u128)>;
+
+/// What the journal knows of a source
+#[derive(Debug, PartialEq, Eq)]
+pub enum Lookup {
+    /// Done with this size and time already
+    Done,
+    /// To be recorded once finished
+    Pending(Record),
+    /// Without a journal, or a source that cannot be recorded
+    Untracked,
+}
+
+/// The records waiting to be written
+#[derive(Debug)]
+struct Writer {
+    file: Arc<File>,
+    pending: String,
+    synced: Instant,
+}
+
+impl Writer {
+    /// Append the pending records and flush them to the disk
+    async fn sync(&mut self) -> Result<()> {
+        let (file, pending) = (self.file.clone(), std::mem::take(&mut self.pending));
+        tokio::task::spawn_blocking(move || {
+            (&*file).write_all(pending.as_bytes())?;
+            file.sync_data()
+        }).await??;
+        self.synced = Instant::now();
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct Journal {
+    path: PathBuf,
+    /// The source of the run, the paths of the records are relative to it
+    base: PathBuf,
+    /// The size and time of the sources done, in this run or in the previous ones
+    done: std::sync::Mutex<DoneFiles>,
+    writer: tokio::sync::Mutex<Writer>,
+}
+
+impl Journal {
+    /// Open the journal `path` of the source `base`, created when it does not exist yet
+    pub async fn open(path: &Path, base: &Path) -> Result<Self> {
+        let content = match tokio::fs::read(path).await {
+            Ok(content) => content,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(error) => return Err(error).with_context(|| format!("Cannot read the journal: {:?}", path)),
+        };
+        let (done, valid) = parse(&content).with_context(|| format!("Invalid journal: {:?}", path))?;
+        if valid < content.len() {
+            warn!("Dropping {} bytes of incomplete records at the end of the journal {:?}", content.len() - valid, path);
+        }
+        let file = std::fs::OpenOptions::new().create(true).truncate(false).write(true).open(path)
+            .with_context(|| format!("Cannot open the journal: {:?}", path))?;
+        file.set_len(valid as u64).with_context(|| format!("Cannot truncate the journal: {:?}", path))?;
+        (&file).seek(SeekFrom::End(0))?;
+        let pending = if valid == 0 { format!("{}\n", HEADER) } else { String::new() };
+        Ok(Self {
+            path: path.to_owned(),
+            base: base.to_owned(),
+            done: std::sync::Mutex::new(done),
+            writer: tokio::sync::Mutex::new(Writer { file: Arc::new(file), pending, synced: Instant::now() }),
+        })
+    }
+
+    /// Whether the source `from` is done already, or its record once it is finished
+    pub fn lookup(&self, from: &Path, metadata: &FileInfo) -> Lookup {
+        let path = paths::escape(from.strip_prefix(&self.base).unwrap_or(from)).into_owned();
+        let modified = metadata.modified
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |modified| modified.as_nanos());
+        let record = Record { path, size: metadata.len, modified };
+        match self.done.lock().unwrap().get(&record.path) {
+            Some(&(size, modified)) if size == record.size && modified == record.modified => Lookup::Done,
+            _ => Lookup::Pending(record),
+        }
+    }
+
+    /// Append the `record` of a file that was copied (`done`) or failed
+    pub async fn record(&self, record: Record, done: bool) -> Result<()> {
+        let mut writer = self.writer.lock().await;
+        let status = if done { "done" } else { "failed" };
+        let _ = writeln!(writer.pending, "{}\t{}\t{}\t{}", status, record.size, record.modified, record.path);
+        {
+            let mut recorded = self.done.lock().unwrap();
+            match done {
+                true => recorded.insert(record.path, (record.size, record.modified)),
+                false => recorded.remove(&record.path),
+            };
+        }
+        if writer.synced.elapsed() >= SYNC_INTERVAL {
+            writer.sync().await.with_context(|| format!("Cannot write the journal: {:?}", self.path))?;
+        }
+        Ok(())
+    }
+
+    /// Write the records still pending, at the end of the run
+    pub async fn sync(&self) -> Result<()> {
+        self.writer.lock().await.sync().await.with_context(|| format!("Cannot write the journal: {:?}", self.path))
+    }
+
+    /// `--journal-compact`: remove the journal of a `complete` run, or rewrite it with a single record per file done
+    pub async fn compact(&self, complete: bool) -> Result<()> {
+        if complete {
+            return tokio::fs::remove_file(&self.path).await
+                .with_context(|| format!("Cannot remove the journal: {:?}", self.path));
+        }
+        let mut content = format!("{}\n", HEADER);
+        let mut done: Vec<_> = self.done.lock().unwrap().iter().map(|(path, &(size, modified))| (path.clone(), size, modified)).collect();
+        done.sort();
+        for (path, size, modified) in done {
+            let _ = writeln!(content, "done\t{}\t{}\t{}", size, modified, path);
+        }
+        crate::stats::write_replacing(&self.path, content, "journal").await
+    }
+}
+
+/// The sources done in the journal `content`, and the length of its valid records. The invalid ones are only
+/// tolerated at the end, where a crash cuts them
+fn parse(content: &[u8]) -> Result<(DoneFiles, usize)> {
+    let mut done = HashMap::new();
+    let mut valid = 0;
+    let mut lines = content.split_inclusive(|byte| *byte == b'\n');
+    let Some(header) = lines.next() else {
+        return Ok((done, 0));
+    };
+    match std::str::from_utf8(header).map(str::trim_end) {
+        // A header cut by a crash
+        _ if !header.ends_with(b"\n") && format!("{}\n", HEADER).as_bytes().starts_with(header) => return Ok((done, 0)),
+        Ok(HEADER) => valid += header.len(),
+        Ok(line) if line.starts_with("# rs-copier journal ") => return Err(anyhow!("Unsupported version: {:?}", line)),
+        _ => return Err(anyhow!("Not a journal of rs-copier")),
+    }
+    for line in lines {
+        let Some(line) = line.strip_suffix(b"\n").and_then(|line| std::str::from_utf8(line).ok()) else { break };
+        let fields: Vec<_> = line.splitn(4, '\t').collect();
+        let [status, size, modified, path] = fields[..] else { break };
+        let (Ok(size), Ok(modified)) = (size.parse::<u64>(), modified.parse::<u128>()) else { break };
+        match status {
+            "done" => done.insert(path.to_owned(), (size, modified)),
+            "failed" => done.remove(path),
+            _ => break,
+        };
+        valid += line.len() + 1;
+    }
+    Ok((done, valid))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+    use crate::backend::FileInfo;
+    use crate::test_support::init;
+    use super::{Journal, Lookup, HEADER};
+
+    #[tokio::test]
+    async fn crashed_journal() {
+        let base_dir = init("crashed_journal").await;
+
+        let path = base_dir.join("journal");
+        let source = base_dir.join("source");
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+        let info = |len| FileInfo { len, is_dir: false, modified: Some(modified) };
+        let nanos = 1_000_000_000_000u128;
+        // The last record was cut by a crash
+        let content = format!("{HEADER}\ndone\t4\t{nanos}\tdone\nfailed\t4\t{nanos}\tfailed\ndone\t4\t{nanos}\tcut\ndone\t4\t{nanos}\tcu");
+        tokio::fs::write(&path, &content).await.unwrap();
+
+        let journal = Journal::open(&path, &source).await.unwrap();
+        assert_eq!(journal.lookup(&source.join("done"), &info(4)), Lookup::Done);
+        assert!(matches!(journal.lookup(&source.join("failed"), &info(4)), Lookup::Pending(_)));
+        assert_eq!(journal.lookup(&source.join("cut"), &info(4)), Lookup::Done);
+        // Changed since it was recorded
+        assert!(matches!(journal.lookup(&source.join("done"), &info(5)), Lookup::Pending(_)));
+        // The next records follow the last valid one
+        let Lookup::Pending(record) = journal.lookup(&source.join("new"), &info(1)) else { panic!("Not pending") };
+        journal.record(record, true).await.unwrap();
+        journal.sync().await.unwrap();
+        let written = tokio::fs::read_to_string(&path).await.unwrap();
+        assert_eq!(written, format!("{}done\t1\t{nanos}\tnew\n", &content[..content.rfind("done").unwrap()]));
+        assert_eq!(Journal::open(&path, &source).await.unwrap().lookup(&source.join("new"), &info(1)), Lookup::Done);
+
+        // Compacted: the last record of every file done
+        journal.compact(false).await.unwrap();
+        assert_eq!(
+            tokio::fs::read_to_string(&path).await.unwrap(),
+            format!("{HEADER}\ndone\t4\t{nanos}\tcut\ndone\t4\t{nanos}\tdone\ndone\t1\t{nanos}\tnew\n"),
+        );
+        journal.compact(true).await.unwrap();
+        assert!(!path.exists());
+
+        tokio::fs::write(&path, "# rs-copier jour").await.unwrap();
+        assert!(Journal::open(&path, &source).await.is_ok());
+        tokio::fs::write(&path, "# rs-copier journal 2\n").await.unwrap();
+        assert!(Journal::open(&path, &source).await.is_err());
+    }
+}
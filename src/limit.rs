@@ -0,0 +1,319 @@
+//! Limits shared by all the copy tasks
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Same value as the `--concurrency` default
+const DEFAULT_PERMITS: usize = 10;
+
+/// Permits to copy a file. They are shared by all the directories so the number of copies in flight is bounded globally
+#[derive(Debug, Clone)]
+pub struct CopyPermits(Arc<Semaphore>, usize);
+
+impl CopyPermits {
+    pub fn new(permits: usize) -> Self {
+        Self(Arc::new(Semaphore::new(permits)), permits)
+    }
+
+    /// Room for `capacity` copies but only `permits` of them for now, see `add`
+    pub fn limited(capacity: usize, permits: usize) -> Self {
+        Self(Arc::new(Semaphore::new(permits.min(capacity))), capacity)
+    }
+
+    /// Allow `count` more copies in flight
+    pub fn add(&self, count: usize) {
+        self.0.add_permits(count);
+    }
+
+    /// Remove up to `count` of the free permits, returns how many were removed
+    pub fn forget(&self, count: usize) -> usize {
+        self.0.forget_permits(count)
+    }
+
+    /// Wait for a free slot. The slot is released when the permit is dropped
+    pub async fn acquire(&self) -> OwnedSemaphorePermit {
+        self.0.clone().acquire_owned().await.expect("the semaphore is never closed")
+    }
+
+    /// Take up to `count` permits without waiting, only the ones that are free right now
+    pub fn try_acquire_up_to(&self, count: usize) -> Vec<OwnedSemaphorePermit> {
+        (0..count).map_while(|_| self.0.clone().try_acquire_owned().ok()).collect()
+    }
+
+    /// Total number of permits, the most that can be in use with `limited`
+    pub fn capacity(&self) -> usize {
+        self.1
+    }
+
+    #[cfg(test)]
+    pub fn available(&self) -> usize {
+        self.0.available_permits()
+    }
+}
+
+impl Default for CopyPermits {
+    fn default() -> Self {
+        Self::new(DEFAULT_PERMITS)
+    }
+}
+
+/// Fraction of a second of bytes that can be written in a burst
+const BURST_SECONDS: f64 = 0.1;
+
+/// Token bucket that limits the aggregated bytes per second written by all the copy tasks.
+/// A rate of 0 means unlimited. Writes bigger than the bucket take tokens in advance (the bucket goes in debt)
+/// so the following writes wait for the refill: the waits are as long as the chunks need, not whole seconds
+#[derive(Debug)]
+pub struct Bandwidth {
+    rate: AtomicU64,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Bandwidth {
+    pub fn new(rate: u64) -> Self {
+        let bucket = Bucket { tokens: rate as f64 * BURST_SECONDS, refilled: Instant::now() };
+        Self { rate: AtomicU64::new(rate), bucket: Mutex::new(bucket) }
+    }
+
+    /// Bytes per second, 0 when unlimited
+    pub fn rate(&self) -> u64 {
+        self.rate.load(Ordering::Relaxed)
+    }
+
+    /// Change the rate of a running copy
+    #[cfg_attr(not(unix), allow(dead_code))]
+    pub fn set_rate(&self, rate: u64) {
+        self.rate.store(rate, Ordering::Relaxed);
+    }
+
+    /// Take the tokens for `bytes` and return how long to wait before writing them
+    fn reserve(&self, bytes: usize) -> Duration {
+        let rate = self.rate() as f64;
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = Instant::now();
+        if rate == 0.0 {
+            bucket.refilled = now;
+            return Duration::ZERO;
+        }
+        let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(rate * BURST_SECONDS);
+        bucket.refilled = now;
+        bucket.tokens -= bytes as f64;
+        if bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / rate)
+        }
+    }
+
+    /// Wait until `bytes` can be written
+    pub async fn acquire(&self, bytes: usize) {
+        let wait = self.reserve(bytes);
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Same as `acquire` for the copies running in the blocking pool
+    pub fn acquire_blocking(&self, bytes: usize) {
+        let wait = self.reserve(bytes);
+        if !wait.is_zero() {
+            std::thread::sleep(wait);
+        }
+    }
+}
+
+/// Permits to copy a file per source device (`--workers-per-disk`), on top of the global CopyPermits: the copies
+/// from a single disk are bounded on their own so a spinning disk is not read in many places at once, while the
+/// copies from other disks go on
+#[derive(Debug)]
+pub struct DevicePermits {
+    per_device: usize,
+    devices: Mutex<HashMap<u64, Arc<Semaphore>>>,
+}
+
+impl DevicePermits {
+    pub fn new(per_device: usize) -> Self {
+        Self { per_device: per_device.max(1), devices: Mutex::default() }
+    }
+
+    pub fn per_device(&self) -> usize {
+        self.per_device
+    }
+
+    /// Wait for a free slot of the device. The semaphore of a device is created the first time it is seen
+    pub async fn acquire(&self, device: u64) -> OwnedSemaphorePermit {
+        let semaphore = self.devices.lock().unwrap()
+            .entry(device)
+            .or_insert_with(|| Arc::new(Semaphore::new(self.per_device)))
+            .clone();
+        semaphore.acquire_owned().await.expect("the semaphore is never closed")
+    }
+
+    /// Wait for a free slot of the device of the source `path`. None when its device cannot be read (the copy
+    /// reports the error) or outside Unix
+    pub async fn acquire_for(&self, path: &Path) -> Option<OwnedSemaphorePermit> {
+        Some(self.acquire(device(path).await?).await)
+    }
+}
+
+#[cfg(unix)]
+async fn device(path: &Path) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    tokio::fs::metadata(path).await.ok().map(|metadata| metadata.dev())
+}
+
+#[cfg(not(unix))]
+async fn device(_path: &Path) -> Option<u64> {
+    None
+}
+
+/// Bytes of the GB of `--timeout-per-gb`
+const GB: f64 = 1e9;
+
+/// How long a single copy can take (`--file-timeout`), longer for big files with `--timeout-per-gb`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileTimeout {
+    pub base: Duration,
+    pub per_gb: Option<Duration>,
+}
+
+impl FileTimeout {
+    /// The time of a copy of `len` bytes
+    pub fn limit(&self, len: u64) -> Duration {
+        match self.per_gb {
+            Some(per_gb) => self.base + per_gb.mul_f64(len as f64 / GB),
+            None => self.base,
+        }
+    }
+}
+
+/// Interval limiter of the files started per second. The slots are handed out in order, one every interval,
+/// so a burst of waiting tasks is spread evenly instead of starting together
+#[derive(Debug)]
+pub struct FileRate {
+    per_second: u64,
+    interval: Duration,
+    next: Mutex<Instant>,
+}
+
+impl FileRate {
+    pub fn new(per_second: u64) -> Self {
+        let per_second = per_second.max(1);
+        let interval = Duration::from_secs_f64(1.0 / per_second as f64);
+        Self { per_second, interval, next: Mutex::new(Instant::now()) }
+    }
+
+    pub fn per_second(&self) -> u64 {
+        self.per_second
+    }
+
+    /// Wait for the next slot
+    pub async fn wait(&self) {
+        let slot = {
+            let mut next = self.next.lock().unwrap();
+            let slot = (*next).max(Instant::now());
+            *next = slot + self.interval;
+            slot
+        };
+        tokio::time::sleep_until(slot.into()).await;
+    }
+}
+
+/// Every SIGUSR2 switches to the next rate: the configured one, a half, a quarter and unlimited
+#[cfg(unix)]
+pub fn step_rate_on_signal(bandwidth: Arc<Bandwidth>) -> std::io::Result<()> {
+    use log::info;
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut signals = signal(SignalKind::user_defined2())?;
+    let configured = bandwidth.rate();
+    let rates = [configured, configured / 2, configured / 4, 0];
+    tokio::spawn(async move {
+        for rate in rates.iter().cycle().skip(1) {
+            if signals.recv().await.is_none() {
+                break;
+            }
+            bandwidth.set_rate(*rate);
+            match rate {
+                0 => info!("Bandwidth limit disabled"),
+                rate => info!("Bandwidth limit set to {} bytes/s", rate),
+            }
+        }
+    });
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use super::{Bandwidth, DevicePermits, FileRate, FileTimeout};
+
+    #[test]
+    fn bandwidth_waits() {
+        let bandwidth = Bandwidth::new(1000);
+        // The burst is free, the rest waits for its share of the rate
+        assert_eq!(bandwidth.reserve(100), Duration::ZERO);
+        let wait = bandwidth.reserve(500);
+        assert!(wait > Duration::from_millis(450) && wait <= Duration::from_millis(500), "{:?}", wait);
+        // The debt is shared by the next writers
+        assert!(bandwidth.reserve(500) > Duration::from_millis(950));
+
+        bandwidth.set_rate(0);
+        assert_eq!(bandwidth.reserve(1 << 30), Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn file_rate_spreads_the_starts() {
+        let rate = FileRate::new(20);
+        let started = Instant::now();
+        // The first one starts right away, the rest one every 50 ms
+        for _ in 0..5 {
+            rate.wait().await;
+        }
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(200) && elapsed < Duration::from_millis(400), "{:?}", elapsed);
+    }
+
+    #[tokio::test]
+    async fn permits_per_device() {
+        let permits = DevicePermits::new(2);
+        let first = permits.acquire(1).await;
+        let _second = permits.acquire(1).await;
+        // The device is busy, the others are not
+        assert!(tokio::time::timeout(Duration::from_millis(50), permits.acquire(1)).await.is_err());
+        let _other = tokio::time::timeout(Duration::from_millis(50), permits.acquire(2)).await.unwrap();
+        drop(first);
+        let _third = tokio::time::timeout(Duration::from_millis(50), permits.acquire(1)).await.unwrap();
+
+        #[cfg(unix)]
+        {
+            use std::path::Path;
+            let permits = DevicePermits::new(1);
+            let root = permits.acquire_for(Path::new("/")).await;
+            assert!(root.is_some());
+            assert!(tokio::time::timeout(Duration::from_millis(50), permits.acquire_for(Path::new("/"))).await.is_err());
+            assert!(permits.acquire_for(Path::new("/nonexistent")).await.is_none());
+        }
+    }
+
+    #[test]
+    fn timeouts_grow_with_the_size() {
+        let timeout = FileTimeout { base: Duration::from_secs(30), per_gb: None };
+        assert_eq!(timeout.limit(10_000_000_000), Duration::from_secs(30));
+        let timeout = FileTimeout { per_gb: Some(Duration::from_secs(60)), ..timeout };
+        assert_eq!(timeout.limit(0), Duration::from_secs(30));
+        assert_eq!(timeout.limit(2_500_000_000), Duration::from_secs(180));
+    }
+}
@@ -0,0 +1,73 @@
+//! `--relativize-links`: the copied links whose absolute target is inside the source tree get a target relative to
+//! the link instead, so they point inside the destination tree once copied (and keep working after a move)
+
+use std::path::{Component, Path, PathBuf};
+
+/// The source tree as the links can name it
+#[derive(Debug)]
+pub struct SourceTree {
+    /// The source as given, the prefix of the paths of the walk
+    base: PathBuf,
+    /// The absolute and the canonical (without links) forms of the source
+    roots: Vec<PathBuf>,
+}
+
+impl SourceTree {
+    pub fn new(base: &Path) -> Self {
+        let mut roots: Vec<PathBuf> = [std::path::absolute(base).ok(), std::fs::canonicalize(base).ok()]
+            .into_iter()
+            .flatten()
+            .map(|root| normalize(&root))
+            .collect();
+        roots.dedup();
+        Self { base: base.to_owned(), roots }
+    }
+
+    /// The target of `link` (a path of the walk) relative to the directory of the link when `target` is absolute and
+    /// inside the tree. None otherwise
+    pub fn relative_target(&self, link: &Path, target: &Path) -> Option<PathBuf> {
+        if !target.is_absolute() {
+            return None;
+        }
+        let target = normalize(target);
+        let inside = self.roots.iter().find_map(|root| target.strip_prefix(root).ok())?;
+        let depth = link.parent()?.strip_prefix(&self.base).ok()?.components().count();
+        let relative: PathBuf = std::iter::repeat_n(Path::new(".."), depth).chain([inside]).collect();
+        Some(if relative.as_os_str().is_empty() { PathBuf::from(".") } else { relative })
+    }
+}
+
+/// Remove the `.` and resolve the `..` of the path without reading the filesystem
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {},
+            Component::ParentDir => {
+                normalized.pop();
+            },
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use super::SourceTree;
+
+    #[test]
+    fn relative_targets() {
+        let base = std::env::temp_dir().join("rs-copier-links").join("source");
+        let tree = SourceTree::new(&base);
+        let link = base.join("a").join("b").join("link");
+        assert_eq!(tree.relative_target(&link, &base.join("dir").join("file")), Some(PathBuf::from("../../dir/file")));
+        assert_eq!(tree.relative_target(&base.join("link"), &base.join("a/./x/../file")), Some(PathBuf::from("a/file")));
+        assert_eq!(tree.relative_target(&base.join("link"), &base), Some(PathBuf::from(".")));
+        // Outside the tree or already relative
+        assert_eq!(tree.relative_target(&link, &base.join("../other")), None);
+        assert_eq!(tree.relative_target(&link, Path::new("dir/file")), None);
+    }
+}
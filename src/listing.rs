@@ -0,0 +1,120 @@
+//! `--list-only`: the files a copy would select, printed instead of copied
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+use crate::filter::Filters;
+use crate::paths;
+
+/// A file selected for the copy
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Listed {
+    /// Path relative to the source
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+/// Walk the source and return the files that would be copied, in traversal order or sorted by path when `sorted`.
+/// Like the copy, only the regular files selected by the filters are listed and only directories are descended into.
+/// A source that is a file is listed alone
+pub async fn list_tree(source: &Path, filters: &Filters, sorted: bool) -> Result<Vec<Listed>> {
+    let mut listed = vec![];
+    // A single file is listed by its name
+    let metadata = tokio::fs::metadata(source).await
+        .with_context(|| format!("Cannot read metadata: {:?}", source))?;
+    if metadata.is_file() {
+        if filters.selects(metadata.modified().ok()) {
+            let name = source.file_name().ok_or_else(|| anyhow::anyhow!("The source file has no name: {:?}", source))?;
+            listed.push(Listed { path: name.into(), size: metadata.len() });
+        }
+        return Ok(listed);
+    }
+    let mut dirs = vec![source.to_owned()];
+    while let Some(dir) = dirs.pop() {
+        let mut entries = tokio::fs::read_dir(&dir).await
+            .with_context(|| format!("Cannot list directory: {:?}", dir))?;
+        while let Some(entry) = entries.next_entry().await? {
+            let file_type = entry.file_type().await?;
+            if file_type.is_file() {
+                let path = entry.path();
+                let metadata = entry.metadata().await?;
+                if !filters.selects(metadata.modified().ok()) {
+                    continue;
+                }
+                let size = metadata.len();
+                listed.push(Listed { path: path.strip_prefix(source)?.to_owned(), size });
+            } else if file_type.is_dir() {
+                dirs.push(entry.path());
+            }
+        }
+    }
+    if sorted {
+        listed.sort();
+    }
+    Ok(listed)
+}
+
+/// Print one file per line: the relative path and, with `sizes`, a tab and the size in bytes
+pub fn write_listing(listed: &[Listed], sizes: bool, out: &mut impl Write) -> Result<()> {
+    for file in listed {
+        if sizes {
+            writeln!(out, "{}\t{}", paths::escape(&file.path), file.size)?;
+        } else {
+            writeln!(out, "{}", paths::escape(&file.path))?;
+        }
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+    use super::{list_tree, write_listing};
+    use crate::filter::{Filters, TimeWindow};
+    use crate::test_support::init;
+
+    #[tokio::test]
+    async fn list_tree_sorted() {
+        let base_dir = init("list_tree_sorted").await;
+
+        let source = base_dir.join("source");
+        tokio::fs::create_dir_all(source.join("b/c")).await.unwrap();
+        tokio::fs::create_dir_all(source.join("a")).await.unwrap();
+        tokio::fs::write(source.join("b/c/file"), "text").await.unwrap();
+        tokio::fs::write(source.join("a/file"), "te").await.unwrap();
+        tokio::fs::write(source.join("root"), "").await.unwrap();
+
+        let listed = list_tree(&source, &Filters::default(), true).await.unwrap();
+        let mut out = vec![];
+        write_listing(&listed, false, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "a/file\nb/c/file\nroot\n");
+
+        let mut out = vec![];
+        write_listing(&listed, true, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "a/file\t2\nb/c/file\t4\nroot\t0\n");
+        // A source that is a single file
+        let listed = list_tree(&source.join("b/c/file"), &Filters::default(), true).await.unwrap();
+        assert_eq!(listed.iter().map(|file| (file.path.to_str().unwrap(), file.size)).collect::<Vec<_>>(), [("file", 4)]);
+        // Nothing is created
+        assert!(!base_dir.join("dest").exists());
+    }
+
+    #[tokio::test]
+    async fn list_filtered_tree() {
+        let base_dir = init("list_filtered_tree").await;
+
+        let source = base_dir.join("source");
+        tokio::fs::create_dir_all(source.join("dir")).await.unwrap();
+        tokio::fs::write(source.join("dir/recent"), "text").await.unwrap();
+        let old = std::fs::File::create(source.join("dir/old")).unwrap();
+        old.set_modified(SystemTime::now() - Duration::from_secs(3600)).unwrap();
+
+        let newest = Some(SystemTime::now() - Duration::from_secs(60));
+        let filters = Filters { time_window: TimeWindow { newest, ..Default::default() } };
+        let listed = list_tree(&source, &filters, true).await.unwrap();
+        let mut out = vec![];
+        write_listing(&listed, false, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "dir/old\n");
+    }
+}
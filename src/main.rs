@@ -6,274 +6,4979 @@
 //!
 //!But you can always run with `--help` to get more details
 
-use std::vec;
+mod adaptive;
+mod archive;
+mod atomic;
+mod backend;
+mod bench;
+mod casefold;
+mod checksum;
+mod checksum_cache;
+mod claims;
+mod config;
+mod confirm;
+mod copy;
+mod dedupe;
+mod direct;
+mod failures;
+mod files_from;
+mod filter;
+mod hook;
+mod interrupt;
+mod journal;
+mod limit;
+mod links;
+mod listing;
+mod manifest;
+mod metadata;
+mod overlap;
+mod paths;
+mod pipeline;
+mod prescan;
+mod priority;
+mod reflink;
+mod rename;
+mod resume;
+mod retry;
+mod runtime;
+mod sample;
+#[cfg(unix)]
+mod sftp;
+mod size;
+mod source;
+mod space;
+mod stats;
+mod tempdir;
+#[cfg(test)]
+mod test_support;
+mod trash;
+mod verify;
+mod volatile;
+mod walk;
+mod winpath;
+mod xxh3;
+mod zip;
+#[cfg(all(feature = "uring", target_os = "linux"))]
+mod uring;
+
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::io::{self, IsTerminal, Write};
 use std::path::PathBuf;
-use anyhow::Result;
-use tokio::task::JoinSet;
-use log::{info, debug, error};
+use std::pin::Pin;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use anyhow::{Context, Result};
+use backend::{Backend, FileInfo, LocalBackend};
+use bench::{BenchArgs, Measure};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::task::{JoinError, JoinSet};
+use log::{info, debug, error, warn};
 use std::path::Path;
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
+use serde::Deserialize;
+use adaptive::{Adaptive, Concurrency, AUTO_MAX, AUTO_START};
+use atomic::TempFile;
+use volatile::{Changed, Outcome, Snapshot, VolatileFiles};
+use casefold::CaseFolds;
+use claims::Claims;
+use copy::{BufferSize, Copied, Moved, PipelineDepth};
+use dedupe::{Dedupe, Original};
+use failures::{Failures, Incomplete, Panicked};
+use interrupt::{Interrupt, Interrupted};
+use journal::Journal;
+use filter::{Filters, TimeWindow};
+use hook::{HookCommand, Hooks};
+use limit::{Bandwidth, CopyPermits, DevicePermits, FileRate, FileTimeout};
+use links::SourceTree;
+use priority::{IoNice, Priority};
+use retry::Retry;
+use runtime::Threads;
+use checksum::ChecksumAlgorithm;
+use checksum_cache::ChecksumCache;
+use manifest::Manifest;
+use metadata::PreserveFlags;
+use pipeline::{CreatedDirs, WorkItem};
+use prescan::{Scan, Totals};
+use rename::RenamePattern;
+use stats::{Checkpoints, CopyStats};
+use tempdir::TempDir;
+use trash::Trash;
+use walk::{Entry, LinkMode, Links};
+
+/// What to do when the destination file already exists
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum IfExists {
+    /// Replace the destination
+    #[default]
+    Overwrite,
+    /// Keep the destination and do not copy the file
+    Skip,
+    /// Fail the file
+    Error,
+    /// Replace the destination when the source is newer (by more than --modify-window), keep it otherwise
+    Newer,
+}
+
+/// How `--skip-unchanged` tells that a destination file is already a copy of its source
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum SkipUnchanged {
+    /// Same size and same checksum of the head, the middle and the tail
+    Sample,
+}
+
+/// What to do when the destination has a directory where the source has a file, or the other way round
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum OnTypeConflict {
+    /// Fail the entry, the rest is copied
+    #[default]
+    Error,
+    /// Remove the destination entry first, with everything in it when it is a directory
+    Replace,
+    /// Keep the destination and do not copy the entry
+    Skip,
+}
+
+/// What happens to a followed link whose target does not exist (`--dangling-links`)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum DanglingLinks {
+    /// Leave it out with a warning
+    Skip,
+    /// Copy it as a link, to the same missing target
+    Preserve,
+    /// Fail it like a file that cannot be copied
+    #[default]
+    Error,
+}
+
+/// What happens to a file whose source changed while it was copied (`--volatile`, `--on-change`)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Volatile {
+    /// Copy it again, up to `--retries` times, and then fail it
+    #[default]
+    Retry,
+    /// Leave it out without failing the run
+    Skip,
+    /// Fail it at once
+    Fail,
+    /// Keep its copy, which can be torn, and warn about it
+    Warn,
+}
+
+/// The case of the destination directories with `--case-fold-merge`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum CaseFold {
+    Lower,
+    Upper,
+}
+
+/// How much of the source path is kept at the destination
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum SourceBase {
+    /// The content of the source is copied into the destination
+    #[default]
+    Parent,
+    /// The source directory itself is created in the destination, with its name
+    #[clap(name = "self")]
+    #[serde(rename = "self")]
+    Itself,
+}
+
+/// When to clone the files with a reflink (copy-on-write filesystems) instead of copying their bytes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Reflink {
+    /// Clone when the filesystem supports it, copy otherwise
+    Auto,
+    /// Fail the files that cannot be cloned
+    Always,
+    /// Always copy the bytes
+    #[default]
+    Never,
+}
+
+/// How `--dedupe-dest` writes a file with the content of an earlier copy
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum DedupeMethod {
+    /// A hard link to the earlier copy, with its metadata
+    #[default]
+    HardLink,
+    /// A clone of the earlier copy (copy-on-write filesystems), a file of its own with the metadata of its source
+    Reflink,
+}
+
+/// How the bytes of the files are copied
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Engine {
+    /// Let the operating system copy the file (copy_file_range on Linux), unless some feature needs to read the bytes
+    #[default]
+    Auto,
+    /// Read and write the file with a buffer of `--buffer-size` bytes
+    Stream,
+    /// Queue batches of reads and writes in an io_uring (Linux, built with the `uring` feature)
+    Uring,
+}
+
+/// What happens when a file cannot be copied
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum ErrorMode {
+    /// Log the file and go on with the others
+    #[default]
+    Continue,
+    /// Cancel the copies in flight, remove their partial destinations and stop with the error
+    FailFast,
+}
+
+/// In which order the files are copied
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Order {
+    /// Copy the files of every directory as the tree is walked
+    #[default]
+    Walk,
+    /// The biggest files first, so a huge file does not start last and finish the run alone (needs the pre-scan)
+    LargestFirst,
+    /// The smallest files first (needs the pre-scan)
+    SmallestFirst,
+    /// By path, the same order in every run (needs the pre-scan)
+    Path,
+}
+
+/// Options that control how the files are copied
+#[derive(Debug, Clone, Default)]
+struct CopyOptions {
+    /// Remove the source files once copied
+    remove_source: bool,
+    /// Remove the empty source directories at the end even when some files failed
+    force_source_cleanup: bool,
+    /// Number of ranges copied concurrently for big files. 0 or 1 disables the chunked copy
+    chunk_parallelism: usize,
+    /// Files bigger than this size (in bytes) are copied in chunks
+    chunk_threshold: u64,
+    /// Compare the checksums of source and destination after a chunked copy
+    verify_chunked: bool,
+    /// Read every copy back and compare it with the checksum of its source
+    verify: bool,
+    /// Copy the files bigger than chunk_threshold through a `.part` that a later copy can resume
+    resume: bool,
+    /// Where the `.part` files are written instead of next to their destination
+    tempdir: Option<Arc<TempDir>>,
+    /// What is carried from the sources to the destination files (times, mode, owner...)
+    preserve: PreserveFlags,
+    /// The copied links with an absolute target inside this tree get a relative one
+    relativize_links: Option<Arc<SourceTree>>,
+    /// With preserve.links, the links at the top of the source are followed all the same
+    follow_top_level_links: bool,
+    /// Collect the checksum of every copied file
+    manifest: Option<Arc<Manifest>>,
+    /// The copied files by content, the next files with the same one are hard links to them
+    dedupe: Option<Arc<Dedupe>>,
+    /// How the files are written from their earlier copy
+    dedupe_method: DedupeMethod,
+    /// Algorithm of all the checksums
+    checksum_algorithm: ChecksumAlgorithm,
+    /// Checksums of the sources computed by previous runs
+    checksum_cache: Option<Arc<ChecksumCache>>,
+    /// The files done in the previous runs, skipped, and the ones finished in this one
+    journal: Option<Arc<Journal>>,
+    /// Abort at the first file that cannot be copied instead of logging it and going on
+    stop_on_error: bool,
+    /// Abort once more files than this could not be copied, 0 never does
+    max_errors: u64,
+    /// Stops the run on Ctrl-C and SIGTERM, after a grace period for the copies in flight
+    interrupt: Option<Arc<Interrupt>>,
+    /// What is done with the files whose source changed while they were copied
+    volatile: Volatile,
+    /// What is done with the followed links whose target does not exist
+    dangling_links: DanglingLinks,
+    /// The files that changed while they were copied
+    volatile_files: Arc<VolatileFiles>,
+    /// Do not compare the sources before and after their copy (--no-detect-changes)
+    ignore_changes: bool,
+    /// Stop the copy when the free space of the destination drops below a minimum
+    min_free_space: Option<space::Monitor>,
+    /// Bound of the files copied at the same time across all the directories
+    copy_permits: CopyPermits,
+    /// Resizes copy_permits with `--concurrency auto`
+    adaptive: Option<Arc<Adaptive>>,
+    /// Bound of the files copied at the same time from a source device, taken before copy_permits
+    device_permits: Option<Arc<DevicePermits>>,
+    /// Flush the destination files and directories to the disk. The sources are only removed once they are flushed
+    fsync: bool,
+    /// Number of files copied into a directory between the flushes of that directory (and the removal of their sources)
+    fsync_batch: usize,
+    /// Write the files under a temporary name in their directory and rename them once complete
+    atomic: bool,
+    /// Leading components of the relative path removed at the destination
+    strip_components: usize,
+    /// Rename the entries whose names are not valid on Windows instead of failing them
+    sanitize_names: bool,
+    /// The command run after every file is copied
+    hooks: Option<Arc<Hooks>>,
+    /// The directories whose names differ only by their case, when the destination ignores it or --case-fold-merge
+    case_folds: Option<Arc<CaseFolds>>,
+    /// New names of the destination files
+    rename_pattern: Option<RenamePattern>,
+    /// Policy for the destination files that already exist
+    if_exists: IfExists,
+    /// Difference of the mtimes still taken as the same time by `--if-exists newer`
+    modify_window: Duration,
+    /// Policy for the destination entries of another type (a directory for a file, a file for a directory)
+    on_type_conflict: OnTypeConflict,
+    /// The destination files that match their source this way are not copied again
+    skip_unchanged: Option<SkipUnchanged>,
+    /// Destinations written in this run, only tracked when several sources can map to the same destination
+    claims: Option<Arc<Claims>>,
+    /// Clone the files instead of copying them
+    reflink: Reflink,
+    /// Counters of the run
+    stats: Arc<CopyStats>,
+    /// The files that could not be copied
+    failures: Arc<Failures>,
+    /// How the bytes are copied
+    copy_engine: Engine,
+    /// Buffer of the streaming and chunked copies
+    buffer_size: BufferSize,
+    /// Buffers read ahead by the streaming copy
+    pipeline_depth: PipelineDepth,
+    /// Limit of the bytes per second written by all the copies together
+    bandwidth: Option<Arc<Bandwidth>>,
+    /// Limit of the file copies started per second
+    file_rate: Option<Arc<FileRate>>,
+    /// Copies that take longer fail
+    file_timeout: Option<FileTimeout>,
+    /// The copies that fail with a transient error are tried again
+    retry: Option<Retry>,
+    /// The source deletions wait for the file rate too
+    rate_limit_deletions: bool,
+    /// Mode of the destination files instead of the source one (Unix only)
+    chmod: Option<u32>,
+    /// Mode of the destination directories (Unix only)
+    chmod_dirs: Option<u32>,
+    /// Reserve the size of the destination before writing it
+    preallocate: bool,
+    /// Bypass the page cache where the filesystem allows it
+    direct_io: bool,
+    /// Which files of the source are copied
+    filters: Filters,
+    /// Only create the destination directories that get a file, or have one below them
+    prune_empty_dirs: bool,
+    /// Files smaller than this size (in bytes) are copied in batches in the blocking pool. 0 disables the batches
+    small_file_threshold: u64,
+    /// The removed sources are moved here instead of being deleted
+    trash: Option<Arc<Trash>>,
+    /// Bound of the directories found and not started yet, QUEUED_DIRECTORIES_PER_TASK per concurrent directory when None
+    max_pending: Option<usize>,
+    /// Where the files are read, the local filesystem when None
+    source: Option<Arc<dyn Backend>>,
+    /// Where the files are written, the local filesystem when None
+    destination: Option<Arc<dyn Backend>>,
+}
+
+impl CopyOptions {
+    /// Whether the content of the files must be hashed while copying
+    fn needs_digest(&self) -> bool {
+        self.manifest.is_some() || self.dedupe.is_some() || self.verify
+    }
+
+    /// Whether more files than max_errors failed, and the run must stop
+    fn too_many_errors(&self) -> bool {
+        self.max_errors > 0 && self.stats.files_failed.load(std::sync::atomic::Ordering::Relaxed) > self.max_errors
+    }
+
+    /// Whether the failures stop the run now: at the first one with stop_on_error, or past max_errors
+    fn stops(&self) -> bool {
+        self.stop_on_error || self.too_many_errors()
+    }
+
+    /// Whether the removal of the sources waits for `collect` instead of being done by the copy: to flush the
+    /// directory first, or because a copy that times out must not remove its source half way
+    fn defers_removal(&self) -> bool {
+        self.fsync || self.file_timeout.is_some()
+    }
+
+    /// Whether the small files can be copied in batches: nothing but a plain system copy is needed for them
+    fn batches_small_files(&self) -> bool {
+        self.small_file_threshold > 0
+            && self.file_timeout.is_none()
+            && !self.needs_digest()
+            && self.bandwidth.is_none()
+            && self.reflink == Reflink::Never
+            && self.claims.is_none()
+            && self.copy_engine == Engine::Auto
+            && !self.direct_io
+            && !self.preallocate
+            && self.skip_unchanged.is_none()
+            && self.journal.is_none()
+            && !self.atomic
+            && self.source.is_none()
+            && self.destination.is_none()
+    }
+
+    /// Which links of the source are copied as links
+    fn links(&self) -> Links {
+        let mode = match self.preserve.links {
+            false => LinkMode::Followed,
+            true if self.follow_top_level_links => LinkMode::FollowedAtTopLevel,
+            true => LinkMode::Preserved,
+        };
+        Links { mode, dangling: self.dangling_links }
+    }
+
+    /// Where the files are read
+    fn source(&self) -> &dyn Backend {
+        self.source.as_deref().unwrap_or(&LocalBackend)
+    }
+
+    /// Same as `source`, for the tasks that keep it
+    fn source_backend(&self) -> Arc<dyn Backend> {
+        self.source.clone().unwrap_or_else(|| Arc::new(LocalBackend))
+    }
+
+    /// Where the files are written
+    fn destination(&self) -> &dyn Backend {
+        self.destination.as_deref().unwrap_or(&LocalBackend)
+    }
+}
+
+/// Number of small files copied by a single blocking task
+const SMALL_FILES_PER_BATCH: usize = 64;
+
+/// What process_small_files returns
+#[derive(Debug, Default)]
+struct Finished {
+    /// Source, destination and result of every file copied by the batch
+    results: Vec<(PathBuf, PathBuf, Result<Option<PathBuf>>)>,
+    /// Files of a batch that are not small, they are copied on their own
+    large: Vec<(PathBuf, PathBuf)>,
+}
+
+/// Send all the files of the directory from source to dest to the workers of the pipeline and then the end of the
+/// directory, so the workers copy them (and remove the sources if options.remove_source = true).
+/// The subdirectories are pushed to the queue as they are found.
+/// Every file is sent on its own, except the files smaller than options.small_file_threshold: they are sent in batches
+/// copied by a blocking task each. The size is only known in the batch, the bigger files are copied on their own.
+/// The work channel is bounded: when the workers are busy the listing waits for them.
+/// Listing errors are logged and skipped unless options.stop_on_error = true, then the first one is returned. A
+/// directory that cannot be listed is counted as failed and skipped the same way.
+/// A directory that cannot be created at the destination is reported the same way and nothing below it is copied
+async fn process_directory(source: &Path, dest: &Path, options: &Arc<CopyOptions>, queue: &DirectoryQueue) -> Result<()> {
+    info!("Processing dir: {:?}", source);
+    let mut entries = match options.source().read_dir(source).await {
+        Ok(entries) => entries,
+        Err(error) => return report_listing_error(source, error, options, queue),
+    };
+    let existing = options.destination().metadata(dest).await.ok();
+    if let Some(existing) = existing.filter(|existing| !existing.is_dir) {
+        if !type_conflict(source, dest, existing, options).await? {
+            return Ok(());
+        }
+    }
+    // Only a destination that was already there can have entries in the way
+    let merging = existing.is_some_and(|existing| existing.is_dir);
+    // With --no-include-empty-dirs the directory waits for its first file, the top of the tree is always created
+    let mut created = !options.prune_empty_dirs || dest == &*queue.base_dest;
+    if created && !queue.created.create(dest, source, options).await? {
+        // Nothing of this directory can be copied
+        return Ok(());
+    }
+    let mut files = 0;
+    let batches_small_files = options.batches_small_files();
+    let with_metadata = options.rename_pattern.as_ref().is_some_and(RenamePattern::needs_metadata);
+    let mut small_files = vec![];
+    let depth = queue.depth(source);
+    while let Some(entry) = walk::next_entry(options.source(), &mut *entries, &options.filters, with_metadata, options.links(), depth).await {
+        match entry {
+            Ok(Entry::Link(from)) => {
+                if let Some(file_rate) = &options.file_rate {
+                    file_rate.wait().await;
+                }
+                let to = match destination_file(&from, dest, None, files + 1, options).await {
+                    Ok(to) => to,
+                    Err(error) => {
+                        report_path_error(&from, error, options)?;
+                        continue;
+                    },
+                };
+                if merging && !file_fits(&from, &to, options).await? {
+                    continue;
+                }
+                if !created && !queue.create_with_parents(dest, source, options).await? {
+                    return Ok(());
+                }
+                created = true;
+                files += 1;
+                queue.send(WorkItem::Link { from, to }).await?;
+            },
+            Ok(Entry::File { path: from, metadata }) => {
+                // Wait for the rate before queueing the file so the waiting files do not block the others
+                if let Some(file_rate) = &options.file_rate {
+                    file_rate.wait().await;
+                }
+                let to = match destination_file(&from, dest, metadata, files + 1, options).await {
+                    Ok(to) => to,
+                    Err(error) => {
+                        report_path_error(&from, error, options)?;
+                        continue;
+                    },
+                };
+                if merging && !file_fits(&from, &to, options).await? {
+                    continue;
+                }
+                if !created && !queue.create_with_parents(dest, source, options).await? {
+                    return Ok(());
+                }
+                created = true;
+                files += 1;
+                // The size is checked by the batch unless the filters already read it
+                if batches_small_files && metadata.is_none_or(|metadata| metadata.len < options.small_file_threshold) {
+                    small_files.push((from, to));
+                    if small_files.len() == SMALL_FILES_PER_BATCH {
+                        queue.send(WorkItem::SmallFiles(std::mem::take(&mut small_files))).await?;
+                    }
+                } else {
+                    queue.send(WorkItem::File { from, to, metadata }).await?;
+                }
+            },
+            Ok(Entry::Directory(path)) => queue.push(path, options).await?,
+            // The listing goes on with the next entries
+            Err(error) => report_file_error(error, options)?,
+        }
+    }
+    if !small_files.is_empty() {
+        queue.send(WorkItem::SmallFiles(small_files)).await?;
+    }
+    if !created {
+        // Pruned, nothing to flush
+        return Ok(());
+    }
+    queue.send(WorkItem::Dir { source: source.to_owned(), dest: dest.to_owned(), files }).await
+}
+
+/// Whether the file (or link) `from` can be copied to `to`: true unless a directory is in the way and
+/// --on-type-conflict does not replace it
+async fn file_fits(from: &Path, to: &Path, options: &CopyOptions) -> Result<bool> {
+    match options.destination().metadata(to).await {
+        Ok(existing) if existing.is_dir => type_conflict(from, to, existing, options).await,
+        _ => Ok(true),
+    }
+}
+
+/// Apply --on-type-conflict to the source `from` whose destination `to` is the `existing` entry of another type.
+/// True when `from` can be copied, the conflicting entry was removed
+async fn type_conflict(from: &Path, to: &Path, existing: FileInfo, options: &CopyOptions) -> Result<bool> {
+    let (found, copied) = if existing.is_dir { ("a directory", "file") } else { ("a file", "directory") };
+    match options.on_type_conflict {
+        OnTypeConflict::Error => {
+            let error = anyhow::anyhow!("The destination {:?} is {}, the {} {:?} is not copied (see --on-type-conflict)", to, found, copied, from);
+            report_path_error(from, error, options)?;
+            Ok(false)
+        },
+        OnTypeConflict::Skip => {
+            info!("Skip {:?}, the destination {:?} is {}", from, to, found);
+            if existing.is_dir {
+                options.stats.skipped();
+            }
+            Ok(false)
+        },
+        OnTypeConflict::Replace => {
+            info!("Replace {} {:?} with the {} {:?}", found, to, copied, from);
+            let removed = if existing.is_dir {
+                options.destination().remove_dir_all(to).await
+            } else {
+                options.destination().remove_file(to).await
+            };
+            match removed {
+                Ok(()) => Ok(true),
+                Err(error) => report_path_error(from, error, options).map(|()| false),
+            }
+        },
+    }
+}
+
+/// Where the entry `from` of a source directory is copied in `dest`, with the name given by --rename-pattern.
+/// `index` is its position in the directory, from 1. The metadata is read here when the pattern needs it
+async fn destination_file(from: &Path, dest: &Path, metadata: Option<FileInfo>, index: usize, options: &CopyOptions) -> Result<PathBuf> {
+    let name = from.file_name().expect("a directory entry has a name");
+    let Some(pattern) = &options.rename_pattern else {
+        return Ok(dest.join(winpath::destination_name(name, options.sanitize_names)?));
+    };
+    let name = name.to_str().ok_or_else(|| anyhow::anyhow!("Cannot rename a file whose name is not UTF-8: {:?}", from))?;
+    let mtime = match metadata {
+        Some(metadata) => metadata.modified,
+        None if pattern.needs_metadata() => options.source().metadata(from).await
+            .with_context(|| format!("Cannot read metadata: {:?}", from))?.modified,
+        None => None,
+    };
+    let renamed = pattern.apply(name, mtime, index).with_context(|| format!("Cannot rename file: {:?}", from))?;
+    Ok(dest.join(winpath::destination_name(renamed.as_ref(), options.sanitize_names)?))
+}
+
+/// Create the destination of the source directory `source`. False when it cannot be created, the error is reported.
+/// The failures to flush it or to set its mode and ACLs are reported too but the directory can be used
+async fn create_destination_directory(dest: &Path, source: &Path, options: &CopyOptions) -> Result<bool> {
+    if let Err(error) = options.destination().create_dir_all(dest).await {
+        report_path_error(dest, error, options)?;
+        return Ok(false);
+    }
+    if options.atomic {
+        if let Err(error) = atomic::remove_leftovers(dest).await {
+            report_path_error(dest, error, options)?;
+        }
+    }
+    if options.fsync {
+        // The entry of the directory itself
+        if let Some(parent) = dest.parent() {
+            if let Err(error) = options.destination().sync_directory(parent).await {
+                report_file_error(error.context(format!("Cannot sync directory: {:?}", parent)), options)?;
+            }
+        }
+    }
+    if options.preserve.acls {
+        if let Err(error) = metadata::copy_acls(source, dest).await {
+            report_file_error(error.context(format!("Cannot set the ACLs of directory: {:?}", dest)), options)?;
+        }
+    }
+    if let Some(mode) = options.chmod_dirs {
+        if let Err(error) = metadata::set_mode(dest, mode).await {
+            report_file_error(error.context(format!("Cannot set the mode of directory: {:?}", dest)), options)?;
+        }
+    }
+    Ok(true)
+}
+
+/// Flush the destination directory and then remove the sources of the files already copied into it
+async fn remove_synced(dest: &Path, sources: &mut Vec<PathBuf>, options: &CopyOptions) -> Result<()> {
+    if let Err(error) = options.destination().sync_directory(dest).await {
+        let error = error.context(format!("Cannot sync directory, the sources are kept: {:?}", dest));
+        // Every file whose source is kept fails
+        for source in sources.drain(..) {
+            report_path_error(&source, anyhow::anyhow!("{:#}", error), options)?;
+        }
+        return Ok(());
+    }
+    for source in sources.drain(..) {
+        if let Err(error) = remove_source(&source, options).await {
+            report_path_error(&source, error, options)?;
+        }
+    }
+    Ok(())
+}
+
+async fn remove_source(from: &Path, options: &CopyOptions) -> Result<()> {
+    if let (Some(file_rate), true) = (&options.file_rate, options.rate_limit_deletions) {
+        file_rate.wait().await;
+    }
+    match &options.trash {
+        Some(trash) => trash.move_file(from).await,
+        None => options.source().remove_file(from).await,
+    }
+}
+
+/// Remove the source directories once their files were moved, after the copy of the whole tree. Nothing is removed
+/// when a file failed, unless `--force-source-cleanup`. Only the empty directories are removed, bottom-up: the files
+/// left behind (failed, skipped or not selected) keep their directories. An interrupted run removes nothing
+async fn clean_source_tree(base_source: &Path, options: &CopyOptions) -> Result<()> {
+    let failed = options.stats.files_failed.load(std::sync::atomic::Ordering::Relaxed);
+    if !cleans_source(failed, options)? {
+        return Err(anyhow::anyhow!(
+            "{} files could not be copied, the source directories are kept (see --force-source-cleanup)", failed,
+        ));
+    }
+    let dir = base_source.to_owned();
+    let left = tokio::task::spawn_blocking(move || remove_empty_dirs(&dir)).await?
+        .with_context(|| format!("Cannot remove the source directories: {:?}", base_source))?;
+    if left > 0 {
+        warn!("{} entries were not moved, they are kept in the source {:?}", left, base_source);
+    }
+    Ok(())
+}
+
+/// Whether what is left of the source is removed at the end of the run, once `failed` entries could not be copied:
+/// not when one did, unless `--force-source-cleanup`. An interrupted run is an error
+fn cleans_source(failed: u64, options: &CopyOptions) -> Result<bool> {
+    if options.interrupt.as_ref().is_some_and(|interrupt| interrupt.is_requested()) {
+        return Err(Interrupted { abandoned: 0 }.into());
+    }
+    Ok(failed == 0 || options.force_source_cleanup)
+}
+
+/// Remove `dir` and the directories below it that are empty once the empty ones below them are removed, the number
+/// of other entries found
+fn remove_empty_dirs(dir: &Path) -> io::Result<u64> {
+    let mut left = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            left += remove_empty_dirs(&entry.path())?;
+        } else {
+            left += 1;
+        }
+    }
+    if left == 0 {
+        std::fs::remove_dir(dir)?;
+    }
+    Ok(left)
+}
+
+/// Remove the source tree once it is packed into `archive`. Like after a copy, nothing is removed when a file could
+/// not be archived or an entry was left out of it, unless `--force-source-cleanup`. The archive is flushed first, then
+/// only the entries that went into it are removed (or moved to the trash), bottom-up: the directories of the entries
+/// left behind are kept
+async fn remove_packed_source(base_source: &Path, archive: &Path, packed: archive::Packed, options: &CopyOptions) -> Result<()> {
+    let failed = options.stats.files_failed.load(std::sync::atomic::Ordering::Relaxed);
+    if !cleans_source(failed + packed.skipped, options)? {
+        return Err(anyhow::anyhow!(
+            "{} files could not be archived and {} entries are neither files nor directories, the source {:?} is kept \
+            (see --force-source-cleanup)",
+            failed, packed.skipped, base_source,
+        ));
+    }
+    copy::sync_file(archive).await
+        .with_context(|| format!("Cannot sync archive, the source is kept: {:?}", archive))?;
+    if let Some(parent) = archive.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        copy::sync_directory(parent).await
+            .with_context(|| format!("Cannot sync directory, the source is kept: {:?}", parent))?;
+    }
+    for file in &packed.files {
+        if let Err(error) = remove_source(file, options).await {
+            report_path_error(file, error, options)?;
+        }
+    }
+    let mut directories = packed.directories;
+    directories.push(base_source.to_owned());
+    // The deepest first
+    directories.sort_by_key(|dir| std::cmp::Reverse(dir.components().count()));
+    let mut left = 0;
+    for dir in &directories {
+        if let Err(error) = tokio::fs::remove_dir(dir).await {
+            debug!("Cannot remove directory {:?}: {}", dir, error);
+            left += 1;
+        }
+    }
+    if left > 0 {
+        warn!("{} directories are not empty, they are kept in the source {:?}", left, base_source);
+    }
+    Ok(())
+}
+
+/// List a directory that --strip-components removes from the destination.
+/// Its files are not deep enough to be placed so they are reported as errors
+async fn skip_directory(source: &Path, options: &Arc<CopyOptions>, queue: &DirectoryQueue) -> Result<()> {
+    info!("Processing stripped dir: {:?}", source);
+    let mut entries = match options.source().read_dir(source).await {
+        Ok(entries) => entries,
+        Err(error) => return report_listing_error(source, error, options, queue),
+    };
+    while let Some(entry) = walk::next_entry(options.source(), &mut *entries, &options.filters, false, options.links(), queue.depth(source)).await {
+        match entry {
+            Ok(Entry::File { path, .. } | Entry::Link(path)) => {
+                let error = anyhow::anyhow!("Cannot strip {} components from {:?}", options.strip_components, path);
+                report_path_error(&path, error, options)?;
+            },
+            Ok(Entry::Directory(path)) => queue.push(path, options).await?,
+            Err(error) => report_file_error(error, options)?,
+        }
+    }
+    Ok(())
+}
+
+/// Destination of the source directory `dir` once the first --strip-components of its relative path are removed,
+/// with the names --sanitize-names and --case-fold-merge give. None when the directory is not deeper than that, then
+/// its files cannot be placed
+fn destination_dir(base_source: &Path, base_dest: &Path, dir: &Path, options: &CopyOptions) -> Result<Option<PathBuf>> {
+    let mut components = dir.strip_prefix(base_source)
+        .map_err(|_| anyhow::anyhow!("The directory {:?} is not inside the source {:?}", dir, base_source))?
+        .components();
+    for _ in 0..options.strip_components {
+        if components.next().is_none() {
+            return Ok(None);
+        }
+    }
+    let relative = winpath::destination_path(components.as_path(), options.sanitize_names)?;
+    let Some(folds) = &options.case_folds else {
+        return Ok(Some(base_dest.join(relative)));
+    };
+    let dest = base_dest.join(folds.fold(relative));
+    folds.claim(dir, &dest)?;
+    Ok(Some(dest))
+}
+
+/// Copy the directory into its destination or just list it when it is stripped
+async fn visit_directory(dir: &Path, dest: Option<&Path>, options: &Arc<CopyOptions>, queue: &DirectoryQueue) -> Result<()> {
+    match dest {
+        Some(dest) => process_directory(dir, dest, options, queue).await,
+        None => skip_directory(dir, options, queue).await,
+    }
+}
+
+/// Directories found and not processed yet, up to this number per concurrent directory unless options.max_pending
+const QUEUED_DIRECTORIES_PER_TASK: usize = 64;
+
+/// Directories found and not processed yet, shared by all the tasks of copy_tree. The queue is bounded:
+/// when it is full the task that found the directory processes it right away (depth first), so the memory does not
+/// grow with the width of the tree and the tasks never wait for each other.
+/// The memory of the walk is then the queue, the directories in flight and, for every task, the chain of directories
+/// it is processing right away (as long as the depth of the tree at most)
+#[derive(Debug, Clone)]
+struct DirectoryQueue {
+    sender: mpsc::Sender<PathBuf>,
+    /// The files found go to the workers of the pipeline
+    work: mpsc::Sender<WorkItem>,
+    /// The destination directories created so far
+    created: Arc<CreatedDirs>,
+    base_source: Arc<Path>,
+    base_dest: Arc<Path>,
+}
+
+impl DirectoryQueue {
+    fn new(base_source: &Path, base_dest: &Path, capacity: usize, work: mpsc::Sender<WorkItem>) -> (Self, mpsc::Receiver<PathBuf>) {
+        let (sender, receiver) = mpsc::channel(capacity.max(1));
+        (Self { sender, work, created: Arc::default(), base_source: base_source.into(), base_dest: base_dest.into() }, receiver)
+    }
+
+    /// Create the destination directory `dest` and the ones above it in the destination of the tree, the parents
+    /// first, for the directories that wait for their first file with --no-include-empty-dirs. `source` is the source
+    /// of `dest`, the levels above them match from the bottom
+    async fn create_with_parents(&self, dest: &Path, source: &Path, options: &CopyOptions) -> Result<bool> {
+        let dirs: Vec<_> = dest.ancestors().take_while(|dir| dir.starts_with(&self.base_dest))
+            .zip(source.ancestors())
+            .collect();
+        for (dir, source) in dirs.into_iter().rev() {
+            if !self.created.create(dir, source, options).await? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Levels of the source directory `dir` below the source, 0 for the source itself
+    fn depth(&self, dir: &Path) -> usize {
+        dir.strip_prefix(&self.base_source).map_or(0, |relative| relative.components().count())
+    }
+
+    /// Send work to the workers, it waits while they are busy
+    async fn send(&self, item: WorkItem) -> Result<()> {
+        self.work.send(item).await.map_err(|_| anyhow::anyhow!("The copy was cancelled"))
+    }
+
+    /// Queue the directory, or process it now when the queue is full.
+    /// The future is boxed because processing the directory can push (and process) more directories
+    fn push<'a>(&'a self, dir: PathBuf, options: &'a Arc<CopyOptions>) -> Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>> {
+        Box::pin(async move {
+            let dir = match self.sender.try_send(dir) {
+                Ok(()) => {
+                    options.stats.queued_directories(self.sender.max_capacity() - self.sender.capacity());
+                    return Ok(());
+                },
+                Err(TrySendError::Full(dir) | TrySendError::Closed(dir)) => dir,
+            };
+            let visited = match destination_dir(&self.base_source, &self.base_dest, &dir, options) {
+                Ok(dest) => visit_directory(&dir, dest.as_deref(), options, self).await,
+                Err(error) => report_path_error(&dir, error, options),
+            };
+            match visited {
+                Ok(()) => Ok(()),
+                Err(error) if options.stops() => Err(error),
+                // Not reported yet, nothing below it was copied
+                Err(error) => report_path_error(&dir, error, options),
+            }
+        })
+    }
+}
+
+/// Log a failed file, or return the error when the copy must stop at the first one
+fn report_file_error(error: anyhow::Error, options: &CopyOptions) -> Result<()> {
+    report_failure(None, error, options)
+}
+
+/// Report the directory `dir` that could not be listed like a failed file: it is counted, logged and in the error
+/// report, and the rest of the tree is still copied. The root of the tree is not skipped, its error fails the copy
+fn report_listing_error(dir: &Path, error: anyhow::Error, options: &CopyOptions, queue: &DirectoryQueue) -> Result<()> {
+    let error = error.context(format!("Cannot list directory: {:?}", dir));
+    if dir == &*queue.base_source {
+        return Err(error);
+    }
+    options.stats.directory_failed();
+    report_path_error(dir, error, options)
+}
+
+/// Same as `report_file_error` for an error about `path`, which the error report names
+fn report_path_error(path: &Path, error: anyhow::Error, options: &CopyOptions) -> Result<()> {
+    report_failure(Some(path), error, options)
+}
+
+fn report_failure(path: Option<&Path>, error: anyhow::Error, options: &CopyOptions) -> Result<()> {
+    options.stats.failed();
+    options.failures.record(path, &error);
+    if let Some(Changed(source)) = error.downcast_ref() {
+        options.volatile_files.record(source, Outcome::Failed);
+    }
+    if options.stop_on_error {
+        return Err(error);
+    }
+    error!("{:#}", error);
+    if options.too_many_errors() {
+        return Err(error.context(format!("More than --max-errors {} files could not be copied, the run is aborted", options.max_errors)));
+    }
+    Ok(())
+}
+
+/// Same as `report_file_error` for a finished task. The value is None when the task failed
+fn report_file_result<T>(result: Result<Result<T>, JoinError>, options: &CopyOptions) -> Result<Option<T>> {
+    match result.map_err(anyhow::Error::from).and_then(|result| result) {
+        Ok(value) => Ok(Some(value)),
+        Err(error) => report_file_error(error, options).map(|()| None),
+    }
+}
+
+/// Copy (or move) a single file into the local filesystem and apply the requested metadata.
+/// With options.fsync the source is not removed here, it is returned to be removed once the directory is flushed
+async fn process_file(from: &Path, to: &Path, options: &CopyOptions) -> Result<Option<PathBuf>> {
+    // Copies to the same destination must not overlap
+    let _claim = match &options.claims {
+        Some(claims) => {
+            let (claimed, guard) = claims.claim(to).await;
+            if claimed && options.if_exists == IfExists::Overwrite {
+                warn!("{:?} is overwritten by {:?}, several sources are copied to it", to, from);
+            }
+            Some(guard)
+        },
+        None => None,
+    };
+    // Whether a destination there once the file is written is replaced
+    let mut replaces = options.if_exists == IfExists::Overwrite;
+    if options.if_exists != IfExists::Overwrite {
+        if let Ok(dest) = tokio::fs::symlink_metadata(to).await {
+            match options.if_exists {
+                IfExists::Error => return Err(anyhow::anyhow!("Destination already exists: {:?}", to)),
+                IfExists::Newer if filter::is_outdated(&tokio::fs::metadata(from).await?, &dest, options.modify_window) => replaces = true,
+                _ => {
+                    info!("Skip existing file: {:?}", to);
+                    options.stats.skipped();
+                    return Ok(None);
+                },
+            }
+        }
+    }
+    if options.skip_unchanged == Some(SkipUnchanged::Sample) && sample::unchanged(from, to, options.checksum_algorithm).await? {
+        info!("Skip unchanged file: {:?}", to);
+        options.stats.skipped_by_sample();
+        return Ok(None);
+    }
+    if let Some(dedupe) = &options.dedupe {
+        match dedupe.original(from, options.checksum_algorithm, options.checksum_cache.as_deref()).await? {
+            // A destination written twice is not linked to itself
+            Some(original) if original.path != to => return linked_file(from, to, original, options).await,
+            _ => {},
+        }
+    }
+
+    // Compared with the source once it is copied. The copy reports the error when it cannot be read
+    let source = tokio::fs::metadata(from).await.ok();
+    let before = source.as_ref().filter(|_| !options.ignore_changes).map(Snapshot::of);
+    // The resumed copies have their own `.part`, renamed once complete
+    let resumed = options.resume && source.is_some_and(|metadata| metadata.len() > options.chunk_threshold);
+    let temp = (options.atomic && !resumed).then(|| TempFile::new(to, replaces));
+    let written = temp.as_ref().map_or(to, TempFile::path);
+
+    debug!("Copy: {:?} to {:?}", from, to);
+    options.stats.started(from);
+    let copied = if options.remove_source {
+        match copy::move_file(from, to, written, options).await.with_context(|| format!("Cannot move file: {:?}", from))? {
+            Moved::Renamed => {
+                options.stats.renamed();
+                return renamed_file(from, to, options).await.map(|()| None);
+            },
+            Moved::Copied(copied) => copied,
+        }
+    } else {
+        copy::copy_file(from, written, options).await
+            .with_context(|| format!("Cannot copy file: {:?}", from))?
+    };
+    let copied = match options.verify {
+        true => verify::check_copy(from, written, copied, options).await?,
+        false => copied,
+    };
+    copied_file(from, to, temp, copied, before, options).await
+}
+
+/// Whether an entry of an archive, modified at `modified`, is extracted over what `to` already has: `--if-exists`
+/// like a copied file. A kept destination is counted as skipped
+fn extracts_over(to: &Path, modified: Option<SystemTime>, options: &CopyOptions) -> Result<bool> {
+    let Ok(dest) = std::fs::symlink_metadata(to) else { return Ok(true) };
+    let replaced = match options.if_exists {
+        IfExists::Overwrite => true,
+        IfExists::Error => return Err(anyhow::anyhow!("Destination already exists: {:?}", to)),
+        IfExists::Skip => false,
+        IfExists::Newer => match (modified, dest.modified()) {
+            (Some(entry), Ok(dest)) => filter::newer_than(entry, dest, options.modify_window),
+            _ => true,
+        },
+    };
+    if !replaced {
+        info!("Skip existing file: {:?}", to);
+        options.stats.skipped();
+    }
+    Ok(replaced)
+}
+
+/// Create the link `to` with the target of the source link `from` (`--preserve links`). The target is kept as it is,
+/// relative or absolute, and it does not need to exist. The source link is removed like a copied file
+async fn process_link(from: &Path, to: &Path, options: &CopyOptions) -> Result<Option<PathBuf>> {
+    let target = tokio::fs::read_link(from).await
+        .with_context(|| format!("Cannot read link: {:?}", from))?;
+    if let Ok(dest) = tokio::fs::symlink_metadata(to).await {
+        let replaced = match options.if_exists {
+            IfExists::Error => return Err(anyhow::anyhow!("Destination already exists: {:?}", to)),
+            IfExists::Skip => false,
+            IfExists::Newer => filter::is_outdated(&tokio::fs::symlink_metadata(from).await?, &dest, options.modify_window),
+            IfExists::Overwrite => true,
+        };
+        if !replaced {
+            info!("Skip existing file: {:?}", to);
+            options.stats.skipped();
+            return Ok(None);
+        }
+        tokio::fs::remove_file(to).await
+            .with_context(|| format!("Cannot replace: {:?}", to))?;
+    }
+    let target = match &options.relativize_links {
+        Some(tree) if target.is_absolute() => match tree.relative_target(from, &target) {
+            Some(relative) => relative,
+            None => {
+                warn!("The link {:?} points outside the source, its target is kept: {:?}", from, target);
+                target
+            },
+        },
+        _ => target,
+    };
+    debug!("Link: {:?} to {:?}", to, target);
+    copy::symlink(&target, from, to).await
+        .with_context(|| format!("Cannot copy link: {:?}", from))?;
+    options.stats.copied(0, false);
+    metadata::apply_link(from, to, options).await
+        .with_context(|| format!("Cannot preserve metadata: {:?}", to))?;
+    if options.remove_source {
+        if options.fsync {
+            return Ok(Some(from.to_owned()));
+        }
+        remove_source(from, options).await?;
+    }
+    Ok(None)
+}
+
+/// Copy (or move) the small files of a batch with plain system calls in a single blocking call and then finish every
+/// one like `process_file`. The files that are not small are left to be copied on their own.
+/// Every file has its own result, a failure does not affect the rest of the batch
+async fn process_small_files(batch: Vec<(PathBuf, PathBuf)>, options: &Arc<CopyOptions>) -> Finished {
+    let blocking_options = options.clone();
+    // Every file is reported when the batch fails as a whole
+    let files = batch.clone();
+    let copied = tokio::task::spawn_blocking(move || {
+        let mut large = vec![];
+        let mut copied = vec![];
+        for (from, to) in batch {
+            let moved = match std::fs::metadata(&from) {
+                Ok(metadata) if metadata.len() >= blocking_options.small_file_threshold => {
+                    large.push((from, to));
+                    continue;
+                },
+                Ok(metadata) => {
+                    blocking_options.stats.started(&from);
+                    copy::copy_small_file(&from, &to, &blocking_options).map(|moved| moved.map(|moved| (moved, Snapshot::of(&metadata))))
+                },
+                Err(error) => Err(anyhow::Error::new(error).context(format!("Cannot read metadata: {:?}", from))),
+            };
+            copied.push((from, to, moved));
+        }
+        (copied, large)
+    }).await;
+    let (copied, large) = match copied {
+        Ok(copied) => copied,
+        Err(error) => {
+            let error = anyhow::Error::from(error);
+            let results = files.into_iter()
+                .map(|(from, to)| {
+                    let error = anyhow::anyhow!("{:#}", error).context(format!("Cannot copy file: {:?}", from));
+                    (from, to, Err(error))
+                })
+                .collect();
+            return Finished { results, ..Default::default() };
+        },
+    };
+    let mut results = Vec::with_capacity(copied.len());
+    for (from, to, moved) in copied {
+        let result = match moved {
+            Ok(None) => {
+                info!("Skip existing file: {:?}", to);
+                options.stats.skipped();
+                Ok(None)
+            },
+            Ok(Some((Moved::Renamed, _))) => {
+                options.stats.renamed();
+                renamed_file(&from, &to, options).await.map(|()| None)
+            },
+            Ok(Some((Moved::Copied(copied), before))) => {
+                copied_file(&from, &to, None, copied, (!options.ignore_changes).then_some(before), options).await
+            },
+            Err(error) => Err(error),
+        };
+        results.push((from, to, result));
+    }
+    Finished { results, large }
+}
+
+/// Finish a file whose content was copied: check that its source did not change since `before` and its size, count
+/// it, record it and apply the metadata.
+/// With options.fsync the source is not removed here, it is returned to be removed once the directory is flushed
+async fn copied_file(
+    from: &Path,
+    to: &Path,
+    temp: Option<TempFile>,
+    copied: Copied,
+    before: Option<Snapshot>,
+    options: &CopyOptions,
+) -> Result<Option<PathBuf>> {
+    let atomic = temp.is_some();
+    let torn = match before {
+        Some(before) => before.changed(from).await?,
+        None => false,
+    };
+    if torn && options.volatile == Volatile::Warn {
+        warn!("{:?} changed while it was copied, its copy can be torn", from);
+        options.volatile_files.record(from, Outcome::Copied);
+    } else if torn {
+        discard_copy(to, temp).await;
+        if options.volatile == Volatile::Skip {
+            warn!("Skip {:?}, it changed while it was copied", from);
+            options.stats.skipped_volatile();
+            options.volatile_files.record(from, Outcome::Skipped);
+            return Ok(None);
+        }
+        return Err(Changed(from.to_owned()).into());
+    }
+    // The size of a source that changed is not the one of its copy any more
+    let checked = if torn { Ok(()) } else { copy::check_size(from, temp.as_ref().map_or(to, TempFile::path), copied.bytes).await };
+    if let Err(error) = checked {
+        discard_copy(to, temp).await;
+        return Err(error);
+    }
+    if let Some(temp) = temp {
+        metadata::apply(from, temp.path(), options).await
+            .with_context(|| format!("Cannot preserve metadata: {:?}", to))?;
+        if !temp.commit(to, options.if_exists).await? {
+            info!("Skip existing file, it appeared during the copy: {:?}", to);
+            options.stats.skipped();
+            return Ok(None);
+        }
+    }
+    options.stats.copied(copied.bytes, copied.cloned);
+    if let Some(digest) = copied.digest {
+        if let Some(dedupe) = &options.dedupe {
+            dedupe.record(to, copied.bytes, digest.clone());
+        }
+        if let Some(manifest) = &options.manifest {
+            manifest.record(to, digest, copied.bytes);
+        }
+    }
+    if !atomic {
+        metadata::apply(from, to, options).await
+            .with_context(|| format!("Cannot preserve metadata: {:?}", to))?;
+    }
+    if let Some(hooks) = &options.hooks {
+        hooks.run(from, to).await?;
+    }
+    // A copy kept with --volatile warn does not have all of its source, which is kept
+    if options.remove_source && !torn {
+        copy::confirm_copy(from, to, copied.bytes).await?;
+        if options.defers_removal() {
+            // With fsync the copy already flushed the file, the directory entry has to be flushed too
+            return Ok(Some(from.to_owned()));
+        }
+        remove_source(from, options).await?;
+    }
+    Ok(None)
+}
+
+/// Remove the copy `to` that cannot be kept, or its temporary file with --atomic
+async fn discard_copy(to: &Path, temp: Option<TempFile>) {
+    // The temporary file is removed when dropped
+    if temp.is_none() {
+        if let Err(error) = tokio::fs::remove_file(to).await {
+            warn!("Cannot remove the incomplete copy {:?}: {}", to, error);
+        }
+    }
+}
+
+/// Write `to` as a hard link to the copy with the same content as `from` (`--dedupe-dest`). The link shares the
+/// metadata of that copy, the metadata of `from` is not applied. With `--dedupe-method reflink` it is a clone of the
+/// copy instead, which gets the metadata of `from`. The source is removed like a copied file
+async fn linked_file(from: &Path, to: &Path, original: Original, options: &CopyOptions) -> Result<Option<PathBuf>> {
+    if options.dedupe_method == DedupeMethod::Reflink {
+        debug!("Clone: {:?} from the copy {:?}", to, original.path);
+        reflink::clone_file(&original.path, to).await
+            .with_context(|| format!("Cannot clone {:?} from {:?}", to, original.path))?;
+        if options.fsync {
+            copy::sync_file(to).await.with_context(|| format!("Cannot sync file: {:?}", to))?;
+        }
+        metadata::apply(from, to, options).await
+            .with_context(|| format!("Cannot preserve metadata: {:?}", to))?;
+    } else if let Err(error) = tokio::fs::hard_link(&original.path, to).await {
+        if error.kind() != io::ErrorKind::AlreadyExists {
+            return Err(error).with_context(|| format!("Cannot link {:?} to {:?}", to, original.path));
+        }
+        // Only an overwrite gets here with an existing destination
+        tokio::fs::remove_file(to).await
+            .with_context(|| format!("Cannot replace: {:?}", to))?;
+        tokio::fs::hard_link(&original.path, to).await
+            .with_context(|| format!("Cannot link {:?} to {:?}", to, original.path))?;
+    }
+    options.stats.linked(original.len);
+    if let Some(manifest) = &options.manifest {
+        manifest.record(to, original.digest, original.len);
+    }
+    if let Some(hooks) = &options.hooks {
+        hooks.run(from, to).await?;
+    }
+    if options.remove_source {
+        copy::confirm_copy(from, to, original.len).await?;
+        if options.defers_removal() {
+            return Ok(Some(from.to_owned()));
+        }
+        remove_source(from, options).await?;
+    }
+    Ok(None)
+}
+
+/// Finish the file `from` moved to `to` with a rename. The content and metadata are the ones of the source already,
+/// the new entry is flushed with the directory
+async fn renamed_file(from: &Path, to: &Path, options: &CopyOptions) -> Result<()> {
+    debug!("Renamed: {:?}", to);
+    if let Some(mode) = options.chmod {
+        metadata::set_mode(to, mode).await
+            .with_context(|| format!("Cannot preserve metadata: {:?}", to))?;
+    }
+    if let Some(manifest) = &options.manifest {
+        let size = tokio::fs::metadata(to).await?.len();
+        let digest = checksum::hash_file(to, options.checksum_algorithm).await
+            .with_context(|| format!("Cannot hash file: {:?}", to))?;
+        manifest.record(to, digest, size);
+    }
+    if let Some(hooks) = &options.hooks {
+        hooks.run(from, to).await?;
+    }
+    Ok(())
+}
+
+/// Time between progress reports
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Time between the snapshots of --progress-json
+const PROGRESS_JSON_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Count what the copy will go through, and keep the files found with `keep`. None when it takes longer than `timeout`
+async fn run_prescan(source: &Path, options: &CopyOptions, concurrency: usize, timeout: Option<Duration>, keep: bool) -> Option<Scan> {
+    info!("Pre-scanning {:?}", source);
+    let scan = prescan::prescan(options.source_backend(), source, &options.filters, options.strip_components, concurrency, keep, options.links());
+    let scan = match timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, scan).await {
+            Ok(scan) => scan,
+            Err(_) => {
+                warn!("The pre-scan did not finish in {:?}, the progress is reported without totals", timeout);
+                return None;
+            }
+        },
+        None => scan.await,
+    };
+    info!("Pre-scan: {} files, {} to copy", scan.totals.files, options.stats.size(scan.totals.bytes));
+    Some(scan)
+}
+
+/// Log the progress of the copy every PROGRESS_INTERVAL until the task is aborted, with the current level of the
+/// automatic concurrency
+async fn report_progress(stats: Arc<CopyStats>, totals: Option<Totals>, adaptive: Option<Arc<Adaptive>>) {
+    let mut interval = tokio::time::interval(PROGRESS_INTERVAL);
+    // The first tick is immediate
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        match &adaptive {
+            Some(adaptive) => info!("Progress: {}, {} copies in flight", stats.progress(totals.as_ref()), adaptive.level()),
+            None => info!("Progress: {}", stats.progress(totals.as_ref())),
+        }
+    }
+}
+
+/// Write a snapshot of the progress into `out` every `period` until the task is aborted, a JSON object per line
+/// (--progress-json). The first one is written right away
+async fn report_progress_json(stats: Arc<CopyStats>, totals: Option<Totals>, period: Duration, mut out: impl Write + Send) {
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        // A reader that went away does not stop the copy
+        let _ = writeln!(out, "{}", stats.progress_json(totals.as_ref())).and_then(|()| out.flush());
+    }
+}
+
+/// The source of the command line
+#[derive(Debug)]
+struct SourceRoot {
+    /// What is copied
+    path: PathBuf,
+    /// The source is this link, --delete-source removes it
+    link: Option<PathBuf>,
+    /// Whether --delete-source removes the copied files
+    remove_files: bool,
+}
+
+/// A source that is a link is read through the link. --delete-source then only removes the link and keeps what it
+/// points to, unless `dereference`: then its target is the source, it is moved and the link is removed too
+async fn resolve_source_root(source: PathBuf, delete_source: bool, dereference: bool) -> Result<SourceRoot> {
+    let is_link = tokio::fs::symlink_metadata(&source).await.is_ok_and(|metadata| metadata.file_type().is_symlink());
+    if !is_link {
+        return Ok(SourceRoot { path: source, link: None, remove_files: delete_source });
+    }
+    if !dereference {
+        return Ok(SourceRoot { path: source.clone(), link: Some(source), remove_files: false });
+    }
+    // A dangling link is reported as a missing source
+    let path = tokio::fs::canonicalize(&source).await.unwrap_or_else(|_| source.clone());
+    Ok(SourceRoot { path, link: Some(source), remove_files: delete_source })
+}
+
+/// Remove the link of a source root, not what it points to
+async fn remove_source_link(link: &Path, options: &CopyOptions) -> Result<()> {
+    if options.trash.is_some() || cfg!(not(windows)) || !link.is_dir() {
+        return remove_source(link, options).await;
+    }
+    // A link to a directory is removed like a directory on Windows
+    tokio::fs::remove_dir(link).await
+        .with_context(|| format!("Cannot remove the link: {:?}", link))
+}
+
+/// Directory where the source tree lands with `--source-base self` and without `--as`: a directory named like the
+/// source inside the destination. The name given in the command line is kept, also when it is a link, and a source
+/// like `.` gets the name it resolves to
+async fn source_base_root(base_dest: &Path, source: &Path) -> Result<PathBuf> {
+    let name = match source.file_name() {
+        Some(name) => name.to_owned(),
+        None => tokio::fs::canonicalize(source).await
+            .with_context(|| format!("Cannot resolve the source: {:?}", source))?
+            .file_name()
+            .ok_or_else(|| anyhow::anyhow!("--source-base self needs a source with a name: {:?}", source))?
+            .to_owned(),
+    };
+    Ok(base_dest.join(name))
+}
+
+/// Where a source that is a single file is copied: into the destination when it is a directory (or ends with a
+/// separator), with its own name or `name`, otherwise to the destination itself
+async fn single_file_destination(base_dest: &Path, name: Option<&Path>, source: &Path, backend: &dyn Backend) -> Result<PathBuf> {
+    let into_directory = base_dest.as_os_str().to_string_lossy().ends_with(std::path::is_separator)
+        || backend.metadata(base_dest).await.is_ok_and(|metadata| metadata.is_dir);
+    if !into_directory {
+        return Ok(base_dest.to_owned());
+    }
+    match name {
+        Some(name) => destination_root(base_dest, Some(name)),
+        None => {
+            let name = source.file_name().ok_or_else(|| anyhow::anyhow!("The source file has no name: {:?}", source))?;
+            Ok(base_dest.join(name))
+        },
+    }
+}
+
+/// Copy the source file `from` to `to`, creating the directories above it, like any file of a tree: through the
+/// workers with their retries, checks and reports. With options.remove_source it is moved
+async fn copy_single_file(from: &Path, to: &Path, options: Arc<CopyOptions>) -> Result<()> {
+    if let Some(parent) = to.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        options.destination().create_dir_all(parent).await?;
+    }
+    let metadata = options.source().metadata(from).await
+        .with_context(|| format!("Cannot read metadata: {:?}", from))?;
+    if !options.filters.selects(metadata.modified) {
+        info!("The source file is filtered out, nothing is copied: {:?}", from);
+        return Ok(());
+    }
+    let (from, to) = (from.to_owned(), to.to_owned());
+    pipeline::run(&options, move |work| async move {
+        work.send(WorkItem::File { from, to, metadata: Some(metadata) }).await
+            .map_err(|_| anyhow::anyhow!("The copy was cancelled"))
+    }).await
+}
+
+/// Directory where the source tree lands: the destination itself, or a directory called `name` inside it
+fn destination_root(base_dest: &Path, name: Option<&Path>) -> Result<PathBuf> {
+    let Some(name) = name else {
+        return Ok(base_dest.to_owned());
+    };
+    let mut components = name.components();
+    match (components.next(), components.next()) {
+        (Some(std::path::Component::Normal(_)), None) => Ok(base_dest.join(name)),
+        _ => Err(anyhow::anyhow!("--as must be a plain directory name: {:?}", name)),
+    }
+}
+
+/// Copy the whole tree of base_source into base_dest listing up to `concurrency` directories at the same time.
+/// The directories are listed while the tree is discovered, through a bounded queue, and their files are copied by the
+/// workers of the pipeline. It only returns once every file has been copied, then the source is removed if
+/// options.remove_source = true
+async fn copy_tree(base_source: &Path, base_dest: &Path, options: Arc<CopyOptions>, concurrency: usize) -> Result<()> {
+    let (source, dest, walk_options) = (base_source.to_owned(), base_dest.to_owned(), options.clone());
+    pipeline::run(&options, move |work| async move { walk_tree(&source, &dest, walk_options, concurrency, work).await }).await?;
+
+    // Every copy is finished, what is left of the source is its directories and the files that were not moved
+    if options.remove_source {
+        clean_source_tree(base_source, &options).await?;
+    }
+    Ok(())
+}
+
+/// The producer of copy_tree: list the tree of base_source with up to `concurrency` directories at the same time
+/// and send their files to `work`
+async fn walk_tree(base_source: &Path, base_dest: &Path, options: Arc<CopyOptions>, concurrency: usize, work: mpsc::Sender<WorkItem>) -> Result<()> {
+    let capacity = options.max_pending.unwrap_or(concurrency * QUEUED_DIRECTORIES_PER_TASK);
+    let (queue, mut pending) = DirectoryQueue::new(base_source, base_dest, capacity, work);
+    let mut set = JoinSet::new();
+    // The directory of every task in flight
+    let mut dirs = HashMap::new();
+    let spawn = |set: &mut JoinSet<Result<()>>, dirs: &mut HashMap<tokio::task::Id, PathBuf>, dir: PathBuf| {
+        let dest = destination_dir(base_source, base_dest, &dir, &options);
+        let task_options = options.clone();
+        let task_queue = queue.clone();
+        let task_dir = dir.clone();
+        let id = set.spawn(async move {
+            match dest {
+                Ok(dest) => visit_directory(&task_dir, dest.as_deref(), &task_options, &task_queue).await,
+                Err(error) => report_path_error(&task_dir, error, &task_options),
+            }
+        }).id();
+        dirs.insert(id, dir);
+        id
+    };
+    // The errors of the root are not skipped, the whole copy fails
+    let root = spawn(&mut set, &mut dirs, base_source.to_owned());
+
+    loop {
+        while set.len() < concurrency {
+            let Ok(dir) = pending.try_recv() else { break };
+            spawn(&mut set, &mut dirs, dir);
+        }
+        // Nothing in flight means nothing else can be queued
+        if set.is_empty() {
+            break;
+        }
+
+        // Wait for a directory to finish, or for a new one when there is room for it
+        let res = tokio::select! {
+            res = set.join_next_with_id() => res.expect("the set is not empty"),
+            Some(dir) = pending.recv(), if set.len() < concurrency => {
+                spawn(&mut set, &mut dirs, dir);
+                continue;
+            },
+        };
+        let id = match &res {
+            Ok((id, _)) => *id,
+            Err(err) => err.id(),
+        };
+        let dir = dirs.remove(&id).expect("every task has its directory");
+        let failed = match res {
+            Ok((_, Ok(()))) => None,
+            Ok((_, Err(err))) if options.stops() || id == root => Some(err),
+            // Not reported yet, nothing below it was copied
+            Ok((_, Err(err))) => report_path_error(&dir, err, &options).err(),
+            Err(err) => match Panicked::of(err, format!("the copy of the directory {:?}", dir)) {
+                Some(panicked) => Some(panicked.into()),
+                None => {
+                    error!("The copy of the directory {:?} was cancelled", dir);
+                    None
+                },
+            },
+        };
+        if let Some(err) = failed {
+            // Cancel the in-flight directories
+            set.shutdown().await;
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+/// Sort the files found by the pre-scan. The ties are sorted by path so every order is the same in every run
+fn sort_files(files: &mut [(PathBuf, u64)], order: Order) {
+    match order {
+        Order::LargestFirst => files.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0))),
+        Order::SmallestFirst => files.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(&b.0))),
+        Order::Path | Order::Walk => files.sort_by(|a, b| a.0.cmp(&b.0)),
+    }
+}
+
+/// Copy the tree from the files found by the pre-scan instead of walking it: the destination directories are created
+/// first and then the files are sent to the workers in `order`, whatever their directory.
+/// The small files are still batched, the sources are removed like in copy_tree
+async fn copy_ordered(base_source: &Path, base_dest: &Path, scan: Scan, order: Order, options: Arc<CopyOptions>) -> Result<()> {
+    let (source, dest, send_options) = (base_source.to_owned(), base_dest.to_owned(), options.clone());
+    pipeline::run(&options, move |work| async move { send_ordered(&source, &dest, scan, order, &send_options, work).await }).await?;
+
+    if options.remove_source {
+        clean_source_tree(base_source, &options).await?;
+    }
+    Ok(())
+}
+
+/// Copy the files of a `--files-from` list like copy_ordered. The source directories are kept with
+/// `--delete-source`, they have the files that were not listed
+async fn copy_listed(base_source: &Path, base_dest: &Path, scan: Scan, order: Order, options: Arc<CopyOptions>) -> Result<()> {
+    let (source, dest, send_options) = (base_source.to_owned(), base_dest.to_owned(), options.clone());
+    pipeline::run(&options, move |work| async move { send_ordered(&source, &dest, scan, order, &send_options, work).await }).await
+}
+
+/// The producer of copy_ordered and copy_listed
+async fn send_ordered(base_source: &Path, base_dest: &Path, mut scan: Scan, order: Order, options: &CopyOptions, work: mpsc::Sender<WorkItem>) -> Result<()> {
+    let closed = || anyhow::anyhow!("The copy was cancelled");
+    for error in scan.errors.drain(..) {
+        report_file_error(error, options)?;
+    }
+    // The parents before their children
+    let mut dirs = scan.dirs;
+    if options.prune_empty_dirs {
+        // Only the directories with a file below them
+        let needed: HashSet<&Path> = scan.files.iter().map(|(file, _)| file).chain(&scan.links)
+            .flat_map(|file| file.ancestors().skip(1))
+            .collect();
+        dirs.retain(|dir| needed.contains(dir.as_path()));
+    }
+    dirs.push(base_source.to_owned());
+    dirs.sort();
+    let mut created = HashMap::new();
+    let created_dirs = CreatedDirs::default();
+    let mut invalid = HashSet::new();
+    for dir in dirs {
+        // Only the first directory with a name that cannot be copied is reported, not all the ones below it
+        if dir.ancestors().skip(1).any(|parent| invalid.contains(parent)) {
+            continue;
+        }
+        let dest = match destination_dir(base_source, base_dest, &dir, options) {
+            Ok(dest) => dest,
+            Err(error) => {
+                report_path_error(&dir, error, options)?;
+                invalid.insert(dir);
+                continue;
+            },
+        };
+        if let Some(dest) = dest {
+            // Several directories can land in the same destination with strip
+            if created_dirs.create(&dest, &dir, options).await? {
+                created.insert(dir, dest);
+            }
+        }
+    }
+
+    sort_files(&mut scan.files, order);
+    let mut files = HashMap::<PathBuf, usize>::new();
+    let batches_small_files = options.batches_small_files();
+    let mut small_files = vec![];
+    for (from, size) in scan.files {
+        let Some((dir, dest)) = from.parent().and_then(|dir| created.get_key_value(dir)) else { continue };
+        let index = files.entry(dir.clone()).or_default();
+        let to = match destination_file(&from, dest, None, *index + 1, options).await {
+            Ok(to) => to,
+            Err(error) => {
+                report_path_error(&from, error, options)?;
+                continue;
+            },
+        };
+        *index += 1;
+        if let Some(file_rate) = &options.file_rate {
+            file_rate.wait().await;
+        }
+        if batches_small_files && size < options.small_file_threshold {
+            small_files.push((from, to));
+            if small_files.len() == SMALL_FILES_PER_BATCH {
+                work.send(WorkItem::SmallFiles(std::mem::take(&mut small_files))).await.map_err(|_| closed())?;
+            }
+        } else {
+            work.send(WorkItem::File { from, to, metadata: None }).await.map_err(|_| closed())?;
+        }
+    }
+    // The links have no size to order them, they go after the files
+    for from in scan.links {
+        let Some((dir, dest)) = from.parent().and_then(|dir| created.get_key_value(dir)) else { continue };
+        let index = files.entry(dir.clone()).or_default();
+        let to = match destination_file(&from, dest, None, *index + 1, options).await {
+            Ok(to) => to,
+            Err(error) => {
+                report_path_error(&from, error, options)?;
+                continue;
+            },
+        };
+        *index += 1;
+        if let Some(file_rate) = &options.file_rate {
+            file_rate.wait().await;
+        }
+        work.send(WorkItem::Link { from, to }).await.map_err(|_| closed())?;
+    }
+    if !small_files.is_empty() {
+        work.send(WorkItem::SmallFiles(small_files)).await.map_err(|_| closed())?;
+    }
+    for (source, dest) in created {
+        let files = files.get(&source).copied().unwrap_or_default();
+        work.send(WorkItem::Dir { source, dest, files }).await.map_err(|_| closed())?;
+    }
+    Ok(())
+}
+
+
+/// Logger configuration. The logs go to stderr with `stderr`, to leave stdout to --progress-json
+fn setup_logger(loglevel: &str, logfile: Option<&str>, stderr: bool) -> Result<()>{   
+    let level = match loglevel {
+        "INFO" => log::LevelFilter::Info,
+        "DEBUG" => log::LevelFilter::Debug,
+        "WARN" => log::LevelFilter::Warn,
+        "ERROR" => log::LevelFilter::Error,
+        &_ => log::LevelFilter::Info
+    };
+    let mut f = fern::Dispatch::new()
+    // Perform allocation-free log formatting
+    .format(|out, message, record| {
+        out.finish(format_args!(
+            "{}[{}][{}] {}",
+            chrono::Local::now().format("[%Y-%m-%d][%H:%M:%S]"),
+            record.target(),
+            record.level(),
+            message
+        ))
+    })
+    // Add blanket level filter -
+    .level(level);
+    f = if stderr { f.chain(std::io::stderr()) } else { f.chain(std::io::stdout()) };
+
+    if let Some(logfile) = logfile {
+        f = f.chain(fern::log_file(logfile)?);
+    }
+
+    f.apply()?;
+    Ok(())
+
+}
+
+/// Arguments parser
+/// `--source` the source directory (or a single file, or a `.tar`/`.tar.gz`/`.zip` archive to extract)
+/// `--destination` the destination directory (or a `.tar`/`.tar.gz` archive to create, or `sftp://user@host/path`
+/// through the OpenSSH `ssh` and `sftp` programs, Unix only)
+/// `--delete-source` to act like moving (first copy and the remove the source file)
+/// `--force-source-cleanup` to remove the empty source directories also when some files failed
+/// `--concurrency` to set the maximum concurrency, or `auto` to adjust it while copying, twice the cores by default
+/// `--list-concurrency` to set how many directories are listed at the same time, twice the concurrency by default
+/// `--runtime-threads` and `--blocking-threads` to size the threads of the runtime, derived from the concurrency
+/// `--workers-per-disk` to bound the files copied at the same time from every source device
+/// `--chunk-parallelism` (or `--big-file-streams`) to copy big files using several concurrent ranges
+/// `--chunk-threshold` (or `--big-file-threshold`) the size above which a file is considered big
+/// `--verify-big-files` to compare the checksums of the big files after copying them in chunks
+/// `--resume` to go on with the interrupted copies of the big files instead of starting them over
+/// `--tempdir` the directory of the `.part` files of `--resume`, instead of the destination
+/// `--preserve` the comma separated metadata kept at the destination, like `timestamps,mode,ownership,xattrs,links`
+/// `--archive` (or `-a`) to preserve everything the platform can
+/// `--preserve-attributes` to keep the Windows file attributes, same as `--preserve attributes`
+/// `--preserve-crtime` to keep the file creation time, same as `--preserve crtime`
+/// `--preserve-acls` to keep the POSIX ACLs of the files and directories, same as `--preserve acls` (Linux only)
+/// `--relativize-links` to rewrite the absolute targets of the copied links that point inside the source
+/// `--follow-top-level-links` to follow the links at the top of the source and copy the deeper ones as links
+/// `--dangling-links` to skip, copy as links or fail the links whose target does not exist
+/// `--manifest` to write the checksum of every copied file
+/// `--dedupe-dest` (or `--dedup`) to write the files with the content of an earlier copy as hard links to it,
+/// `--dedupe-method` to clone that copy instead
+/// `--io-nice` to yield the disk to the other programs (`idle` or `best-effort:N`, Linux only), `--nice` the CPU
+/// `--stats-file` to write the counters of the run as JSON once it ends, also when it fails
+/// `--error-report` to write every file that could not be copied, with the kind of the error
+/// `--metrics-file` to write them in the text format of Prometheus, for the textfile collector of the node exporter
+/// `--checksum-algorithm` (or `--checksum-algo`) the algorithm of the checksums
+/// `--checksum-cache` to keep the checksums of the unchanged sources between runs
+/// `--verify` to read every copy back and compare it with its source, copying it again once when it does not match
+/// `--verify-after` to read the destination again once the copy is done and compare it with the source
+/// `--journal` to record the finished files and skip them in the next run, `--journal-compact` to clean it up at the end
+/// `--verify-manifest` to check the destination against a manifest instead of copying
+/// `--ignore-errors` to log the files that cannot be copied and go on (default)
+/// `--stop-on-error` to abort at the first file that cannot be copied
+/// `--error-mode` to choose between both, `continue` or `fail-fast`
+/// `--max-errors` (or `--on-error-keep-going-but-limit`) to abort once more files than this could not be copied
+/// `--grace-period` the time the copies in flight have to finish after Ctrl-C
+/// `--no-fsync` to remove the source without flushing the destination to the disk first
+/// `--fsync` to flush the destination files and directories to the disk even when the source is kept
+/// `--fsync-batch` the number of files copied into a directory between its flushes
+/// `--atomic` to write the files under a temporary name and rename them once complete
+/// `--strip-components` to remove the leading directories of the paths at the destination
+/// `--sanitize-names` to rename the entries whose names are not valid on Windows instead of failing them
+/// `--case-fold-merge` to give the destination directories their names in `lower` or `upper` case, merging `Docs` and `docs`
+/// `--rename-pattern` to rename the destination files, like `{stem}_{mtime}.{ext}` or `s/ /_/`
+/// `--if-exists` to choose what happens with the destination files that already exist
+/// `--modify-window` the difference of mtimes still taken as the same time by `--if-exists newer`
+/// `--skip-unchanged sample` to keep the destination files with the size and the sampled checksum of their source
+/// `--on-type-conflict` for the destination directories where the source has a file, and the other way round
+/// `--as` to copy the source root into a directory with this name inside the destination
+/// `--source-base self` to copy the source root into a directory with its own name inside the destination
+/// `--reflink` to clone the files in copy-on-write filesystems
+/// `--copy-engine` to choose how the bytes are copied
+/// `--buffer-size` the buffer of the streaming copy, like `1MiB`
+/// `--pipeline-depth` the buffers of the streaming copy read ahead while the previous ones are written
+/// `--bwlimit` the maximum bytes per second of the whole copy, like `50MB/s`
+/// `--max-files-per-sec` (or `--rate-limit-files-per-sec`) the maximum file copies started per second
+/// `--rate-limit-deletions` to apply `--max-files-per-sec` to the source deletions too
+/// `--list-only` to print the files that would be copied instead of copying them
+/// `--list-sizes` to print the size of every listed file
+/// `--deterministic` to sort the listed files by path
+/// `--chmod` the octal mode of the destination files, like `644`
+/// `--chmod-dirs` the octal mode of the destination directories, like `755`
+/// `--preallocate` to reserve the space of the destination files before writing them
+/// `--direct-io` to copy the files without going through the page cache (Linux)
+/// `--exclude-newer-than` to skip the files modified after a time or in the last duration, like `60s`
+/// `--exclude-older-than` to skip the files modified before a time or a duration ago, like `30d`
+/// `--no-include-empty-dirs` to only create the destination directories that get a file, not the empty ones
+/// `--small-file-threshold` to copy the smaller files in batches by blocking threads
+/// `--order` to copy the largest or smallest files first, or by path, from the files found by the pre-scan
+/// `--no-prescan` to skip the walk that computes the totals of the progress, `--prescan-timeout` to bound it
+/// `--human-readable` to show the sizes of the progress and the summary like `1.5 GiB` instead of bytes
+/// `--progress-json` to write snapshots of the progress into stdout, a JSON object per line, and the logs into stderr
+/// `--checkpoint-every` (or `--keep-going-summary-every`) to log a summary every time this many more files are done
+/// `--files-from` to copy only the files of a list instead of walking the source, `--null-separated` for NUL separators
+/// `--file-timeout` to fail the copies that take longer, `--timeout-per-gb` to give the big files more time
+/// `--retries` to copy again the files that fail with a transient error, `--retry-delay` before the first retry
+/// `--volatile` (`--on-change`) to copy again, skip, fail or keep the files that change while they are copied,
+/// `--no-detect-changes` to not check them
+/// `--max-pending` the maximum number of directories found and waiting to be copied
+/// `--dereference-source` to delete what a source link points to with `--delete-source`, not just the link
+/// `--trash` to move the removed sources into a directory instead of deleting them
+/// `--interactive` to confirm the deletions of `--delete-source` before copying, `--yes` to confirm them in advance
+/// `--force` to copy even when the destination does not have the free space for the pre-scanned bytes
+/// `--no-space-check` to skip that check, `--min-free-space` to stop the copy when the destination gets fuller
+/// `--post-copy-hook` a command run after every file is copied, with `{dest}` and `{source}`, up to `--hook-concurrency`
+/// at the same time, `--hook-must-succeed` to fail the files whose hook fails, `--hook-timeout` to kill the hooks that
+/// run too long
+/// `--config` to load the options from a TOML file
+/// `bench` to time the copy of a synthetic tree into the destination with a sweep of concurrencies and buffer sizes
+#[derive(Parser, Debug, Clone)]
+#[clap(author, version, about, long_about = None, after_help = failures::EXIT_CODES)]
+struct Args {
+   /// The source directory, a single file to copy or a `.tar`/`.tar.gz`/`.zip` archive to extract. With --files-from
+   /// (a list path or `-` for the standard input), the directory its paths are relative to
+   #[clap(short, long, value_parser)]
+   source: Option<PathBuf>,
+   /// The destination directory, a `.tar`/`.tar.gz` archive to create or `sftp://[user@]host[:port]/path`. SFTP runs
+   /// the OpenSSH `ssh` and `sftp` programs, which must be installed (Unix only)
+   #[clap(short, long, value_parser, global = true)]
+   destination: Option<PathBuf>,
+   /// Delete source or not
+   #[clap(long, value_parser, default_value = "false")]
+   delete_source: bool,
+   /// With --delete-source, remove the empty source directories at the end even when some files could not be copied,
+   /// and the archived sources of a tar even when some entries could not be archived. Without it nothing is removed
+   /// and the run fails
+   #[clap(long, value_parser)]
+   force_source_cleanup: bool,
+   /// Concurrency, a number of copies or `auto` to adjust it while copying. Twice the cores by default, between 4
+   /// and 64
+   #[clap(long, value_parser = adaptive::parse_concurrency)]
+   concurrency: Option<Concurrency>,
+   /// Directories listed at the same time, independently of the copies of --concurrency. Twice --concurrency by
+   /// default, 16 with `auto`
+   #[clap(long, value_parser)]
+   list_concurrency: Option<usize>,
+   /// Worker threads of the runtime, one per core up to --concurrency by default
+   #[clap(long, value_parser)]
+   runtime_threads: Option<usize>,
+   /// Most threads doing blocking I/O, by default one per copy (and per range of --chunk-parallelism) and per
+   /// directory listed
+   #[clap(long, value_parser)]
+   blocking_threads: Option<usize>,
+   /// Maximum number of files copied at the same time from a single source device (Unix), on top of --concurrency.
+   /// Low values suit spinning disks, the copies from other disks go on meanwhile
+   #[clap(long, value_parser)]
+   workers_per_disk: Option<usize>,
+   /// Number of concurrent ranges used to copy a big file. They count against the concurrency
+   #[clap(long, visible_alias = "big-file-streams", value_parser, default_value = "1")]
+   chunk_parallelism: usize,
+   /// Files bigger than this size are copied in chunks
+   #[clap(long, visible_alias = "big-file-threshold", value_parser = size::parse_size, default_value = "256MiB")]
+   chunk_threshold: u64,
+   /// Compare the checksums of source and destination after copying a big file in chunks
+   #[clap(long, value_parser)]
+   verify_big_files: bool,
+   /// Write the big files into a `.part` with a record of the bytes flushed, so that a copy interrupted (or that
+   /// failed) goes on from there in the next run. The complete file is checked against the source
+   #[clap(long, value_parser)]
+   resume: bool,
+   /// Write the `.part` of --resume in this directory instead of next to their destination. The destinations in
+   /// another filesystem keep them next to them, a rename cannot move a file across filesystems
+   #[clap(long, value_parser)]
+   tempdir: Option<PathBuf>,
+   /// What the destination keeps from the sources, a comma separated list of timestamps, mode, ownership, xattrs,
+   /// acls (Linux only), links (copy the symbolic links as links), crtime, attributes or all. Only the mode is kept by
+   /// default and a list keeps only what it names
+   #[clap(long, value_parser = metadata::parse_preserve)]
+   preserve: Option<PreserveFlags>,
+   /// Preserve everything the platform can, like `--preserve all`
+   #[clap(short, long, value_parser)]
+   archive: bool,
+   /// Keep the hidden, system, archive and read-only attributes (Windows only)
+   #[clap(long, value_parser, default_value = "false")]
+   preserve_attributes: bool,
+   /// Keep the creation time (Windows and macOS, other platforms warn)
+   #[clap(long, value_parser, default_value = "false")]
+   preserve_crtime: bool,
+   /// Keep the POSIX ACLs of the files and directories, with the default ACLs of the directories. Linux only, they are
+   /// copied as their `system.posix_acl_*` extended attributes; other platforms warn and ignore it
+   #[clap(long, value_parser, default_value = "false")]
+   preserve_acls: bool,
+   /// With --preserve links, the links whose absolute target is inside the source get a target relative to the link,
+   /// so they point inside the destination. The links that point outside are kept as they are
+   #[clap(long, visible_alias = "copy-links-as-relative", value_parser)]
+   relativize_links: bool,
+   /// Follow the links at the top of the source, like `source/project -> /elsewhere`, and copy the deeper ones as
+   /// links
+   #[clap(long, value_parser)]
+   follow_top_level_links: bool,
+   /// What to do with a followed link whose target does not exist: `skip` leaves it out with a warning, `preserve`
+   /// copies it as a link and `error` fails it like a file that cannot be copied
+   #[clap(long, value_enum, default_value = "error")]
+   dangling_links: DanglingLinks,
+   /// Write a manifest with the checksum and size of every copied file
+   #[clap(long, value_parser)]
+   manifest: Option<PathBuf>,
+   /// The files with the same content as a file already copied in this run become hard links to it instead of
+   /// another copy (they share its metadata). The checksums are compared and then the bytes
+   #[clap(long, alias = "dedup", value_parser)]
+   dedupe_dest: bool,
+   /// How --dedupe-dest writes the duplicates: `hard-link` (the default) or `reflink`, a copy-on-write clone of the
+   /// first copy with the metadata of its own source (btrfs, XFS, APFS)
+   #[clap(long, value_enum, default_value_t)]
+   dedupe_method: DedupeMethod,
+   /// Write the counters of the run (files copied, skipped, failed, bytes, elapsed time, throughput) to this JSON
+   /// file at the end, also when the copy fails
+   #[clap(long, value_parser)]
+   stats_file: Option<PathBuf>,
+   /// Write the totals of the run (files, bytes, errors, duration) to this file in the text format of Prometheus at
+   /// the end, also when the copy fails. Point it into the directory of the textfile collector of the node exporter
+   #[clap(long, value_parser)]
+   metrics_file: Option<PathBuf>,
+   /// Write every file that could not be copied to this file at the end, a line with its path, the kind of the error
+   /// and the message separated by tabs. Only the first ones are logged
+   #[clap(long, value_parser)]
+   error_report: Option<PathBuf>,
+   /// Algorithm of the checksums (manifest and verification): sha256 with --manifest, else xxh3 with --verify and
+   /// blake3 otherwise
+   #[clap(long, alias = "checksum-algo", value_enum)]
+   checksum_algorithm: Option<ChecksumAlgorithm>,
+   /// Keep the checksums of the sources in this file between runs, a source with the same size and modification time
+   /// is not hashed again
+   #[clap(long, value_parser)]
+   checksum_cache: Option<PathBuf>,
+   /// Append every finished file to this journal. A run with the same journal skips the files it has as done (with
+   /// the same size and modification time) without looking at their destination
+   #[clap(long, value_parser)]
+   journal: Option<PathBuf>,
+   /// Once the run ends, remove the journal when every file was copied, or rewrite it with one record per file done
+   #[clap(long, value_parser, requires = "journal")]
+   journal_compact: bool,
+   /// Check the files of the destination against the given manifest and exit
+   #[clap(long, value_parser)]
+   verify_manifest: Option<PathBuf>,
+   /// Once the copy is done, read the whole destination again and compare it with the source (or with --manifest):
+   /// the missing, mismatched and extra files are reported, the first two fail the run
+   #[clap(long, value_parser)]
+   verify_after: bool,
+   /// Read every copied file back from the destination and compare it with the checksum of its source, computed while
+   /// copying. A copy that does not match is written once more and fails when it still does not, its source is kept
+   #[clap(long, value_parser)]
+   verify: bool,
+   /// Log the files that cannot be copied and go on (default)
+   #[clap(long, value_parser, conflicts_with = "stop-on-error")]
+   ignore_errors: bool,
+   /// Abort at the first file that cannot be copied
+   #[clap(long, value_parser)]
+   stop_on_error: bool,
+   /// What happens when a file cannot be copied: `continue` logs it and goes on (--ignore-errors), `fail-fast`
+   /// cancels the copies in flight, removes their partial destinations and stops (--stop-on-error)
+   #[clap(long, value_enum, conflicts_with_all = &["ignore-errors", "stop-on-error"])]
+   error_mode: Option<ErrorMode>,
+   /// Abort the run once more than this number of files could not be copied, like fail-fast. 0 never aborts
+   #[clap(long, alias = "on-error-keep-going-but-limit", value_parser, default_value = "0")]
+   max_errors: u64,
+   /// On Ctrl-C or SIGTERM no other file is copied and the copies in flight have this time to finish (`10s`, `1m`),
+   /// the ones still running then are removed. The run exits with 130, a second signal exits at once
+   #[clap(long, value_parser = size::parse_duration, default_value = "10s")]
+   grace_period: Duration,
+   /// Do not flush the destination files to the disk before deleting the source (faster but unsafe)
+   #[clap(long, value_parser)]
+   no_fsync: bool,
+   /// Flush every copied file and directory to the disk, also when the source is kept
+   /// (it is the default with --delete-source)
+   #[clap(long, value_parser, conflicts_with = "no-fsync")]
+   fsync: bool,
+   /// Flush a directory every this number of files copied into it. The sources of --delete-source are removed
+   /// after the flush, so bigger batches are faster but keep more sources around
+   #[clap(long, value_parser, default_value = "1")]
+   fsync_batch: usize,
+   /// Write every file as `.rscopier-tmp.<name>.<id>` in its directory and rename it once it is complete (and
+   /// flushed with --fsync), so no destination file is ever partial. The temporary files left by a run that died are
+   /// removed from the directories a later run goes through. --if-exists applies to the rename
+   #[clap(long, value_parser)]
+   atomic: bool,
+   /// Remove this number of leading directories from the paths at the destination (like tar)
+   #[clap(long, value_parser, default_value = "0")]
+   strip_components: usize,
+   /// Rename the files and directories whose names Windows does not allow (a trailing dot or space, one of <>:"/\|?*
+   /// or a control character, a device name like `nul`) instead of failing them on Windows. Elsewhere they are only
+   /// renamed with this, for a destination read from Windows. The archives keep their names
+   #[clap(long, value_parser)]
+   sanitize_names: bool,
+   /// Give every destination directory its name in this case, merging the source directories whose names differ only by
+   /// their case (`Docs` and `docs`) on purpose. Without it, on a destination that ignores the case the second one of
+   /// them fails
+   #[clap(long, value_enum)]
+   case_fold_merge: Option<CaseFold>,
+   /// Rename the destination files: a template with {name}, {stem}, {ext}, {mtime} and {index} (`{name:lower}` to
+   /// change the case) or `s/find/replace/`. The names that collide follow --if-exists
+   #[clap(long, value_parser = rename::parse_rename_pattern)]
+   rename_pattern: Option<RenamePattern>,
+   /// What to do when a destination file already exists
+   #[clap(long, value_enum, default_value = "overwrite")]
+   if_exists: IfExists,
+   /// Two mtimes closer than this (`2` seconds, `500ms`) are the same one for `--if-exists newer`. Exact by default,
+   /// 2 seconds for a FAT destination
+   #[clap(long, value_parser = size::parse_duration, default_value = "0")]
+   modify_window: Duration,
+   /// What to do when the destination has a directory where the source has a file, or a file where the source has a
+   /// directory: fail the entry, replace the destination (a directory with everything in it) or skip the entry
+   #[clap(long, value_enum, default_value = "error")]
+   on_type_conflict: OnTypeConflict,
+   /// Do not copy again the destination files that match their source: `sample` compares the size and the checksums
+   /// of the first, middle and last 64KiB (the whole file when it is smaller), without trusting the times
+   #[clap(long, value_enum)]
+   skip_unchanged: Option<SkipUnchanged>,
+   /// Copy the source into a directory with this name inside the destination instead of merging it into the destination.
+   /// A source that is a single file gets this name in the destination directory
+   #[clap(long = "as", value_parser)]
+   as_name: Option<PathBuf>,
+   /// `parent` copies the content of the source into the destination, `self` creates the source directory itself
+   /// (`--source /a/b/c` lands in `dest/c`). `--as` names that directory instead
+   #[clap(long, value_enum, default_value = "parent")]
+   source_base: SourceBase,
+   /// Clone the files with a reflink (btrfs, XFS, APFS) instead of copying their bytes
+   #[clap(long, value_enum, default_value = "never")]
+   reflink: Reflink,
+   /// How the bytes are copied
+   #[clap(long, visible_alias = "engine", value_enum, default_value = "auto")]
+   copy_engine: Engine,
+   /// Buffer of the streaming and chunked copies
+   #[clap(long, value_parser = size::parse_size, default_value = "1MiB")]
+   buffer_size: u64,
+   /// Buffers of the streaming copy: the next ones are read while the previous ones are written. 1 reads and writes
+   /// in turns
+   #[clap(long, value_parser, default_value = "2")]
+   pipeline_depth: usize,
+   /// Maximum bytes per second written by all the copies together, like `50MB/s`. On Unix SIGUSR2 steps through
+   /// a half, a quarter, unlimited and back to this rate
+   #[clap(long, value_parser = size::parse_rate)]
+   bwlimit: Option<u64>,
+   /// Maximum number of file copies started per second, independent from --bwlimit
+   #[clap(long, visible_alias = "rate-limit-files-per-sec", value_parser)]
+   max_files_per_sec: Option<u64>,
+   /// The source deletions of --delete-source count against --max-files-per-sec too
+   #[clap(long, value_parser)]
+   rate_limit_deletions: bool,
+   /// Print the relative path of every file that would be copied and exit without creating the destination
+   #[clap(long, value_parser, conflicts_with = "verify-manifest")]
+   list_only: bool,
+   /// Print the size after the path of every listed file, separated by a tab
+   #[clap(long, value_parser)]
+   list_sizes: bool,
+   /// Sort the listed files by path so the output is stable between runs
+   #[clap(long, value_parser)]
+   deterministic: bool,
+   /// Octal mode of the destination files, like 644, instead of the source one (Unix only)
+   #[clap(long, value_parser = metadata::parse_mode)]
+   chmod: Option<u32>,
+   /// Octal mode of the destination directories, like 755 (Unix only)
+   #[clap(long, value_parser = metadata::parse_mode)]
+   chmod_dirs: Option<u32>,
+   /// Reserve the whole size of every destination file before writing it to avoid fragmentation.
+   /// The files are streamed and the filesystems that cannot preallocate just skip it
+   #[clap(long, value_parser)]
+   preallocate: bool,
+   /// Copy the files with O_DIRECT so they do not evict the page cache (Linux). The filesystems that do not
+   /// support it (tmpfs) are copied through the cache with a warning
+   #[clap(long, value_parser)]
+   direct_io: bool,
+   /// Skip the files modified after this time: a duration before now (`60s`, `15m`, `2h`, `7d`, `1w`),
+   /// an RFC 3339 timestamp or a local `2024-05-01 13:00:00`. Together with --exclude-older-than it defines
+   /// the window (both bounds included) where the mtime of a copied file must fall
+   #[clap(long, value_parser = filter::parse_time)]
+   exclude_newer_than: Option<SystemTime>,
+   /// Skip the files modified before this time, same formats as --exclude-newer-than
+   #[clap(long, value_parser = filter::parse_time)]
+   exclude_older_than: Option<SystemTime>,
+   /// Create every source directory at the destination, also the empty ones and the ones whose files are all filtered
+   /// out. It is the default unless --no-include-empty-dirs
+   #[clap(long, value_parser, conflicts_with = "no-include-empty-dirs")]
+   include_empty_dirs: bool,
+   /// Only create the destination directories that get a file or have one below them
+   #[clap(long, value_parser)]
+   no_include_empty_dirs: bool,
+   /// Files smaller than this size are copied in batches by blocking threads instead of one task each. 0 disables it
+   #[clap(long, value_parser = size::parse_size, default_value = "64KiB")]
+   small_file_threshold: u64,
+   /// Walk the source before copying to know the totals of the progress reports. It is the default unless --no-prescan
+   #[clap(long, value_parser, conflicts_with = "no-prescan")]
+   prescan: bool,
+   /// Do not walk the source before copying, the progress reports only count what is already copied
+   #[clap(long, value_parser)]
+   no_prescan: bool,
+   /// In which order the files are copied. Anything but `walk` copies the files found by the pre-scan, across all the
+   /// directories, instead of the files of every directory as it is walked
+   #[clap(long, value_enum, default_value = "walk")]
+   order: Order,
+   /// Give up the pre-scan after this time (`30s`, `5m`) and report the progress without totals
+   #[clap(long, value_parser = size::parse_duration)]
+   prescan_timeout: Option<Duration>,
+   /// Show the sizes of the progress, the summary and the logs like `10.0 MiB` or `1.5 GiB` instead of a number of
+   /// bytes. The stats file and the metrics keep the bytes
+   #[clap(long, value_parser)]
+   human_readable: bool,
+   /// Write a snapshot of the progress into stdout twice a second, a JSON object per line with `files_done`,
+   /// `files_total`, `bytes_done`, `bytes_total` (the totals are null without the pre-scan) and `current_file`. The
+   /// logs go to stderr instead
+   #[clap(long, value_parser)]
+   progress_json: bool,
+   /// Log a summary of the run so far (files and bytes done, failures, elapsed time) every time this number of files
+   /// more are done, so the logs tell the progress without a terminal
+   #[clap(long, visible_alias = "keep-going-summary-every", value_parser)]
+   checkpoint_every: Option<u64>,
+   /// Copy only the files of this list (`-` reads it from the standard input), one path relative to the source per
+   /// line, instead of walking the source. The missing entries are warned about and skipped
+   #[clap(long, value_parser)]
+   files_from: Option<PathBuf>,
+   /// The paths of --files-from are separated by NUL characters (`find -print0`) instead of new lines
+   #[clap(long, value_parser, requires = "files-from")]
+   null_separated: bool,
+   /// Fail the copy of a file that takes longer than this (`5m`), its partial destination is removed
+   #[clap(long, value_parser = size::parse_duration)]
+   file_timeout: Option<Duration>,
+   /// Time added to --file-timeout for every GB of the file
+   #[clap(long, value_parser = size::parse_duration, requires = "file-timeout")]
+   timeout_per_gb: Option<Duration>,
+   /// Copy again, up to this number of times, the files that fail with a transient error (EIO, ESTALE, EAGAIN,
+   /// ETIMEDOUT, EBUSY, --file-timeout). The other errors fail right away
+   #[clap(long, value_parser, default_value = "0")]
+   retries: u32,
+   /// Delay before the first retry, it doubles on every attempt (up to a minute) with some jitter
+   #[clap(long, value_parser = size::parse_duration, default_value = "1s")]
+   retry_delay: Duration,
+   /// What to do with a file whose source changes (size or modification time) while it is copied: `retry` copies it
+   /// again up to --retries times and then fails it, `skip` leaves it out, `fail` fails it at once, `warn` keeps its
+   /// copy, which can be torn. Its destination is removed, but with `warn`, and its source is never deleted
+   #[clap(long, visible_alias = "on-change", value_enum, default_value = "retry")]
+   volatile: Volatile,
+   /// Compare the size and modification time of every source before and after its copy. It is the default unless
+   /// --no-detect-changes
+   #[clap(long, value_parser, conflicts_with = "no-detect-changes")]
+   detect_changes: bool,
+   /// Do not check whether the sources change while they are copied, for sources known to be stable
+   #[clap(long, value_parser)]
+   no_detect_changes: bool,
+   /// I/O priority of the copy: `idle` only uses the disk when nothing else does, `best-effort:N` goes from 0 (the
+   /// highest) to 7 (the lowest). Linux only
+   #[clap(long, value_parser = priority::parse_io_nice)]
+   io_nice: Option<IoNice>,
+   /// CPU niceness of the copy, from -20 to 19 (only root can go below 0). Unix only
+   #[clap(long, value_parser = clap::value_parser!(i32).range(-20..=19), allow_hyphen_values = true)]
+   nice: Option<i32>,
+   /// Maximum number of directories found and waiting to be copied, 64 per --list-concurrency by default. When it is
+   /// reached the task that finds a directory copies it right away, so the memory of the walk stays bounded
+   #[clap(long, value_parser)]
+   max_pending: Option<usize>,
+   /// When the source is a link, --delete-source moves the files of its target and removes both. Without it the target
+   /// is only copied and the link is the only thing removed
+   #[clap(long, value_parser)]
+   dereference_source: bool,
+   /// With --delete-source, move the sources into this directory (keeping their relative paths) instead of deleting them
+   #[clap(long, value_parser)]
+   trash: Option<PathBuf>,
+   /// Ask on the terminal before copying when the sources will be deleted, and abort unless it is confirmed. Nothing
+   /// is asked when the standard input is not a terminal
+   #[clap(short, long, value_parser)]
+   interactive: bool,
+   /// Confirm in advance what --interactive would ask
+   #[clap(short, long, value_parser)]
+   yes: bool,
+   /// Copy even when the pre-scan finds more bytes than the free space of the destination, only warning about it
+   #[clap(long, value_parser)]
+   force: bool,
+   /// Do not compare the bytes of the pre-scan with the free space of the destination before copying
+   #[clap(long, value_parser)]
+   no_space_check: bool,
+   /// Stop the copy (exit code 3) when the free space of the destination drops below this size, like `10GiB`,
+   /// instead of failing the files that follow. The pre-scanned bytes must leave it free too
+   #[clap(long, value_parser = size::parse_size)]
+   min_free_space: Option<u64>,
+   /// Run this command after every file is copied, like `scan --quiet {dest}`. `{dest}` and `{source}` are replaced
+   /// with the paths of the file (the destination is the last argument without them), which are also in the variables
+   /// RS_COPIER_DEST and RS_COPIER_SOURCE. It is split like a shell does but run without one
+   #[clap(long, value_parser = hook::parse_hook_command)]
+   post_copy_hook: Option<HookCommand>,
+   /// The maximum number of hooks running at the same time, the copies wait for them
+   #[clap(long, value_parser, default_value = "4")]
+   hook_concurrency: usize,
+   /// Fail the files whose hook fails (a nonzero exit), and keep their copied sources. Without it the failure is only
+   /// logged
+   #[clap(long, value_parser)]
+   hook_must_succeed: bool,
+   /// Kill the hooks still running after this (`30s`), which then fail like a hook that exits with an error. The
+   /// default is --file-timeout, without it the hooks can run as long as they need
+   #[clap(long, value_parser = size::parse_duration)]
+   hook_timeout: Option<Duration>,
+   /// Load the options from a TOML file whose keys are the names of these options with underscores
+   /// (`delete_source = true`). The flags given in the command line take precedence
+   #[clap(long, value_parser)]
+   config: Option<PathBuf>,
+   #[clap(subcommand)]
+   command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    /// Copy a synthetic tree into the destination with every configuration of a sweep and print the throughput of
+    /// each one. The other options apply to every copy
+    Bench(BenchArgs),
+}
+
+/// The metadata that the destination keeps: `--archive`, else the `--preserve` list or the default, plus the
+/// separate `--preserve-*` flags
+fn preserve_flags(args: &Args) -> PreserveFlags {
+    let mut preserve = match args.preserve {
+        _ if args.archive => PreserveFlags::archive(),
+        Some(preserve) => preserve,
+        None => PreserveFlags::default(),
+    };
+    preserve.attributes |= args.preserve_attributes;
+    preserve.crtime |= args.preserve_crtime;
+    preserve.acls |= args.preserve_acls;
+    preserve.links |= args.follow_top_level_links;
+    preserve
+}
+
+/// Remote directory of an `sftp://` destination
+#[cfg(unix)]
+fn remote_path(url: &str) -> Result<PathBuf> {
+    Ok(sftp::SftpUrl::parse(url)?.path)
+}
+
+#[cfg(not(unix))]
+fn remote_path(_url: &str) -> Result<PathBuf> {
+    Err(anyhow::anyhow!("SFTP destinations are only supported on Unix"))
+}
+
+/// Write the copy into an `sftp://` destination once the options that need a local destination are ruled out
+#[cfg(unix)]
+async fn connect_sftp(url: &str, options: Arc<CopyOptions>) -> Result<Arc<CopyOptions>> {
+    if let Some(flag) = sftp::unsupported_option(&options) {
+        return Err(anyhow::anyhow!("{} is not supported with an SFTP destination", flag));
+    }
+    let url = sftp::SftpUrl::parse(url)?;
+    info!("Connecting to {}", url.host);
+    let sftp = sftp::Sftp::connect(&url).await?;
+    let mut options = Arc::unwrap_or_clone(options);
+    options.destination = Some(Arc::new(sftp));
+    Ok(Arc::new(options))
+}
+
+#[cfg(not(unix))]
+async fn connect_sftp(_url: &str, _options: Arc<CopyOptions>) -> Result<Arc<CopyOptions>> {
+    Err(anyhow::anyhow!("SFTP destinations are only supported on Unix"))
+}
+
+/// Check the destination against a manifest and fail if any file does not match
+async fn verify_manifest(manifest: &Path, dest: &Path, algorithm: ChecksumAlgorithm) -> Result<()> {
+    info!("Verifying {:?} against the manifest {:?}", dest, manifest);
+    let mismatches = manifest::verify(manifest, dest, algorithm).await?;
+    for mismatch in &mismatches {
+        error!("Manifest mismatch: {:?}", mismatch);
+    }
+    if !mismatches.is_empty() {
+        return Err(anyhow::anyhow!("{} files do not match the manifest", mismatches.len()));
+    }
+    info!("All the files match the manifest");
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    match start() {
+        Ok(()) => ExitCode::from(failures::EXIT_SUCCESS),
+        Err(error) => {
+            eprintln!("Error: {:?}", error);
+            ExitCode::from(failures::exit_code(&error))
+        },
+    }
+}
+
+/// The whole program, its error sets the exit code
+fn start() -> Result<()> {
+    let args = config::parse_args(std::env::args_os())?;
+
+    // The logs of the copies would bury the results of the bench
+    let bench = matches!(args.command, Some(Command::Bench(_)));
+    setup_logger(if bench { "WARN" } else { "INFO" }, None::<&str>, args.progress_json)?;
+
+    let concurrency = match &args.command {
+        // The runtime is sized for the largest copy of the sweep
+        Some(Command::Bench(bench)) => Concurrency::Fixed(bench.max_concurrency()),
+        None => args.concurrency.unwrap_or_else(adaptive::default_concurrency),
+    };
+    let list_concurrency = args.list_concurrency.unwrap_or(concurrency.directories());
+    let defaults = Threads::for_copies(concurrency.max_copies(), args.chunk_parallelism, list_concurrency);
+    let threads = Threads {
+        workers: args.runtime_threads.unwrap_or(defaults.workers),
+        blocking: args.blocking_threads.unwrap_or(defaults.blocking),
+    };
+    let priority = Priority { io: args.io_nice, nice: args.nice };
+    let runtime = runtime::build(threads, priority)?;
+    match args.command.clone() {
+        Some(Command::Bench(bench)) => runtime.block_on(run_bench(args, bench)),
+        None => runtime.block_on(run(args, list_concurrency)),
+    }
+}
+
+/// `bench`: generate the synthetic tree in a temporary directory, copy it into the destination with every
+/// configuration of the sweep through `run`, print the results and remove both trees
+async fn run_bench(args: Args, bench: BenchArgs) -> Result<()> {
+    let (tree, measures) = bench_sweep(&args, &bench, &std::env::temp_dir()).await?;
+    if bench.json {
+        print!("{}", bench::to_json(tree, &measures));
+    } else {
+        print!("{}", bench::table(tree, &measures));
+    }
+    Ok(())
+}
+
+/// The runs of the bench with the synthetic tree generated in `temp`
+async fn bench_sweep(args: &Args, bench: &BenchArgs, temp: &Path) -> Result<(bench::Tree, Vec<Measure>)> {
+    bench.check()?;
+    let destination = args.destination.clone().ok_or_else(|| anyhow::anyhow!("The destination is required"))?;
+    if backend::is_sftp(&destination) {
+        return Err(anyhow::anyhow!("The bench needs a local destination"));
+    }
+    let name = format!("rs-copier-bench-{}", std::process::id());
+    let source = temp.join(&name);
+    let dest = destination.join(&name);
+    let measured = async {
+        let tree = bench::generate(&source, bench).await?;
+        let mut measures = vec![];
+        for (concurrency, buffer_size) in bench.configurations(args.buffer_size) {
+            let copy = Args {
+                source: Some(source.clone()),
+                destination: Some(dest.join(measures.len().to_string())),
+                concurrency: Some(Concurrency::Fixed(concurrency)),
+                buffer_size,
+                delete_source: false,
+                // A failed file would make the copy look faster
+                stop_on_error: true,
+                ignore_errors: false,
+                list_only: false,
+                verify_manifest: None,
+                command: None,
+                ..args.clone()
+            };
+            let list_concurrency = args.list_concurrency.unwrap_or(Concurrency::Fixed(concurrency).directories());
+            let started = std::time::Instant::now();
+            run(copy, list_concurrency).await?;
+            measures.push(Measure { concurrency, buffer_size, elapsed: started.elapsed() });
+            // Every run starts from an empty destination
+            remove_bench_tree(&dest).await?;
+        }
+        Ok::<_, anyhow::Error>((tree, measures))
+    }.await;
+    let removed = (remove_bench_tree(&source).await, remove_bench_tree(&dest).await);
+    let measured = measured?;
+    removed.0?;
+    removed.1?;
+    Ok(measured)
+}
+
+/// With `interactive` (--interactive without --yes), ask before copying the `files` sources that will be deleted and
+/// fail when it is not confirmed. A standard input that is not a terminal (a script) skips the question
+async fn confirm_deletion(interactive: bool, options: &CopyOptions, files: Option<u64>) -> Result<()> {
+    if !interactive || !options.remove_source {
+        return Ok(());
+    }
+    if !std::io::stdin().is_terminal() {
+        warn!("The standard input is not a terminal, the sources are deleted without asking");
+        return Ok(());
+    }
+    let question = confirm::deletion_question(files);
+    let confirmed = tokio::task::spawn_blocking(move || {
+        confirm::ask(&question, &mut std::io::stdin().lock(), &mut std::io::stderr())
+    }).await??;
+    if !confirmed {
+        return Err(anyhow::anyhow!("The deletion of the sources was not confirmed, nothing was copied"));
+    }
+    Ok(())
+}
+
+/// Remove a tree of the bench, if it is there
+async fn remove_bench_tree(dir: &Path) -> Result<()> {
+    match tokio::fs::remove_dir_all(dir).await {
+        Err(error) if error.kind() != std::io::ErrorKind::NotFound => {
+            Err(anyhow::Error::new(error).context(format!("Cannot remove directory: {:?}", dir)))
+        },
+        _ => Ok(()),
+    }
+}
+
+/// The whole run, in the runtime built by `main`, listing up to `list_concurrency` directories at the same time
+async fn run(args: Args, list_concurrency: usize) -> Result<()> {
+    let filters = Filters {
+        time_window: TimeWindow { oldest: args.exclude_older_than, newest: args.exclude_newer_than },
+    };
+    if filters.time_window.is_empty() {
+        return Err(anyhow::anyhow!("--exclude-older-than is newer than --exclude-newer-than, no file can be copied"));
+    }
+
+    let ordered = args.order != Order::Walk;
+    if ordered && args.no_prescan {
+        return Err(anyhow::anyhow!("--order needs the pre-scan, it cannot be used with --no-prescan"));
+    }
+
+    if args.list_only {
+        let source = args.source.ok_or_else(|| anyhow::anyhow!("The source is required"))?;
+        source::check(&source).await?;
+        let listed = listing::list_tree(&source, &filters, args.deterministic).await?;
+        return listing::write_listing(&listed, args.list_sizes, &mut std::io::stdout().lock());
+    }
+
+    let preserve = preserve_flags(&args);
+    let destination = args.destination.ok_or_else(|| anyhow::anyhow!("The destination is required"))?;
+    let remote = backend::is_sftp(&destination);
+    if remote && args.verify_manifest.is_some() {
+        return Err(anyhow::anyhow!("--verify-manifest cannot check an SFTP destination"));
+    }
+    // An sftp:// URL is UTF-8
+    let base_dest = if remote { remote_path(&destination.to_string_lossy())? } else { winpath::extended(&destination)? };
+    // A manifest is sha256 whatever the other options, so the same copy always writes the same one. The fastest
+    // algorithm when the checksums are only compared with the copies
+    let checksum_algorithm = args.checksum_algorithm.unwrap_or(if args.manifest.is_some() || args.verify_manifest.is_some() {
+        ChecksumAlgorithm::Sha256
+    } else if args.verify {
+        ChecksumAlgorithm::Xxh3
+    } else {
+        ChecksumAlgorithm::Blake3
+    });
+    if let Some(manifest) = args.verify_manifest {
+        return verify_manifest(&manifest, &base_dest, checksum_algorithm).await;
+    }
+
+    let base_source = args.source.ok_or_else(|| anyhow::anyhow!("The source is required"))?;
+    let delete_source = args.delete_source;
+    let interactive = args.interactive && !args.yes;
+    let root = resolve_source_root(base_source, delete_source, args.dereference_source).await?;
+    // The long paths of Windows
+    let base_source = winpath::extended(&root.path)?;
+    source::check(&base_source).await?;
+    // A regular file is copied on its own, the archives are extracted
+    let single_file = base_source.is_file() && !archive::is_tar(&base_source) && !zip::is_zip(&base_source);
+    let file_dest = match single_file && !remote {
+        true => Some(single_file_destination(&base_dest, args.as_name.as_deref(), root.link.as_deref().unwrap_or(&base_source), &LocalBackend).await?),
+        false => None,
+    };
+    let trash = match &args.trash {
+        Some(dir) if delete_source => Some(Arc::new(Trash::create(&base_source, dir).await?)),
+        Some(_) => {
+            warn!("Nothing is removed without --delete-source, ignoring --trash");
+            None
+        },
+        None => None,
+    };
+    let tempdir = match &args.tempdir {
+        Some(dir) if args.resume => Some(Arc::new(TempDir::create(dir).await?)),
+        Some(_) => {
+            warn!("The only temporary files are the `.part` of --resume, ignoring --tempdir");
+            None
+        },
+        None => None,
+    };
+    let concurrency = args.concurrency.unwrap_or_else(adaptive::default_concurrency);
+    let copy_permits = match concurrency {
+        Concurrency::Auto => CopyPermits::limited(AUTO_MAX, AUTO_START),
+        Concurrency::Fixed(copies) => CopyPermits::new(copies),
+    };
+    let adaptive = (concurrency == Concurrency::Auto).then(|| Arc::new(Adaptive::new(copy_permits.clone())));
+    let checksum_cache = match &args.checksum_cache {
+        Some(path) => Some(Arc::new(ChecksumCache::load(path, checksum_algorithm).await?)),
+        None => None,
+    };
+    let journal = match &args.journal {
+        Some(path) => Some(Arc::new(Journal::open(path, &base_source).await?)),
+        None => None,
+    };
+    let options = Arc::new(CopyOptions {
+        remove_source: root.remove_files,
+        force_source_cleanup: args.force_source_cleanup,
+        chunk_parallelism: args.chunk_parallelism,
+        chunk_threshold: args.chunk_threshold,
+        verify_chunked: args.verify_big_files,
+        verify: args.verify,
+        resume: args.resume,
+        tempdir,
+        preserve,
+        relativize_links: (args.relativize_links && preserve.links).then(|| Arc::new(SourceTree::new(&base_source))),
+        follow_top_level_links: args.follow_top_level_links,
+        // A file copied to an exact path is recorded by its name
+        manifest: args.manifest.as_ref().map(|_| {
+            let root = file_dest.as_deref().and_then(Path::parent).unwrap_or(&base_dest);
+            Arc::new(Manifest::new(root, checksum_algorithm))
+        }),
+        dedupe: args.dedupe_dest.then(Default::default),
+        dedupe_method: args.dedupe_method,
+        checksum_algorithm,
+        checksum_cache,
+        journal,
+        // clap rejects both flags together, ignoring errors is the default
+        stop_on_error: (args.stop_on_error && !args.ignore_errors) || args.error_mode == Some(ErrorMode::FailFast),
+        max_errors: args.max_errors,
+        interrupt: Some(Interrupt::install(args.grace_period)?),
+        // Measured at its nearest parent until the destination is created
+        min_free_space: args.min_free_space.map(|min| space::Monitor::new(&base_dest, min)),
+        copy_permits,
+        adaptive,
+        device_permits: args.workers_per_disk.map(|per_device| Arc::new(DevicePermits::new(per_device))),
+        fsync: args.fsync || (delete_source && !args.no_fsync),
+        fsync_batch: args.fsync_batch.max(1),
+        atomic: args.atomic,
+        strip_components: args.strip_components,
+        sanitize_names: args.sanitize_names,
+        // Checked on the local destinations only, an SFTP server does not tell
+        case_folds: (args.case_fold_merge.is_some() || (!remote && casefold::ignores_case(&base_dest)))
+            .then(|| Arc::new(CaseFolds::new(args.case_fold_merge))),
+        hooks: args.post_copy_hook.clone().map(|command| {
+            Arc::new(Hooks::new(command, args.hook_concurrency, args.hook_must_succeed, args.hook_timeout.or(args.file_timeout)))
+        }),
+        rename_pattern: args.rename_pattern.clone(),
+        if_exists: args.if_exists,
+        modify_window: args.modify_window,
+        on_type_conflict: args.on_type_conflict,
+        skip_unchanged: args.skip_unchanged,
+        // They can give the same destination to several sources
+        claims: (args.strip_components > 0 || args.rename_pattern.is_some() || args.sanitize_names || args.case_fold_merge.is_some())
+            .then(Default::default),
+        reflink: args.reflink,
+        stats: Arc::new(CopyStats {
+            human_readable: args.human_readable,
+            checkpoints: args.checkpoint_every.filter(|every| *every > 0).map(Checkpoints::new),
+            ..Default::default()
+        }),
+        failures: Arc::default(),
+        copy_engine: args.copy_engine,
+        buffer_size: BufferSize(args.buffer_size.try_into()?),
+        pipeline_depth: PipelineDepth(args.pipeline_depth),
+        bandwidth: args.bwlimit.filter(|rate| *rate > 0).map(|rate| Arc::new(Bandwidth::new(rate))),
+        file_timeout: args.file_timeout.map(|base| FileTimeout { base, per_gb: args.timeout_per_gb }),
+        retry: (args.retries > 0).then_some(Retry { retries: args.retries, delay: args.retry_delay }),
+        volatile: args.volatile,
+        volatile_files: Arc::default(),
+        ignore_changes: args.no_detect_changes,
+        dangling_links: args.dangling_links,
+        file_rate: args.max_files_per_sec.filter(|rate| *rate > 0).map(|rate| Arc::new(FileRate::new(rate))),
+        rate_limit_deletions: args.rate_limit_deletions,
+        chmod: args.chmod,
+        chmod_dirs: args.chmod_dirs,
+        preallocate: args.preallocate,
+        direct_io: args.direct_io,
+        filters,
+        prune_empty_dirs: args.no_include_empty_dirs,
+        small_file_threshold: args.small_file_threshold,
+        trash,
+        max_pending: args.max_pending,
+        source: None,
+        destination: None,
+    });
+    if concurrency.max_copies() == 0 {
+        return Err(anyhow::anyhow!("The concurrency must be at least 1"));
+    }
+    if list_concurrency == 0 {
+        return Err(anyhow::anyhow!("--list-concurrency must be at least 1"));
+    }
+    if args.relativize_links && !preserve.links {
+        warn!("The links are followed without --preserve links, ignoring --relativize-links");
+    }
+    if options.relativize_links.is_some() && options.strip_components > 0 {
+        return Err(anyhow::anyhow!("--relativize-links cannot be used with --strip-components"));
+    }
+    if args.workers_per_disk == Some(0) {
+        return Err(anyhow::anyhow!("--workers-per-disk must be at least 1"));
+    }
+    if options.hooks.is_some() && args.hook_concurrency == 0 {
+        return Err(anyhow::anyhow!("--hook-concurrency must be at least 1"));
+    }
+    if options.hooks.is_none() && args.hook_must_succeed {
+        warn!("There is no --post-copy-hook, ignoring --hook-must-succeed");
+    }
+    if options.hooks.is_none() && args.hook_timeout.is_some() {
+        warn!("There is no --post-copy-hook, ignoring --hook-timeout");
+    }
+    if options.max_pending == Some(0) {
+        return Err(anyhow::anyhow!("--max-pending must be at least 1"));
+    }
+    if options.buffer_size.0 == 0 {
+        return Err(anyhow::anyhow!("The buffer size must be at least 1 byte"));
+    }
+    if options.pipeline_depth.0 == 0 {
+        return Err(anyhow::anyhow!("--pipeline-depth must be at least 1"));
+    }
+    if let (Some(link), true) = (&root.link, delete_source && !root.remove_files) {
+        info!("The source {:?} is a link, only the link is deleted once copied (see --dereference-source)", link);
+    } else if delete_source {
+        info!("Source files will be deleted once copied");
+        if !options.fsync {
+            warn!("The copies are not flushed to the disk before deleting the source");
+        }
+    }
+    
+    if cfg!(not(windows)) && options.preserve.attributes {
+        warn!("File attributes can only be preserved on Windows, ignoring --preserve attributes");
+    }
+    if cfg!(not(unix)) && options.preserve.ownership {
+        warn!("The ownership can only be preserved on Unix, ignoring --preserve ownership");
+    }
+    if cfg!(not(any(target_os = "linux", target_os = "macos"))) && options.preserve.xattrs {
+        warn!("Extended attributes can only be preserved on Linux and macOS, ignoring --preserve xattrs");
+    }
+    if cfg!(not(target_os = "linux")) && options.preserve.acls {
+        warn!("ACLs can only be preserved on Linux, ignoring --preserve acls");
+    }
+    if !options.preserve.mode {
+        // Before any file is created
+        metadata::umask();
+    }
+    if options.copy_engine == Engine::Uring {
+        if cfg!(not(all(feature = "uring", target_os = "linux"))) {
+            return Err(anyhow::anyhow!("The uring engine needs Linux and a build with the `uring` feature"));
+        }
+        if options.chunk_parallelism > 1 || options.direct_io {
+            warn!("The uring engine already keeps several reads and writes in flight, ignoring --chunk-parallelism and --direct-io");
+        }
+    }
+    if cfg!(not(unix)) && (options.chmod.is_some() || options.chmod_dirs.is_some()) {
+        warn!("Modes can only be set on Unix, ignoring --chmod and --chmod-dirs");
+    }
+
+    let options = if remote {
+        connect_sftp(&destination.to_string_lossy(), options).await?
+    } else {
+        options
+    };
+
+    match concurrency {
+        Concurrency::Auto => info!("The concurrency is adjusted while copying, starting at {} copies", AUTO_START),
+        Concurrency::Fixed(copies) if args.concurrency.is_none() => info!(
+            "The concurrency is set to {copies}: {} per core for {} cores, between {} and {} (see --concurrency)",
+            adaptive::COPIES_PER_CORE, adaptive::available_cores(), adaptive::DEFAULT_MIN, adaptive::DEFAULT_MAX,
+        ),
+        Concurrency::Fixed(copies) => info!("The concurrency is set to {copies}"),
+    }
+    info!("Up to {} directories are listed at the same time", list_concurrency);
+    if options.chunk_parallelism > 1 {
+        info!("Files bigger than {} bytes will be copied using {} ranges", options.chunk_threshold, options.chunk_parallelism);
+    }
+
+    if options.stop_on_error {
+        info!("The copy will stop at the first error");
+    } else if options.max_errors > 0 {
+        info!("The copy will stop once more than {} files fail", options.max_errors);
+    }
+
+    if let Some(bandwidth) = &options.bandwidth {
+        info!("The bandwidth is limited to {} bytes/s", bandwidth.rate());
+        #[cfg(unix)]
+        limit::step_rate_on_signal(bandwidth.clone())?;
+    }
+    if let Some(file_rate) = &options.file_rate {
+        info!("Up to {} file copies are started per second", file_rate.per_second());
+    }
+    if let Some(device_permits) = &options.device_permits {
+        if cfg!(unix) {
+            info!("Up to {} files are copied at the same time from every source disk", device_permits.per_device());
+        } else {
+            warn!("The source devices are only known on Unix, ignoring --workers-per-disk");
+        }
+    }
+    let started = std::time::Instant::now();
+
+    // The stats file is written also when the copy fails
+    let copied: Result<()> = async {
+        let tree_dest = match (args.as_name.as_deref(), args.source_base) {
+            _ if single_file => match &file_dest {
+                Some(file_dest) => file_dest.clone(),
+                None => single_file_destination(&base_dest, args.as_name.as_deref(), root.link.as_deref().unwrap_or(&base_source), options.destination()).await?,
+            },
+            (None, SourceBase::Itself) => source_base_root(&base_dest, root.link.as_deref().unwrap_or(&base_source)).await?,
+            (name, _) => destination_root(&base_dest, name)?,
+        };
+        if !remote {
+            overlap::check(&base_source, &tree_dest, delete_source).await?;
+        }
+        let archives = !single_file && (archive::is_tar(&base_source) || zip::is_zip(&base_source) || archive::is_tar(&tree_dest));
+        if remote && archives {
+            return Err(anyhow::anyhow!("Archives cannot be extracted or created through SFTP"));
+        }
+        if args.files_from.is_some() && archives {
+            return Err(anyhow::anyhow!("--files-from cannot be used with archives"));
+        }
+        if options.journal.is_some() && archives {
+            warn!("The archives are not journaled, ignoring --journal");
+        }
+        if options.hooks.is_some() && archives {
+            warn!("The files of archives do not run hooks, ignoring --post-copy-hook");
+        }
+        if options.dedupe.is_some() {
+            if archives {
+                warn!("The archives are not deduplicated, ignoring --dedupe-dest");
+            } else {
+                dedupe::check_links(space::existing_ancestor(&tree_dest), options.dedupe_method == DedupeMethod::Reflink).await?;
+            }
+        }
+        if single_file {
+            if let (Ok(metadata), false, false) = (tokio::fs::metadata(&base_source).await, remote, args.no_space_check) {
+                if !(options.remove_source && space::same_filesystem(&base_source, &tree_dest)) {
+                    let reserve = args.min_free_space.unwrap_or(0);
+                    space::check(&tree_dest, metadata.len(), reserve, space::Estimate::Exact, args.force, space::free_space)?;
+                }
+            }
+            if args.files_from.is_some() {
+                warn!("The source is a single file, ignoring --files-from");
+            }
+            confirm_deletion(interactive, &options, Some(1)).await?;
+            copy_single_file(&base_source, &tree_dest, options.clone()).await?;
+        } else if base_source.is_file() && archive::is_tar(&base_source) {
+            confirm_deletion(interactive, &options, Some(1)).await?;
+            archive::extract(&base_source, &tree_dest, &options).await?;
+            // Only once every entry was extracted
+            if options.remove_source && options.stats.files_failed.load(std::sync::atomic::Ordering::Relaxed) == 0 {
+                remove_source(&base_source, &options).await?;
+            }
+        } else if base_source.is_file() && zip::is_zip(&base_source) {
+            confirm_deletion(interactive, &options, Some(1)).await?;
+            zip::extract(&base_source, &tree_dest, &options).await?;
+            // Only once every entry was extracted
+            if options.remove_source && options.stats.files_failed.load(std::sync::atomic::Ordering::Relaxed) == 0 {
+                remove_source(&base_source, &options).await?;
+            }
+        } else {
+            let archived = archive::is_tar(&tree_dest);
+            if archived && options.rename_pattern.is_some() {
+                warn!("The files keep their names in the archive, ignoring --rename-pattern");
+            }
+            let scan = if let Some(list) = &args.files_from {
+                Some(files_from::scan(&base_source, Path::new(list), args.null_separated, options.links()).await?)
+            } else if args.no_prescan {
+                None
+            } else {
+                run_prescan(&base_source, &options, list_concurrency, args.prescan_timeout, ordered && !archived).await
+            };
+            // A move inside a filesystem only renames the files
+            let renames = options.remove_source && space::same_filesystem(&base_source, &tree_dest);
+            if let (Some(scan), false, false, false) = (&scan, remote, renames, args.no_space_check) {
+                // Clones share the blocks of the sources, links share the blocks of the first copy and an archive is
+                // compressed
+                let estimate = if options.reflink != Reflink::Never || options.dedupe.is_some() || archive::is_gzip(&tree_dest) {
+                    space::Estimate::UpperBound
+                } else {
+                    space::Estimate::Exact
+                };
+                let reserve = args.min_free_space.unwrap_or(0);
+                space::check(&tree_dest, scan.totals.bytes, reserve, estimate, args.force, space::free_space)?;
+            }
+            if ordered && (archived || scan.is_none()) {
+                warn!("Ignoring --order, the files are copied as the tree is walked");
+            }
+            confirm_deletion(interactive, &options, scan.as_ref().map(|scan| scan.totals.files)).await?;
+            let totals = scan.as_ref().map(|scan| scan.totals);
+            let progress = tokio::spawn(report_progress(options.stats.clone(), totals, options.adaptive.clone()));
+            let progress_json = args.progress_json
+                .then(|| tokio::spawn(report_progress_json(options.stats.clone(), totals, PROGRESS_JSON_INTERVAL, std::io::stdout())));
+            let mut packed = None;
+            let result = match scan {
+                _ if archived => archive::create(&base_source, &tree_dest, &options).await.map(|tree| packed = Some(tree)),
+                Some(scan) if args.files_from.is_some() => copy_listed(&base_source, &tree_dest, scan, args.order, options.clone()).await,
+                Some(scan) if ordered => copy_ordered(&base_source, &tree_dest, scan, args.order, options.clone()).await,
+                _ => copy_tree(&base_source, &tree_dest, options.clone(), list_concurrency).await,
+            };
+            progress.abort();
+            if let Some(progress_json) = progress_json {
+                progress_json.abort();
+                // The last snapshot has the end of the copy
+                println!("{}", options.stats.progress_json(totals.as_ref()));
+            }
+            result?;
+            if let (Some(packed), true) = (packed, options.remove_source) {
+                remove_packed_source(&base_source, &tree_dest, packed, &options).await?;
+            }
+        }
+        if let (Some(link), true) = (&root.link, delete_source) {
+            remove_source_link(link, &options).await?;
+        }
+
+        if let (Some(manifest), Some(path)) = (&options.manifest, &args.manifest) {
+            manifest.write(path).await?;
+            info!("Manifest written to {:?}", path);
+        }
+        if args.verify_after {
+            verify_after(args.manifest.as_deref(), args.files_from.is_some(), &base_source, &base_dest, &tree_dest, &options, list_concurrency).await?;
+        }
+        Ok(())
+    }.await;
+    if let Some(path) = &args.stats_file {
+        let error = copied.as_ref().err().map(|error| format!("{:#}", error));
+        options.stats.write_file(path, started.elapsed(), error.as_deref()).await?;
+        info!("Stats written to {:?}", path);
+    }
+    if let Some(path) = &args.metrics_file {
+        options.stats.write_metrics(path, started.elapsed(), copied.is_ok()).await?;
+        info!("Metrics written to {:?}", path);
+    }
+    if let (Some(cache), Some(path)) = (&options.checksum_cache, &args.checksum_cache) {
+        cache.save(path).await?;
+        info!("Checksum cache written to {:?}, {} sources were hashed", path, cache.hashed());
+    }
+    if let Some(journal) = &options.journal {
+        journal.sync().await?;
+        if args.journal_compact {
+            let complete = copied.is_ok() && options.stats.files_failed.load(std::sync::atomic::Ordering::Relaxed) == 0;
+            journal.compact(complete).await?;
+        }
+    }
+    if let Some(path) = &args.error_report {
+        options.failures.write_report(path).await?;
+        info!("Error report written to {:?}", path);
+    }
+    let incomplete = Incomplete {
+        copied: options.stats.files_done(),
+        failed: options.stats.files_failed.load(std::sync::atomic::Ordering::Relaxed),
+    };
+    log_failures(&options.failures, incomplete.failed, args.error_report.as_deref());
+    options.volatile_files.log();
+    if let Err(error) = copied {
+        if error.downcast_ref::<Interrupted>().is_some() {
+            warn!("Interrupted after {}", options.stats.report(started.elapsed()));
+        }
+        return Err(error.context(incomplete));
+    }
+
+    debug!("Up to {} directories waited in the queue", options.stats.max_queued_directories.load(std::sync::atomic::Ordering::Relaxed));
+    info!("All done: {}", options.stats.report(started.elapsed()));
+    if options.bandwidth.is_some() {
+        info!("Average throughput: {}/s", options.stats.size(options.stats.throughput(started.elapsed())));
+    }
+    if options.file_rate.is_some() {
+        info!("Average rate: {:.1} files/s", options.stats.files_per_second(started.elapsed()));
+    }
+    if incomplete.failed > 0 {
+        return Err(incomplete.into());
+    }
+
+    Ok(())
+}
+
+/// `--verify-after`: read the destination again and compare it with the manifest of the run, or with the source
+/// when its files are where the copy put them (not with a `listed` copy of --files-from)
+async fn verify_after(manifest: Option<&Path>, listed: bool, base_source: &Path, base_dest: &Path, tree_dest: &Path, options: &CopyOptions, concurrency: usize) -> Result<()> {
+    let verification = match manifest {
+        Some(path) => {
+            let root = options.manifest.as_ref().map_or(base_dest, |manifest| manifest.root());
+            info!("Verifying {:?} against the manifest {:?}", root, path);
+            verify::against_manifest(path, root, options.checksum_algorithm).await?
+        },
+        None => {
+            let unmapped = [
+                (options.remove_source, "--delete-source removed the source"),
+                (options.strip_components > 0, "--strip-components"),
+                (options.rename_pattern.is_some(), "--rename-pattern"),
+                (options.sanitize_names, "--sanitize-names"),
+                (options.case_folds.as_ref().is_some_and(|folds| folds.merges()), "--case-fold-merge"),
+                (listed, "--files-from"),
+                (archive::is_tar(base_source) || zip::is_zip(base_source) || archive::is_tar(tree_dest), "archives"),
+                (options.destination.is_some(), "SFTP"),
+                (base_source.is_file(), "a single file"),
+            ].into_iter().find_map(|(set, reason)| set.then_some(reason));
+            if let Some(reason) = unmapped {
+                warn!("--verify-after can only compare the destination with --manifest with {}, it is skipped", reason);
+                return Ok(());
+            }
+            info!("Verifying {:?} against the source {:?}", tree_dest, base_source);
+            verify::against_source(
+                base_source, tree_dest, &options.filters, options.checksum_algorithm, options.checksum_cache.clone(), concurrency,
+            ).await?
+        },
+    };
+    verification.check()
+}
+
+/// Log the first failures of the run, the others are in the error report
+fn log_failures(failures: &Failures, failed: u64, report: Option<&Path>) {
+    if failed == 0 {
+        return;
+    }
+    error!("{} files could not be copied:", failed);
+    let list = failures.list();
+    for failure in list.iter().take(failures::LOGGED_FAILURES) {
+        error!("  {}", failure);
+    }
+    if list.len() > failures::LOGGED_FAILURES {
+        match report {
+            Some(report) => error!("  ... and {} more, see {:?}", list.len() - failures::LOGGED_FAILURES, report),
+            None => error!("  ... and {} more, --error-report writes all of them", list.len() - failures::LOGGED_FAILURES),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::path::Path;
+    use std::time::{Duration, SystemTime};
+    use anyhow::Result;
+    use super::{copy_ordered, copy_tree, destination_root, failures, pipeline, prescan, process_directory, remove_source, rename, sort_files, CaseFolds, Claims, CopyOptions, CopyPermits, DirectoryQueue, Filters, IfExists, OnTypeConflict, Order, PreserveFlags, TimeWindow, Trash};
+    use crate::test_support::{init, MemoryBackend};
+
+    /// Process a single directory and return the subdirectories it found
+    async fn process(source: &Path, dest: &Path, options: &Arc<CopyOptions>) -> Result<Vec<PathBuf>> {
+        process_queued(source, dest, 64, options).await
+    }
+
+    /// Same as `process` with a queue of `capacity` directories
+    async fn process_queued(source: &Path, dest: &Path, capacity: usize, options: &Arc<CopyOptions>) -> Result<Vec<PathBuf>> {
+        let (source, dest, task_options) = (source.to_owned(), dest.to_owned(), options.clone());
+        pipeline::run(options, move |work| async move {
+            let (queue, mut found) = DirectoryQueue::new(&source, &dest, capacity, work);
+            process_directory(&source, &dest, &task_options, &queue).await?;
+            drop(queue);
+            let mut directories = vec![];
+            while let Some(dir) = found.recv().await {
+                directories.push(dir);
+            }
+            Ok(directories)
+        }).await
+    }
+
+    fn delete_source() -> Arc<CopyOptions> {
+        Arc::new(CopyOptions { remove_source: true, ..Default::default() })
+    }
+
+    #[tokio::test]
+    async fn empty_directory() {
+        let base_dir = init("empty_directory").await;
+        
+        let source = base_dir.join("source");
+        let dest = base_dir.join("dest");
+        assert!(!dest.exists());
+        tokio::fs::create_dir_all(&source).await.unwrap();
+        process(&source, &dest, &Arc::default()).await.unwrap();
+
+        assert!(source.exists());
+        assert!(dest.exists());
+    }
+
+    #[tokio::test]
+    async fn short_copies() {
+        use std::future::Future;
+        use std::pin::Pin;
+        use crate::copy::{CopyEngine, Copied, Standard};
+
+        /// Loses the end of every file, like a destination that runs out of space without failing the writes
+        struct Truncating;
+
+        impl CopyEngine for Truncating {
+            fn copy<'a>(&'a self, from: &'a Path, to: &'a Path, options: &'a CopyOptions) -> Pin<Box<dyn Future<Output = Result<Copied>> + Send + 'a>> {
+                Box::pin(async move {
+                    let copied = Standard.copy(from, to, options).await?;
+                    std::fs::File::options().write(true).open(to)?.set_len(copied.bytes / 2)?;
+                    Ok(copied)
+                })
+            }
+        }
+
+        let base_dir = init("short_copies").await;
+
+        let source = base_dir.join("source");
+        let dest = base_dir.join("dest");
+        tokio::fs::write(&source, "some text").await.unwrap();
+        let options = CopyOptions { remove_source: true, ..Default::default() };
+        let copied = Truncating.copy(&source, &dest, &options).await.unwrap();
+        let error = super::copied_file(&source, &dest, None, copied, None, &options).await.unwrap_err();
+        assert_eq!(error.to_string(), format!("The copy of {:?} has 4 bytes (9 copied) instead of the 9 of its source", source));
+        assert!(!dest.exists());
+        assert!(source.exists());
+
+        // The count returned by the copy is checked too
+        tokio::fs::write(&dest, "some text").await.unwrap();
+        let copied = Copied { bytes: 4, digest: None, cloned: false };
+        assert!(super::copied_file(&source, &dest, None, copied, None, &options).await.is_err());
+        assert!(!dest.exists());
+        assert_eq!(options.stats.files_copied.load(std::sync::atomic::Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn unconfirmed_copies() {
+        use crate::copy::Copied;
+
+        let base_dir = init("unconfirmed_copies").await;
+
+        let source = base_dir.join("source");
+        let dest = base_dir.join("dest");
+        tokio::fs::write(&source, "some text").await.unwrap();
+        tokio::fs::write(&dest, "some text").await.unwrap();
+        // The destination loses bytes after its copy was checked, right before the source is deleted
+        #[cfg(unix)]
+        {
+            use crate::hook::{parse_hook_command, Hooks};
+            let hook = parse_hook_command("truncate -s 4 {dest}").unwrap();
+            let options = CopyOptions { remove_source: true, hooks: Some(Arc::new(Hooks::new(hook, 1, false, None))), ..Default::default() };
+            let copied = Copied { bytes: 9, digest: None, cloned: false };
+            let error = super::copied_file(&source, &dest, None, copied, None, &options).await.unwrap_err();
+            assert_eq!(error.to_string(), format!("The copy of {:?} is not confirmed, its source is kept", source));
+            assert!(source.exists());
+            tokio::fs::write(&dest, "some text").await.unwrap();
+        }
+
+        // The directory of the copies cannot be flushed: every source is kept and fails
+        let options = CopyOptions { remove_source: true, fsync: true, ..Default::default() };
+        let copied = Copied { bytes: 9, digest: None, cloned: false };
+        let deferred = super::copied_file(&source, &dest, None, copied, None, &options).await.unwrap();
+        assert_eq!(deferred.as_deref(), Some(source.as_path()));
+        let mut sources = vec![source.clone()];
+        super::remove_synced(&base_dir.join("missing"), &mut sources, &options).await.unwrap();
+        assert!(source.exists());
+        let failures = options.failures.list();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].path.as_deref(), Some(source.as_path()));
+
+        // Confirmed
+        super::remove_synced(&base_dir, &mut vec![source.clone()], &options).await.unwrap();
+        assert!(!source.exists());
+        assert_eq!(tokio::fs::read_to_string(&dest).await.unwrap(), "some text");
+    }
+
+    #[tokio::test]
+    async fn volatile_sources() {
+        use std::sync::atomic::Ordering;
+        use crate::copy::Copied;
+        use crate::volatile::{Changed, Outcome, Snapshot};
+        use super::Volatile;
+
+        let base_dir = init("volatile_sources").await;
+
+        let source = base_dir.join("source");
+        let dest = base_dir.join("dest");
+        for volatile in [Volatile::Skip, Volatile::Fail] {
+            tokio::fs::write(&source, "some text").await.unwrap();
+            let before = Snapshot::of(&tokio::fs::metadata(&source).await.unwrap());
+            tokio::fs::write(&dest, "some text").await.unwrap();
+            // Written again while it was copied
+            let file = std::fs::File::options().write(true).open(&source).unwrap();
+            file.set_modified(SystemTime::now() + Duration::from_secs(5)).unwrap();
+
+            let options = CopyOptions { volatile, remove_source: true, ..Default::default() };
+            let copied = Copied { bytes: 9, digest: None, cloned: false };
+            let result = super::copied_file(&source, &dest, None, copied, Some(before), &options).await;
+            match volatile {
+                Volatile::Skip => {
+                    assert!(result.unwrap().is_none());
+                    assert_eq!(options.stats.files_skipped_volatile.load(Ordering::Relaxed), 1);
+                    assert_eq!(options.volatile_files.list(), [(source.clone(), Outcome::Skipped)]);
+                },
+                _ => {
+                    let error = result.unwrap_err();
+                    assert_eq!(error.downcast_ref::<Changed>(), Some(&Changed(source.clone())));
+                    super::report_file_error(error, &options).unwrap();
+                    assert_eq!(options.volatile_files.list(), [(source.clone(), Outcome::Failed)]);
+                },
+            }
+            assert_eq!(options.stats.files_copied.load(Ordering::Relaxed), 0);
+            // The torn copy is removed and the source kept
+            assert!(!dest.exists());
+            assert!(source.exists());
+        }
+    }
+
+    #[tokio::test]
+    async fn changes_during_copy() {
+        use std::future::Future;
+        use std::io::Write;
+        use std::pin::Pin;
+        use crate::copy::{CopyEngine, Copied, Standard};
+        use crate::volatile::{Changed, Outcome, Snapshot};
+        use super::Volatile;
+
+        /// Copies the file and then appends to its source, like a log written during the copy
+        struct Appending;
+
+        impl CopyEngine for Appending {
+            fn copy<'a>(&'a self, from: &'a Path, to: &'a Path, options: &'a CopyOptions) -> Pin<Box<dyn Future<Output = Result<Copied>> + Send + 'a>> {
+                Box::pin(async move {
+                    let copied = Standard.copy(from, to, options).await?;
+                    std::fs::File::options().append(true).open(from)?.write_all(b" and more")?;
+                    Ok(copied)
+                })
+            }
+        }
+
+        let base_dir = init("changes_during_copy").await;
+
+        let source = base_dir.join("source");
+        let dest = base_dir.join("dest");
+        for volatile in [Volatile::Fail, Volatile::Warn] {
+            tokio::fs::write(&source, "some text").await.unwrap();
+            let options = CopyOptions { volatile, remove_source: true, ..Default::default() };
+            let before = Snapshot::of(&tokio::fs::metadata(&source).await.unwrap());
+            let copied = Appending.copy(&source, &dest, &options).await.unwrap();
+            let result = super::copied_file(&source, &dest, None, copied, Some(before), &options).await;
+            match volatile {
+                Volatile::Warn => {
+                    assert!(result.unwrap().is_none());
+                    assert_eq!(options.volatile_files.list(), [(source.clone(), Outcome::Copied)]);
+                    // The copy is kept, with what the source had when it was read
+                    assert_eq!(tokio::fs::read_to_string(&dest).await.unwrap(), "some text");
+                },
+                _ => {
+                    assert_eq!(result.unwrap_err().downcast_ref::<Changed>(), Some(&Changed(source.clone())));
+                    assert!(!dest.exists());
+                },
+            }
+            // The source is not removed, the rest of it was not copied
+            assert_eq!(tokio::fs::read_to_string(&source).await.unwrap(), "some text and more");
+        }
+
+        let args = crate::config::parse_args(["rs-copier", "--on-change", "warn", "--no-detect-changes"]).unwrap();
+        assert_eq!(args.volatile, Volatile::Warn);
+        assert!(args.no_detect_changes);
+    }
+
+    #[tokio::test]
+    async fn only_files() {
+        let base_dir = init("only_files").await;
+
+        let source = base_dir.join("source");
+        tokio::fs::create_dir_all(&source).await.unwrap();
+        let dest = base_dir.join("dest");
+        tokio::fs::write(source.join("file1"), "text").await.unwrap();
+        tokio::fs::write(source.join("file2"), "text").await.unwrap();
+        assert!(!dest.exists());
+        process(&source, &dest, &Arc::default()).await.unwrap();
+
+        assert!(source.join("file1").exists());
+        assert!(source.join("file2").exists());
+        
+        assert!(dest.exists());
+        assert!(dest.join("file1").exists());
+        assert!(dest.join("file2").exists());
+    }
+
+    #[tokio::test]
+    async fn only_files_delete() {
+        let base_dir = init("only_files_delete").await;
+
+        let source = base_dir.join("source");
+        tokio::fs::create_dir_all(&source).await.unwrap();
+        tokio::fs::write(source.join("file1"), "text").await.unwrap();
+        tokio::fs::write(source.join("file2"), "text").await.unwrap();
+        let dest = base_dir.join("dest");
+        assert!(!dest.exists());
+        let res = process(&source, &dest, &delete_source()).await.unwrap();
+
+        assert!(!source.join("file1").exists());
+        assert!(!source.join("file2").exists());
+        
+        assert!(dest.exists());
+        assert!(dest.join("file1").exists());
+        assert!(dest.join("file2").exists());
+        assert_eq!(res.len(), 0);
+    }
+
+
+    #[tokio::test]
+    async fn nested() {
+        let base_dir = init("nested").await;
+
+        let source = base_dir.join("source");
+        tokio::fs::create_dir_all(&source).await.unwrap();
+        let dest = base_dir.join("dest");
+        tokio::fs::write(source.join("file1"), "text").await.unwrap();
+        tokio::fs::write(source.join("file2"), "text").await.unwrap();
+        let nested = source.join("nested");
+        tokio::fs::create_dir_all(source.join("nested")).await.unwrap();
+        tokio::fs::write(nested.join("file3"), "text").await.unwrap();
+        tokio::fs::write(nested.join("file4"), "text").await.unwrap();
+
+        assert!(!dest.exists());
+        let res = process(&source, &dest, &Arc::default()).await.unwrap();
+
+        assert!(source.join("file1").exists());
+        assert!(source.join("file2").exists());
+        
+        
+        
+        assert!(dest.exists());
+        assert!(dest.join("file1").exists());
+        assert!(dest.join("file2").exists());
+        
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0], base_dir.join("source").join("nested"));
+
+        let nested_dest = base_dir.join("dest").join("nested");
+        
+        let res = process(&res[0], &nested_dest, &Arc::default()).await.unwrap();
+        
+        assert_eq!(res.len(), 0);
+        
+        
+        assert!(nested_dest.exists());
+        assert!(nested_dest.join("file3").exists());
+        assert!(nested_dest.join("file4").exists());
+    }
+
+    /// A tree where the file `conflict` cannot be copied because the destination has a directory with the same name
+    async fn tree_with_failure(name: &str) -> (PathBuf, PathBuf) {
+        let base_dir = init(name).await;
+
+        let source = base_dir.join("source");
+        let dest = base_dir.join("dest");
+        tokio::fs::create_dir_all(source.join("nested")).await.unwrap();
+        tokio::fs::write(source.join("conflict"), "text").await.unwrap();
+        tokio::fs::write(source.join("nested").join("file1"), "text").await.unwrap();
+        tokio::fs::create_dir_all(dest.join("conflict")).await.unwrap();
+        (source, dest)
+    }
+
+    #[tokio::test]
+    async fn move_with_rename() {
+        let base_dir = init("move_with_rename").await;
+
+        let source = base_dir.join("source");
+        let dest = base_dir.join("dest");
+        tokio::fs::create_dir_all(&source).await.unwrap();
+        let content: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+        tokio::fs::write(source.join("file1"), &content).await.unwrap();
+        #[cfg(unix)]
+        let inode = std::os::unix::fs::MetadataExt::ino(&std::fs::metadata(source.join("file1")).unwrap());
+
+        process(&source, &dest, &delete_source()).await.unwrap();
+
+        assert!(!source.join("file1").exists());
+        assert_eq!(tokio::fs::read(dest.join("file1")).await.unwrap(), content);
+        // Same filesystem, so it is the same file
+        #[cfg(unix)]
+        assert_eq!(std::os::unix::fs::MetadataExt::ino(&std::fs::metadata(dest.join("file1")).unwrap()), inode);
+    }
+
+    #[tokio::test]
+    async fn delete_after_sync() {
+        let base_dir = init("delete_after_sync").await;
+
+        let source = base_dir.join("source");
+        let dest = base_dir.join("dest");
+        tokio::fs::create_dir_all(&source).await.unwrap();
+        tokio::fs::write(source.join("file1"), "text").await.unwrap();
+
+        let options = Arc::new(CopyOptions { fsync: true, ..(*delete_source()).clone() });
+        process(&source, &dest, &options).await.unwrap();
+
+        assert!(!source.join("file1").exists());
+        assert_eq!(tokio::fs::read_to_string(dest.join("file1")).await.unwrap(), "text");
+    }
+
+    #[tokio::test]
+    async fn delete_after_sync_batches() {
+        let base_dir = init("delete_after_sync_batches").await;
+
+        let source = base_dir.join("source");
+        let dest = base_dir.join("dest");
+        tokio::fs::create_dir_all(&source).await.unwrap();
+        for i in 0..10 {
+            tokio::fs::write(source.join(format!("file{i}")), format!("text{i}")).await.unwrap();
+        }
+        // This one cannot be copied and its source is kept
+        tokio::fs::create_dir_all(dest.join("file3")).await.unwrap();
+
+        // 9 copies: two full batches and the rest when the directory is complete
+        let options = Arc::new(CopyOptions { fsync: true, fsync_batch: 4, ..(*delete_source()).clone() });
+        process(&source, &dest, &options).await.unwrap();
+
+        for i in (0..10).filter(|i| *i != 3) {
+            assert!(!source.join(format!("file{i}")).exists());
+            assert_eq!(tokio::fs::read_to_string(dest.join(format!("file{i}"))).await.unwrap(), format!("text{i}"));
+        }
+        assert!(source.join("file3").exists());
+    }
+
+    #[tokio::test]
+    async fn small_file_batches() {
+        let base_dir = init("small_file_batches").await;
+
+        let source = base_dir.join("source");
+        let dest = base_dir.join("dest");
+        tokio::fs::create_dir_all(&source).await.unwrap();
+        // Two full batches and a partial one, plus a file copied on its own
+        for i in 0..150 {
+            tokio::fs::write(source.join(format!("file{i}")), format!("text{i}")).await.unwrap();
+        }
+        tokio::fs::write(source.join("big"), vec![b'x'; 2048]).await.unwrap();
+        // This one cannot be copied, the rest of its batch is
+        tokio::fs::create_dir_all(dest.join("file3")).await.unwrap();
+
+        let options = Arc::new(CopyOptions { small_file_threshold: 1024, ..Default::default() });
+        assert!(options.batches_small_files());
+        process(&source, &dest, &options).await.unwrap();
+
+        for i in (0..150).filter(|i| *i != 3) {
+            assert_eq!(tokio::fs::read_to_string(dest.join(format!("file{i}"))).await.unwrap(), format!("text{i}"));
+        }
+        assert_eq!(tokio::fs::read(dest.join("big")).await.unwrap().len(), 2048);
+        assert!(options.stats.summary().starts_with("150 files copied"));
+    }
+
+    #[tokio::test]
+    async fn summary_has_elapsed_time() {
+        let base_dir = init("summary_has_elapsed_time").await;
+
+        let source = base_dir.join("source");
+        tokio::fs::create_dir_all(&source).await.unwrap();
+        tokio::fs::write(source.join("file1"), "text").await.unwrap();
+
+        let started = std::time::Instant::now();
+        let options = Arc::new(CopyOptions::default());
+        copy_tree(&source, &base_dir.join("dest"), options.clone(), 1).await.unwrap();
+        let report = options.stats.report(started.elapsed());
+
+        assert!(report.starts_with("1 files copied"), "{}", report);
+        assert!(report.contains(", elapsed ") && report.ends_with(" MiB/s"), "{}", report);
+    }
+
+    #[tokio::test]
+    async fn small_file_batches_delete() {
+        let base_dir = init("small_file_batches_delete").await;
+
+        let source = base_dir.join("source");
+        let dest = base_dir.join("dest");
+        tokio::fs::create_dir_all(&source).await.unwrap();
+        for i in 0..100 {
+            tokio::fs::write(source.join(format!("file{i}")), format!("text{i}")).await.unwrap();
+        }
+        tokio::fs::create_dir_all(dest.join("file70")).await.unwrap();
+
+        let options = Arc::new(CopyOptions { small_file_threshold: 1024, fsync: true, fsync_batch: 16, ..(*delete_source()).clone() });
+        process(&source, &dest, &options).await.unwrap();
+
+        // Only the sources of the files moved are removed
+        for i in (0..100).filter(|i| *i != 70) {
+            assert!(!source.join(format!("file{i}")).exists());
+            assert_eq!(tokio::fs::read_to_string(dest.join(format!("file{i}"))).await.unwrap(), format!("text{i}"));
+        }
+        assert!(source.join("file70").exists());
+    }
+
+    #[tokio::test]
+    async fn memory_backend() {
+        let memory = Arc::new(MemoryBackend::default());
+        let now = SystemTime::now();
+        memory.add_file("/source/top", "top", now);
+        memory.add_file("/source/a/b/deep", "deep", now);
+        memory.add_file("/source/a/old", "old", now - Duration::from_secs(7200));
+        memory.add_directory("/source/empty");
+
+        let oldest = Some(now - Duration::from_secs(3600));
+        let options = Arc::new(CopyOptions {
+            copy_permits: CopyPermits::new(2),
+            filters: Filters { time_window: TimeWindow { oldest, ..Default::default() } },
+            source: Some(memory.clone()),
+            destination: Some(memory.clone()),
+            ..Default::default()
+        });
+        copy_tree(Path::new("/source"), Path::new("/dest"), options.clone(), 2).await.unwrap();
+
+        assert_eq!(memory.read("/dest/top").as_deref(), Some("top"));
+        assert_eq!(memory.read("/dest/a/b/deep").as_deref(), Some("deep"));
+        assert_eq!(memory.read("/dest/a/old"), None);
+        assert!(memory.is_dir("/dest/empty"));
+        assert_eq!(memory.read("/source/top").as_deref(), Some("top"));
+        assert_eq!(options.stats.summary(), "2 files copied, 0 files cloned, 0 files renamed, 7 bytes");
+
+        // Only the moved sources are removed
+        memory.add_file("/source/a/new", "new", now);
+        let options = Arc::new(CopyOptions { remove_source: true, ..(*options).clone() });
+        let found = process(Path::new("/source/a"), Path::new("/moved"), &options).await.unwrap();
+        assert_eq!(found, [PathBuf::from("/source/a/b")]);
+        assert_eq!(memory.read("/moved/new").as_deref(), Some("new"));
+        assert_eq!(memory.read("/source/a/new"), None);
+        assert_eq!(memory.read("/moved/old"), None);
+        assert_eq!(memory.read("/source/a/old").as_deref(), Some("old"));
+    }
+
+    #[test]
+    fn file_orders() {
+        let files = [("b", 10), ("a", 10), ("c", 1), ("d", 100)].map(|(name, size)| (PathBuf::from(name), size));
+        let sorted = |order| {
+            let mut files = files.clone();
+            sort_files(&mut files, order);
+            files.map(|(path, _)| path.into_os_string().into_string().unwrap()).join("")
+        };
+        assert_eq!(sorted(Order::LargestFirst), "dabc");
+        assert_eq!(sorted(Order::SmallestFirst), "cabd");
+        assert_eq!(sorted(Order::Path), "abcd");
+    }
+
+    #[tokio::test]
+    async fn progress_json() {
+        use std::io::Write;
+        use std::sync::Mutex;
+
+        /// The output of the snapshots, shared with the test
+        #[derive(Clone, Default)]
+        struct Output(Arc<Mutex<Vec<u8>>>);
+
+        impl Write for Output {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let base_dir = init("progress_json").await;
+
+        let source = base_dir.join("source");
+        let dest = base_dir.join("dest");
+        tokio::fs::create_dir_all(source.join("nested")).await.unwrap();
+        for name in ["a", "b", "nested/c"] {
+            tokio::fs::write(source.join(name), "some text").await.unwrap();
+        }
+        let options = Arc::new(CopyOptions::default());
+        let scan = prescan::prescan(options.source_backend(), &source, &options.filters, 0, 2, false, options.links()).await;
+        let output = Output::default();
+        let progress = tokio::spawn(super::report_progress_json(
+            options.stats.clone(), Some(scan.totals), Duration::from_millis(10), output.clone(),
+        ));
+        copy_tree(&source, &dest, options.clone(), 2).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        progress.abort();
+
+        let text = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        let snapshots: Vec<_> = text.lines().collect();
+        assert!(snapshots.len() >= 2, "{}", text);
+        for snapshot in &snapshots {
+            assert!(snapshot.starts_with('{') && snapshot.ends_with('}'), "{}", snapshot);
+            assert!(snapshot.contains(&format!("\"files_total\":{},", scan.totals.files)), "{}", snapshot);
+            assert!(snapshot.contains("\"bytes_total\":27,"), "{}", snapshot);
+        }
+        assert_eq!(scan.totals.files, 3);
+        // Written once the copy is done
+        let last = snapshots.last().unwrap();
+        assert!(last.starts_with("{\"files_done\":3,\"files_total\":3,\"bytes_done\":27,"), "{}", last);
+        assert!(!last.ends_with("\"current_file\":null}"), "{}", last);
+    }
+
+    #[tokio::test]
+    async fn ordered_copy() {
+        let base_dir = init("ordered_copy").await;
+
+        let source = base_dir.join("source");
+        let dest = base_dir.join("dest");
+        tokio::fs::create_dir_all(source.join("a/b")).await.unwrap();
+        tokio::fs::create_dir_all(source.join("empty")).await.unwrap();
+        tokio::fs::write(source.join("big"), vec![1; 4096]).await.unwrap();
+        tokio::fs::write(source.join("a/file"), "text").await.unwrap();
+        tokio::fs::write(source.join("a/b/file"), "nested").await.unwrap();
+
+        let options = Arc::new(CopyOptions { small_file_threshold: 1024, fsync: true, fsync_batch: 2, ..(*delete_source()).clone() });
+        let scan = prescan::prescan(options.source_backend(), &source, &options.filters, 0, 2, true, options.links()).await;
+        copy_ordered(&source, &dest, scan, Order::LargestFirst, options.clone()).await.unwrap();
+
+        assert!(!source.exists());
+        assert_eq!(tokio::fs::read(dest.join("big")).await.unwrap(), vec![1; 4096]);
+        assert_eq!(tokio::fs::read_to_string(dest.join("a/file")).await.unwrap(), "text");
+        assert_eq!(tokio::fs::read_to_string(dest.join("a/b/file")).await.unwrap(), "nested");
+        assert!(dest.join("empty").is_dir());
+        // Same filesystem, the files are moved with a rename
+        assert_eq!(options.stats.summary(), "0 files copied, 0 files cloned, 3 files renamed, 0 bytes");
+    }
+
+    #[tokio::test]
+    async fn files_from() {
+        let base_dir = init("files_from").await;
+
+        let source = base_dir.join("source");
+        let dest = base_dir.join("dest");
+        tokio::fs::create_dir_all(source.join("a/b")).await.unwrap();
+        tokio::fs::create_dir_all(source.join("c")).await.unwrap();
+        for name in ["top", "other", "a/file", "a/b/file", "c/file"] {
+            tokio::fs::write(source.join(name), name).await.unwrap();
+        }
+        let list = base_dir.join("list");
+        tokio::fs::write(&list, "a/b/file\0./top\0missing\0").await.unwrap();
+
+        let options = Arc::new((*delete_source()).clone());
+        let scan = crate::files_from::scan(&source, &list, true, options.links()).await.unwrap();
+        super::copy_listed(&source, &dest, scan, Order::Walk, options.clone()).await.unwrap();
+
+        assert_eq!(tokio::fs::read_to_string(dest.join("top")).await.unwrap(), "top");
+        assert_eq!(tokio::fs::read_to_string(dest.join("a/b/file")).await.unwrap(), "a/b/file");
+        for name in ["other", "a/file", "c"] {
+            assert!(!dest.join(name).exists(), "{}", name);
+        }
+        // Only the listed sources are removed
+        assert!(!source.join("top").exists() && !source.join("a/b/file").exists());
+        assert!(source.join("other").exists() && source.join("a/file").exists() && source.join("c/file").exists());
+        assert_eq!(options.stats.files_done(), 2);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn source_root_link() {
+        use super::{remove_source_link, resolve_source_root};
+
+        let base_dir = init("source_root_link").await;
+
+        let target = base_dir.join("target");
+        let link = base_dir.join("link");
+        tokio::fs::create_dir_all(target.join("nested")).await.unwrap();
+        tokio::fs::write(target.join("nested/file"), "text").await.unwrap();
+        tokio::fs::symlink(&target, &link).await.unwrap();
+
+        // The target is only copied, the link is removed
+        let root = resolve_source_root(link.clone(), true, false).await.unwrap();
+        assert_eq!((root.path.as_path(), root.link.as_deref(), root.remove_files), (link.as_path(), Some(link.as_path()), false));
+        let options = Arc::new(CopyOptions { remove_source: root.remove_files, ..Default::default() });
+        copy_tree(&root.path, &base_dir.join("dest"), options.clone(), 2).await.unwrap();
+        remove_source_link(&link, &options).await.unwrap();
+        assert_eq!(tokio::fs::read_to_string(base_dir.join("dest/nested/file")).await.unwrap(), "text");
+        assert_eq!(tokio::fs::read_to_string(target.join("nested/file")).await.unwrap(), "text");
+        assert!(tokio::fs::symlink_metadata(&link).await.is_err());
+
+        // Dereferenced, the target is moved
+        tokio::fs::symlink(&target, &link).await.unwrap();
+        let root = resolve_source_root(link.clone(), true, true).await.unwrap();
+        assert_eq!((root.path.as_path(), root.remove_files), (target.as_path(), true));
+        let options = Arc::new(CopyOptions { remove_source: root.remove_files, ..Default::default() });
+        copy_tree(&root.path, &base_dir.join("dest2"), options.clone(), 2).await.unwrap();
+        remove_source_link(&link, &options).await.unwrap();
+        assert_eq!(tokio::fs::read_to_string(base_dir.join("dest2/nested/file")).await.unwrap(), "text");
+        assert!(!target.exists());
+        assert!(tokio::fs::symlink_metadata(&link).await.is_err());
+
+        // Not a link
+        let root = resolve_source_root(base_dir.join("dest"), true, false).await.unwrap();
+        assert!(root.link.is_none() && root.remove_files);
+    }
+
+    #[test]
+    fn preserve_bundles() {
+        use super::preserve_flags;
+        use crate::config::parse_args;
+
+        let archive = preserve_flags(&parse_args(["rs-copier", "-a"]).unwrap());
+        assert_eq!(archive, PreserveFlags::archive());
+        assert!(archive.timestamps && archive.mode && archive.ownership && archive.xattrs && archive.links);
+        assert_eq!(preserve_flags(&parse_args(["rs-copier", "--archive", "--preserve", "mode"]).unwrap()), archive);
+
+        let partial = preserve_flags(&parse_args(["rs-copier", "--preserve", "timestamps,links"]).unwrap());
+        assert_eq!(partial, PreserveFlags { timestamps: true, links: true, ..PreserveFlags::NONE });
+
+        // The separate flags add to the default
+        let crtime = preserve_flags(&parse_args(["rs-copier", "--preserve-crtime"]).unwrap());
+        assert_eq!(crtime, PreserveFlags { crtime: true, ..Default::default() });
+        let acls = preserve_flags(&parse_args(["rs-copier", "--preserve-acls"]).unwrap());
+        assert_eq!(acls, PreserveFlags { acls: true, ..Default::default() });
+        assert_eq!(preserve_flags(&parse_args(["rs-copier"]).unwrap()), PreserveFlags::default());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn preserved_links() {
+        let base_dir = init("preserved_links").await;
+
+        let source = base_dir.join("source");
+        let dest = base_dir.join("dest");
+        tokio::fs::create_dir_all(source.join("dir")).await.unwrap();
+        tokio::fs::write(source.join("dir/file"), "text").await.unwrap();
+        tokio::fs::symlink("dir/file", source.join("relative")).await.unwrap();
+        tokio::fs::symlink("missing", source.join("dangling")).await.unwrap();
+        tokio::fs::symlink(source.join("dir"), source.join("directory")).await.unwrap();
+
+        let preserve = PreserveFlags { links: true, ..Default::default() };
+        let options = Arc::new(CopyOptions { preserve, fsync: true, ..(*delete_source()).clone() });
+        copy_tree(&source, &dest, options.clone(), 2).await.unwrap();
+
+        assert_eq!(tokio::fs::read_link(dest.join("relative")).await.unwrap(), Path::new("dir/file"));
+        assert_eq!(tokio::fs::read_to_string(dest.join("relative")).await.unwrap(), "text");
+        assert_eq!(tokio::fs::read_link(dest.join("dangling")).await.unwrap(), Path::new("missing"));
+        // The link to a directory is not walked
+        assert_eq!(tokio::fs::read_link(dest.join("directory")).await.unwrap(), source.join("dir"));
+        assert!(!source.exists());
+        assert_eq!(options.stats.summary(), "3 files copied, 0 files cloned, 1 files renamed, 0 bytes");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn dangling_links() {
+        use super::DanglingLinks;
+
+        let base_dir = init("dangling_links").await;
+
+        let source = base_dir.join("source");
+        tokio::fs::create_dir_all(source.join("nested")).await.unwrap();
+        tokio::fs::write(source.join("file"), "text").await.unwrap();
+        // Where a file and where a directory would be
+        tokio::fs::symlink("missing", source.join("broken")).await.unwrap();
+        tokio::fs::symlink("../gone/", source.join("nested/dir")).await.unwrap();
+
+        for dangling_links in [DanglingLinks::Error, DanglingLinks::Skip, DanglingLinks::Preserve] {
+            let dest = base_dir.join(format!("{:?}", dangling_links));
+            let options = Arc::new(CopyOptions { dangling_links, ..Default::default() });
+            copy_tree(&source, &dest, options.clone(), 2).await.unwrap();
+            let scan = prescan::prescan(options.source_backend(), &source, &options.filters, 0, 2, false, options.links()).await;
+
+            assert_eq!(tokio::fs::read_to_string(dest.join("file")).await.unwrap(), "text");
+            assert!(dest.join("nested").is_dir());
+            match dangling_links {
+                DanglingLinks::Error => {
+                    let mut failures: Vec<_> = options.failures.list().into_iter().map(|failure| failure.message).collect();
+                    failures.sort();
+                    assert_eq!(failures, [
+                        format!("Cannot follow dangling symlink {:?}, its target does not exist: \"missing\"", source.join("broken")),
+                        format!("Cannot follow dangling symlink {:?}, its target does not exist: \"../gone/\"", source.join("nested/dir")),
+                    ]);
+                    assert_eq!(scan.errors.len(), 2);
+                },
+                DanglingLinks::Skip => {
+                    assert!(options.failures.list().is_empty());
+                    assert_eq!(scan.totals.files, 1);
+                },
+                DanglingLinks::Preserve => {
+                    assert!(options.failures.list().is_empty());
+                    assert_eq!(tokio::fs::read_link(dest.join("broken")).await.unwrap(), Path::new("missing"));
+                    assert_eq!(tokio::fs::read_link(dest.join("nested/dir")).await.unwrap(), Path::new("../gone/"));
+                    assert_eq!(scan.totals.files, 3);
+                },
+            }
+            if dangling_links != DanglingLinks::Preserve {
+                assert!(tokio::fs::symlink_metadata(dest.join("broken")).await.is_err());
+                assert!(tokio::fs::symlink_metadata(dest.join("nested/dir")).await.is_err());
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn invalid_utf8_names() {
+        use std::ffi::{OsStr, OsString};
+        use std::os::unix::ffi::OsStrExt;
+
+        let base_dir = init("invalid_utf8_names").await;
+
+        // Even the source itself
+        let source = base_dir.join(OsStr::from_bytes(b"source\xff"));
+        let dest = base_dir.join("dest");
+        let dir = OsStr::from_bytes(b"dir\xc3(");
+        let file = OsStr::from_bytes(b"bad\xfe\x1b[31mname\n%");
+        tokio::fs::create_dir_all(source.join(dir)).await.unwrap();
+        tokio::fs::write(source.join(dir).join(file), "some text").await.unwrap();
+        // A directory in the way of the second
+        tokio::fs::write(source.join(file), "text").await.unwrap();
+        tokio::fs::create_dir_all(dest.join(file)).await.unwrap();
+        let (manifest, report) = (base_dir.join("manifest"), base_dir.join("errors.tsv"));
+        let args: [OsString; 10] = [
+            "rs-copier".into(), "--no-prescan".into(), "--source".into(), source.clone().into(),
+            "--destination".into(), dest.clone().into(), "--manifest".into(), manifest.clone().into(),
+            "--error-report".into(), report.clone().into(),
+        ];
+
+        let error = crate::config::parse_args(args).map(|args| super::run(args, 2)).unwrap().await.unwrap_err();
+        assert_eq!(error.to_string(), "copied 1 files, 1 failed");
+        assert_eq!(tokio::fs::read_to_string(dest.join(dir).join(file)).await.unwrap(), "some text");
+        // A line per entry, with the bytes that are not printable escaped
+        let manifest_text = tokio::fs::read_to_string(&manifest).await.unwrap();
+        assert!(manifest_text.contains("  dir%C3(/bad%FE%1B[31mname%0A%25  9\n"), "{}", manifest_text);
+        let manifest = crate::manifest::read(&manifest).await.unwrap();
+        assert_eq!(manifest.entries.keys().collect::<Vec<_>>(), [&Path::new(dir).join(file)]);
+        let report = tokio::fs::read_to_string(&report).await.unwrap();
+        assert!(report.starts_with(&format!("{}/bad%FE%1B[31mname%0A%25\t", crate::paths::escape(&source))), "{}", report);
+        assert_eq!(report.lines().count(), 1);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn relativized_links() {
+        let base_dir = init("relativized_links").await;
+
+        let source = base_dir.join("source");
+        let dest = base_dir.join("dest");
+        tokio::fs::create_dir_all(source.join("dir")).await.unwrap();
+        tokio::fs::create_dir_all(source.join("links")).await.unwrap();
+        tokio::fs::write(source.join("dir/file"), "text").await.unwrap();
+        tokio::fs::write(base_dir.join("outside"), "outside").await.unwrap();
+        tokio::fs::symlink(source.join("dir/file"), source.join("links/internal")).await.unwrap();
+        tokio::fs::symlink(base_dir.join("outside"), source.join("links/external")).await.unwrap();
+
+        let options = CopyOptions {
+            preserve: PreserveFlags { links: true, ..Default::default() },
+            relativize_links: Some(Arc::new(super::SourceTree::new(&source))),
+            ..Default::default()
+        };
+        copy_tree(&source, &dest, Arc::new(options), 2).await.unwrap();
+        // The source can go away, the link points inside the destination
+        tokio::fs::remove_dir_all(&source).await.unwrap();
+
+        assert_eq!(tokio::fs::read_link(dest.join("links/internal")).await.unwrap(), Path::new("../dir/file"));
+        assert_eq!(tokio::fs::read_to_string(dest.join("links/internal")).await.unwrap(), "text");
+        assert_eq!(tokio::fs::read_link(dest.join("links/external")).await.unwrap(), base_dir.join("outside"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn top_level_links() {
+        let base_dir = init("top_level_links").await;
+
+        let source = base_dir.join("source");
+        let dest = base_dir.join("dest");
+        let elsewhere = base_dir.join("elsewhere");
+        tokio::fs::create_dir_all(elsewhere.join("nested")).await.unwrap();
+        tokio::fs::write(elsewhere.join("file"), "text").await.unwrap();
+        tokio::fs::symlink("../file", elsewhere.join("nested/link")).await.unwrap();
+        tokio::fs::create_dir_all(source.join("dir")).await.unwrap();
+        tokio::fs::symlink(&elsewhere, source.join("project")).await.unwrap();
+        tokio::fs::symlink(elsewhere.join("file"), source.join("alias")).await.unwrap();
+        tokio::fs::symlink(&elsewhere, source.join("dir/project")).await.unwrap();
+
+        let options = Arc::new(CopyOptions {
+            preserve: PreserveFlags { links: true, ..Default::default() },
+            follow_top_level_links: true,
+            ..Default::default()
+        });
+        // The pre-scan goes through the same links
+        let mut scan = prescan::prescan(options.source_backend(), &source, &options.filters, 0, 2, true, options.links()).await;
+        scan.links.sort();
+        assert_eq!(scan.links, [source.join("dir/project"), source.join("project/nested/link")]);
+        assert_eq!(scan.totals.files, 4);
+        copy_tree(&source, &dest, options, 2).await.unwrap();
+
+        // The links at the top are copied as what they point to
+        assert!(tokio::fs::symlink_metadata(dest.join("project")).await.unwrap().is_dir());
+        assert!(tokio::fs::symlink_metadata(dest.join("alias")).await.unwrap().is_file());
+        assert_eq!(tokio::fs::read_to_string(dest.join("alias")).await.unwrap(), "text");
+        assert_eq!(tokio::fs::read_to_string(dest.join("project/file")).await.unwrap(), "text");
+        // The deeper ones are kept as links
+        assert_eq!(tokio::fs::read_link(dest.join("project/nested/link")).await.unwrap(), Path::new("../file"));
+        assert_eq!(tokio::fs::read_link(dest.join("dir/project")).await.unwrap(), elsewhere);
+    }
+
+    #[tokio::test]
+    async fn delete_into_trash() {
+        let base_dir = init("delete_into_trash").await;
+
+        let source = base_dir.join("source");
+        let trash_dir = base_dir.join("trash");
+        tokio::fs::create_dir_all(source.join("nested")).await.unwrap();
+        tokio::fs::write(source.join("nested/file1"), "one").await.unwrap();
+        tokio::fs::write(source.join("nested/file2"), "two").await.unwrap();
+
+        let trash = Trash::create(&source, &trash_dir).await.unwrap();
+        let options = CopyOptions { trash: Some(Arc::new(trash)), ..(*delete_source()).clone() };
+        // The copied files and then the empty directories
+        remove_source(&source.join("nested/file1"), &options).await.unwrap();
+        remove_source(&source.join("nested/file2"), &options).await.unwrap();
+        super::clean_source_tree(&source, &options).await.unwrap();
+
+        assert!(!source.exists());
+        assert_eq!(tokio::fs::read_to_string(trash_dir.join("nested/file1")).await.unwrap(), "one");
+        assert_eq!(tokio::fs::read_to_string(trash_dir.join("nested/file2")).await.unwrap(), "two");
+    }
+
+    #[tokio::test]
+    async fn many_files_delete() {
+        let base_dir = init("many_files_delete").await;
+
+        let source = base_dir.join("source");
+        let dest = base_dir.join("dest");
+        tokio::fs::create_dir_all(&source).await.unwrap();
+        for i in 0..50 {
+            tokio::fs::write(source.join(format!("file{i}")), format!("text{i}")).await.unwrap();
+        }
+        // This one cannot be copied
+        tokio::fs::create_dir_all(dest.join("file7")).await.unwrap();
+
+        let options = Arc::new(CopyOptions { copy_permits: CopyPermits::new(4), ..(*delete_source()).clone() });
+        process(&source, &dest, &options).await.unwrap();
+
+        for i in (0..50).filter(|i| *i != 7) {
+            assert!(!source.join(format!("file{i}")).exists());
+            assert_eq!(tokio::fs::read_to_string(dest.join(format!("file{i}"))).await.unwrap(), format!("text{i}"));
+        }
+        assert!(source.join("file7").exists());
+    }
+
+    #[tokio::test]
+    async fn deep_tree() {
+        let base_dir = init("deep_tree").await;
+
+        let source = base_dir.join("source");
+        let dest = base_dir.join("dest");
+        let mut level = PathBuf::new();
+        for depth in 0..6 {
+            level = level.join(format!("level{depth}"));
+            tokio::fs::create_dir_all(source.join(&level)).await.unwrap();
+            tokio::fs::write(source.join(&level).join("file"), "text").await.unwrap();
+        }
+
+        copy_tree(&source, &dest, delete_source(), 2).await.unwrap();
+
+        let mut level = PathBuf::new();
+        for depth in 0..6 {
+            level = level.join(format!("level{depth}"));
+            assert!(dest.join(&level).join("file").exists());
+        }
+        assert!(!source.exists());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn chmod_files_and_directories() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let base_dir = init("chmod_files_and_directories").await;
+
+        let source = base_dir.join("source");
+        let dest = base_dir.join("dest");
+        tokio::fs::create_dir_all(source.join("dir")).await.unwrap();
+        tokio::fs::write(source.join("dir/file"), "text").await.unwrap();
+
+        let options = CopyOptions { chmod: Some(0o640), chmod_dirs: Some(0o750), ..Default::default() };
+        copy_tree(&source, &dest, Arc::new(options), 2).await.unwrap();
+
+        let mode = |path: PathBuf| std::fs::metadata(path).unwrap().permissions().mode() & 0o7777;
+        assert_eq!(mode(dest.join("dir")), 0o750);
+        assert_eq!(mode(dest.join("dir/file")), 0o640);
+    }
+
+    #[tokio::test]
+    async fn skip_recently_modified() {
+        let base_dir = init("skip_recently_modified").await;
+
+        let source = base_dir.join("source");
+        let dest = base_dir.join("dest");
+        tokio::fs::create_dir_all(&source).await.unwrap();
+        // Still being written
+        tokio::fs::write(source.join("recent"), "text").await.unwrap();
+        let old = std::fs::File::create(source.join("old")).unwrap();
+        old.set_modified(SystemTime::now() - Duration::from_secs(3600)).unwrap();
+
+        let newest = Some(SystemTime::now() - Duration::from_secs(60));
+        let filters = Filters { time_window: TimeWindow { newest, ..Default::default() } };
+        let options = Arc::new(CopyOptions { filters, ..Default::default() });
+        process(&source, &dest, &options).await.unwrap();
+
+        assert!(dest.join("old").exists());
+        assert!(!dest.join("recent").exists());
+    }
+
+    #[tokio::test]
+    async fn empty_directories() {
+        let base_dir = init("empty_directories").await;
+
+        let source = base_dir.join("source");
+        let chain = Path::new("a/b/c/d/e/f/g/h");
+        tokio::fs::create_dir_all(source.join(chain)).await.unwrap();
+        tokio::fs::create_dir_all(source.join("kept/deeper")).await.unwrap();
+        tokio::fs::write(source.join("kept/deeper/file"), "text").await.unwrap();
+        tokio::fs::create_dir_all(source.join("filtered")).await.unwrap();
+        let old = std::fs::File::create(source.join("filtered/old")).unwrap();
+        old.set_modified(SystemTime::now() - Duration::from_secs(3600)).unwrap();
+        let oldest = Some(SystemTime::now() - Duration::from_secs(60));
+        let filters = Filters { time_window: TimeWindow { oldest, ..Default::default() } };
+
+        // By default the whole chain and the directory without selected files are there
+        let dest = base_dir.join("dest");
+        let options = Arc::new(CopyOptions { filters: filters.clone(), ..Default::default() });
+        copy_tree(&source, &dest, options, 2).await.unwrap();
+        assert!(dest.join(chain).is_dir());
+        assert!(dest.join("filtered").is_dir() && !dest.join("filtered/old").exists());
+        assert!(dest.join("kept/deeper/file").exists());
+
+        let pruned = Arc::new(CopyOptions { filters, prune_empty_dirs: true, ..Default::default() });
+        let dest = base_dir.join("pruned");
+        copy_tree(&source, &dest, pruned.clone(), 2).await.unwrap();
+        let ordered = base_dir.join("ordered");
+        let scan = prescan::prescan(pruned.source_backend(), &source, &pruned.filters, 0, 2, true, pruned.links()).await;
+        copy_ordered(&source, &ordered, scan, Order::Path, pruned).await.unwrap();
+        for dest in [dest, ordered] {
+            assert!(dest.join("kept/deeper/file").exists());
+            assert!(!dest.join("a").exists() && !dest.join("filtered").exists());
+        }
+
+        // Also the top of an empty source
+        let empty = base_dir.join("empty");
+        tokio::fs::create_dir_all(&empty).await.unwrap();
+        let options = Arc::new(CopyOptions { prune_empty_dirs: true, ..Default::default() });
+        copy_tree(&empty, &base_dir.join("empty copy"), options, 2).await.unwrap();
+        assert!(base_dir.join("empty copy").is_dir());
+    }
+
+    #[tokio::test]
+    async fn full_directory_queue() {
+        let base_dir = init("full_directory_queue").await;
+
+        let source = base_dir.join("source");
+        let dest = base_dir.join("dest");
+        for i in 0..10 {
+            tokio::fs::create_dir_all(source.join(format!("dir{i}"))).await.unwrap();
+            tokio::fs::write(source.join(format!("dir{i}/file")), "text").await.unwrap();
+        }
+
+        // Only 2 directories fit in the queue, the rest are processed right away
+        let queued: Vec<_> = process_queued(&source, &dest, 2, &Arc::default()).await.unwrap()
+            .into_iter().map(|dir| dir.file_name().unwrap().to_owned()).collect();
+        assert_eq!(queued.len(), 2);
+        for i in 0..10 {
+            let name = format!("dir{i}");
+            let copied = dest.join(&name).join("file").exists();
+            assert_eq!(copied, !queued.iter().any(|queued| *queued == *name));
+        }
+    }
+
+    #[tokio::test]
+    async fn wide_tree() {
+        let base_dir = init("wide_tree").await;
+
+        let source = base_dir.join("source");
+        let dest = base_dir.join("dest");
+        // More directories than the queue of a single task
+        for i in 0..150 {
+            tokio::fs::create_dir_all(source.join(format!("dir{i}/nested"))).await.unwrap();
+            tokio::fs::write(source.join(format!("dir{i}/nested/file")), format!("text{i}")).await.unwrap();
+        }
+
+        copy_tree(&source, &dest, Arc::default(), 1).await.unwrap();
+
+        for i in 0..150 {
+            let copied = tokio::fs::read_to_string(dest.join(format!("dir{i}/nested/file"))).await.unwrap();
+            assert_eq!(copied, format!("text{i}"));
+        }
+    }
+
+    #[tokio::test]
+    async fn bounded_pending_directories() {
+        // A wide and deep tree in memory
+        let memory = Arc::new(MemoryBackend::default());
+        for i in 0..300 {
+            memory.add_file(&format!("/source/dir{i}/a/b/c/file"), "text", SystemTime::now());
+            memory.add_file(&format!("/source/dir{i}/d/file"), "text", SystemTime::now());
+        }
+
+        let options = Arc::new(CopyOptions {
+            max_pending: Some(8),
+            source: Some(memory.clone()),
+            destination: Some(memory.clone()),
+            ..Default::default()
+        });
+        copy_tree(Path::new("/source"), Path::new("/dest"), options.clone(), 2).await.unwrap();
+
+        for i in 0..300 {
+            assert!(memory.read(&format!("/dest/dir{i}/a/b/c/file")).is_some());
+            assert!(memory.read(&format!("/dest/dir{i}/d/file")).is_some());
+        }
+        let max_queued = options.stats.max_queued_directories.load(std::sync::atomic::Ordering::Relaxed);
+        assert!((1..=8).contains(&max_queued), "{max_queued} directories queued");
+    }
+
+    #[tokio::test]
+    async fn strip_components() {
+        let base_dir = init("strip_components").await;
+
+        let source = base_dir.join("source");
+        let dest = base_dir.join("dest");
+        tokio::fs::create_dir_all(source.join("a").join("b").join("d")).await.unwrap();
+        tokio::fs::write(source.join("top.txt"), "top").await.unwrap();
+        tokio::fs::write(source.join("a").join("b").join("c.txt"), "c").await.unwrap();
+        tokio::fs::write(source.join("a").join("b").join("d").join("e.txt"), "e").await.unwrap();
+
+        let options = CopyOptions { strip_components: 2, claims: Some(Default::default()), ..Default::default() };
+        copy_tree(&source, &dest, Arc::new(options), 2).await.unwrap();
+
+        assert_eq!(tokio::fs::read_to_string(dest.join("c.txt")).await.unwrap(), "c");
+        assert_eq!(tokio::fs::read_to_string(dest.join("d").join("e.txt")).await.unwrap(), "e");
+        assert!(!dest.join("top.txt").exists());
+        assert!(!dest.join("a").exists());
+
+        // top.txt is not deep enough
+        let options = CopyOptions { strip_components: 2, stop_on_error: true, ..Default::default() };
+        assert!(copy_tree(&source, &base_dir.join("dest2"), Arc::new(options), 2).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn strip_components_collision() {
+        let base_dir = init("strip_components_collision").await;
+
+        let source = base_dir.join("source");
+        tokio::fs::create_dir_all(source.join("a")).await.unwrap();
+        tokio::fs::create_dir_all(source.join("b")).await.unwrap();
+        tokio::fs::write(source.join("a").join("c.txt"), "a").await.unwrap();
+        tokio::fs::write(source.join("b").join("c.txt"), "b").await.unwrap();
+
+        let options = |dest: &str, if_exists| {
+            let options = CopyOptions {
+                strip_components: 1,
+                if_exists,
+                stop_on_error: if_exists == IfExists::Error,
+                claims: Some(Arc::new(Claims::default())),
+                ..Default::default()
+            };
+            (base_dir.join(dest), Arc::new(options))
+        };
+
+        for if_exists in [IfExists::Overwrite, IfExists::Skip] {
+            let (dest, options) = options("dest", if_exists);
+            copy_tree(&source, &dest, options, 2).await.unwrap();
+            let content = tokio::fs::read_to_string(dest.join("c.txt")).await.unwrap();
+            assert!(content == "a" || content == "b");
+            tokio::fs::remove_dir_all(&dest).await.unwrap();
+        }
+
+        let (dest, options) = options("dest", IfExists::Error);
+        assert!(copy_tree(&source, &dest, options, 2).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rename_pattern() {
+        let base_dir = init("rename_pattern").await;
+
+        let source = base_dir.join("source");
+        tokio::fs::create_dir_all(source.join("my dir")).await.unwrap();
+        tokio::fs::write(source.join("one file.txt"), "one").await.unwrap();
+        tokio::fs::write(source.join("my dir").join("two.txt"), "two").await.unwrap();
+
+        let options = |pattern: &str, if_exists| CopyOptions {
+            rename_pattern: Some(rename::parse_rename_pattern(pattern).unwrap()),
+            if_exists,
+            stop_on_error: if_exists == IfExists::Error,
+            claims: Some(Default::default()),
+            ..Default::default()
+        };
+
+        // Only the files are renamed, not the directories
+        let dest = base_dir.join("prefixed");
+        copy_tree(&source, &dest, Arc::new(options("new_{name}", IfExists::Overwrite)), 2).await.unwrap();
+        assert_eq!(tokio::fs::read_to_string(dest.join("new_one file.txt")).await.unwrap(), "one");
+        assert_eq!(tokio::fs::read_to_string(dest.join("my dir").join("new_two.txt")).await.unwrap(), "two");
+        assert!(!dest.join("one file.txt").exists());
+
+        let dest = base_dir.join("underscores");
+        copy_tree(&source, &dest, Arc::new(options("s/ /_/", IfExists::Overwrite)), 2).await.unwrap();
+        assert_eq!(tokio::fs::read_to_string(dest.join("one_file.txt")).await.unwrap(), "one");
+        assert!(dest.join("my dir").join("two.txt").exists());
+
+        // Both sources get the same name
+        tokio::fs::write(source.join("one_file.txt"), "other").await.unwrap();
+        let dest = base_dir.join("collision");
+        copy_tree(&source, &dest, Arc::new(options("s/ /_/", IfExists::Skip)), 2).await.unwrap();
+        let content = tokio::fs::read_to_string(dest.join("one_file.txt")).await.unwrap();
+        assert!(content == "one" || content == "other");
+        let dest = base_dir.join("collision_error");
+        assert!(copy_tree(&source, &dest, Arc::new(options("s/ /_/", IfExists::Error)), 2).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn renamed_root() {
+        let base_dir = init("renamed_root").await;
+
+        let source = base_dir.join("projectA");
+        let dest = base_dir.join("backups");
+        tokio::fs::create_dir_all(source.join("nested").join("deeper")).await.unwrap();
+        tokio::fs::write(source.join("file1"), "text").await.unwrap();
+        tokio::fs::write(source.join("nested").join("deeper").join("file2"), "text").await.unwrap();
+        tokio::fs::create_dir_all(&dest).await.unwrap();
+        tokio::fs::write(dest.join("other"), "text").await.unwrap();
+
+        let root = destination_root(&dest, Some(Path::new("projectA-2024"))).unwrap();
+        copy_tree(&source, &root, Arc::default(), 2).await.unwrap();
+
+        assert!(dest.join("other").exists());
+        assert!(!dest.join("file1").exists());
+        assert!(dest.join("projectA-2024").join("file1").exists());
+        assert!(dest.join("projectA-2024").join("nested").join("deeper").join("file2").exists());
+
+        assert_eq!(destination_root(&dest, None).unwrap(), dest);
+        assert!(destination_root(&dest, Some(Path::new("../elsewhere"))).is_err());
+        assert!(destination_root(&dest, Some(Path::new("a/b"))).is_err());
+    }
+
+    #[tokio::test]
+    async fn unreadable_entry() {
+        let (source, dest) = tree_with_failure("unreadable_entry").await;
+        tokio::fs::write(source.join("file2"), "text").await.unwrap();
+        tokio::fs::write(source.join("file3"), "text").await.unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            // Neither a file nor a directory
+            let fifo = std::ffi::CString::new(source.join("fifo").to_str().unwrap()).unwrap();
+            assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o644) }, 0);
+            // Only unreadable when the test does not run as root
+            tokio::fs::write(source.join("locked"), "text").await.unwrap();
+            tokio::fs::set_permissions(source.join("locked"), std::fs::Permissions::from_mode(0o000)).await.unwrap();
+        }
+
+        copy_tree(&source, &dest, Arc::default(), 2).await.unwrap();
+
+        assert!(dest.join("file2").exists());
+        assert!(dest.join("file3").exists());
+        assert!(dest.join("nested").join("file1").exists());
+    }
+
+    #[tokio::test]
+    async fn destination_directory_cannot_be_created() {
+        let base_dir = init("destination_directory_cannot_be_created").await;
+
+        let source = base_dir.join("source");
+        tokio::fs::create_dir_all(source.join("nested")).await.unwrap();
+        tokio::fs::write(source.join("file1"), "text").await.unwrap();
+        // The destination is a file
+        let dest = base_dir.join("dest");
+        tokio::fs::write(&dest, "text").await.unwrap();
+
+        let res = process(&source, &dest, &Arc::default()).await.unwrap();
+        assert!(res.is_empty());
+
+        let options = Arc::new(CopyOptions { stop_on_error: true, ..Default::default() });
+        assert!(process(&source, &dest, &options).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn stop_on_error() {
+        let (source, dest) = tree_with_failure("stop_on_error").await;
+
+        // A single worker copies the files in the order of the walk
+        let options = CopyOptions { stop_on_error: true, copy_permits: CopyPermits::new(1), ..Default::default() };
+        let res = copy_tree(&source, &dest, Arc::new(options), 1).await;
+
+        assert!(res.is_err());
+        assert!(!dest.join("nested").join("file1").exists());
+    }
+
+    #[tokio::test]
+    async fn ignore_errors() {
+        let (source, dest) = tree_with_failure("ignore_errors").await;
+
+        copy_tree(&source, &dest, Arc::new(CopyOptions::default()), 1).await.unwrap();
+
+        assert!(dest.join("conflict").is_dir());
+        assert!(dest.join("nested").join("file1").exists());
+    }
+
+    #[tokio::test]
+    async fn type_conflicts() {
+        let base_dir = init("type_conflicts").await;
+
+        let source = base_dir.join("source");
+        tokio::fs::create_dir_all(source.join("was_file")).await.unwrap();
+        tokio::fs::write(source.join("was_file").join("inner"), "inner").await.unwrap();
+        tokio::fs::write(source.join("was_dir"), "file").await.unwrap();
+        tokio::fs::write(source.join("plain"), "plain").await.unwrap();
+        let conflicting = |name: &str| {
+            let dest = base_dir.join(name);
+            async move {
+                // A file where the source has a directory and a directory where it has a file
+                tokio::fs::create_dir_all(dest.join("was_dir").join("nested")).await.unwrap();
+                tokio::fs::write(dest.join("was_dir").join("nested").join("old"), "old").await.unwrap();
+                tokio::fs::write(dest.join("was_file"), "old").await.unwrap();
+                dest
+            }
+        };
+        let options = |on_type_conflict| Arc::new(CopyOptions { on_type_conflict, ..Default::default() });
+
+        let dest = conflicting("error").await;
+        copy_tree(&source, &dest, options(OnTypeConflict::Error), 2).await.unwrap();
+        assert!(dest.join("was_dir").join("nested").join("old").exists());
+        assert_eq!(tokio::fs::read_to_string(dest.join("was_file")).await.unwrap(), "old");
+        assert_eq!(tokio::fs::read_to_string(dest.join("plain")).await.unwrap(), "plain");
+        let stop = Arc::new(CopyOptions { stop_on_error: true, ..(*options(OnTypeConflict::Error)).clone() });
+        let error = copy_tree(&source, &dest, stop, 2).await.unwrap_err();
+        assert!(error.to_string().contains("--on-type-conflict"), "{}", error);
+
+        let dest = conflicting("skip").await;
+        let skip = options(OnTypeConflict::Skip);
+        copy_tree(&source, &dest, skip.clone(), 2).await.unwrap();
+        assert!(dest.join("was_dir").join("nested").join("old").exists());
+        assert_eq!(tokio::fs::read_to_string(dest.join("was_file")).await.unwrap(), "old");
+        assert_eq!(skip.stats.files_skipped.load(std::sync::atomic::Ordering::Relaxed), 1);
 
-/// Copy all the files of the directory from source to dest. Remove the source files if remove_source = true
-/// Then list all the directories and return them.
-async fn process_directory(source: &Path, dest: &Path, remove_source: bool) -> Result<Vec<PathBuf>> {
-    info!("Processing dir: {:?}", source);
-    let mut paths = tokio::fs::read_dir(&source).await?;
-    tokio::fs::create_dir_all(&dest).await?;
-    let mut directories = vec![];
-    while let Some(path) = paths.next_entry().await? {
-        match path.file_type().await {
-            Ok(file_type) => {
-                if file_type.is_file() {
-                    // Move file
-                    let from = path.path();
-                    let to = dest.join(path.file_name());
-                    debug!("Copy: {:?} to {:?}", from, to);
-                    if let Err(error) = tokio::fs::copy(&from, &to).await {
-                        error!("Cannot copy file: {:?}: {:?}", from, error);
-                    } else {
-                        if remove_source {
-                            if let Err(error) = tokio::fs::remove_file(&from).await {
-                                error!("Cannot remove file: {:?}: {:?}", from, error);
-                            }
-                        }
-                    }
-                } else {
-                    directories.push(path.path());
-                }
-            } ,
-            Err(error) => { 
-                error!("Cannot get file type: {:?}", error);
+        let dest = conflicting("replace").await;
+        copy_tree(&source, &dest, options(OnTypeConflict::Replace), 2).await.unwrap();
+        assert_eq!(tokio::fs::read_to_string(dest.join("was_dir")).await.unwrap(), "file");
+        assert_eq!(tokio::fs::read_to_string(dest.join("was_file").join("inner")).await.unwrap(), "inner");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn destination_link_to_a_directory() {
+        let base_dir = init("destination_link_to_a_directory").await;
+        let source = base_dir.join("source");
+        tokio::fs::create_dir_all(source.join("data")).await.unwrap();
+        tokio::fs::write(source.join("data").join("file"), "text").await.unwrap();
+        // The destination of `data` is a link to a directory elsewhere, it is not a conflict
+        let dest = base_dir.join("dest");
+        tokio::fs::create_dir_all(base_dir.join("big")).await.unwrap();
+        tokio::fs::create_dir_all(&dest).await.unwrap();
+        tokio::fs::symlink(base_dir.join("big"), dest.join("data")).await.unwrap();
+
+        let options = Arc::new(CopyOptions { stop_on_error: true, ..Default::default() });
+        copy_tree(&source, &dest, options, 2).await.unwrap();
+        assert_eq!(tokio::fs::read_to_string(base_dir.join("big").join("file")).await.unwrap(), "text");
+    }
+
+    #[tokio::test]
+    async fn bench_sweep() {
+        let base_dir = init("bench_sweep").await;
+
+        let dest = base_dir.join("dest");
+        tokio::fs::create_dir_all(&dest).await.unwrap();
+        let args = crate::config::parse_args([
+            "rs-copier", "--no-prescan", "bench", "--destination", dest.to_str().unwrap(), "--files", "30",
+            "--files-per-dir", "8", "--max-size", "64K", "--concurrency-levels", "1,3",
+        ]).unwrap();
+        let Some(super::Command::Bench(bench)) = args.command.clone() else { panic!("Not a bench: {:?}", args.command) };
+        let (tree, measures) = super::bench_sweep(&args, &bench, &base_dir).await.unwrap();
+
+        assert_eq!(tree.files, 30);
+        assert_eq!(measures.iter().map(|measure| measure.concurrency).collect::<Vec<_>>(), [1, 3]);
+        assert!(measures.iter().all(|measure| measure.buffer_size == args.buffer_size && !measure.elapsed.is_zero()));
+        // Both trees are gone
+        assert_eq!(std::fs::read_dir(&dest).unwrap().count(), 0);
+        assert_eq!(std::fs::read_dir(&base_dir).unwrap().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn stats_file() {
+        let (source, dest) = tree_with_failure("stats_file").await;
+        let options = Arc::new(CopyOptions::default());
+        copy_tree(&source, &dest, options.clone(), 1).await.unwrap();
+
+        let path = dest.parent().unwrap().join("stats.json");
+        options.stats.write_file(&path, Duration::from_secs(1), None).await.unwrap();
+        let json = tokio::fs::read_to_string(&path).await.unwrap();
+        // One `"name": value` per line
+        let fields: std::collections::HashMap<&str, &str> = json.lines()
+            .filter_map(|line| line.trim().trim_end_matches(',').split_once(": "))
+            .map(|(name, value)| (name.trim_matches('"'), value))
+            .collect();
+        assert_eq!(fields["files_copied"], "1");
+        assert_eq!(fields["files_failed"], "1");
+        assert_eq!(fields["bytes_copied"], "4");
+        assert_eq!(fields["elapsed_seconds"], "1.000");
+        assert_eq!(fields["completed"], "true");
+        assert!(!path.with_file_name("stats.json.part").exists());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn sanitized_names() {
+        let base_dir = init("sanitized_names").await;
+
+        let source = base_dir.join("source");
+        tokio::fs::create_dir_all(source.join("dir. /nested")).await.unwrap();
+        for name in ["a:b?", "nul.txt", "dir. /nested/file.", "dir. /nested/fine"] {
+            tokio::fs::write(source.join(name), name).await.unwrap();
+        }
+        for order in ["walk", "path"] {
+            let dest = base_dir.join(order);
+            crate::config::parse_args([
+                "rs-copier", "--source", source.to_str().unwrap(), "--destination", dest.to_str().unwrap(),
+                "--sanitize-names", "--order", order,
+            ]).map(|args| super::run(args, 2)).unwrap().await.unwrap();
+
+            for (name, renamed) in [("a:b?", "a_b_"), ("nul.txt", "nul_.txt"), ("dir. /nested/file.", "dir/nested/file"), ("dir. /nested/fine", "dir/nested/fine")] {
+                assert_eq!(tokio::fs::read_to_string(dest.join(renamed)).await.unwrap(), name);
             }
-        }  
+            assert!(!dest.join("dir. ").exists());
+        }
+
+        // Kept as they are without the option (only Windows fails them)
+        let dest = base_dir.join("kept");
+        copy_tree(&source, &dest, Arc::new(CopyOptions::default()), 2).await.unwrap();
+        assert_eq!(tokio::fs::read_to_string(dest.join("dir. /nested/file.")).await.unwrap(), "dir. /nested/file.");
     }
-    Ok(directories)
-}
 
-/// Logger configuration
-fn setup_logger(loglevel: &str, logfile: Option<&str>) -> Result<()>{   
-    let level = match loglevel {
-        "INFO" => log::LevelFilter::Info,
-        "DEBUG" => log::LevelFilter::Debug,
-        "WARN" => log::LevelFilter::Warn,
-        "ERROR" => log::LevelFilter::Error,
-        &_ => log::LevelFilter::Info
-    };
-    let mut f = fern::Dispatch::new()
-    // Perform allocation-free log formatting
-    .format(|out, message, record| {
-        out.finish(format_args!(
-            "{}[{}][{}] {}",
-            chrono::Local::now().format("[%Y-%m-%d][%H:%M:%S]"),
-            record.target(),
-            record.level(),
-            message
-        ))
-    })
-    // Add blanket level filter -
-    .level(level)
-    .chain(std::io::stdout());
+    #[tokio::test]
+    async fn case_variants() {
+        let base_dir = init("case_variants").await;
+        crate::test_support::captured_logs();
 
-    if let Some(logfile) = logfile {
-        f = f.chain(fern::log_file(logfile)?);
+        let source = base_dir.join("source");
+        tokio::fs::create_dir_all(source.join("Docs/Sub")).await.unwrap();
+        tokio::fs::create_dir_all(source.join("docs/sub")).await.unwrap();
+        for name in ["Docs/a", "Docs/Sub/c", "docs/b", "docs/sub/d"] {
+            tokio::fs::write(source.join(name), name).await.unwrap();
+        }
+        for order in ["walk", "path"] {
+            let dest = base_dir.join(order);
+            crate::config::parse_args([
+                "rs-copier", "--source", source.to_str().unwrap(), "--destination", dest.to_str().unwrap(),
+                "--case-fold-merge", "lower", "--order", order,
+            ]).map(|args| super::run(args, 2)).unwrap().await.unwrap();
+
+            for name in ["Docs/a", "Docs/Sub/c", "docs/b", "docs/sub/d"] {
+                assert_eq!(tokio::fs::read_to_string(dest.join(name.to_lowercase())).await.unwrap(), name);
+            }
+            assert!(!dest.join("Docs").exists());
+        }
+        let merged = crate::test_support::captured_logs().into_iter()
+            .filter(|message| message.starts_with("The directories") && message.contains("case_variants/source"))
+            .count();
+        // Docs and Sub, in both orders
+        assert_eq!(merged, 4);
+
+        // A destination that ignores the case, without the option
+        let dest = base_dir.join("insensitive");
+        let options = Arc::new(CopyOptions { case_folds: Some(Arc::new(CaseFolds::new(None))), ..Default::default() });
+        copy_tree(&source, &dest, options.clone(), 2).await.unwrap();
+        let failures = options.failures.list();
+        assert_eq!(failures.len(), 1, "{:?}", failures);
+        assert!(failures[0].message.contains("with another case"), "{}", failures[0].message);
+        assert!(failures[0].message.contains("--case-fold-merge"));
+        // The first one of them is copied, nothing of the other
+        let copied = ["Docs", "docs"].into_iter().filter(|name| dest.join(name).exists()).collect::<Vec<_>>();
+        assert_eq!(copied.len(), 1);
+        let files = if copied[0] == "Docs" { ["Docs/a", "Docs/Sub/c"] } else { ["docs/b", "docs/sub/d"] };
+        for name in files {
+            assert_eq!(tokio::fs::read_to_string(dest.join(name)).await.unwrap(), name);
+        }
+        assert_eq!(failures[0].path.as_deref(), Some(source.join(if copied[0] == "Docs" { "docs" } else { "Docs" }).as_path()));
     }
 
-    f.apply()?;
-    Ok(())
+    #[cfg(windows)]
+    #[tokio::test]
+    async fn long_paths() {
+        let base_dir = crate::winpath::extended(&init("long_paths").await).unwrap();
 
-}
+        // Deeper than the 260 characters of Win32
+        let deep: PathBuf = (0..30).map(|level| format!("directory{:02}", level)).collect();
+        let source = base_dir.join("source");
+        tokio::fs::create_dir_all(source.join(&deep)).await.unwrap();
+        tokio::fs::write(source.join(&deep).join("file"), "text").await.unwrap();
+        tokio::fs::write(source.join("invalid."), "text").await.unwrap();
+        let dest = base_dir.join("dest");
+        assert!(dest.join(&deep).join("file").as_os_str().len() > 300);
 
-/// Arguments parser
-/// `--source` the source directory
-/// `--destination` the destination directory
-/// `--delete-source` to act like moving (first copy and the remove the source file)
-/// `--concurrency` to set the maximum concurrency
-#[derive(Parser, Debug)]
-#[clap(author, version, about, long_about = None)]
-struct Args {
-   /// Name of the person to greet
-   #[clap(short, long, value_parser)]
-   source: String,
-   /// Name of the person to greet
-   #[clap(short, long, value_parser)]
-   destination: String,
-   /// Delete source or not
-   #[clap(long, value_parser, default_value = "false")]
-   delete_source: bool,
-   /// Concurrency
-   #[clap(long, value_parser, default_value = "10")]
-   concurrency: usize,
-}
+        let options = Arc::new(CopyOptions::default());
+        copy_tree(&source, &dest, options.clone(), 2).await.unwrap();
+        assert_eq!(tokio::fs::read_to_string(dest.join(&deep).join("file")).await.unwrap(), "text");
+        // Not creatable through Win32
+        let failures = options.failures.list();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].path.as_deref(), Some(source.join("invalid.").as_path()));
 
+        let options = Arc::new(CopyOptions { sanitize_names: true, ..Default::default() });
+        copy_tree(&source, &base_dir.join("sanitized"), options.clone(), 2).await.unwrap();
+        assert_eq!(tokio::fs::read_to_string(base_dir.join("sanitized").join("invalid")).await.unwrap(), "text");
+        assert!(options.failures.list().is_empty());
+    }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let args = Args::parse();
+    #[tokio::test]
+    async fn checkpoints() {
+        let base_dir = init("checkpoints").await;
 
-    setup_logger("INFO", None::<&str>)?;
+        let source = base_dir.join("source");
+        let dest = base_dir.join("dest");
+        tokio::fs::create_dir_all(&source).await.unwrap();
+        for index in 0..7 {
+            tokio::fs::write(source.join(format!("file{}", index)), "text").await.unwrap();
+        }
+        crate::test_support::captured_logs();
+        crate::config::parse_args([
+            "rs-copier", "--source", source.to_str().unwrap(), "--destination", dest.to_str().unwrap(),
+            "--checkpoint-every", "3",
+        ]).map(|args| super::run(args, 2)).unwrap().await.unwrap();
 
-    let base_source = PathBuf::from(args.source);
-    let base_dest = PathBuf::from(args.destination);
-    let delete_source = args.delete_source;
-    let batch_size = args.concurrency;
-    if delete_source {
-        info!("Source files will be deleted once copied");
+        // After 3 and 6 files, not at the end
+        let logs = crate::test_support::captured_logs();
+        let checkpoints: Vec<_> = logs.iter().filter(|line| line.starts_with("Checkpoint: ")).collect();
+        assert_eq!(checkpoints.len(), 2, "{:?}", checkpoints);
+        assert!(checkpoints[0].starts_with("Checkpoint: 3 files done, 12 bytes, 0 failed, elapsed "), "{}", checkpoints[0]);
+        assert!(checkpoints[1].starts_with("Checkpoint: 6 files done, 24 bytes, 0 failed, elapsed "), "{}", checkpoints[1]);
     }
-    
-    if !base_source.exists() {
-        return Err(anyhow::anyhow!("Source directory does not exist"));
+
+    #[tokio::test]
+    async fn files_per_second() {
+        let base_dir = init("files_per_second").await;
+
+        let source = base_dir.join("source");
+        let dest = base_dir.join("dest");
+        tokio::fs::create_dir_all(source.join("nested")).await.unwrap();
+        for index in 0..10 {
+            let dir = if index % 2 == 0 { source.clone() } else { source.join("nested") };
+            tokio::fs::write(dir.join(format!("file{}", index)), "text").await.unwrap();
+        }
+        let started = std::time::Instant::now();
+        crate::config::parse_args([
+            "rs-copier", "--source", source.to_str().unwrap(), "--destination", dest.to_str().unwrap(),
+            "--rate-limit-files-per-sec", "20", "--bwlimit", "1MB/s", "--concurrency", "8",
+        ]).map(|args| super::run(args, 2)).unwrap().await.unwrap();
+
+        // The first one starts at once, then one every 50 ms whatever the concurrency and the bandwidth
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(450), "{:?}", elapsed);
+        for index in 0..10 {
+            let dir = if index % 2 == 0 { dest.clone() } else { dest.join("nested") };
+            assert_eq!(tokio::fs::read_to_string(dir.join(format!("file{}", index))).await.unwrap(), "text");
+        }
     }
 
-    info!("The concurrency is set to {batch_size}");
+    #[tokio::test]
+    async fn single_file() {
+        let base_dir = init("single_file").await;
 
-    let mut set = JoinSet::new();
-    let mut dirs = process_directory(&base_source.clone(), &base_dest.clone(), delete_source).await?;
-    
-    while let Some(dir) = dirs.pop() {
-        let dest = base_dest.join(dir.strip_prefix(&base_source).unwrap());
-        set.spawn(async move {            
-            process_directory(&dir, &dest, delete_source).await.unwrap()
-        });
+        let source = base_dir.join("huge.iso");
+        tokio::fs::write(&source, "image").await.unwrap();
+        let run = |source: &Path, dest: &Path, more: &[&str]| {
+            let mut args = vec!["rs-copier", "--source", source.to_str().unwrap(), "--destination", dest.to_str().unwrap()];
+            args.extend(more);
+            crate::config::parse_args(args).map(|args| super::run(args, 2)).unwrap()
+        };
 
-        if set.len() >= batch_size {
-            // Max concurrency
-            if let Some(res) = set.join_next().await {
-                match res {
-                    Ok(mut new_dirs) => {
-                        dirs.append(&mut new_dirs);
-                    },
-                    Err(err) => {
-                        error!("Error {:?}", err);
-                    }
-                }
-            }
+        // Into a directory that exists, or that is created when the destination ends with a separator
+        let out = base_dir.join("out");
+        tokio::fs::create_dir(&out).await.unwrap();
+        let manifest = base_dir.join("manifest");
+        run(&source, &out, &["--verify", "--manifest", manifest.to_str().unwrap(), "--verify-after"]).await.unwrap();
+        assert_eq!(tokio::fs::read_to_string(out.join("huge.iso")).await.unwrap(), "image");
+        assert!(tokio::fs::read_to_string(&manifest).await.unwrap().lines().any(|line| line.contains("  huge.iso  5")));
+        let created = base_dir.join("created");
+        run(&source, Path::new(&format!("{}/", created.display())), &[]).await.unwrap();
+        assert_eq!(tokio::fs::read_to_string(created.join("huge.iso")).await.unwrap(), "image");
+        assert!(run(&source, &out, &["--if-exists", "error"]).await.is_err());
+
+        // To an exact path, with its parents
+        let exact = base_dir.join("nested/dir/copy.iso");
+        run(&source, &exact, &["--verify", "--manifest", manifest.to_str().unwrap(), "--verify-after"]).await.unwrap();
+        assert_eq!(tokio::fs::read_to_string(&exact).await.unwrap(), "image");
+        assert!(tokio::fs::read_to_string(&manifest).await.unwrap().lines().any(|line| line.contains("  copy.iso  5")));
+
+        // --delete-source moves it
+        let moved = base_dir.join("moved.iso");
+        run(&source, &moved, &["--delete-source"]).await.unwrap();
+        assert_eq!(tokio::fs::read_to_string(&moved).await.unwrap(), "image");
+        assert!(!source.exists());
+
+        // A link is read through, and only the link is deleted without --dereference-source
+        #[cfg(unix)]
+        {
+            let link = base_dir.join("link.iso");
+            std::os::unix::fs::symlink(&moved, &link).unwrap();
+            run(&link, &out, &["--delete-source"]).await.unwrap();
+            assert!(!tokio::fs::symlink_metadata(out.join("link.iso")).await.unwrap().file_type().is_symlink());
+            assert_eq!(tokio::fs::read_to_string(out.join("link.iso")).await.unwrap(), "image");
+            assert!(tokio::fs::symlink_metadata(&link).await.is_err());
+            assert!(moved.exists());
         }
     }
 
-    // Remove source (which is only the directory structure empty of files)
-    if delete_source {
-        tokio::fs::remove_dir_all(base_source).await?;
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn post_copy_hook() {
+        let base_dir = init("post_copy_hook").await;
+
+        let source = base_dir.join("source");
+        tokio::fs::create_dir_all(source.join("nested")).await.unwrap();
+        for name in ["a", "b", "nested/c"] {
+            tokio::fs::write(source.join(name), name).await.unwrap();
+        }
+        let sentinels = base_dir.join("sentinels");
+        tokio::fs::create_dir(&sentinels).await.unwrap();
+        let run = |dest: &Path, more: &[&str]| {
+            let mut args = vec!["rs-copier", "--source", source.to_str().unwrap(), "--destination", dest.to_str().unwrap()];
+            args.extend(more);
+            crate::config::parse_args(args).map(|args| super::run(args, 2)).unwrap()
+        };
+
+        // Once per file, with the paths in the arguments and the variables
+        let dest = base_dir.join("dest");
+        let hook = format!("sh -c 'touch \"$0.hooked\" \"{}/$(basename \"$RS_COPIER_SOURCE\")\"' {{dest}}", sentinels.display());
+        run(&dest, &["--post-copy-hook", &hook, "--hook-concurrency", "2"]).await.unwrap();
+        for name in ["a", "b", "nested/c"] {
+            assert!(dest.join(format!("{}.hooked", name)).exists(), "{}", name);
+        }
+        let mut touched: Vec<_> = std::fs::read_dir(&sentinels).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        touched.sort();
+        assert_eq!(touched, ["a", "b", "c"]);
+
+        // A failed hook is only logged, unless it must succeed
+        run(&base_dir.join("logged"), &["--post-copy-hook", "false"]).await.unwrap();
+        assert!(base_dir.join("logged/nested/c").exists());
+        let error = run(&base_dir.join("failed"), &["--post-copy-hook", "false", "--hook-must-succeed"]).await.unwrap_err();
+        assert_eq!(error.downcast_ref::<super::Incomplete>().map(|incomplete| incomplete.failed), Some(3), "{:#}", error);
+        let error = run(&base_dir.join("missing"), &["--post-copy-hook", "/missing/hook", "--hook-must-succeed"]).await.unwrap_err();
+        assert_eq!(error.downcast_ref::<super::Incomplete>().map(|incomplete| incomplete.failed), Some(3), "{:#}", error);
     }
-    info!("All done");
 
-    Ok(())
-}
+    #[tokio::test]
+    async fn invalid_source() {
+        let base_dir = init("invalid_source").await;
 
+        let dest = base_dir.join("dest");
+        let run = |source: &Path| crate::config::parse_args([
+            "rs-copier", "--source", source.to_str().unwrap(), "--destination", dest.to_str().unwrap(),
+        ]).map(|args| super::run(args, 2)).unwrap();
 
-#[cfg(test)]
-mod tests {
-    use std::path::PathBuf;
-    use super::process_directory;
+        let error = run(&base_dir.join("missing")).await.unwrap_err();
+        assert_eq!(error.to_string(), format!("The source does not exist: {:?}", base_dir.join("missing")));
+        assert_eq!(failures::exit_code(&error), failures::EXIT_SOURCE_MISSING);
+        #[cfg(unix)]
+        {
+            let error = run(Path::new("/dev/null")).await.unwrap_err();
+            assert_eq!(error.to_string(), "The source is neither a directory nor a regular file: \"/dev/null\"");
+            assert_eq!(failures::exit_code(&error), failures::EXIT_SOURCE_NOT_DIRECTORY);
+        }
+        assert!(!dest.exists());
+    }
 
-    const BASE_DIR: &str = "/tmp/test";
+    #[tokio::test]
+    async fn error_report() {
+        let (source, dest) = tree_with_failure("error_report").await;
+        let unreadable = source.join("unreadable");
+        tokio::fs::write(&unreadable, "secret").await.unwrap();
+        #[cfg(unix)]
+        std::fs::set_permissions(&unreadable, std::os::unix::fs::PermissionsExt::from_mode(0o000)).unwrap();
+        // The permissions do not stop root, then only the conflict fails
+        let unreadable_fails = std::fs::File::open(&unreadable).is_err();
+        let report = dest.parent().unwrap().join("errors.tsv");
+        let run = |report: &Path| crate::config::parse_args([
+            "rs-copier", "--no-prescan", "--source", source.to_str().unwrap(), "--destination", dest.to_str().unwrap(),
+            "--error-report", report.to_str().unwrap(),
+        ]).map(|args| super::run(args, 2)).unwrap();
 
-    async fn init(name: &str) -> PathBuf {
-        let base_dir = PathBuf::from(BASE_DIR).join(name);
-        
-        if base_dir.exists() {
-            tokio::fs::remove_dir_all(&base_dir).await.unwrap();
+        let error = run(&report).await.unwrap_err();
+        assert_eq!(failures::exit_code(&error), failures::EXIT_PARTIAL);
+        let summary = if unreadable_fails { "copied 1 files, 2 failed" } else { "copied 2 files, 1 failed" };
+        assert_eq!(error.to_string(), summary);
+        let text = tokio::fs::read_to_string(&report).await.unwrap();
+        assert_eq!(text.lines().count(), if unreadable_fails { 2 } else { 1 }, "{}", text);
+        assert!(text.contains(&format!("{}\tOther\tThe destination ", source.join("conflict").display())), "{}", text);
+        if unreadable_fails {
+            assert!(text.contains(&format!("{}\tPermissionDenied\t", unreadable.display())), "{}", text);
         }
-        tokio::fs::create_dir_all(&base_dir).await.unwrap();
-        
-        base_dir
+
+        // Only the conflict is left
+        tokio::fs::remove_dir_all(source.join("nested")).await.unwrap();
+        tokio::fs::remove_file(&unreadable).await.unwrap();
+        let error = run(&report).await.unwrap_err();
+        assert_eq!(failures::exit_code(&error), failures::EXIT_NOTHING_COPIED);
+        assert_eq!(tokio::fs::read_to_string(&report).await.unwrap().lines().count(), 1);
     }
 
     #[tokio::test]
-    async fn empty_directory() {
-        let base_dir = init("empty_directory").await;
-        
+    async fn unlistable_directory() {
+        let base_dir = init("unlistable_directory").await;
+
         let source = base_dir.join("source");
         let dest = base_dir.join("dest");
-        assert_eq!(dest.exists(), false);
-        tokio::fs::create_dir_all(&source).await.unwrap();
-        process_directory(&source, &dest, false).await.unwrap();
+        for name in ["first", "locked", "last"] {
+            tokio::fs::create_dir_all(source.join(name)).await.unwrap();
+            tokio::fs::write(source.join(name).join("file"), name).await.unwrap();
+        }
+        let locked = source.join("locked");
+        #[cfg(unix)]
+        std::fs::set_permissions(&locked, std::os::unix::fs::PermissionsExt::from_mode(0o000)).unwrap();
+        // The permissions do not stop root, then everything is copied
+        let locked_fails = std::fs::read_dir(&locked).is_err();
+        let report = base_dir.join("errors.tsv");
+        let copied = crate::config::parse_args([
+            "rs-copier", "--no-prescan", "--source", source.to_str().unwrap(), "--destination", dest.to_str().unwrap(),
+            "--error-report", report.to_str().unwrap(),
+        ]).map(|args| super::run(args, 2)).unwrap().await;
+        #[cfg(unix)]
+        std::fs::set_permissions(&locked, std::os::unix::fs::PermissionsExt::from_mode(0o755)).unwrap();
 
-        assert_eq!(source.exists(), true);
-        assert_eq!(dest.exists(), true);
+        assert_eq!(tokio::fs::read_to_string(dest.join("first/file")).await.unwrap(), "first");
+        assert_eq!(tokio::fs::read_to_string(dest.join("last/file")).await.unwrap(), "last");
+        if locked_fails {
+            let error = copied.unwrap_err();
+            assert_eq!(failures::exit_code(&error), failures::EXIT_PARTIAL);
+            assert_eq!(error.to_string(), "copied 2 files, 1 failed");
+            let text = tokio::fs::read_to_string(&report).await.unwrap();
+            assert!(text.starts_with(&format!("{}\tPermissionDenied\tCannot list directory: ", locked.display())), "{}", text);
+            assert!(!dest.join("locked").exists());
+        } else {
+            copied.unwrap();
+        }
     }
 
     #[tokio::test]
-    async fn only_files() {
-        let base_dir = init("only_files").await;
+    async fn journal() {
+        let base_dir = init("journal").await;
 
         let source = base_dir.join("source");
-        tokio::fs::create_dir_all(&source).await.unwrap();
         let dest = base_dir.join("dest");
-        tokio::fs::write(source.join("file1"), "text").await.unwrap();
-        tokio::fs::write(source.join("file2"), "text").await.unwrap();
-        assert_eq!(dest.exists(), false);
-        process_directory(&source, &dest, false).await.unwrap();
+        let journal = base_dir.join("journal");
+        tokio::fs::create_dir_all(source.join("nested")).await.unwrap();
+        tokio::fs::write(source.join("first"), "text").await.unwrap();
+        tokio::fs::write(source.join("nested/second"), "text").await.unwrap();
+        let run = |compact: bool| crate::config::parse_args(
+            ["rs-copier", "--no-prescan", "--source", source.to_str().unwrap(), "--destination", dest.to_str().unwrap(),
+                "--journal", journal.to_str().unwrap()].into_iter().chain(compact.then_some("--journal-compact")),
+        ).map(|args| super::run(args, 2)).unwrap();
 
-        assert_eq!(source.join("file1").exists(), true);
-        assert_eq!(source.join("file2").exists(), true);
-        
-        assert_eq!(dest.exists(), true);
-        assert_eq!(dest.join("file1").exists(), true);
-        assert_eq!(dest.join("file2").exists(), true);
+        run(false).await.unwrap();
+        assert_eq!(tokio::fs::read_to_string(&journal).await.unwrap().lines().filter(|line| line.starts_with("done\t")).count(), 2);
+        // The files done are not copied again, whatever their destination
+        tokio::fs::remove_file(dest.join("first")).await.unwrap();
+        tokio::fs::remove_file(dest.join("nested/second")).await.unwrap();
+        tokio::fs::write(source.join("nested/second"), "changed").await.unwrap();
+        run(true).await.unwrap();
+        assert!(!dest.join("first").exists());
+        assert_eq!(tokio::fs::read_to_string(dest.join("nested/second")).await.unwrap(), "changed");
+        // Every file was copied, the journal is not needed any more
+        assert!(!journal.exists());
     }
 
     #[tokio::test]
-    async fn only_files_delete() {
-        let base_dir = init("only_files_delete").await;
+    async fn source_base() {
+        let base_dir = init("source_base").await;
+
+        let source = base_dir.join("a").join("c");
+        tokio::fs::create_dir_all(source.join("nested")).await.unwrap();
+        tokio::fs::write(source.join("nested/file"), "text").await.unwrap();
+        for (mode, dest, landed) in [("parent", "parent", "nested/file"), ("self", "self", "c/nested/file")] {
+            let dest = base_dir.join(dest);
+            crate::config::parse_args([
+                "rs-copier", "--no-prescan", "--source-base", mode, "--source", source.to_str().unwrap(), "--destination", dest.to_str().unwrap(),
+            ]).map(|args| super::run(args, 2)).unwrap().await.unwrap();
+            assert_eq!(tokio::fs::read_to_string(dest.join(landed)).await.unwrap(), "text");
+        }
+        assert!(!base_dir.join("parent/c").exists());
+        assert!(!base_dir.join("self/nested").exists());
+
+        // The name of a source like `a/c/..` is the one it resolves to
+        let root = super::source_base_root(&base_dir.join("self"), &source.join("..")).await.unwrap();
+        assert_eq!(root, base_dir.join("self/a"));
+    }
+
+    #[tokio::test]
+    async fn verify() {
+        let base_dir = init("verify").await;
+
+        let source = base_dir.join("source");
+        let dest = base_dir.join("dest");
+        let manifest = base_dir.join("manifest");
+        let unverified = base_dir.join("unverified");
+        tokio::fs::create_dir_all(source.join("nested")).await.unwrap();
+        tokio::fs::write(source.join("first"), "text").await.unwrap();
+        tokio::fs::write(source.join("nested/second"), "more text").await.unwrap();
+        crate::config::parse_args([
+            "rs-copier", "--no-prescan", "--verify", "--manifest", manifest.to_str().unwrap(), "--source", source.to_str().unwrap(),
+            "--destination", dest.to_str().unwrap(),
+        ]).map(|args| super::run(args, 2)).unwrap().await.unwrap();
+        assert_eq!(tokio::fs::read_to_string(dest.join("nested/second")).await.unwrap(), "more text");
+
+        // The manifest is sha256 with or without --verify
+        crate::config::parse_args([
+            "rs-copier", "--no-prescan", "--manifest", unverified.to_str().unwrap(), "--source", source.to_str().unwrap(),
+            "--destination", base_dir.join("unverified dest").to_str().unwrap(),
+        ]).map(|args| super::run(args, 2)).unwrap().await.unwrap();
+        let written = tokio::fs::read_to_string(&manifest).await.unwrap();
+        assert!(written.starts_with("# algorithm: sha256\n"));
+        let mut lines: Vec<_> = written.lines().collect();
+        let mut unverified_lines: Vec<_> = tokio::fs::read_to_string(&unverified).await.unwrap().lines().map(str::to_owned).collect();
+        lines.sort_unstable();
+        unverified_lines.sort_unstable();
+        assert_eq!(lines, unverified_lines);
+        assert!(written.contains("982d9e3eb996f559e633f4d194def3761d909f5a3b647d1a851fead67c32c9d1  first  4\n"));
+    }
+
+    #[tokio::test]
+    async fn atomic() {
+        let base_dir = init("atomic").await;
+
+        let source = base_dir.join("source");
+        let dest = base_dir.join("dest");
+        tokio::fs::create_dir_all(source.join("nested")).await.unwrap();
+        tokio::fs::create_dir_all(dest.join("nested")).await.unwrap();
+        tokio::fs::write(source.join("first"), "text").await.unwrap();
+        tokio::fs::write(source.join("nested/second"), "text").await.unwrap();
+        tokio::fs::write(dest.join("first"), "kept").await.unwrap();
+        // Left by a run that died
+        let leftover = dest.join("nested/.rscopier-tmp.second.1f2e3d-0");
+        tokio::fs::write(&leftover, "te").await.unwrap();
+        crate::config::parse_args([
+            "rs-copier", "--no-prescan", "--atomic", "--if-exists", "skip", "--source", source.to_str().unwrap(),
+            "--destination", dest.to_str().unwrap(),
+        ]).map(|args| super::run(args, 2)).unwrap().await.unwrap();
+
+        assert_eq!(tokio::fs::read_to_string(dest.join("first")).await.unwrap(), "kept");
+        assert_eq!(tokio::fs::read_to_string(dest.join("nested/second")).await.unwrap(), "text");
+        assert!(!leftover.exists());
+        assert_eq!(std::fs::read_dir(dest.join("nested")).unwrap().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn burst_of_failures() {
+        let base_dir = init("burst_of_failures").await;
+
+        let source = base_dir.join("source");
+        let dest = base_dir.join("dest");
+        // Every file of the source has a directory in the way in the destination
+        tokio::fs::create_dir_all(source.join("burst")).await.unwrap();
+        for i in 0..50 {
+            tokio::fs::write(source.join(format!("burst/{i}")), "text").await.unwrap();
+            tokio::fs::create_dir_all(dest.join(format!("burst/{i}"))).await.unwrap();
+        }
+        let error = crate::config::parse_args([
+            "rs-copier", "--no-prescan", "--concurrency", "2", "--small-file-threshold", "0", "--source", source.to_str().unwrap(),
+            "--destination", dest.to_str().unwrap(), "--on-error-keep-going-but-limit", "5",
+        ]).map(|args| super::run(args, 1)).unwrap().await.unwrap_err();
+
+        assert!(format!("{:#}", error).contains("More than --max-errors 5 files could not be copied"), "{:#}", error);
+        assert_eq!(failures::exit_code(&error), failures::EXIT_NOTHING_COPIED);
+        // The copies in flight when the threshold was passed may fail too, none is started after
+        let failed = error.downcast_ref::<failures::Incomplete>().unwrap().failed;
+        assert!((6..=8).contains(&failed), "{} failed", failed);
+    }
+
+    #[tokio::test]
+    async fn metrics_file() {
+        let (source, dest) = tree_with_failure("metrics_file").await;
+        let options = Arc::new(CopyOptions::default());
+        copy_tree(&source, &dest, options.clone(), 1).await.unwrap();
+
+        let path = dest.parent().unwrap().join("rscopier.prom");
+        options.stats.write_metrics(&path, Duration::from_secs(2), true).await.unwrap();
+        let text = tokio::fs::read_to_string(&path).await.unwrap();
+        // `name value` samples, every one after its HELP and TYPE lines
+        let mut samples = std::collections::HashMap::new();
+        let mut described = vec![];
+        for line in text.lines() {
+            match line.split_whitespace().collect::<Vec<_>>()[..] {
+                ["#", "HELP", name, ..] => described.push(name.to_owned()),
+                ["#", "TYPE", name, "counter" | "gauge"] => assert_eq!(described.last().map(String::as_str), Some(name)),
+                [name, value] => {
+                    assert_eq!(described.last().map(String::as_str), Some(name));
+                    samples.insert(name.to_owned(), value.parse::<f64>().unwrap());
+                },
+                _ => panic!("Invalid line: {:?}", line),
+            }
+        }
+        assert_eq!(samples["rscopier_files_copied_total"], options.stats.files_done() as f64);
+        assert_eq!(samples["rscopier_files_copied_total"], 1.0);
+        assert_eq!(samples["rscopier_bytes_copied_total"], 4.0);
+        assert_eq!(samples["rscopier_errors_total"], 1.0);
+        assert_eq!(samples["rscopier_duration_seconds"], 2.0);
+        assert_eq!(samples["rscopier_completed"], 1.0);
+        assert!(!path.with_file_name("rscopier.prom.part").exists());
+    }
+
+    #[tokio::test]
+    async fn skip_unchanged() {
+        let base_dir = init("skip_unchanged").await;
 
         let source = base_dir.join("source");
+        let dest = base_dir.join("dest");
         tokio::fs::create_dir_all(&source).await.unwrap();
-        tokio::fs::write(source.join("file1"), "text").await.unwrap();
-        tokio::fs::write(source.join("file2"), "text").await.unwrap();
+        tokio::fs::create_dir_all(&dest).await.unwrap();
+        for (name, content) in [("same", "unchanged"), ("changed", "new text"), ("new", "new")] {
+            tokio::fs::write(source.join(name), content).await.unwrap();
+        }
+        tokio::fs::write(dest.join("same"), "unchanged").await.unwrap();
+        tokio::fs::write(dest.join("changed"), "old text").await.unwrap();
+
+        let options = Arc::new(CopyOptions { skip_unchanged: Some(super::SkipUnchanged::Sample), ..Default::default() });
+        copy_tree(&source, &dest, options.clone(), 2).await.unwrap();
+        assert_eq!(tokio::fs::read_to_string(dest.join("changed")).await.unwrap(), "new text");
+        assert_eq!(tokio::fs::read_to_string(dest.join("new")).await.unwrap(), "new");
+        // Not among the certain skips
+        assert_eq!(options.stats.files_skipped.load(std::sync::atomic::Ordering::Relaxed), 0);
+        assert_eq!(options.stats.summary(), "2 files copied, 0 files cloned, 0 files renamed, 11 bytes, 1 files unchanged by sample");
+    }
+
+    #[tokio::test]
+    async fn failed_files_keep_the_source() {
+        let base_dir = init("failed_files_keep_the_source").await;
+
+        let source = base_dir.join("source");
         let dest = base_dir.join("dest");
-        assert_eq!(dest.exists(), false);
-        let res = process_directory(&source, &dest, true).await.unwrap();
+        tokio::fs::create_dir_all(source.join("a/b")).await.unwrap();
+        tokio::fs::create_dir_all(source.join("c/d")).await.unwrap();
+        tokio::fs::create_dir_all(dest.join("a/b")).await.unwrap();
+        for name in ["a/b/failed", "a/copied", "c/d/copied"] {
+            tokio::fs::write(source.join(name), name).await.unwrap();
+        }
+        // The copy of this file fails, like an unreadable one (that root could read all the same)
+        tokio::fs::write(dest.join("a/b/failed"), "taken").await.unwrap();
 
-        assert_eq!(source.join("file1").exists(), false);
-        assert_eq!(source.join("file2").exists(), false);
-        
-        assert_eq!(dest.exists(), true);
-        assert_eq!(dest.join("file1").exists(), true);
-        assert_eq!(dest.join("file2").exists(), true);
-        assert_eq!(res.len(), 0);
+        let options = Arc::new(CopyOptions { if_exists: IfExists::Error, ..(*delete_source()).clone() });
+        let error = copy_tree(&source, &dest, options.clone(), 2).await.unwrap_err();
+        assert!(format!("{:#}", error).contains("--force-source-cleanup"), "{:#}", error);
+        assert!(source.join("a/b/failed").exists());
+        // The copied files are gone, but no directory was removed
+        assert!(!source.join("a/copied").exists() && !source.join("c/d/copied").exists());
+        assert!(source.join("c/d").is_dir());
+
+        // Forced, only the empty directories go
+        let options = Arc::new(CopyOptions { force_source_cleanup: true, ..(*options).clone() });
+        super::clean_source_tree(&source, &options).await.unwrap();
+        assert_eq!(tokio::fs::read_to_string(source.join("a/b/failed")).await.unwrap(), "a/b/failed");
+        assert!(!source.join("c").exists());
     }
 
+    #[tokio::test]
+    async fn modify_window() {
+        let base_dir = init("modify_window").await;
+
+        let (from, to) = (base_dir.join("source"), base_dir.join("dest"));
+        let set_mtime = |path: &Path, mtime: SystemTime| {
+            std::fs::File::options().write(true).open(path).unwrap().set_modified(mtime).unwrap();
+        };
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let write = |source: &'static str, dest: &'static str, ahead: u64| {
+            let (from, to) = (from.clone(), to.clone());
+            async move {
+                tokio::fs::write(&from, source).await.unwrap();
+                tokio::fs::write(&to, dest).await.unwrap();
+                set_mtime(&from, mtime + Duration::from_secs(ahead));
+                set_mtime(&to, mtime);
+            }
+        };
+
+        // The source is a second ahead, like a copy to FAT that rounded the mtime
+        write("new", "old", 1).await;
+        let options = CopyOptions { if_exists: IfExists::Newer, modify_window: Duration::from_secs(2), ..Default::default() };
+        super::process_file(&from, &to, &options).await.unwrap();
+        assert_eq!(tokio::fs::read_to_string(&to).await.unwrap(), "old");
+        assert_eq!(options.stats.files_skipped.load(std::sync::atomic::Ordering::Relaxed), 1);
+        // The small files compare the same way
+        assert!(crate::copy::copy_small_file(&from, &to, &options).unwrap().is_none());
+
+        // Exact by default
+        let exact = CopyOptions { if_exists: IfExists::Newer, ..Default::default() };
+        super::process_file(&from, &to, &exact).await.unwrap();
+        assert_eq!(tokio::fs::read_to_string(&to).await.unwrap(), "new");
+
+        // Beyond the window, and never an older source
+        write("newer", "old", 3).await;
+        super::process_file(&from, &to, &options).await.unwrap();
+        assert_eq!(tokio::fs::read_to_string(&to).await.unwrap(), "newer");
+        write("new", "old", 0).await;
+        set_mtime(&from, mtime - Duration::from_secs(10));
+        super::process_file(&from, &to, &exact).await.unwrap();
+        assert_eq!(tokio::fs::read_to_string(&to).await.unwrap(), "old");
+    }
 
     #[tokio::test]
-    async fn nested() {
-        let base_dir = init("nested").await;
+    async fn dedupe_dest() {
+        let base_dir = init("dedupe_dest").await;
 
         let source = base_dir.join("source");
+        let dest = base_dir.join("dest");
         tokio::fs::create_dir_all(&source).await.unwrap();
+        tokio::fs::create_dir_all(&dest).await.unwrap();
+        for (name, content) in [("first", "same content"), ("second", "same content"), ("other", "some content")] {
+            tokio::fs::write(source.join(name), content).await.unwrap();
+        }
+        // An overwritten destination is replaced by the link
+        tokio::fs::write(dest.join("second"), "old").await.unwrap();
+
+        let options = CopyOptions { dedupe: Some(Arc::default()), ..Default::default() };
+        for name in ["first", "second", "other"] {
+            super::process_file(&source.join(name), &dest.join(name), &options).await.unwrap();
+        }
+        for name in ["first", "second", "other"] {
+            let (copy, original) = (tokio::fs::read(dest.join(name)).await.unwrap(), tokio::fs::read(source.join(name)).await.unwrap());
+            assert_eq!(copy, original);
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let inode = |name| std::fs::metadata(dest.join(name)).unwrap().ino();
+            assert_eq!(inode("first"), inode("second"));
+            assert_ne!(inode("first"), inode("other"));
+        }
+        assert_eq!(
+            options.stats.summary(),
+            "2 files copied, 0 files cloned, 0 files renamed, 24 bytes, 1 files deduplicated (36 logical bytes, 24 written, 12 saved)",
+        );
+    }
+
+    #[tokio::test]
+    async fn dedup() {
+        let base_dir = init("dedup").await;
+
+        let source = base_dir.join("source");
         let dest = base_dir.join("dest");
-        tokio::fs::write(source.join("file1"), "text").await.unwrap();
-        tokio::fs::write(source.join("file2"), "text").await.unwrap();
-        let nested = source.join("nested");
-        tokio::fs::create_dir_all(source.join("nested")).await.unwrap();
-        tokio::fs::write(nested.join("file3"), "text").await.unwrap();
-        tokio::fs::write(nested.join("file4"), "text").await.unwrap();
+        tokio::fs::create_dir_all(source.join("photos")).await.unwrap();
+        tokio::fs::write(source.join("photo.jpg"), "the same picture").await.unwrap();
+        tokio::fs::write(source.join("photos/copy of photo.jpg"), "the same picture").await.unwrap();
+        let run = |method: &str| crate::config::parse_args([
+            "rs-copier", "--no-prescan", "--concurrency", "1", "--source", source.to_str().unwrap(),
+            "--destination", dest.to_str().unwrap(), "--dedup", "--dedupe-method", method,
+        ]).map(|args| super::run(args, 1)).unwrap();
 
-        assert_eq!(dest.exists(), false);
-        let res = process_directory(&source, &dest, false).await.unwrap();
+        run("hard-link").await.unwrap();
+        for name in ["photo.jpg", "photos/copy of photo.jpg"] {
+            assert_eq!(tokio::fs::read_to_string(dest.join(name)).await.unwrap(), "the same picture");
+        }
+        // Both names are the same storage
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let (first, second) = (std::fs::metadata(dest.join("photo.jpg")).unwrap(), std::fs::metadata(dest.join("photos/copy of photo.jpg")).unwrap());
+            assert_eq!((first.dev(), first.ino()), (second.dev(), second.ino()));
+            assert_eq!(first.nlink(), 2);
+        }
 
-        assert_eq!(source.join("file1").exists(), true);
-        assert_eq!(source.join("file2").exists(), true);
-        
-        
-        
-        assert_eq!(dest.exists(), true);
-        assert_eq!(dest.join("file1").exists(), true);
-        assert_eq!(dest.join("file2").exists(), true);
-        
-        assert_eq!(res.len(), 1);
-        assert_eq!(res[0], base_dir.join("source").join("nested"));
+        // The clones need a filesystem with reflinks, the run fails before copying anything without them
+        tokio::fs::remove_dir_all(&dest).await.unwrap();
+        tokio::fs::create_dir_all(&dest).await.unwrap();
+        match super::dedupe::check_links(&dest, true).await {
+            Ok(()) => {
+                run("reflink").await.unwrap();
+                assert_eq!(tokio::fs::read_to_string(dest.join("photos/copy of photo.jpg")).await.unwrap(), "the same picture");
+            },
+            Err(_) => {
+                let error = run("reflink").await.unwrap_err();
+                assert!(format!("{:#}", error).contains("needs reflinks"), "{:#}", error);
+                assert_eq!(std::fs::read_dir(&dest).unwrap().count(), 0);
+            },
+        }
+    }
 
-        let nested_dest = base_dir.join("dest").join("nested");
-        
-        let res = process_directory(&res[0], &nested_dest, false).await.unwrap();
-        
-        assert_eq!(res.len(), 0);
-        
-        
-        assert_eq!(nested_dest.exists(), true);
-        assert_eq!(nested_dest.join("file3").exists(), true);
-        assert_eq!(nested_dest.join("file4").exists(), true);
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn packed_source_removal() {
+        let base_dir = init("packed_source_removal").await;
+
+        let source = base_dir.join("source");
+        tokio::fs::create_dir_all(source.join("nested/empty")).await.unwrap();
+        tokio::fs::write(source.join("file1"), "one").await.unwrap();
+        tokio::fs::write(source.join("nested/file2"), "two").await.unwrap();
+        // Left out of the archive
+        std::os::unix::fs::symlink("file1", source.join("link")).unwrap();
+        let archive = base_dir.join("out/source.tar");
+        let archived = || {
+            let mut tar = tar::Archive::new(std::fs::File::open(&archive).unwrap());
+            let mut paths: Vec<_> = tar.entries().unwrap()
+                .map(|entry| entry.unwrap().path().unwrap().to_string_lossy().trim_end_matches('/').to_owned())
+                .collect();
+            paths.sort();
+            paths
+        };
+        let run = || {
+            let args = ["rs-copier", "--source", source.to_str().unwrap(), "--destination", archive.to_str().unwrap(), "--delete-source"];
+            crate::config::parse_args(args).map(|args| super::run(args, 2)).unwrap()
+        };
+
+        // Nothing is removed while an entry is not archived
+        let error = run().await.unwrap_err();
+        assert!(format!("{:#}", error).contains("1 entries are neither files nor directories"), "{:#}", error);
+        assert_eq!(tokio::fs::read_to_string(source.join("nested/file2")).await.unwrap(), "two");
+        assert!(source.join("nested/empty").is_dir());
+        assert_eq!(tokio::fs::read_link(source.join("link")).await.unwrap(), Path::new("file1"));
+
+        // Forced, only the archived entries go
+        let forced = ["rs-copier", "--source", source.to_str().unwrap(), "--destination", archive.to_str().unwrap(), "--delete-source", "--force-source-cleanup"];
+        crate::config::parse_args(forced).map(|args| super::run(args, 2)).unwrap().await.unwrap();
+        assert_eq!(archived(), ["file1", "nested", "nested/empty", "nested/file2"]);
+        assert_eq!(std::fs::read_dir(&source).unwrap().count(), 1);
+        assert_eq!(tokio::fs::read_link(source.join("link")).await.unwrap(), Path::new("file1"));
+
+        tokio::fs::write(source.join("file1"), "one").await.unwrap();
+        tokio::fs::remove_file(source.join("link")).await.unwrap();
+        run().await.unwrap();
+        assert!(!source.exists());
+        assert_eq!(archived(), ["file1"]);
     }
 
-    
-}
\ No newline at end of file
+    #[tokio::test]
+    async fn extracted_source_removal() {
+        let base_dir = init("extracted_source_removal").await;
+
+        let archive = base_dir.join("archive.tar");
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, content) in [("first", "one"), ("second", "two")] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, name, content.as_bytes()).unwrap();
+        }
+        tokio::fs::write(&archive, builder.into_inner().unwrap()).await.unwrap();
+        let dest = base_dir.join("dest");
+        tokio::fs::create_dir(&dest).await.unwrap();
+        tokio::fs::write(dest.join("second"), "taken").await.unwrap();
+        let run = || {
+            let args = ["rs-copier", "--source", archive.to_str().unwrap(), "--destination", dest.to_str().unwrap(), "--delete-source", "--fsync", "--if-exists", "error"];
+            crate::config::parse_args(args).map(|args| super::run(args, 2)).unwrap()
+        };
+
+        // An entry failed, the archive is kept
+        let error = run().await.unwrap_err();
+        assert_eq!(error.downcast_ref::<super::Incomplete>().map(|incomplete| incomplete.failed), Some(1), "{:#}", error);
+        assert!(archive.exists());
+        assert_eq!(tokio::fs::read_to_string(dest.join("first")).await.unwrap(), "one");
+
+        tokio::fs::remove_dir_all(&dest).await.unwrap();
+        run().await.unwrap();
+        assert!(!archive.exists());
+        assert_eq!(tokio::fs::read_to_string(dest.join("second")).await.unwrap(), "two");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unlistable_packed_directory() {
+        use std::os::unix::fs::PermissionsExt;
+        let base_dir = init("unlistable_packed_directory").await;
+
+        let source = base_dir.join("source");
+        tokio::fs::create_dir_all(source.join("locked")).await.unwrap();
+        tokio::fs::write(source.join("file"), "one").await.unwrap();
+        tokio::fs::write(source.join("locked/file"), "two").await.unwrap();
+        tokio::fs::set_permissions(source.join("locked"), std::fs::Permissions::from_mode(0o000)).await.unwrap();
+        // Root lists it all the same
+        let unlistable = std::fs::read_dir(source.join("locked")).is_err();
+        let archive = base_dir.join("source.tar");
+        let args = [
+            "rs-copier", "--source", source.to_str().unwrap(), "--destination", archive.to_str().unwrap(), "--delete-source",
+            "--force-source-cleanup",
+        ];
+        let packed = crate::config::parse_args(args).map(|args| super::run(args, 2)).unwrap().await;
+
+        // The rest of the tree is packed and removed, the directory is kept
+        assert!(archive.exists());
+        assert!(!source.join("file").exists());
+        if unlistable {
+            assert_eq!(packed.unwrap_err().downcast_ref::<super::Incomplete>().map(|incomplete| incomplete.failed), Some(1));
+            tokio::fs::set_permissions(source.join("locked"), std::fs::Permissions::from_mode(0o755)).await.unwrap();
+            assert_eq!(tokio::fs::read_to_string(source.join("locked/file")).await.unwrap(), "two");
+        } else {
+            packed.unwrap();
+            assert!(!source.exists());
+        }
+    }
+}
@@ -0,0 +1,174 @@
+//! Manifest with the checksum of every copied file.
+//! The first line names the algorithm (`# algorithm: blake3`), then every line has the format
+//! `digest  relative/path  size` and the lines are sorted by path
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use anyhow::{anyhow, Result};
+use crate::checksum::{self, ChecksumAlgorithm};
+use crate::paths;
+
+/// Prefix of the line with the name of the algorithm
+const ALGORITHM_HEADER: &str = "# algorithm: ";
+
+/// Digest and size of a copied file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub digest: String,
+    pub size: u64,
+}
+
+/// Entries collected while the copy proceeds. The paths are stored relative to `root`
+#[derive(Debug)]
+pub struct Manifest {
+    root: PathBuf,
+    algorithm: ChecksumAlgorithm,
+    entries: Mutex<BTreeMap<PathBuf, Entry>>,
+}
+
+/// A manifest entry that does not match the file on disk
+#[derive(Debug, PartialEq, Eq)]
+pub enum Mismatch {
+    Missing(PathBuf),
+    Size { path: PathBuf, expected: u64, actual: u64 },
+    Digest { path: PathBuf, expected: String, actual: String },
+}
+
+impl Manifest {
+    pub fn new(root: &Path, algorithm: ChecksumAlgorithm) -> Self {
+        Self { root: root.to_owned(), algorithm, entries: Mutex::new(BTreeMap::new()) }
+    }
+
+    /// The directory the paths are relative to
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Record the copied file `path` (a path under the root)
+    pub fn record(&self, path: &Path, digest: String, size: u64) {
+        let relative = path.strip_prefix(&self.root).unwrap_or(path).to_owned();
+        self.entries.lock().unwrap().insert(relative, Entry { digest, size });
+    }
+
+    /// Write all the entries sorted by path
+    pub async fn write(&self, path: &Path) -> Result<()> {
+        let mut content = format!("{}{}\n", ALGORITHM_HEADER, self.algorithm);
+        for (relative, entry) in self.entries.lock().unwrap().iter() {
+            writeln!(content, "{}  {}  {}", entry.digest, paths::escape(relative), entry.size)?;
+        }
+        tokio::fs::write(path, content).await?;
+        Ok(())
+    }
+}
+
+/// The entries of a manifest file
+#[derive(Debug)]
+pub struct ManifestFile {
+    /// Only known when the manifest names it
+    pub algorithm: Option<ChecksumAlgorithm>,
+    pub entries: BTreeMap<PathBuf, Entry>,
+}
+
+/// Read the entries of a manifest file
+pub async fn read(path: &Path) -> Result<ManifestFile> {
+    let content = tokio::fs::read_to_string(path).await?;
+    let mut algorithm = None;
+    let mut entries = BTreeMap::new();
+    for (number, line) in content.lines().enumerate().filter(|(_, line)| !line.is_empty()) {
+        let invalid = || anyhow!("Invalid manifest line {}: {:?}", number + 1, line);
+        if let Some(name) = line.strip_prefix(ALGORITHM_HEADER) {
+            algorithm = Some(ChecksumAlgorithm::from_name(name.trim()).ok_or_else(invalid)?);
+            continue;
+        }
+        let (digest, rest) = line.split_once("  ").ok_or_else(invalid)?;
+        let (relative, size) = rest.rsplit_once("  ").ok_or_else(invalid)?;
+        let size = size.parse().map_err(|_| invalid())?;
+        entries.insert(paths::unescape(relative), Entry { digest: digest.to_owned(), size });
+    }
+    Ok(ManifestFile { algorithm, entries })
+}
+
+/// Check every entry of the manifest against the files under `root` and return the ones that do not match.
+/// `algorithm` is only used when the manifest does not name its algorithm
+pub async fn verify(manifest: &Path, root: &Path, algorithm: ChecksumAlgorithm) -> Result<Vec<Mismatch>> {
+    let manifest = read(manifest).await?;
+    let algorithm = manifest.algorithm.unwrap_or(algorithm);
+    let mut mismatches = vec![];
+    for (relative, expected) in manifest.entries {
+        let path = root.join(&relative);
+        let size = match tokio::fs::metadata(&path).await {
+            Ok(metadata) => metadata.len(),
+            Err(_) => {
+                mismatches.push(Mismatch::Missing(relative));
+                continue;
+            }
+        };
+        if size != expected.size {
+            mismatches.push(Mismatch::Size { path: relative, expected: expected.size, actual: size });
+            continue;
+        }
+        let digest = checksum::hash_file(&path, algorithm).await?;
+        if digest != expected.digest {
+            mismatches.push(Mismatch::Digest { path: relative, expected: expected.digest, actual: digest });
+        }
+    }
+    Ok(mismatches)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use super::{verify, Manifest, Mismatch};
+    use crate::checksum::ChecksumAlgorithm;
+    use crate::{copy_tree, CopyOptions};
+    use crate::test_support::init;
+
+    const TEXT_SHA256: &str = "982d9e3eb996f559e633f4d194def3761d909f5a3b647d1a851fead67c32c9d1";
+
+    async fn copy_with_manifest(name: &str) -> (std::path::PathBuf, std::path::PathBuf) {
+        let base_dir = init(name).await;
+
+        let source = base_dir.join("source");
+        let dest = base_dir.join("dest");
+        tokio::fs::create_dir_all(&source).await.unwrap();
+        tokio::fs::write(source.join("file2"), "text").await.unwrap();
+        tokio::fs::write(source.join("file1"), "text").await.unwrap();
+
+        let manifest = Arc::new(Manifest::new(&dest, ChecksumAlgorithm::Sha256));
+        let options = CopyOptions {
+            manifest: Some(manifest.clone()),
+            checksum_algorithm: ChecksumAlgorithm::Sha256,
+            ..Default::default()
+        };
+        copy_tree(&source, &dest, Arc::new(options), 1).await.unwrap();
+
+        let manifest_path = base_dir.join("manifest");
+        manifest.write(&manifest_path).await.unwrap();
+        (dest, manifest_path)
+    }
+
+    #[tokio::test]
+    async fn manifest_generation() {
+        let (_, manifest_path) = copy_with_manifest("manifest_generation").await;
+
+        let content = tokio::fs::read_to_string(&manifest_path).await.unwrap();
+        assert_eq!(content, format!("# algorithm: sha256\n{TEXT_SHA256}  file1  4\n{TEXT_SHA256}  file2  4\n"));
+    }
+
+    #[tokio::test]
+    async fn manifest_detects_tampering() {
+        let (dest, manifest_path) = copy_with_manifest("manifest_detects_tampering").await;
+        // The manifest names its algorithm
+        assert!(verify(&manifest_path, &dest, ChecksumAlgorithm::Md5).await.unwrap().is_empty());
+
+        tokio::fs::write(dest.join("file1"), "TEXT").await.unwrap();
+        tokio::fs::remove_file(dest.join("file2")).await.unwrap();
+
+        let mismatches = verify(&manifest_path, &dest, ChecksumAlgorithm::Md5).await.unwrap();
+        assert_eq!(mismatches.len(), 2);
+        assert!(matches!(&mismatches[0], Mismatch::Digest { path, .. } if path.as_os_str() == "file1"));
+        assert_eq!(mismatches[1], Mismatch::Missing("file2".into()));
+    }
+}
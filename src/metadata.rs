@@ -0,0 +1,713 @@
+//! Source metadata applied to the destination once the content has been copied
+
+use std::fmt::Display;
+use std::io;
+use std::path::Path;
+use std::sync::Once;
+use std::time::SystemTime;
+use anyhow::Result;
+use log::warn;
+use crate::CopyOptions;
+
+/// The lack of creation time support is only reported once
+static CRTIME_UNSUPPORTED: Once = Once::new();
+/// The ownership that cannot be given (the copy does not run as root) is only reported once
+#[cfg(unix)]
+static OWNERSHIP_UNSUPPORTED: Once = Once::new();
+/// The extended attributes that cannot be set are only reported once
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+static XATTRS_UNSUPPORTED: Once = Once::new();
+/// The ACLs the destination filesystem does not take are only reported once
+#[cfg(target_os = "linux")]
+static ACLS_UNSUPPORTED: Once = Once::new();
+
+/// What is carried from the sources to the destination (`--preserve`, `--archive`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PreserveFlags {
+    /// Access and modification times
+    pub timestamps: bool,
+    /// Permission bits. Without it the files get the mode of new files (0666 without the umask)
+    pub mode: bool,
+    /// Owner and group (Unix, only root can give the files to other users)
+    pub ownership: bool,
+    /// Extended attributes (Linux and macOS)
+    pub xattrs: bool,
+    /// POSIX ACLs, with the default ACLs of the directories (Linux)
+    pub acls: bool,
+    /// Copy the symbolic links as links instead of walking them
+    pub links: bool,
+    /// Creation time (Windows and macOS)
+    pub crtime: bool,
+    /// Hidden, system, archive and read-only attributes (Windows)
+    pub attributes: bool,
+}
+
+/// The names of `--preserve`
+pub const PRESERVE_NAMES: &str = "timestamps, mode, ownership, xattrs, acls, links, crtime, attributes or all";
+
+impl PreserveFlags {
+    pub const NONE: Self = Self {
+        timestamps: false,
+        mode: false,
+        ownership: false,
+        xattrs: false,
+        acls: false,
+        links: false,
+        crtime: false,
+        attributes: false,
+    };
+
+    /// Everything the platform can preserve (`--archive`)
+    pub fn archive() -> Self {
+        Self {
+            timestamps: true,
+            mode: true,
+            ownership: true,
+            xattrs: true,
+            acls: cfg!(target_os = "linux"),
+            links: true,
+            crtime: cfg!(any(windows, target_os = "macos")),
+            attributes: cfg!(windows),
+        }
+    }
+}
+
+/// Without `--preserve` only the mode is kept, like the copy always did
+impl Default for PreserveFlags {
+    fn default() -> Self {
+        Self { mode: true, ..Self::NONE }
+    }
+}
+
+/// Parse a comma separated list like `timestamps,mode`. Only the named flags are set
+pub fn parse_preserve(value: &str) -> Result<PreserveFlags, String> {
+    let mut flags = PreserveFlags::NONE;
+    for name in value.split(',').map(str::trim) {
+        match name {
+            "timestamps" => flags.timestamps = true,
+            "mode" => flags.mode = true,
+            "ownership" => flags.ownership = true,
+            "xattrs" => flags.xattrs = true,
+            "acls" => flags.acls = true,
+            "links" => flags.links = true,
+            "crtime" => flags.crtime = true,
+            "attributes" => flags.attributes = true,
+            "all" => flags = PreserveFlags::archive(),
+            _ => return Err(format!("unknown attribute {:?}, expected {}", name, PRESERVE_NAMES)),
+        }
+    }
+    Ok(flags)
+}
+
+/// Apply the requested source metadata to the destination file.
+/// The mode and attributes are applied last because a read-only destination would reject any further change
+pub async fn apply(from: &Path, to: &Path, options: &CopyOptions) -> Result<()> {
+    let preserve = options.preserve;
+    let source = if preserve.timestamps || preserve.ownership {
+        Some(tokio::fs::symlink_metadata(from).await?)
+    } else {
+        None
+    };
+    if let (true, Some(source)) = (preserve.ownership, &source) {
+        copy_ownership(source, to).await?;
+    }
+    if preserve.xattrs {
+        copy_xattrs(from, to).await?;
+    }
+    if preserve.acls {
+        copy_acls(from, to).await?;
+    }
+    if let (true, Some(source)) = (preserve.timestamps, &source) {
+        let (to, source) = (to.to_owned(), source.clone());
+        tokio::task::spawn_blocking(move || set_times(&to, &source)).await??;
+    }
+    if preserve.crtime {
+        copy_creation_time(from, to).await?;
+    }
+    if let Some(mode) = options.chmod {
+        set_mode(to, mode).await?;
+    } else if !preserve.mode {
+        set_mode(to, 0o666 & !umask()).await?;
+    } else if let (true, Some(source)) = (preserve.ownership, &source) {
+        // The change of owner clears the setuid and setgid bits
+        restore_special_bits(source, to).await?;
+    }
+    if preserve.attributes {
+        copy_attributes(from, to).await?;
+    }
+    Ok(())
+}
+
+/// Apply the requested metadata to a copied link: its owner and its times, the target is not followed
+pub async fn apply_link(from: &Path, to: &Path, options: &CopyOptions) -> Result<()> {
+    if !options.preserve.timestamps && !options.preserve.ownership {
+        return Ok(());
+    }
+    let source = tokio::fs::symlink_metadata(from).await?;
+    if options.preserve.ownership {
+        copy_ownership(&source, to).await?;
+    }
+    if options.preserve.timestamps && cfg!(unix) {
+        let to = to.to_owned();
+        tokio::task::spawn_blocking(move || set_times(&to, &source)).await??;
+    }
+    Ok(())
+}
+
+/// Warn about something that cannot be preserved the first time it happens
+fn warn_once(once: &Once, what: &str, reason: impl Display) {
+    once.call_once(|| {
+        warn!("{} cannot be preserved: {}. Only the first occurrence is reported", what, reason);
+    });
+}
+
+/// Whether the error tells that the destination (or the user) cannot have the metadata, not that the copy failed
+#[cfg(unix)]
+fn is_unsupported(error: &io::Error) -> bool {
+    matches!(error.kind(), io::ErrorKind::PermissionDenied | io::ErrorKind::Unsupported)
+}
+
+/// Copy the creation (birth) time. Warn once and go on when the source or the platform does not support it
+async fn copy_creation_time(from: &Path, to: &Path) -> Result<()> {
+    match tokio::fs::metadata(from).await?.created() {
+        Ok(created) => set_creation_time(to, created).await,
+        Err(error) => {
+            warn_crtime_unsupported(error);
+            Ok(())
+        }
+    }
+}
+
+fn warn_crtime_unsupported(reason: impl Display) {
+    warn_once(&CRTIME_UNSUPPORTED, "Creation time", reason);
+}
+
+/// Set the access and modification times of the source. A link is changed itself, not its target
+#[cfg(unix)]
+fn set_times(to: &Path, source: &std::fs::Metadata) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::MetadataExt;
+
+    let path = CString::new(to.as_os_str().as_bytes())?;
+    let times = [
+        libc::timespec { tv_sec: source.atime() as _, tv_nsec: source.atime_nsec() as _ },
+        libc::timespec { tv_sec: source.mtime() as _, tv_nsec: source.mtime_nsec() as _ },
+    ];
+    // SAFETY: path is NUL terminated and times has the access and modification times that utimensat reads
+    if unsafe { libc::utimensat(libc::AT_FDCWD, path.as_ptr(), times.as_ptr(), libc::AT_SYMLINK_NOFOLLOW) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_times(to: &Path, source: &std::fs::Metadata) -> io::Result<()> {
+    let mut open = std::fs::OpenOptions::new();
+    // Only the attributes are written, so a read-only destination can be changed too
+    #[cfg(windows)]
+    std::os::windows::fs::OpenOptionsExt::access_mode(&mut open, windows_sys::Win32::Storage::FileSystem::FILE_WRITE_ATTRIBUTES);
+    #[cfg(not(windows))]
+    open.write(true);
+    let times = std::fs::FileTimes::new().set_accessed(source.accessed()?).set_modified(source.modified()?);
+    open.open(to)?.set_times(times)
+}
+
+/// Give the destination the owner and group of the source. Warn once and go on when it is not allowed
+#[cfg(unix)]
+async fn copy_ownership(source: &std::fs::Metadata, to: &Path) -> Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    let (uid, gid, to) = (source.uid(), source.gid(), to.to_owned());
+    match tokio::task::spawn_blocking(move || std::os::unix::fs::lchown(to, Some(uid), Some(gid))).await? {
+        Err(error) if is_unsupported(&error) => {
+            warn_once(&OWNERSHIP_UNSUPPORTED, "Ownership", error);
+            Ok(())
+        },
+        result => Ok(result?),
+    }
+}
+
+/// The files of the rest of platforms have no Unix owner
+#[cfg(not(unix))]
+async fn copy_ownership(_source: &std::fs::Metadata, _to: &Path) -> Result<()> {
+    Ok(())
+}
+
+/// Set the setuid, setgid and sticky bits of the source again
+#[cfg(unix)]
+async fn restore_special_bits(source: &std::fs::Metadata, to: &Path) -> Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    let mode = source.mode() & 0o7777;
+    if mode & 0o7000 != 0 {
+        set_mode(to, mode).await?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn restore_special_bits(_source: &std::fs::Metadata, _to: &Path) -> Result<()> {
+    Ok(())
+}
+
+/// Copy the extended attributes. Warn once and go on when the destination does not take them
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+async fn copy_xattrs(from: &Path, to: &Path) -> Result<()> {
+    let (from, to) = (from.to_owned(), to.to_owned());
+    match tokio::task::spawn_blocking(move || xattr::copy(&from, &to)).await? {
+        Err(error) if is_unsupported(&error) => {
+            warn_once(&XATTRS_UNSUPPORTED, "Extended attributes", error);
+            Ok(())
+        },
+        result => Ok(result?),
+    }
+}
+
+/// Extended attributes are only copied on Linux and macOS
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+async fn copy_xattrs(_from: &Path, _to: &Path) -> Result<()> {
+    Ok(())
+}
+
+/// Copy the access ACL, and the default ACL of a directory. A destination the source has no ACL for loses the one it
+/// inherited from its parent's default ACL. Warn once and go on when the destination filesystem has no ACLs.
+/// Linux keeps the ACLs in the `system.posix_acl_*` extended attributes, so they are copied as they are, entries
+/// and mask included, without an ACL library
+#[cfg(target_os = "linux")]
+pub async fn copy_acls(from: &Path, to: &Path) -> Result<()> {
+    let (from, to) = (from.to_owned(), to.to_owned());
+    let names = [c"system.posix_acl_access", c"system.posix_acl_default"];
+    match tokio::task::spawn_blocking(move || xattr::copy_named(&from, &to, &names)).await? {
+        Err(error) if is_unsupported(&error) => {
+            warn_once(&ACLS_UNSUPPORTED, "ACLs", error);
+            Ok(())
+        },
+        result => Ok(result?),
+    }
+}
+
+/// ACLs are only copied on Linux
+#[cfg(not(target_os = "linux"))]
+pub async fn copy_acls(_from: &Path, _to: &Path) -> Result<()> {
+    Ok(())
+}
+
+/// The default mode of the new files is 0666 without this mask. It is read once, before the copy creates any file,
+/// because reading it means changing it for the whole process for a moment
+#[cfg(unix)]
+pub fn umask() -> u32 {
+    static UMASK: std::sync::OnceLock<u32> = std::sync::OnceLock::new();
+    *UMASK.get_or_init(|| {
+        // SAFETY: umask cannot fail and the previous mask is set back right away
+        let mask = unsafe { libc::umask(0o022) };
+        unsafe { libc::umask(mask) };
+        mask as u32
+    })
+}
+
+#[cfg(not(unix))]
+pub fn umask() -> u32 {
+    0
+}
+
+#[cfg(any(windows, target_os = "macos"))]
+async fn set_creation_time(to: &Path, created: SystemTime) -> Result<()> {
+    #[cfg(target_os = "macos")]
+    use std::os::macos::fs::FileTimesExt;
+    #[cfg(windows)]
+    use std::os::windows::fs::FileTimesExt;
+
+    let to = to.to_owned();
+    tokio::task::spawn_blocking(move || {
+        let file = std::fs::OpenOptions::new().write(true).open(&to)?;
+        file.set_times(std::fs::FileTimes::new().set_created(created))?;
+        Ok(())
+    }).await?
+}
+
+/// Linux and the rest of platforms do not provide any way to set the birth time
+#[cfg(not(any(windows, target_os = "macos")))]
+async fn set_creation_time(_to: &Path, _created: SystemTime) -> Result<()> {
+    warn_crtime_unsupported("the platform cannot set it");
+    Ok(())
+}
+
+/// Parse an octal mode like `644` or `0755`. Symbolic modes (`u+rw`) are not supported
+pub fn parse_mode(value: &str) -> Result<u32, String> {
+    let digits = value.trim();
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(8)) {
+        return Err(format!("the mode must be octal, like 644: {:?}", value));
+    }
+    match u32::from_str_radix(digits, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!("the mode is out of range: {:?}", value)),
+    }
+}
+
+/// Replace the permission bits of the destination, whatever the copy carried over from the source
+#[cfg(unix)]
+pub async fn set_mode(to: &Path, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    tokio::fs::set_permissions(to, std::fs::Permissions::from_mode(mode)).await?;
+    Ok(())
+}
+
+/// Unix modes do not exist in the rest of platforms
+#[cfg(not(unix))]
+pub async fn set_mode(_to: &Path, _mode: u32) -> Result<()> {
+    Ok(())
+}
+
+/// Copy the hidden, system, archive and read-only attributes (Windows only)
+#[cfg(windows)]
+async fn copy_attributes(from: &Path, to: &Path) -> Result<()> {
+    let from = from.to_owned();
+    let to = to.to_owned();
+    tokio::task::spawn_blocking(move || windows::copy_attributes(&from, &to)).await?
+}
+
+/// File attributes only exist on Windows
+#[cfg(not(windows))]
+async fn copy_attributes(_from: &Path, _to: &Path) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(windows)]
+mod windows {
+    use std::io;
+    use std::os::windows::ffi::OsStrExt;
+    use std::path::Path;
+    use anyhow::Result;
+    use windows_sys::Win32::Storage::FileSystem::{
+        GetFileAttributesW, SetFileAttributesW, FILE_ATTRIBUTE_ARCHIVE, FILE_ATTRIBUTE_HIDDEN,
+        FILE_ATTRIBUTE_READONLY, FILE_ATTRIBUTE_SYSTEM, INVALID_FILE_ATTRIBUTES,
+    };
+
+    /// Attributes carried from the source to the destination
+    const PRESERVED: u32 = FILE_ATTRIBUTE_HIDDEN | FILE_ATTRIBUTE_SYSTEM | FILE_ATTRIBUTE_ARCHIVE | FILE_ATTRIBUTE_READONLY;
+
+    fn wide(path: &Path) -> Vec<u16> {
+        path.as_os_str().encode_wide().chain(Some(0)).collect()
+    }
+
+    fn get_attributes(path: &[u16]) -> io::Result<u32> {
+        // SAFETY: path is a NUL terminated wide string
+        let attributes = unsafe { GetFileAttributesW(path.as_ptr()) };
+        if attributes == INVALID_FILE_ATTRIBUTES {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(attributes)
+    }
+
+    pub fn copy_attributes(from: &Path, to: &Path) -> Result<()> {
+        let source = get_attributes(&wide(from))?;
+        let to = wide(to);
+        let dest = get_attributes(&to)?;
+        let attributes = (dest & !PRESERVED) | (source & PRESERVED);
+        // SAFETY: to is a NUL terminated wide string
+        if unsafe { SetFileAttributesW(to.as_ptr(), attributes) } == 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+mod xattr {
+    use std::ffi::{c_char, c_void, CString};
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    #[cfg(target_os = "linux")]
+    unsafe fn list(path: *const c_char, names: *mut c_char, size: usize) -> isize {
+        libc::llistxattr(path, names, size)
+    }
+
+    #[cfg(target_os = "macos")]
+    unsafe fn list(path: *const c_char, names: *mut c_char, size: usize) -> isize {
+        libc::listxattr(path, names, size, libc::XATTR_NOFOLLOW)
+    }
+
+    #[cfg(target_os = "linux")]
+    unsafe fn get(path: *const c_char, name: *const c_char, value: *mut c_void, size: usize) -> isize {
+        libc::lgetxattr(path, name, value, size)
+    }
+
+    #[cfg(target_os = "macos")]
+    unsafe fn get(path: *const c_char, name: *const c_char, value: *mut c_void, size: usize) -> isize {
+        libc::getxattr(path, name, value, size, 0, libc::XATTR_NOFOLLOW)
+    }
+
+    #[cfg(target_os = "linux")]
+    unsafe fn set(path: *const c_char, name: *const c_char, value: *const c_void, size: usize) -> i32 {
+        libc::lsetxattr(path, name, value, size, 0)
+    }
+
+    #[cfg(target_os = "macos")]
+    unsafe fn set(path: *const c_char, name: *const c_char, value: *const c_void, size: usize) -> i32 {
+        libc::setxattr(path, name, value, size, 0, libc::XATTR_NOFOLLOW)
+    }
+
+    /// Ask for the size and then read into a buffer of that size, again when it grew in between
+    fn read(call: impl Fn(*mut u8, usize) -> isize) -> io::Result<Vec<u8>> {
+        loop {
+            let size = call(std::ptr::null_mut(), 0);
+            if size < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut buffer = vec![0; size as usize];
+            let read = call(buffer.as_mut_ptr(), buffer.len());
+            if read >= 0 {
+                buffer.truncate(read as usize);
+                return Ok(buffer);
+            }
+            let error = io::Error::last_os_error();
+            if error.raw_os_error() != Some(libc::ERANGE) {
+                return Err(error);
+            }
+        }
+    }
+
+    pub fn copy(from: &Path, to: &Path) -> io::Result<()> {
+        let from = CString::new(from.as_os_str().as_bytes())?;
+        let to = CString::new(to.as_os_str().as_bytes())?;
+        // SAFETY: the paths and names are NUL terminated and the buffers have the given size
+        let names = read(|names, size| unsafe { list(from.as_ptr(), names.cast(), size) })?;
+        for name in names.split(|byte| *byte == 0).filter(|name| !name.is_empty()) {
+            let name = CString::new(name)?;
+            let value = read(|value, size| unsafe { get(from.as_ptr(), name.as_ptr(), value.cast(), size) })?;
+            if unsafe { set(to.as_ptr(), name.as_ptr(), value.as_ptr().cast(), value.len()) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    /// Copy the attributes `names`, the ones the source does not have are removed from the destination
+    #[cfg(target_os = "linux")]
+    pub fn copy_named(from: &Path, to: &Path, names: &[&std::ffi::CStr]) -> io::Result<()> {
+        let from = CString::new(from.as_os_str().as_bytes())?;
+        let to = CString::new(to.as_os_str().as_bytes())?;
+        for name in names {
+            // SAFETY: the paths and names are NUL terminated and the buffers have the given size
+            let value = match read(|value, size| unsafe { get(from.as_ptr(), name.as_ptr(), value.cast(), size) }) {
+                Err(error) if error.raw_os_error() == Some(libc::ENODATA) => {
+                    if unsafe { libc::lremovexattr(to.as_ptr(), name.as_ptr()) } != 0 {
+                        let error = io::Error::last_os_error();
+                        if error.raw_os_error() != Some(libc::ENODATA) {
+                            return Err(error);
+                        }
+                    }
+                    continue;
+                },
+                value => value?,
+            };
+            if unsafe { set(to.as_ptr(), name.as_ptr(), value.as_ptr().cast(), value.len()) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+    use super::{apply, parse_mode, parse_preserve, PreserveFlags};
+    use crate::CopyOptions;
+    use crate::test_support::init;
+
+    #[tokio::test]
+    async fn creation_time() {
+        let base_dir = init("creation_time").await;
+
+        let source = base_dir.join("source");
+        tokio::fs::write(&source, "text").await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        let dest = base_dir.join("dest");
+        tokio::fs::write(&dest, "text").await.unwrap();
+
+        // Unsupported platforms just warn
+        let preserve = PreserveFlags { crtime: true, ..Default::default() };
+        let options = CopyOptions { preserve, ..Default::default() };
+        apply(&source, &dest, &options).await.unwrap();
+
+        if cfg!(any(windows, target_os = "macos")) {
+            let source_created = tokio::fs::metadata(&source).await.unwrap().created().unwrap();
+            let dest_created = tokio::fs::metadata(&dest).await.unwrap().created().unwrap();
+            assert_eq!(source_created, dest_created);
+        }
+    }
+
+    #[test]
+    fn octal_modes() {
+        assert_eq!(parse_mode("644"), Ok(0o644));
+        assert_eq!(parse_mode("0755"), Ok(0o755));
+        assert_eq!(parse_mode("4755"), Ok(0o4755));
+        assert!(parse_mode("u+rw").is_err());
+        assert!(parse_mode("648").is_err());
+        assert!(parse_mode("77777").is_err());
+        assert!(parse_mode("").is_err());
+    }
+
+    #[test]
+    fn preserve_lists() {
+        let flags = parse_preserve("timestamps, links").unwrap();
+        assert_eq!(flags, PreserveFlags { timestamps: true, links: true, ..PreserveFlags::NONE });
+        assert_eq!(parse_preserve("mode").unwrap(), PreserveFlags::default());
+        assert_eq!(parse_preserve("mode,all").unwrap(), PreserveFlags::archive());
+        assert_eq!(parse_preserve("acls").unwrap(), PreserveFlags { acls: true, ..PreserveFlags::NONE });
+        assert!(parse_preserve("timestamps,flags").is_err());
+        assert!(parse_preserve("").is_err());
+    }
+
+    #[tokio::test]
+    async fn timestamps() {
+        let base_dir = init("timestamps").await;
+
+        let source = base_dir.join("source");
+        let dest = base_dir.join("dest");
+        tokio::fs::write(&source, "text").await.unwrap();
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        let file = std::fs::File::options().write(true).open(&source).unwrap();
+        file.set_times(std::fs::FileTimes::new().set_modified(modified).set_accessed(modified)).unwrap();
+        tokio::fs::write(&dest, "text").await.unwrap();
+
+        let options = CopyOptions { preserve: PreserveFlags { timestamps: true, ..Default::default() }, ..Default::default() };
+        apply(&source, &dest, &options).await.unwrap();
+
+        let metadata = tokio::fs::metadata(&dest).await.unwrap();
+        assert_eq!(metadata.modified().unwrap(), modified);
+        assert_eq!(metadata.accessed().unwrap(), modified);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn mode_not_preserved() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let base_dir = init("mode_not_preserved").await;
+
+        let source = base_dir.join("source");
+        let dest = base_dir.join("dest");
+        tokio::fs::write(&source, "text").await.unwrap();
+        tokio::fs::set_permissions(&source, std::fs::Permissions::from_mode(0o700)).await.unwrap();
+        tokio::fs::copy(&source, &dest).await.unwrap();
+
+        let options = CopyOptions { preserve: PreserveFlags::NONE, ..Default::default() };
+        apply(&source, &dest, &options).await.unwrap();
+
+        let mode = tokio::fs::metadata(&dest).await.unwrap().permissions().mode();
+        assert_eq!(mode & 0o7777, 0o666 & !super::umask());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn extended_attributes() {
+        let base_dir = init("extended_attributes").await;
+
+        let source = base_dir.join("source");
+        let dest = base_dir.join("dest");
+        tokio::fs::write(&source, "text").await.unwrap();
+        tokio::fs::write(&dest, "text").await.unwrap();
+        let path = |path: &std::path::Path| std::ffi::CString::new(path.to_str().unwrap()).unwrap();
+        let name = c"user.origin";
+        // SAFETY: the strings are NUL terminated and the buffers have the given size
+        if unsafe { libc::setxattr(path(&source).as_ptr(), name.as_ptr(), b"camera".as_ptr().cast(), 6, 0) } != 0 {
+            // The filesystem does not take user attributes
+            return;
+        }
+
+        let options = CopyOptions { preserve: PreserveFlags { xattrs: true, ..Default::default() }, ..Default::default() };
+        apply(&source, &dest, &options).await.unwrap();
+
+        let mut value = [0u8; 16];
+        let read = unsafe { libc::getxattr(path(&dest).as_ptr(), name.as_ptr(), value.as_mut_ptr().cast(), value.len()) };
+        assert_eq!(&value[..read.max(0) as usize], b"camera");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn acls() {
+        let base_dir = init("acls").await;
+
+        let source = base_dir.join("source");
+        let dest = base_dir.join("dest");
+        tokio::fs::create_dir(&source).await.unwrap();
+        tokio::fs::create_dir(&dest).await.unwrap();
+        let path = |path: &std::path::Path| std::ffi::CString::new(path.to_str().unwrap()).unwrap();
+        // The kernel format: a version and then (tag, permissions, id) entries, user 1234 can read
+        let mut acl = 2u32.to_le_bytes().to_vec();
+        for (tag, perm, id) in [(0x01u16, 7u16, u32::MAX), (0x02, 4, 1234), (0x04, 5, u32::MAX), (0x10, 5, u32::MAX), (0x20, 5, u32::MAX)] {
+            acl.extend(tag.to_le_bytes().into_iter().chain(perm.to_le_bytes()).chain(id.to_le_bytes()));
+        }
+        for name in [c"system.posix_acl_access", c"system.posix_acl_default"] {
+            // SAFETY: the strings are NUL terminated and the buffer has the given size
+            if unsafe { libc::setxattr(path(&source).as_ptr(), name.as_ptr(), acl.as_ptr().cast(), acl.len(), 0) } != 0 {
+                // The filesystem does not have ACLs
+                return;
+            }
+        }
+
+        let options = CopyOptions { preserve: PreserveFlags { acls: true, ..Default::default() }, ..Default::default() };
+        apply(&source, &dest, &options).await.unwrap();
+        for name in [c"system.posix_acl_access", c"system.posix_acl_default"] {
+            let mut value = [0u8; 64];
+            let read = unsafe { libc::getxattr(path(&dest).as_ptr(), name.as_ptr(), value.as_mut_ptr().cast(), value.len()) };
+            assert_eq!(&value[..read.max(0) as usize], acl);
+        }
+
+        // The file inherits the default ACL of its new directory, its source has none
+        tokio::fs::write(base_dir.join("plain"), "text").await.unwrap();
+        tokio::fs::write(dest.join("plain"), "text").await.unwrap();
+        let name = c"system.posix_acl_access";
+        assert!(unsafe { libc::getxattr(path(&dest.join("plain")).as_ptr(), name.as_ptr(), std::ptr::null_mut(), 0) } > 0);
+        apply(&base_dir.join("plain"), &dest.join("plain"), &options).await.unwrap();
+        assert!(unsafe { libc::getxattr(path(&dest.join("plain")).as_ptr(), name.as_ptr(), std::ptr::null_mut(), 0) } < 0);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn chmod() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let base_dir = init("chmod").await;
+
+        let source = base_dir.join("source");
+        let dest = base_dir.join("dest");
+        tokio::fs::write(&source, "text").await.unwrap();
+        tokio::fs::set_permissions(&source, std::fs::Permissions::from_mode(0o600)).await.unwrap();
+        tokio::fs::copy(&source, &dest).await.unwrap();
+
+        let options = CopyOptions { chmod: Some(0o644), ..Default::default() };
+        apply(&source, &dest, &options).await.unwrap();
+
+        let mode = tokio::fs::metadata(&dest).await.unwrap().permissions().mode();
+        assert_eq!(mode & 0o7777, 0o644);
+    }
+
+    #[cfg(windows)]
+    #[tokio::test]
+    async fn hidden_attribute() {
+        use std::os::windows::fs::MetadataExt;
+        use std::process::Command;
+        use windows_sys::Win32::Storage::FileSystem::FILE_ATTRIBUTE_HIDDEN;
+
+        let base_dir = init("hidden_attribute").await;
+
+        let source = base_dir.join("hidden");
+        let dest = base_dir.join("copy");
+        tokio::fs::write(&source, "text").await.unwrap();
+        assert!(Command::new("attrib").arg("+h").arg(&source).status().unwrap().success());
+        tokio::fs::write(&dest, "text").await.unwrap();
+
+        let preserve = PreserveFlags { attributes: true, ..Default::default() };
+        let options = CopyOptions { preserve, ..Default::default() };
+        apply(&source, &dest, &options).await.unwrap();
+
+        let attributes = tokio::fs::metadata(&dest).await.unwrap().file_attributes();
+        assert_ne!(attributes & FILE_ATTRIBUTE_HIDDEN, 0);
+    }
+}
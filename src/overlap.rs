@@ -0,0 +1,168 @@
+//! The source and the destination must not overlap: a destination inside the source would be walked by its own
+//! copy without end, and a removed source inside the destination (`--delete-source`) would take copies with it.
+//! Both paths are compared once their links and `.`/`..` components are resolved, and on Unix also by device and
+//! inode, which catches the same directory mounted twice (a bind mount) where the paths differ
+
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, Result};
+
+/// The path with its links and `.`/`..` components resolved, also when only some of its parents exist yet.
+/// None when no parent can be resolved
+pub async fn resolve(path: &Path) -> Option<PathBuf> {
+    let mut missing = vec![];
+    let mut current = path;
+    loop {
+        let probe = if current.as_os_str().is_empty() { Path::new(".") } else { current };
+        if let Ok(resolved) = tokio::fs::canonicalize(probe).await {
+            return Some(missing.iter().rev().fold(resolved, |resolved, name| resolved.join(name)));
+        }
+        // A `..` after a missing directory cannot be resolved
+        missing.push(current.file_name()?.to_owned());
+        current = current.parent()?;
+    }
+}
+
+/// The device and inode of an existing path
+#[cfg(unix)]
+async fn identity(path: &Path) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    let metadata = tokio::fs::metadata(path).await.ok()?;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+/// Without a portable file id, only the paths are compared
+#[cfg(not(unix))]
+async fn identity(_path: &Path) -> Option<(u64, u64)> {
+    None
+}
+
+/// The first of the resolved `path` and its parents that is the resolved `dir`, by path or by device and inode
+async fn within(path: &Path, dir: &Path) -> Option<PathBuf> {
+    let dir_identity = identity(dir).await;
+    for ancestor in path.ancestors() {
+        if ancestor == dir || (dir_identity.is_some() && identity(ancestor).await == dir_identity) {
+            return Some(ancestor.to_owned());
+        }
+    }
+    None
+}
+
+/// Fail when the destination is the source or inside it, or with `delete_source` when the source is inside the
+/// destination. The errors show the given paths and the resolved ones
+pub async fn check(source: &Path, dest: &Path, delete_source: bool) -> Result<()> {
+    let (Some(resolved_source), Some(resolved_dest)) = (resolve(source).await, resolve(dest).await) else {
+        // A missing source is reported later
+        return Ok(());
+    };
+    match within(&resolved_dest, &resolved_source).await {
+        Some(found) if found == resolved_dest => return Err(anyhow!(
+            "The destination {:?} (resolved to {:?}) is the source {:?} (resolved to {:?})",
+            dest, resolved_dest, source, resolved_source,
+        )),
+        Some(found) => return Err(anyhow!(
+            "The destination {:?} (resolved to {:?}) is inside the source {:?} (resolved to {:?}, found as {:?}), \
+            the copy would copy itself",
+            dest, resolved_dest, source, resolved_source, found,
+        )),
+        None => {},
+    }
+    if delete_source && within(&resolved_source, &resolved_dest).await.is_some() {
+        return Err(anyhow!(
+            "The source {:?} (resolved to {:?}) is inside the destination {:?} (resolved to {:?}), \
+            --delete-source would remove copied files",
+            source, resolved_source, dest, resolved_dest,
+        ));
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Component, PathBuf};
+    use crate::test_support::init;
+    use super::check;
+
+    #[tokio::test]
+    async fn nested_destinations() {
+        let base_dir = init("nested_destinations").await;
+
+        let source = base_dir.join("source");
+        tokio::fs::create_dir_all(source.join("sub")).await.unwrap();
+        tokio::fs::create_dir_all(base_dir.join("dest")).await.unwrap();
+
+        assert!(check(&source, &base_dir.join("dest"), true).await.is_ok());
+        // Also when the destination does not exist yet
+        assert!(check(&source, &base_dir.join("dest/new/tree"), true).await.is_ok());
+        assert!(check(&source, &source, false).await.is_err());
+        assert!(check(&source, &source.join("sub"), false).await.is_err());
+        assert!(check(&source, &source.join("new/tree"), false).await.is_err());
+        assert!(check(&source, &source.join("sub/../../source/new"), false).await.is_err());
+        // A sibling with the same prefix is not inside
+        assert!(check(&source, &base_dir.join("source-copy"), false).await.is_ok());
+
+        // The source inside the destination is only a problem when it is removed
+        assert!(check(&source.join("sub"), &source, false).await.is_ok());
+        assert!(check(&source.join("sub"), &source, true).await.is_err());
+
+        // A file source contains nothing
+        tokio::fs::write(base_dir.join("file"), "").await.unwrap();
+        assert!(check(&base_dir.join("file"), &base_dir.join("file.copy"), false).await.is_ok());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn linked_destinations() {
+        let base_dir = init("linked_destinations").await;
+
+        let source = base_dir.join("source");
+        tokio::fs::create_dir_all(source.join("sub")).await.unwrap();
+        std::os::unix::fs::symlink(source.join("sub"), base_dir.join("link")).unwrap();
+        let error = check(&source, &base_dir.join("link"), false).await.unwrap_err();
+        assert!(error.to_string().contains("inside the source"), "{}", error);
+        assert!(check(&source, &base_dir.join("link/new"), false).await.is_err());
+        assert_eq!(super::resolve(&base_dir.join("link/new")).await, Some(std::fs::canonicalize(&source).unwrap().join("sub/new")));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn same_directories() {
+        let base_dir = init("same_directories").await;
+
+        let source = base_dir.join("source");
+        tokio::fs::create_dir_all(source.join("sub")).await.unwrap();
+        std::os::unix::fs::symlink(&source, base_dir.join("alias")).unwrap();
+        let resolved = std::fs::canonicalize(&source).unwrap();
+        for dest in [base_dir.join("alias"), source.join("."), source.join("sub/.."), base_dir.join("alias/sub/../../source")] {
+            let error = check(&source, &dest, true).await.unwrap_err().to_string();
+            // Both the given paths and the resolved ones
+            assert!(error.contains("is the source"), "{}", error);
+            assert!(error.contains(&format!("{:?}", dest)) && error.contains(&format!("{:?}", resolved)), "{}", error);
+        }
+
+        // The same file under two names (like a directory mounted twice) is told by its inode
+        tokio::fs::write(base_dir.join("file"), "").await.unwrap();
+        std::fs::hard_link(base_dir.join("file"), base_dir.join("other name")).unwrap();
+        let error = check(&base_dir.join("file"), &base_dir.join("other name"), false).await.unwrap_err();
+        assert!(error.to_string().contains("is the source"), "{}", error);
+        assert!(super::within(&base_dir.join("other name"), &base_dir.join("file")).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn relative_destinations() {
+        let base_dir = init("relative_destinations").await;
+
+        let source = base_dir.join("source");
+        tokio::fs::create_dir_all(source.join("sub")).await.unwrap();
+        // The same directory as a path relative to the current one
+        let current = std::env::current_dir().unwrap();
+        let up = current.components().filter(|component| matches!(component, Component::Normal(_))).count();
+        let relative: PathBuf = std::iter::repeat_n(Component::ParentDir.as_os_str(), up)
+            .chain(source.components().filter(|component| matches!(component, Component::Normal(_))).map(|component| component.as_os_str()))
+            .collect();
+        assert!(relative.is_relative());
+        assert!(check(&source, &relative.join("sub"), false).await.is_err());
+        assert!(check(&relative, &source.join("sub"), false).await.is_err());
+        assert!(check(&relative, &base_dir.join("dest"), false).await.is_ok());
+    }
+}
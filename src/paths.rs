@@ -0,0 +1,108 @@
+//! Paths written into reports and logs. A file name can have any byte but `/` and NUL on Unix: invalid UTF-8 and
+//! control characters that would corrupt a terminal or split a line of a report. `escape` writes those bytes (and `%`
+//! itself) as `%XX`, so any path has one printable text, and `unescape` gives the exact path back from it
+
+use std::borrow::Cow;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+/// The printable text of `path`, borrowed when it has nothing to escape
+pub fn escape(path: &Path) -> Cow<'_, str> {
+    let bytes = path.as_os_str().as_encoded_bytes();
+    if let Ok(text) = std::str::from_utf8(bytes) {
+        if !text.chars().any(needs_escape) {
+            return Cow::Borrowed(text);
+        }
+    }
+    let mut escaped = String::with_capacity(bytes.len() + 8);
+    for chunk in bytes.utf8_chunks() {
+        for char in chunk.valid().chars() {
+            if needs_escape(char) {
+                let mut buffer = [0; 4];
+                for byte in char.encode_utf8(&mut buffer).bytes() {
+                    let _ = write!(escaped, "%{:02X}", byte);
+                }
+            } else {
+                escaped.push(char);
+            }
+        }
+        for byte in chunk.invalid() {
+            let _ = write!(escaped, "%{:02X}", byte);
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+/// The path written by `escape`. A `%` without two hexadecimal digits after it is kept as it is
+pub fn unescape(text: &str) -> PathBuf {
+    if !text.contains('%') {
+        return PathBuf::from(text);
+    }
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut rest = text.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let decoded = (byte == b'%').then(|| tail.get(..2)).flatten()
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match decoded {
+            Some(decoded) => {
+                bytes.push(decoded);
+                rest = &tail[2..];
+            },
+            None => {
+                bytes.push(byte);
+                rest = tail;
+            },
+        }
+    }
+    from_bytes(bytes)
+}
+
+fn needs_escape(char: char) -> bool {
+    char == '%' || char.is_control()
+}
+
+#[cfg(unix)]
+fn from_bytes(bytes: Vec<u8>) -> PathBuf {
+    use std::os::unix::ffi::OsStringExt;
+    PathBuf::from(std::ffi::OsString::from_vec(bytes))
+}
+
+/// Elsewhere the names are Unicode and only the escaped characters come back
+#[cfg(not(unix))]
+fn from_bytes(bytes: Vec<u8>) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+    use std::path::Path;
+    use super::{escape, unescape};
+
+    #[test]
+    fn escaped_paths() {
+        assert!(matches!(escape(Path::new("dir/plain name.txt")), Cow::Borrowed("dir/plain name.txt")));
+        assert_eq!(escape(Path::new("tab\there\n")), "tab%09here%0A");
+        assert_eq!(escape(Path::new("100%")), "100%25");
+        assert_eq!(escape(Path::new("ünïcode\u{1b}[31m")), "ünïcode%1B[31m");
+        for text in ["dir/plain name.txt", "tab%09here%0A", "100%25", "ünïcode%1B[31m"] {
+            assert_eq!(escape(&unescape(text)), text);
+        }
+        // Not written by escape
+        assert_eq!(unescape("50%"), Path::new("50%"));
+        assert_eq!(unescape("%zz%4"), Path::new("%zz%4"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn invalid_utf8() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = Path::new(OsStr::from_bytes(b"dir/bad\xff\xfename\xc3"));
+        assert_eq!(escape(path), "dir/bad%FF%FEname%C3");
+        assert_eq!(unescape(&escape(path)), path);
+    }
+}
@@ -0,0 +1,1047 @@
+//! The copy as a pipeline: a producer (the walk of the tree, or the sorted files of the pre-scan) sends the work items
+//! into a bounded channel, a pool of workers copies them and the results are collected in a single place, which
+//! reports the errors and removes the sources once their destination directory is flushed.
+//! The unit of work is a file (or a batch of small files of a directory), not a directory: whatever the shape of the
+//! tree, every worker takes the next item, so a huge directory is copied by all the workers while it is listed.
+//! The channels are bounded so a fast walk waits for the copies instead of piling up items in memory
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use anyhow::Result;
+use log::{debug, info, warn};
+use tokio::sync::{mpsc, Mutex, OnceCell, OwnedSemaphorePermit};
+use tokio::task::JoinSet;
+use crate::adaptive::is_overload;
+use crate::backend::FileInfo;
+use crate::failures::Panicked;
+use crate::interrupt::Interrupted;
+use crate::journal::{Journal, Lookup};
+use crate::retry::is_transient;
+use crate::volatile;
+use crate::{create_destination_directory, process_link, process_small_files, remove_synced, report_file_error, report_path_error, CopyOptions, Volatile};
+
+/// Items waiting for the workers, per worker
+const WORK_ITEMS_PER_WORKER: usize = 64;
+
+/// What the producer asks the workers to do
+#[derive(Debug)]
+pub enum WorkItem {
+    /// Copy a file. The metadata is there when the producer already read it
+    File { from: PathBuf, to: PathBuf, metadata: Option<FileInfo> },
+    /// Copy a symbolic link as a link
+    Link { from: PathBuf, to: PathBuf },
+    /// Copy several small files in a single blocking task
+    SmallFiles(Vec<(PathBuf, PathBuf)>),
+    /// Every file of the source directory `source` was sent, `files` of them, and it is copied into `dest`
+    Dir { source: PathBuf, dest: PathBuf, files: usize },
+}
+
+/// What the workers send back
+enum Done {
+    /// The result of every file of an item
+    Files(Vec<(PathBuf, PathBuf, Result<Option<PathBuf>>)>),
+    Dir { source: PathBuf, dest: PathBuf, files: usize },
+}
+
+/// The copies being written when a failure or an interrupt can stop the run (stop_on_error, max_errors), by
+/// destination, so the partial destinations of the copies cancelled then are removed. Only the destinations that did not exist before
+/// their copy are removed
+#[derive(Debug, Default)]
+struct InFlight(std::sync::Mutex<HashMap<PathBuf, (PathBuf, bool)>>);
+
+impl InFlight {
+    /// The copy of `from` into `to` starts
+    async fn start(&self, from: &Path, to: &Path, options: &CopyOptions) {
+        let existed = options.destination().metadata(to).await.is_ok();
+        self.0.lock().unwrap().insert(to.to_owned(), (from.to_owned(), existed));
+    }
+
+    fn finish(&self, to: &Path) {
+        self.0.lock().unwrap().remove(to);
+    }
+
+    /// Remove what the cancelled copies wrote, returns how many there were. A source that is not there any more was
+    /// renamed to its destination, which is all that is left of it
+    async fn remove_partial(&self, options: &CopyOptions) -> usize {
+        let copies = std::mem::take(&mut *self.0.lock().unwrap());
+        let cancelled = copies.len();
+        for (to, (from, existed)) in copies {
+            if options.source().metadata(&from).await.is_err() {
+                continue;
+            }
+            if existed {
+                warn!("The copy of {:?} was cancelled while it replaced {:?}, which may be partial", from, to);
+                continue;
+            }
+            // The temporary file of the cancelled copy was removed with it, the destination is complete if it is there
+            if options.atomic {
+                continue;
+            }
+            match options.destination().remove_file(&to).await {
+                Ok(()) => info!("Removed the partial copy {:?} of the cancelled {:?}", to, from),
+                Err(error) => debug!("Cannot remove the partial copy {:?}: {:#}", to, error),
+            }
+        }
+        cancelled
+    }
+}
+
+/// Aborts the task when dropped, so a cancelled worker does not leave its copy running
+struct AbortOnDrop(tokio::task::AbortHandle);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Progress of a source directory in the collector
+#[derive(Debug, Default)]
+struct DirState {
+    dest: PathBuf,
+    /// Number of files, known once the producer is done with the directory
+    files: Option<usize>,
+    finished: usize,
+    /// Sources copied and waiting for the flush of the destination directory
+    unsynced: Vec<PathBuf>,
+}
+
+/// Destination directories created in a run. Several source directories can map to the same destination
+/// (`--strip-components`), it is only created (and its parent flushed) by the first one, the rest wait for it
+#[derive(Debug, Default)]
+pub struct CreatedDirs(std::sync::Mutex<HashMap<PathBuf, Arc<OnceCell<bool>>>>);
+
+impl CreatedDirs {
+    /// Create the destination directory unless it was already. False when it cannot be created, like
+    /// create_destination_directory, and then the error is only reported the first time
+    pub async fn create(&self, dest: &Path, source: &Path, options: &CopyOptions) -> Result<bool> {
+        let cell = self.0.lock().unwrap().entry(dest.to_owned()).or_default().clone();
+        cell.get_or_try_init(|| create_destination_directory(dest, source, options)).await.copied()
+    }
+}
+
+/// Run `produce` with the sender of the work items together with a worker per copy permit, and collect the results.
+/// Returns the result of the producer. The first error is returned when options.stop_on_error (or the one past
+/// options.max_errors), the producer and the copies in flight are cancelled then and their partial destinations removed.
+/// On an interrupt the producer is cancelled at once and the copies in flight are given its grace period to finish.
+/// A destination below `--min-free-space` stops the run like a failure with stop_on_error
+pub async fn run<T, P, F>(options: &Arc<CopyOptions>, produce: P) -> Result<T>
+where
+    P: FnOnce(mpsc::Sender<WorkItem>) -> F,
+    F: Future<Output = Result<T>> + Send + 'static,
+    T: Send + 'static,
+{
+    let workers = options.copy_permits.capacity().max(1);
+    let (work, items) = mpsc::channel(workers * WORK_ITEMS_PER_WORKER);
+    let (done, mut results) = mpsc::channel(workers * WORK_ITEMS_PER_WORKER);
+    let items = Arc::new(Mutex::new(items));
+    let stopped = Arc::new(AtomicBool::new(false));
+    let in_flight = (options.stop_on_error || options.max_errors > 0 || options.interrupt.is_some() || options.min_free_space.is_some())
+        .then(Arc::<InFlight>::default);
+    let mut pool = JoinSet::new();
+    for _ in 0..workers {
+        pool.spawn(worker(items.clone(), done.clone(), stopped.clone(), in_flight.clone(), options.clone()));
+    }
+    drop(done);
+    let started = Instant::now();
+    let producer = tokio::spawn(produce(work));
+
+    let collecting = collect(&mut results, options);
+    tokio::pin!(collecting);
+    let interrupted = async {
+        match &options.interrupt {
+            Some(interrupt) => interrupt.requested().await,
+            None => std::future::pending().await,
+        }
+    };
+    let low_space = async {
+        match &options.min_free_space {
+            Some(monitor) => monitor.low().await,
+            None => std::future::pending().await,
+        }
+    };
+    let collected = tokio::select! {
+        collected = &mut collecting => collected,
+        panicked = panicked_worker(&mut pool) => Err(panicked.into()),
+        out_of_space = low_space => Err(out_of_space.into()),
+        () = interrupted => {
+            producer.abort();
+            stopped.store(true, Ordering::Release);
+            let grace = options.interrupt.as_ref().map_or(Duration::ZERO, |interrupt| interrupt.grace);
+            match tokio::time::timeout(grace, &mut collecting).await {
+                Ok(Ok(())) => Err(Interrupted { abandoned: 0 }.into()),
+                Ok(Err(error)) => Err(error),
+                Err(_) => {
+                    warn!("The copies in flight did not finish in the grace period, they are abandoned");
+                    Err(Interrupted { abandoned: 0 }.into())
+                },
+            }
+        },
+    };
+    if let Err(error) = collected {
+        producer.abort();
+        pool.shutdown().await;
+        let cancelled = match &in_flight {
+            Some(in_flight) => in_flight.remove_partial(options).await,
+            None => 0,
+        };
+        let interrupted = Interrupted { abandoned: cancelled };
+        return match error.downcast::<Interrupted>() {
+            Ok(_) => Err(interrupted.into()),
+            // Like a failure in the grace period
+            Err(error) if options.interrupt.as_ref().is_some_and(|interrupt| interrupt.is_requested()) => Err(error.context(interrupted)),
+            Err(error) => Err(error),
+        };
+    }
+    // The results are all in, the workers are done. Nothing follows the copy (like removing the source) before they
+    // have all returned
+    while let Some(joined) = pool.join_next().await {
+        if let Some(panicked) = joined.err().and_then(|error| Panicked::of(error, "a copy worker")) {
+            return Err(panicked.into());
+        }
+    }
+    info!("The {} workers were busy {:.1}% of the copy", workers, options.stats.utilization(started.elapsed(), workers));
+    match producer.await {
+        Ok(produced) => produced,
+        Err(error) => Err(Panicked::of(error, "the walk of the source").map_or_else(|| anyhow::anyhow!("The walk of the source was cancelled"), Into::into)),
+    }
+}
+
+/// The panic of the first worker of `pool` that panics, the items it took are never done. Pending when none does
+async fn panicked_worker(pool: &mut JoinSet<()>) -> Panicked {
+    loop {
+        match pool.join_next().await {
+            Some(Err(error)) => {
+                if let Some(panicked) = Panicked::of(error, "a copy worker") {
+                    return panicked;
+                }
+            },
+            Some(Ok(())) => {},
+            None => std::future::pending().await,
+        }
+    }
+}
+
+/// Copy the items until the producer is done. With options.stop_on_error no worker takes another item after a failure,
+/// nor past options.max_errors
+async fn worker(
+    items: Arc<Mutex<mpsc::Receiver<WorkItem>>>,
+    done: mpsc::Sender<Done>,
+    stopped: Arc<AtomicBool>,
+    in_flight: Option<Arc<InFlight>>,
+    options: Arc<CopyOptions>,
+) {
+    loop {
+        let Some(item) = items.lock().await.recv().await else { break };
+        if stopped.load(Ordering::Acquire) {
+            break;
+        }
+        let busy = Instant::now();
+        // When the copy starts, once it has its permit
+        let mut started = busy;
+        let finished = match item {
+            WorkItem::File { from, to, metadata } => {
+                if let Some(metadata) = &metadata {
+                    debug!("Queued: {:?}, {}", from, options.stats.size(metadata.len));
+                }
+                let journaled = match &options.journal {
+                    Some(journal) => journal_lookup(journal, &from, metadata, &options).await,
+                    None => Lookup::Untracked,
+                };
+                if journaled == Lookup::Done {
+                    debug!("Skip file done in the journal: {:?}", from);
+                    options.stats.skipped_by_journal();
+                    Done::Files(vec![(from, to, Ok(None))])
+                } else {
+                    let _device = device_permit(&from, &options).await;
+                    let _permit = options.copy_permits.acquire().await;
+                    started = Instant::now();
+                    if let Some(in_flight) = &in_flight {
+                        in_flight.start(&from, &to, &options).await;
+                    }
+                    let mut result = copy_file(&from, &to, &options).await;
+                    if let (Lookup::Pending(record), Some(journal)) = (journaled, &options.journal) {
+                        if let Err(error) = journal.record(record, result.is_ok()).await {
+                            result = result.and(Err(error));
+                        }
+                    }
+                    Done::Files(vec![(from, to, result)])
+                }
+            },
+            WorkItem::Link { from, to } => {
+                let _permit = options.copy_permits.acquire().await;
+                started = Instant::now();
+                if let Some(in_flight) = &in_flight {
+                    in_flight.start(&from, &to, &options).await;
+                }
+                let result = process_link(&from, &to, &options).await;
+                Done::Files(vec![(from, to, result)])
+            },
+            WorkItem::SmallFiles(batch) => {
+                // The files of a batch are in the same directory
+                let _device = match batch.first() {
+                    Some((from, _)) => device_permit(from, &options).await,
+                    None => None,
+                };
+                let _permit = options.copy_permits.acquire().await;
+                started = Instant::now();
+                if let Some(in_flight) = &in_flight {
+                    for (from, to) in &batch {
+                        in_flight.start(from, to, &options).await;
+                    }
+                }
+                let finished = process_small_files(batch, &options).await;
+                let mut results = vec![];
+                for (from, to, result) in finished.results {
+                    let result = retried(&from, &to, result, &options).await;
+                    results.push((from, to, result));
+                }
+                // The files that are not small after all
+                for (from, to) in finished.large {
+                    let result = copy_file(&from, &to, &options).await;
+                    results.push((from, to, result));
+                }
+                Done::Files(results)
+            },
+            WorkItem::Dir { source, dest, files } => Done::Dir { source, dest, files },
+        };
+        options.stats.worker_busy(busy.elapsed());
+        if let (Some(in_flight), Done::Files(files)) = (&in_flight, &finished) {
+            for (_, to, _) in files {
+                in_flight.finish(to);
+            }
+        }
+        if let (Some(adaptive), Done::Files(files)) = (&options.adaptive, &finished) {
+            let overloaded = files.iter().any(|(_, _, result)| result.as_ref().is_err_and(is_overload));
+            let bytes = options.stats.bytes_copied.load(Ordering::Relaxed);
+            adaptive.finished(started.elapsed(), overloaded, bytes, options.stats.files_done());
+        }
+        let failed = matches!(&finished, Done::Files(files) if files.iter().any(|(_, _, result)| result.is_err()));
+        if (options.stop_on_error && failed) || options.too_many_errors() {
+            stopped.store(true, Ordering::Release);
+        }
+        if done.send(finished).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Look the source `from` up in the journal, with the metadata of the producer when it has it. A source that cannot
+/// be read is left to the copy, which reports the error
+async fn journal_lookup(journal: &Journal, from: &Path, metadata: Option<FileInfo>, options: &CopyOptions) -> Lookup {
+    let metadata = match metadata {
+        Some(metadata) => metadata,
+        None => match options.source().metadata(from).await {
+            Ok(metadata) => metadata,
+            Err(_) => return Lookup::Untracked,
+        },
+    };
+    journal.lookup(from, &metadata)
+}
+
+/// The permit of the source device with --workers-per-disk. It is taken before the global permit so the copies
+/// waiting for a busy disk do not keep the other disks waiting
+async fn device_permit(from: &Path, options: &CopyOptions) -> Option<OwnedSemaphorePermit> {
+    options.device_permits.as_ref()?.acquire_for(from).await
+}
+
+/// Copy a file, again with --retries while it fails with a transient error
+async fn copy_file(from: &Path, to: &Path, options: &Arc<CopyOptions>) -> Result<Option<PathBuf>> {
+    let result = copy_attempt(from, to, options).await;
+    retried(from, to, result, options).await
+}
+
+/// The final result of a copy that gave `result`: it is copied again after a delay while it fails with a transient
+/// error and there are retries left. Every attempt opens the source again and starts without the partial destination
+/// of the previous one. With --atomic that is a temporary file, removed with its attempt, and the destination is left
+/// as it was
+async fn retried(from: &Path, to: &Path, mut result: Result<Option<PathBuf>>, options: &Arc<CopyOptions>) -> Result<Option<PathBuf>> {
+    let Some(retry) = &options.retry else { return result };
+    let mut attempt = 0;
+    loop {
+        let error = match result {
+            Err(error) if attempt < retry.retries && (is_transient(&error) || retries_changed(&error, options)) => error,
+            Ok(copied) => {
+                if attempt > 0 {
+                    info!("Copied {:?} after {} retries", from, attempt);
+                    options.stats.retried();
+                }
+                return Ok(copied);
+            },
+            Err(error) => return Err(error),
+        };
+        attempt += 1;
+        let delay = retry.delay(attempt);
+        warn!("{:#}, retry {} of {} in {:.1?}", error, attempt, retry.retries, delay);
+        tokio::time::sleep(delay).await;
+        // A source that is not there any more cannot be copied again, its destination may be all that is left of it
+        if options.source().metadata(from).await.is_err() {
+            return Err(error);
+        }
+        if !options.atomic {
+            match options.destination().remove_file(to).await {
+                Err(error) if !error.chain().any(|cause| cause.downcast_ref::<io::Error>().is_some_and(|error| error.kind() == io::ErrorKind::NotFound)) =>
+                    debug!("Cannot remove the partial copy {:?}: {:#}", to, error),
+                _ => {},
+            }
+        }
+        result = copy_attempt(from, to, options).await;
+    }
+}
+
+/// Whether the failure is a source that changed while it was copied, and those are copied again
+fn retries_changed(error: &anyhow::Error, options: &CopyOptions) -> bool {
+    options.volatile == Volatile::Retry && volatile::is_changed(error)
+}
+
+/// Copy a file in its own task so a panic is reported as the failure of the file and the worker goes on.
+/// With --file-timeout the task is aborted when it takes longer
+async fn copy_attempt(from: &Path, to: &Path, options: &Arc<CopyOptions>) -> Result<Option<PathBuf>> {
+    let limit = match &options.file_timeout {
+        Some(timeout) if timeout.per_gb.is_some() => {
+            // The copy reports the error when the source cannot be read
+            let len = options.source().metadata(from).await.map_or(0, |metadata| metadata.len);
+            Some(timeout.limit(len))
+        },
+        Some(timeout) => Some(timeout.base),
+        None => None,
+    };
+    let copy = {
+        let (from, to, options) = (from.to_owned(), to.to_owned(), options.clone());
+        tokio::spawn(async move { options.destination().copy(&from, &to, &options).await })
+    };
+    let _cancel = AbortOnDrop(copy.abort_handle());
+    let Some(limit) = limit else {
+        return copy.await?;
+    };
+    let started = Instant::now();
+    let abort = copy.abort_handle();
+    match tokio::time::timeout(limit, copy).await {
+        Ok(copied) => copied?,
+        Err(_) => {
+            abort.abort();
+            timed_out(from, to, started.elapsed(), options).await
+        },
+    }
+}
+
+/// The failure of a copy that took too long. The partial destination is removed, unless the source is not there
+/// any more: it was renamed to the destination before the copy was aborted. With --atomic the temporary file is
+/// removed with the aborted copy and the destination is left as it was. The sources of the aborted copies are
+/// always kept, their removal is deferred to `collect` (see `CopyOptions::defers_removal`)
+async fn timed_out(from: &Path, to: &Path, elapsed: Duration, options: &CopyOptions) -> Result<Option<PathBuf>> {
+    if options.source().metadata(from).await.is_err() {
+        warn!("{:?} was moved to {:?} while its copy was timing out", from, to);
+        return Ok(None);
+    }
+    if !options.atomic {
+        if let Err(error) = options.destination().remove_file(to).await {
+            debug!("Cannot remove the partial copy {:?}: {:#}", to, error);
+        }
+    }
+    let error = io::Error::new(io::ErrorKind::TimedOut, format!("The copy timed out after {:.1?}", elapsed));
+    Err(anyhow::Error::new(error).context(format!("Cannot copy file: {:?}", from)))
+}
+
+/// Report the results as they come. The sources of a directory are removed after it is flushed: every
+/// options.fsync_batch files and once all its files are finished
+async fn collect(results: &mut mpsc::Receiver<Done>, options: &CopyOptions) -> Result<()> {
+    let mut dirs: HashMap<PathBuf, DirState> = HashMap::new();
+    while let Some(done) = results.recv().await {
+        let finished = match done {
+            Done::Files(files) => {
+                let mut finished = vec![];
+                for (from, to, result) in files {
+                    let (Some(source), Some(dest)) = (from.parent(), to.parent()) else { continue };
+                    let state = dir_state(&mut dirs, source, dest);
+                    state.finished += 1;
+                    let copied = match result {
+                        Ok(copied) => copied,
+                        Err(error) => {
+                            report_path_error(&from, error, options)?;
+                            None
+                        },
+                    };
+                    if let Some(copied) = copied {
+                        state.unsynced.push(copied);
+                        if state.unsynced.len() >= options.fsync_batch {
+                            remove_synced(&state.dest, &mut state.unsynced, options).await?;
+                        }
+                    }
+                    finished.push(source.to_owned());
+                }
+                finished
+            },
+            Done::Dir { source, dest, files } => {
+                dir_state(&mut dirs, &source, &dest).files = Some(files);
+                vec![source]
+            },
+        };
+        for source in finished {
+            if dirs.get(&source).is_some_and(|state| state.files == Some(state.finished)) {
+                let mut state = dirs.remove(&source).expect("the directory is tracked");
+                if options.defers_removal() {
+                    remove_synced(&state.dest, &mut state.unsynced, options).await?;
+                }
+            }
+        }
+    }
+    // The directories whose producer failed
+    for (_, mut state) in dirs {
+        if options.defers_removal() && !state.unsynced.is_empty() {
+            if let Err(error) = remove_synced(&state.dest, &mut state.unsynced, options).await {
+                report_file_error(error, options)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn dir_state<'a>(dirs: &'a mut HashMap<PathBuf, DirState>, source: &Path, dest: &Path) -> &'a mut DirState {
+    dirs.entry(source.to_owned()).or_insert_with(|| DirState { dest: dest.to_owned(), ..Default::default() })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io;
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+    use crate::backend::{Backend, BackendFuture, DirEntries, FileInfo};
+    use crate::failures::{self, Panicked};
+    use crate::interrupt::{Interrupt, Interrupted};
+    use crate::limit::{CopyPermits, FileTimeout};
+    use crate::retry::Retry;
+    use crate::space::{Monitor, OutOfSpace};
+    use crate::test_support::{init, MemoryBackend};
+    use crate::{copy_tree, CopyOptions};
+    use super::{run, CreatedDirs, WorkItem, WORK_ITEMS_PER_WORKER};
+
+    /// A memory tree whose copies take a while and count how many run at the same time. The copies of the files
+    /// named `stuck` write a part of the destination and never finish, the ones in `failures` write a part and fail
+    /// with that error that number of times. With --atomic the part would be a temporary file, nothing is written. The directories in `failures` cannot be listed, with that error, the same.
+    /// Listing or copying a path of `panics` panics, like a bug
+    #[derive(Debug, Default)]
+    struct SlowBackend {
+        memory: MemoryBackend,
+        copying: AtomicUsize,
+        most_copying: AtomicUsize,
+        failures: std::sync::Mutex<HashMap<PathBuf, (usize, io::ErrorKind)>>,
+        panics: std::sync::Mutex<Vec<PathBuf>>,
+        listing: AtomicUsize,
+        most_listing: AtomicUsize,
+    }
+
+    const STUCK: &str = "stuck";
+
+    impl Backend for SlowBackend {
+        fn read_dir<'a>(&'a self, dir: &'a Path) -> BackendFuture<'a, Box<dyn DirEntries>> {
+            Box::pin(async move {
+                let listing = self.listing.fetch_add(1, Ordering::SeqCst) + 1;
+                self.most_listing.fetch_max(listing, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(5)).await;
+                assert!(!self.panics.lock().unwrap().iter().any(|path| path == dir), "injected panic");
+                let failure = self.failures.lock().unwrap().get_mut(dir).filter(|(count, _)| *count > 0).map(|(count, kind)| {
+                    *count -= 1;
+                    *kind
+                });
+                let entries = match failure {
+                    Some(kind) => Err(io::Error::from(kind).into()),
+                    None => self.memory.read_dir(dir).await,
+                };
+                self.listing.fetch_sub(1, Ordering::SeqCst);
+                entries
+            })
+        }
+
+        fn metadata<'a>(&'a self, path: &'a Path) -> BackendFuture<'a, FileInfo> {
+            self.memory.metadata(path)
+        }
+
+        fn create_dir_all<'a>(&'a self, dir: &'a Path) -> BackendFuture<'a, ()> {
+            self.memory.create_dir_all(dir)
+        }
+
+        fn sync_directory<'a>(&'a self, dir: &'a Path) -> BackendFuture<'a, ()> {
+            self.memory.sync_directory(dir)
+        }
+
+        fn copy<'a>(&'a self, from: &'a Path, to: &'a Path, options: &'a CopyOptions) -> BackendFuture<'a, Option<PathBuf>> {
+            Box::pin(async move {
+                assert!(!self.panics.lock().unwrap().iter().any(|path| path == from), "injected panic");
+                if from.file_name() == Some(STUCK.as_ref()) {
+                    if !options.atomic {
+                        self.memory.add_file(to.to_str().unwrap(), "part", SystemTime::now());
+                    }
+                    std::future::pending::<()>().await;
+                }
+                if let Some((count, kind)) = self.failures.lock().unwrap().get_mut(from).filter(|(count, _)| *count > 0) {
+                    *count -= 1;
+                    if !options.atomic {
+                        self.memory.add_file(to.to_str().unwrap(), "part", SystemTime::now());
+                    }
+                    return Err(anyhow::Error::new(io::Error::from(*kind)).context(format!("Cannot copy file: {:?}", from)));
+                }
+                let copying = self.copying.fetch_add(1, Ordering::SeqCst) + 1;
+                self.most_copying.fetch_max(copying, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(5)).await;
+                let result = self.memory.copy(from, to, options).await;
+                self.copying.fetch_sub(1, Ordering::SeqCst);
+                result
+            })
+        }
+
+        fn remove_file<'a>(&'a self, path: &'a Path) -> BackendFuture<'a, ()> {
+            self.memory.remove_file(path)
+        }
+
+        fn remove_dir_all<'a>(&'a self, dir: &'a Path) -> BackendFuture<'a, ()> {
+            self.memory.remove_dir_all(dir)
+        }
+    }
+
+    fn memory_options(memory: &Arc<MemoryBackend>, permits: usize) -> Arc<CopyOptions> {
+        Arc::new(CopyOptions {
+            copy_permits: CopyPermits::new(permits),
+            source: Some(memory.clone()),
+            destination: Some(memory.clone()),
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn backpressure() {
+        let memory = Arc::new(MemoryBackend::default());
+        for i in 0..200 {
+            memory.add_file(&format!("/source/{i}"), "text", SystemTime::now());
+        }
+        memory.add_directory("/dest");
+        let options = memory_options(&memory, 1);
+        // The only worker takes an item and waits for the permit
+        let permit = options.copy_permits.acquire().await;
+
+        let sent = Arc::new(AtomicUsize::new(0));
+        let producer_sent = sent.clone();
+        let pipeline_options = options.clone();
+        let pipeline = tokio::spawn(async move {
+            run(&pipeline_options, move |work| async move {
+                for i in 0..200 {
+                    let item = WorkItem::File { from: PathBuf::from(format!("/source/{i}")), to: PathBuf::from(format!("/dest/{i}")), metadata: None };
+                    work.send(item).await?;
+                    producer_sent.fetch_add(1, Ordering::SeqCst);
+                }
+                let item = WorkItem::Dir { source: PathBuf::from("/source"), dest: PathBuf::from("/dest"), files: 200 };
+                work.send(item).await?;
+                anyhow::Ok(())
+            }).await
+        });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        // The channel is full and the producer waits
+        assert_eq!(sent.load(Ordering::SeqCst), WORK_ITEMS_PER_WORKER + 1);
+        assert_eq!(memory.read("/dest/0"), None);
+
+        drop(permit);
+        pipeline.await.unwrap().unwrap();
+        assert_eq!(sent.load(Ordering::SeqCst), 200);
+        for i in 0..200 {
+            assert_eq!(memory.read(&format!("/dest/{i}")).as_deref(), Some("text"));
+        }
+    }
+
+    #[tokio::test]
+    async fn file_timeout() {
+        let backend = Arc::new(SlowBackend::default());
+        backend.memory.add_file("/source/stuck", "text", SystemTime::now());
+        backend.memory.add_file("/source/fine", "text", SystemTime::now());
+        let options = Arc::new(CopyOptions {
+            remove_source: true,
+            file_timeout: Some(FileTimeout { base: Duration::from_millis(100), per_gb: None }),
+            source: Some(backend.clone()),
+            destination: Some(backend.clone()),
+            ..Default::default()
+        });
+        backend.memory.add_directory("/dest");
+        copy_files(&options, &[STUCK, "fine"]).await.unwrap();
+
+        // The partial copy is removed and the source is kept
+        assert_eq!(backend.memory.read("/dest/stuck"), None);
+        assert_eq!(backend.memory.read("/source/stuck").as_deref(), Some("text"));
+        assert_eq!(backend.memory.read("/dest/fine").as_deref(), Some("text"));
+        assert_eq!(backend.memory.read("/source/fine"), None);
+
+        backend.memory.add_file("/source/fine", "text", SystemTime::now());
+        let options = Arc::new(CopyOptions { stop_on_error: true, ..(*options).clone() });
+        let error = copy_files(&options, &[STUCK]).await.unwrap_err();
+        assert!(format!("{:#}", error).contains("timed out after"), "{:#}", error);
+    }
+
+    /// Copy the files of /source with these names into /dest through the pipeline
+    async fn copy_files(options: &Arc<CopyOptions>, names: &[&'static str]) -> anyhow::Result<()> {
+        let names = names.to_vec();
+        run(options, |work| async move {
+            for name in &names {
+                let item = WorkItem::File { from: Path::new("/source").join(name), to: Path::new("/dest").join(name), metadata: None };
+                work.send(item).await?;
+            }
+            work.send(WorkItem::Dir { source: PathBuf::from("/source"), dest: PathBuf::from("/dest"), files: names.len() }).await?;
+            anyhow::Ok(())
+        }).await
+    }
+
+    #[tokio::test]
+    async fn retries() {
+        let backend = Arc::new(SlowBackend::default());
+        for name in ["flaky", "denied", "broken", "fine"] {
+            backend.memory.add_file(&format!("/source/{name}"), name, SystemTime::now());
+        }
+        backend.memory.add_directory("/dest");
+        *backend.failures.lock().unwrap() = HashMap::from([
+            (PathBuf::from("/source/flaky"), (2, io::ErrorKind::ResourceBusy)),
+            (PathBuf::from("/source/denied"), (1, io::ErrorKind::PermissionDenied)),
+            (PathBuf::from("/source/broken"), (5, io::ErrorKind::TimedOut)),
+        ]);
+        let options = Arc::new(CopyOptions {
+            retry: Some(Retry { retries: 3, delay: Duration::from_millis(1) }),
+            source: Some(backend.clone()),
+            destination: Some(backend.clone()),
+            ..Default::default()
+        });
+        copy_files(&options, &["flaky", "denied", "broken", "fine"]).await.unwrap();
+
+        assert_eq!(backend.memory.read("/dest/flaky").as_deref(), Some("flaky"));
+        assert_eq!(backend.memory.read("/dest/fine").as_deref(), Some("fine"));
+        // Not transient, no retry
+        assert_eq!(backend.failures.lock().unwrap()[Path::new("/source/denied")].0, 0);
+        assert_eq!(backend.memory.read("/dest/denied").as_deref(), Some("part"));
+        // Every retry failed
+        assert_eq!(backend.failures.lock().unwrap()[Path::new("/source/broken")].0, 1);
+        assert_eq!(backend.memory.read("/dest/broken").as_deref(), Some("part"));
+        assert_eq!(options.stats.summary(), "2 files copied, 0 files cloned, 0 files renamed, 9 bytes (1 only after a retry)");
+    }
+
+    #[tokio::test]
+    async fn atomic_failures_keep_the_destination() {
+        let backend = Arc::new(SlowBackend::default());
+        for name in ["broken", STUCK] {
+            backend.memory.add_file(&format!("/source/{name}"), "new", SystemTime::now());
+            backend.memory.add_file(&format!("/dest/{name}"), "original", SystemTime::now());
+        }
+        backend.failures.lock().unwrap().insert(PathBuf::from("/source/broken"), (5, io::ErrorKind::TimedOut));
+        let options = Arc::new(CopyOptions {
+            atomic: true,
+            retry: Some(Retry { retries: 2, delay: Duration::from_millis(1) }),
+            file_timeout: Some(FileTimeout { base: Duration::from_millis(100), per_gb: None }),
+            source: Some(backend.clone()),
+            destination: Some(backend.clone()),
+            ..Default::default()
+        });
+        copy_files(&options, &["broken", STUCK]).await.unwrap();
+
+        // Neither the retries nor the timeout removed the previous copies
+        assert_eq!(backend.failures.lock().unwrap()[Path::new("/source/broken")].0, 2);
+        assert_eq!(backend.memory.read("/dest/broken").as_deref(), Some("original"));
+        assert_eq!(backend.memory.read("/dest/stuck").as_deref(), Some("original"));
+        assert_eq!(options.stats.files_failed.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn unlistable_directories() {
+        let backend = Arc::new(SlowBackend::default());
+        for name in ["first", "denied", "denied/nested", "last"] {
+            backend.memory.add_file(&format!("/source/{name}/file"), name, SystemTime::now());
+        }
+        backend.failures.lock().unwrap().insert(PathBuf::from("/source/denied"), (2, io::ErrorKind::PermissionDenied));
+        let options = Arc::new(CopyOptions {
+            source: Some(backend.clone()),
+            destination: Some(backend.clone()),
+            ..Default::default()
+        });
+        // The rest of the tree is copied
+        copy_tree(Path::new("/source"), Path::new("/dest"), options.clone(), 2).await.unwrap();
+        assert_eq!(backend.memory.read("/dest/first/file").as_deref(), Some("first"));
+        assert_eq!(backend.memory.read("/dest/last/file").as_deref(), Some("last"));
+        assert!(!backend.memory.is_dir("/dest/denied"));
+        assert_eq!(options.stats.files_failed.load(Ordering::Relaxed), 1);
+        assert_eq!(options.stats.directories_failed.load(Ordering::Relaxed), 1);
+        assert!(options.stats.summary().ends_with(", 1 directories could not be listed"), "{}", options.stats.summary());
+        let failures = options.failures.list();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].path.as_deref(), Some(Path::new("/source/denied")));
+        assert_eq!(failures[0].kind, "PermissionDenied");
+        assert!(failures[0].message.starts_with("Cannot list directory: \"/source/denied\""), "{}", failures[0].message);
+
+        // Unless the copy stops at the first error
+        let options = Arc::new(CopyOptions {
+            stop_on_error: true,
+            source: Some(backend.clone()),
+            destination: Some(backend.clone()),
+            ..Default::default()
+        });
+        assert!(copy_tree(Path::new("/source"), Path::new("/other"), options.clone(), 2).await.is_err());
+        assert_eq!(options.stats.directories_failed.load(Ordering::Relaxed), 1);
+        assert_eq!(options.failures.list().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn panicked_tasks() {
+        let backend = Arc::new(SlowBackend::default());
+        for name in ["first", "buggy", "last"] {
+            backend.memory.add_file(&format!("/source/{name}/file"), name, SystemTime::now());
+        }
+        let options = Arc::new(CopyOptions {
+            source: Some(backend.clone()),
+            destination: Some(backend.clone()),
+            ..Default::default()
+        });
+
+        // The subtree is not left out, the run stops
+        *backend.panics.lock().unwrap() = vec![PathBuf::from("/source/buggy")];
+        let error = copy_tree(Path::new("/source"), Path::new("/dest"), options.clone(), 2).await.unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<Panicked>().unwrap(),
+            &Panicked { task: "the copy of the directory \"/source/buggy\"".into(), message: "injected panic".into() },
+        );
+        assert_eq!(failures::exit_code(&error), failures::EXIT_INTERNAL_ERROR);
+
+        // A copy runs in a task of its own, its panic is the failure of the file
+        *backend.panics.lock().unwrap() = vec![PathBuf::from("/source/first/file")];
+        let options = Arc::new(CopyOptions {
+            source: Some(backend.clone()),
+            destination: Some(backend.clone()),
+            ..Default::default()
+        });
+        copy_tree(Path::new("/source"), Path::new("/other"), options.clone(), 2).await.unwrap();
+        assert_eq!(backend.memory.read("/other/buggy/file").as_deref(), Some("buggy"));
+        let failures = options.failures.list();
+        assert_eq!(failures.len(), 1);
+        assert!(failures[0].message.contains("injected panic"), "{}", failures[0].message);
+    }
+
+    #[tokio::test]
+    async fn huge_directory_uses_every_worker() {
+        let backend = Arc::new(SlowBackend::default());
+        for i in 0..100 {
+            backend.memory.add_file(&format!("/source/huge/{i}"), "text", SystemTime::now());
+        }
+        for i in 0..10 {
+            backend.memory.add_file(&format!("/source/small{i}/file"), "text", SystemTime::now());
+        }
+        let options = Arc::new(CopyOptions {
+            copy_permits: CopyPermits::new(4),
+            source: Some(backend.clone()),
+            destination: Some(backend.clone()),
+            ..Default::default()
+        });
+        // A single directory is listed at a time, its files are still copied by all the workers
+        copy_tree(Path::new("/source"), Path::new("/dest"), options.clone(), 1).await.unwrap();
+
+        assert_eq!(backend.most_copying.load(Ordering::SeqCst), 4);
+        assert_eq!(backend.memory.read("/dest/huge/99").as_deref(), Some("text"));
+        assert_eq!(backend.memory.read("/dest/small9/file").as_deref(), Some("text"));
+    }
+
+    #[tokio::test]
+    async fn listings_and_copies_are_bounded_apart() {
+        let backend = Arc::new(SlowBackend::default());
+        for i in 0..20 {
+            for j in 0..10 {
+                backend.memory.add_file(&format!("/source/dir{i}/{j}"), "text", SystemTime::now());
+            }
+        }
+        let options = Arc::new(CopyOptions {
+            copy_permits: CopyPermits::new(2),
+            source: Some(backend.clone()),
+            destination: Some(backend.clone()),
+            ..Default::default()
+        });
+        copy_tree(Path::new("/source"), Path::new("/dest"), options.clone(), 8).await.unwrap();
+        assert_eq!(backend.most_copying.load(Ordering::SeqCst), 2);
+        assert_eq!(backend.most_listing.load(Ordering::SeqCst), 8);
+
+        // More copies than listings, a directory has enough files for all of them
+        let options = Arc::new(CopyOptions { copy_permits: CopyPermits::new(6), ..(*options).clone() });
+        backend.most_copying.store(0, Ordering::SeqCst);
+        backend.most_listing.store(0, Ordering::SeqCst);
+        copy_tree(Path::new("/source"), Path::new("/other"), options, 1).await.unwrap();
+        assert_eq!(backend.most_listing.load(Ordering::SeqCst), 1);
+        assert_eq!(backend.most_copying.load(Ordering::SeqCst), 6);
+        assert_eq!(backend.memory.read("/other/dir19/9").as_deref(), Some("text"));
+    }
+
+    #[tokio::test]
+    async fn directories_created_once() {
+        let base_dir = init("directories_created_once").await;
+
+        let dest = base_dir.join("dest");
+        let created = CreatedDirs::default();
+        let options = CopyOptions::default();
+        assert!(created.create(&dest, &base_dir, &options).await.unwrap());
+        assert!(dest.is_dir());
+        tokio::fs::remove_dir(&dest).await.unwrap();
+        // Already created in this run
+        assert!(created.create(&dest, &base_dir, &options).await.unwrap());
+        assert!(!dest.exists());
+
+        // The failure is remembered too
+        let file = base_dir.join("file");
+        tokio::fs::write(&file, "text").await.unwrap();
+        assert!(!created.create(&file.join("dir"), &base_dir, &options).await.unwrap());
+        tokio::fs::remove_file(&file).await.unwrap();
+        assert!(!created.create(&file.join("dir"), &base_dir, &options).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn stop_cancels_the_producer() {
+        let memory = Arc::new(MemoryBackend::default());
+        let options = Arc::new(CopyOptions { stop_on_error: true, ..(*memory_options(&memory, 2)).clone() });
+
+        // The file does not exist and the producer never ends by itself
+        let result = run::<(), _, _>(&options, |work| async move {
+            loop {
+                let item = WorkItem::File { from: PathBuf::from("/missing"), to: PathBuf::from("/dest/missing"), metadata: None };
+                work.send(item).await?;
+            }
+        }).await;
+        assert!(result.unwrap_err().to_string().contains("/missing"));
+    }
+
+    #[tokio::test]
+    async fn fail_fast_removes_partial_copies() {
+        let backend = Arc::new(SlowBackend::default());
+        for path in ["/source/new/stuck", "/source/old/stuck", "/source/denied"] {
+            backend.memory.add_file(path, "text", SystemTime::now());
+        }
+        backend.memory.add_file("/dest/old/stuck", "previous", SystemTime::now());
+        backend.failures.lock().unwrap().insert(PathBuf::from("/source/denied"), (1, io::ErrorKind::PermissionDenied));
+        let options = Arc::new(CopyOptions {
+            stop_on_error: true,
+            copy_permits: CopyPermits::new(3),
+            source: Some(backend.clone()),
+            destination: Some(backend.clone()),
+            ..Default::default()
+        });
+
+        let error = run(&options, |work| async move {
+            for dir in ["new", "old"] {
+                let (from, to) = (Path::new("/source").join(dir).join(STUCK), Path::new("/dest").join(dir).join(STUCK));
+                work.send(WorkItem::File { from, to, metadata: None }).await?;
+            }
+            // Both copies are writing when the other one fails
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            work.send(WorkItem::File { from: PathBuf::from("/source/denied"), to: PathBuf::from("/dest/denied"), metadata: None }).await?;
+            std::future::pending::<()>().await;
+            anyhow::Ok(())
+        }).await.unwrap_err();
+        assert!(error.to_string().contains("/source/denied"), "{:#}", error);
+        // The cancelled copy into a new destination leaves nothing, the destination that was there is kept
+        assert_eq!(backend.memory.read("/dest/new/stuck"), None);
+        assert!(backend.memory.read("/dest/old/stuck").is_some());
+        assert_eq!(backend.memory.read("/source/new/stuck").as_deref(), Some("text"));
+    }
+
+    #[tokio::test]
+    async fn max_errors() {
+        let backend = Arc::new(SlowBackend::default());
+        let names = ["0", "1", "2", "3", "4", "fine"];
+        for name in names {
+            backend.memory.add_file(&format!("/source/{name}"), "text", SystemTime::now());
+        }
+        backend.memory.add_directory("/dest");
+        *backend.failures.lock().unwrap() = (0..5)
+            .map(|i| (PathBuf::from(format!("/source/{i}")), (1, io::ErrorKind::PermissionDenied)))
+            .collect();
+        let options = Arc::new(CopyOptions {
+            max_errors: 2,
+            copy_permits: CopyPermits::new(1),
+            source: Some(backend.clone()),
+            destination: Some(backend.clone()),
+            ..Default::default()
+        });
+
+        let error = copy_files(&options, &names).await.unwrap_err();
+        assert!(format!("{:#}", error).contains("More than --max-errors 2"), "{:#}", error);
+        assert_eq!(options.stats.files_failed.load(Ordering::Relaxed), 3);
+        assert_eq!(backend.memory.read("/dest/fine"), None);
+
+        // Up to the limit the copy goes on
+        let options = Arc::new(CopyOptions { max_errors: 5, stats: Arc::default(), ..(*options).clone() });
+        backend.failures.lock().unwrap().values_mut().for_each(|(count, _)| *count = 1);
+        copy_files(&options, &names).await.unwrap();
+        assert_eq!(options.stats.files_failed.load(Ordering::Relaxed), 5);
+        assert_eq!(backend.memory.read("/dest/fine").as_deref(), Some("text"));
+    }
+
+    #[tokio::test]
+    async fn interrupted() {
+        let backend = Arc::new(SlowBackend::default());
+        for name in [STUCK, "done", "late"] {
+            backend.memory.add_file(&format!("/source/{name}"), "text", SystemTime::now());
+        }
+        backend.memory.add_directory("/dest");
+        let interrupt = Arc::new(Interrupt::new(Duration::from_millis(100)));
+        let options = Arc::new(CopyOptions {
+            interrupt: Some(interrupt.clone()),
+            copy_permits: CopyPermits::new(2),
+            source: Some(backend.clone()),
+            destination: Some(backend.clone()),
+            ..Default::default()
+        });
+
+        let requested = interrupt.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(30)).await;
+            requested.request();
+        });
+        let error = run(&options, |work| async move {
+            for name in [STUCK, "done"] {
+                work.send(WorkItem::File { from: Path::new("/source").join(name), to: Path::new("/dest").join(name), metadata: None }).await?;
+            }
+            // Not sent before the interrupt
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            work.send(WorkItem::File { from: PathBuf::from("/source/late"), to: PathBuf::from("/dest/late"), metadata: None }).await?;
+            anyhow::Ok(())
+        }).await.unwrap_err();
+        assert_eq!(error.downcast_ref::<Interrupted>(), Some(&Interrupted { abandoned: 1 }));
+        // The copy that finished is kept, the one abandoned after the grace period is removed
+        assert_eq!(backend.memory.read("/dest/done").as_deref(), Some("text"));
+        assert_eq!(backend.memory.read("/dest/stuck"), None);
+        assert_eq!(backend.memory.read("/dest/late"), None);
+        assert_eq!(options.stats.files_done(), 1);
+    }
+
+    #[tokio::test]
+    async fn out_of_space() {
+        let backend = Arc::new(SlowBackend::default());
+        for name in [STUCK, "late"] {
+            backend.memory.add_file(&format!("/source/{name}"), "text", SystemTime::now());
+        }
+        backend.memory.add_directory("/dest");
+        // No filesystem has that much free
+        let options = Arc::new(CopyOptions {
+            min_free_space: Some(Monitor::new(Path::new("/"), u64::MAX)),
+            source: Some(backend.clone()),
+            destination: Some(backend.clone()),
+            ..Default::default()
+        });
+
+        let error = run(&options, |work| async move {
+            work.send(WorkItem::File { from: Path::new("/source").join(STUCK), to: Path::new("/dest").join(STUCK), metadata: None }).await?;
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            work.send(WorkItem::File { from: PathBuf::from("/source/late"), to: PathBuf::from("/dest/late"), metadata: None }).await?;
+            anyhow::Ok(())
+        }).await.unwrap_err();
+        let out_of_space = error.downcast_ref::<OutOfSpace>().unwrap();
+        assert_eq!(out_of_space.min, u64::MAX);
+        assert!(error.to_string().contains("less than --min-free-space"), "{}", error);
+        // Stopped like fail-fast
+        assert_eq!(backend.memory.read("/dest/stuck"), None);
+        assert_eq!(backend.memory.read("/dest/late"), None);
+    }
+}
@@ -0,0 +1,179 @@
+//! `--prescan`: the source is walked before the copy to know how many files and bytes it will copy, so the progress
+//! reports can tell the percentage and what is left. It selects the files like the copy (same walk and filters).
+//! With `--order` it also keeps every file it found: the copy goes through that list instead of walking the tree
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use log::debug;
+use tokio::task::JoinSet;
+use crate::backend::Backend;
+use crate::filter::Filters;
+use crate::walk::{self, Entry, Links};
+
+/// Files and bytes the copy is expected to go through
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Totals {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+/// What the pre-scan found
+#[derive(Debug, Default)]
+pub struct Scan {
+    pub totals: Totals,
+    /// Every counted file and its size, only kept when requested
+    pub files: Vec<(PathBuf, u64)>,
+    /// Every counted link (they are counted as empty files), only kept when requested
+    pub links: Vec<PathBuf>,
+    /// Every directory below the source, only kept when requested
+    pub dirs: Vec<PathBuf>,
+    /// The entries that could not be read
+    pub errors: Vec<anyhow::Error>,
+}
+
+impl Scan {
+    fn add(&mut self, other: Scan) {
+        self.totals.files += other.totals.files;
+        self.totals.bytes += other.totals.bytes;
+        self.files.extend(other.files);
+        self.links.extend(other.links);
+        self.dirs.extend(other.dirs);
+        self.errors.extend(other.errors);
+    }
+}
+
+/// Walk the source with up to `concurrency` directories at the same time and count the files the copy selects.
+/// The files of the directories removed by `strip_components` are not copied so they are not counted.
+/// With `keep` the files and directories found are returned too. The links that `links` preserves are counted as
+/// files instead of followed
+pub async fn prescan(
+    backend: Arc<dyn Backend>,
+    source: &Path,
+    filters: &Filters,
+    strip_components: usize,
+    concurrency: usize,
+    keep: bool,
+    links: Links,
+) -> Scan {
+    let source: Arc<Path> = source.into();
+    let mut scan = Scan::default();
+    let mut pending = vec![source.to_path_buf()];
+    let mut set = JoinSet::new();
+    loop {
+        while set.len() < concurrency.max(1) {
+            let Some(dir) = pending.pop() else { break };
+            let depth = dir.strip_prefix(&source).map_or(0, |relative| relative.components().count());
+            set.spawn(scan_directory(backend.clone(), dir, depth, filters.clone(), strip_components, keep, links));
+        }
+        let Some(result) = set.join_next().await else { break };
+        match result {
+            Ok((found, dirs)) => {
+                scan.add(found);
+                if keep {
+                    scan.dirs.extend(dirs.iter().cloned());
+                }
+                pending.extend(dirs);
+            },
+            Err(error) => scan.errors.push(anyhow::Error::new(error).context("Pre-scan task failed")),
+        }
+    }
+    scan
+}
+
+/// Count the files of the directory `depth` levels below the source (unless they are too shallow for
+/// `strip_components`) and return its subdirectories
+async fn scan_directory(
+    backend: Arc<dyn Backend>,
+    dir: PathBuf,
+    depth: usize,
+    filters: Filters,
+    strip_components: usize,
+    keep: bool,
+    links: Links,
+) -> (Scan, Vec<PathBuf>) {
+    let counted = depth >= strip_components;
+    let mut scan = Scan::default();
+    let mut dirs = vec![];
+    let mut entries = match backend.read_dir(&dir).await {
+        Ok(entries) => entries,
+        Err(error) => {
+            debug!("Pre-scan cannot read {:?}: {}", dir, error);
+            scan.errors.push(error.context(format!("Cannot read directory: {:?}", dir)));
+            return (scan, dirs);
+        }
+    };
+    while let Some(entry) = walk::next_entry(&*backend, &mut *entries, &filters, counted, links, depth).await {
+        match entry {
+            Ok(Entry::File { path, metadata }) if counted => {
+                let size = metadata.map_or(0, |metadata| metadata.len);
+                scan.totals.files += 1;
+                scan.totals.bytes += size;
+                if keep {
+                    scan.files.push((path, size));
+                }
+            },
+            Ok(Entry::Link(path)) if counted => {
+                scan.totals.files += 1;
+                if keep {
+                    scan.links.push(path);
+                }
+            },
+            Ok(Entry::File { path, .. } | Entry::Link(path)) => {
+                if keep {
+                    let error = anyhow::anyhow!("Cannot strip {} components from {:?}", strip_components, path);
+                    scan.errors.push(error);
+                }
+            },
+            Ok(Entry::Directory(path)) => dirs.push(path),
+            Err(error) => {
+                debug!("Pre-scan: {:#}", error);
+                scan.errors.push(error);
+            },
+        }
+    }
+    (scan, dirs)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+    use std::sync::Arc;
+    use super::{prescan, Links, Totals};
+    use crate::backend::{Backend, LocalBackend};
+    use crate::filter::{Filters, TimeWindow};
+    use crate::test_support::init;
+
+    fn local() -> Arc<dyn Backend> {
+        Arc::new(LocalBackend)
+    }
+
+    #[tokio::test]
+    async fn prescan_totals() {
+        let base_dir = init("prescan_totals").await;
+
+        let source = base_dir.join("source");
+        tokio::fs::create_dir_all(source.join("a/b")).await.unwrap();
+        tokio::fs::write(source.join("top"), "12345").await.unwrap();
+        tokio::fs::write(source.join("a/file"), "123").await.unwrap();
+        tokio::fs::write(source.join("a/b/file"), "1").await.unwrap();
+        let old = std::fs::File::create(source.join("a/b/old")).unwrap();
+        old.set_modified(SystemTime::now() - Duration::from_secs(3600)).unwrap();
+
+        let all = prescan(local(), &source, &Filters::default(), 0, 2, false, Links::default()).await;
+        assert_eq!(all.totals, Totals { files: 4, bytes: 9 });
+        assert!(all.files.is_empty() && all.dirs.is_empty());
+        // The files of the stripped directories are not copied
+        let stripped = prescan(local(), &source, &Filters::default(), 1, 2, true, Links::default()).await;
+        assert_eq!(stripped.totals, Totals { files: 3, bytes: 4 });
+        assert_eq!(stripped.errors.len(), 1);
+        let oldest = Some(SystemTime::now() - Duration::from_secs(60));
+        let filters = Filters { time_window: TimeWindow { oldest, ..Default::default() } };
+        let mut recent = prescan(local(), &source, &filters, 0, 1, true, Links::default()).await;
+        assert_eq!(recent.totals, Totals { files: 3, bytes: 9 });
+        recent.files.sort();
+        recent.dirs.sort();
+        assert_eq!(recent.files, [(source.join("a/b/file"), 1), (source.join("a/file"), 3), (source.join("top"), 5)]);
+        assert_eq!(recent.dirs, [source.join("a"), source.join("a/b")]);
+    }
+}
@@ -0,0 +1,120 @@
+//! `--io-nice` and `--nice`: the copy yields the disk and the CPU to the other programs of the host.
+//! Linux keeps both priorities per thread, so they are set on every thread of the runtime when it starts: the
+//! workers and the blocking pool where the files are read and written (`tokio::fs`, the kernel copies, the batches of
+//! small files). The runtime of `#[tokio::main]` has no such hook, the program builds its own
+
+use std::io;
+
+/// The I/O scheduling class of `--io-nice`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoNice {
+    /// Only uses the disk when nothing else does
+    Idle,
+    /// The default class with a level from 0 (highest) to 7 (lowest)
+    BestEffort(u8),
+}
+
+/// Parse `idle`, `best-effort` or `best-effort:N`
+pub fn parse_io_nice(value: &str) -> Result<IoNice, String> {
+    match value.trim().split_once(':') {
+        None if value.trim() == "idle" => Ok(IoNice::Idle),
+        None if value.trim() == "best-effort" => Ok(IoNice::BestEffort(4)),
+        Some(("best-effort", level)) => match level.parse() {
+            Ok(level) if level <= 7 => Ok(IoNice::BestEffort(level)),
+            _ => Err(format!("the best-effort level goes from 0 to 7: {:?}", level)),
+        },
+        _ => Err(format!("expected idle or best-effort:N, not {:?}", value)),
+    }
+}
+
+/// The priorities of the threads of the copy
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Priority {
+    pub io: Option<IoNice>,
+    /// The CPU niceness, from -20 to 19
+    pub nice: Option<i32>,
+}
+
+impl Priority {
+    pub fn is_default(&self) -> bool {
+        self.io.is_none() && self.nice.is_none()
+    }
+
+    /// The flags that this platform ignores, to be warned
+    pub fn unsupported(&self) -> Vec<&'static str> {
+        let mut unsupported = vec![];
+        if self.io.is_some() && cfg!(not(target_os = "linux")) {
+            unsupported.push("--io-nice");
+        }
+        if self.nice.is_some() && cfg!(not(unix)) {
+            unsupported.push("--nice");
+        }
+        unsupported
+    }
+
+    /// Give the priorities to the calling thread
+    pub fn apply_to_current_thread(&self) -> io::Result<()> {
+        #[cfg(target_os = "linux")]
+        if let Some(io_nice) = self.io {
+            set_io_priority(io_nice)?;
+        }
+        #[cfg(unix)]
+        if let Some(nice) = self.nice {
+            // SAFETY: plain system call, 0 is the calling thread on Linux and the process elsewhere
+            #[allow(clippy::unnecessary_cast)] // The type of `which` depends on the platform
+            if unsafe { libc::setpriority(libc::PRIO_PROCESS as _, 0, nice) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// ioprio_set(2) of the calling thread. The libc crate has no wrapper nor constants for it
+#[cfg(target_os = "linux")]
+fn set_io_priority(io_nice: IoNice) -> io::Result<()> {
+    const IOPRIO_WHO_PROCESS: libc::c_long = 1;
+    const IOPRIO_CLASS_SHIFT: u32 = 13;
+    const IOPRIO_CLASS_BE: libc::c_long = 2;
+    const IOPRIO_CLASS_IDLE: libc::c_long = 3;
+    let priority = match io_nice {
+        IoNice::Idle => IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+        IoNice::BestEffort(level) => IOPRIO_CLASS_BE << IOPRIO_CLASS_SHIFT | libc::c_long::from(level),
+    };
+    // SAFETY: plain system call with integer arguments, 0 is the calling thread
+    if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, priority) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_io_nice, IoNice};
+
+    #[test]
+    fn io_nice_values() {
+        assert_eq!(parse_io_nice("idle"), Ok(IoNice::Idle));
+        assert_eq!(parse_io_nice("best-effort:7"), Ok(IoNice::BestEffort(7)));
+        assert_eq!(parse_io_nice("best-effort"), Ok(IoNice::BestEffort(4)));
+        assert!(parse_io_nice("best-effort:8").is_err());
+        assert!(parse_io_nice("realtime:0").is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn lowers_the_priority_of_a_thread() {
+        // In a thread of its own, the test threads are reused
+        std::thread::spawn(|| {
+            let priority = super::Priority { io: Some(IoNice::BestEffort(7)), nice: Some(19) };
+            priority.apply_to_current_thread().unwrap();
+            // SAFETY: plain system calls
+            let (io, nice) = unsafe {
+                (libc::syscall(libc::SYS_ioprio_get, 1, 0), libc::getpriority(libc::PRIO_PROCESS, 0))
+            };
+            assert_eq!(io, 2 << 13 | 7);
+            assert_eq!(nice, 19);
+        }).join().unwrap();
+    }
+}
@@ -0,0 +1,62 @@
+//! Copy-on-write clones (reflinks) for the filesystems that support them: btrfs and XFS on Linux, APFS on macOS
+
+use std::io;
+use std::path::Path;
+
+/// Clone `from` into `to` and return the size of the file.
+/// The data is shared with the source until one of them is modified
+pub async fn clone_file(from: &Path, to: &Path) -> io::Result<u64> {
+    let from = from.to_owned();
+    let to = to.to_owned();
+    tokio::task::spawn_blocking(move || clone_blocking(&from, &to)).await?
+}
+
+/// Whether the clone failed because the filesystem (or the platform) cannot clone files
+pub fn is_unsupported(error: &io::Error) -> bool {
+    #[cfg(unix)]
+    if let Some(code) = error.raw_os_error() {
+        return [libc::EOPNOTSUPP, libc::ENOTSUP, libc::EXDEV, libc::EINVAL, libc::ENOTTY, libc::ENOSYS].contains(&code);
+    }
+    error.kind() == io::ErrorKind::Unsupported
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn clone_blocking(from: &Path, to: &Path) -> io::Result<u64> {
+    use std::os::unix::io::AsRawFd;
+    let source = std::fs::File::open(from)?;
+    let metadata = source.metadata()?;
+    let dest = std::fs::File::create(to)?;
+    // SAFETY: both descriptors are valid while the files are alive
+    if unsafe { libc::ioctl(dest.as_raw_fd(), libc::FICLONE, source.as_raw_fd()) } != 0 {
+        let error = io::Error::last_os_error();
+        drop(dest);
+        let _ = std::fs::remove_file(to);
+        return Err(error);
+    }
+    dest.set_permissions(metadata.permissions())?;
+    Ok(metadata.len())
+}
+
+#[cfg(target_os = "macos")]
+fn clone_blocking(from: &Path, to: &Path) -> io::Result<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    let len = std::fs::metadata(from)?.len();
+    let source = CString::new(from.as_os_str().as_bytes())?;
+    let dest = CString::new(to.as_os_str().as_bytes())?;
+    // clonefile does not replace an existing destination
+    match std::fs::remove_file(to) {
+        Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+        _ => {},
+    }
+    // SAFETY: both paths are NUL terminated strings
+    if unsafe { libc::clonefile(source.as_ptr(), dest.as_ptr(), 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(len)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos")))]
+fn clone_blocking(_from: &Path, _to: &Path) -> io::Result<u64> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "reflinks are not supported in this platform"))
+}
@@ -0,0 +1,229 @@
+//! `--rename-pattern`: the names of the destination files rewritten from the names of the sources.
+//! Either a template like `{stem}_{mtime}.{ext}` that builds the whole name, or `s/find/replace/` that replaces
+//! every occurrence of a text (any character after the `s` can delimit it, like in sed). The placeholders are:
+//! - `{name}`: the whole name of the source
+//! - `{stem}`: the name without its extension
+//! - `{ext}`: the extension without the dot. When it is empty the dot written right before it is dropped too
+//! - `{mtime}`: the local modification time, `YYYYMMDD-HHMMSS`
+//! - `{index}`: the position of the file in its directory, from 1, in the order the files are copied
+//!
+//! `{name:lower}` and `{name:upper}` change the case of any placeholder, `{{` and `}}` are literal braces
+
+use std::path::Path;
+use std::time::SystemTime;
+use anyhow::Result;
+use chrono::{DateTime, Local};
+
+/// What a placeholder is replaced with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Name,
+    Stem,
+    Ext,
+    Mtime,
+    Index,
+}
+
+/// A change of case of a placeholder
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Case {
+    Keep,
+    Lower,
+    Upper,
+}
+
+/// A piece of a template
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Part {
+    Text(String),
+    Field(Field, Case),
+}
+
+/// A parsed `--rename-pattern`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RenamePattern {
+    Template(Vec<Part>),
+    Replace { find: String, replace: String },
+}
+
+impl RenamePattern {
+    /// Whether the modification time of the files is needed
+    pub fn needs_metadata(&self) -> bool {
+        matches!(self, RenamePattern::Template(parts) if parts.iter().any(|part| matches!(part, Part::Field(Field::Mtime, _))))
+    }
+
+    /// The new name of the file `name`, modified at `mtime` and the `index`th (from 1) of its directory
+    pub fn apply(&self, name: &str, mtime: Option<SystemTime>, index: usize) -> Result<String> {
+        let renamed = match self {
+            RenamePattern::Replace { find, replace } => name.replace(find.as_str(), replace),
+            RenamePattern::Template(parts) => {
+                let path = Path::new(name);
+                let stem = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or(name);
+                let ext = path.extension().and_then(|ext| ext.to_str()).unwrap_or_default();
+                let mut renamed = String::new();
+                for part in parts {
+                    let (field, case) = match part {
+                        Part::Text(text) => {
+                            renamed.push_str(text);
+                            continue;
+                        },
+                        Part::Field(field, case) => (field, case),
+                    };
+                    let value = match field {
+                        Field::Name => name.to_owned(),
+                        Field::Stem => stem.to_owned(),
+                        Field::Ext if ext.is_empty() => {
+                            if renamed.ends_with('.') {
+                                renamed.pop();
+                            }
+                            continue;
+                        },
+                        Field::Ext => ext.to_owned(),
+                        Field::Mtime => {
+                            let mtime = mtime.ok_or_else(|| anyhow::anyhow!("The modification time is not known"))?;
+                            DateTime::<Local>::from(mtime).format("%Y%m%d-%H%M%S").to_string()
+                        },
+                        Field::Index => index.to_string(),
+                    };
+                    match case {
+                        Case::Keep => renamed.push_str(&value),
+                        Case::Lower => renamed.push_str(&value.to_lowercase()),
+                        Case::Upper => renamed.push_str(&value.to_uppercase()),
+                    }
+                }
+                renamed
+            },
+        };
+        if renamed.is_empty() || renamed == "." || renamed == ".." || renamed.contains(['/', '\0'])
+            || (cfg!(windows) && renamed.contains('\\')) {
+            return Err(anyhow::anyhow!("The rename pattern gives an invalid name: {:?}", renamed));
+        }
+        Ok(renamed)
+    }
+}
+
+/// Parse a template or a `s/find/replace/`
+pub fn parse_rename_pattern(value: &str) -> Result<RenamePattern, String> {
+    if let Some(replace) = parse_replace(value) {
+        return replace;
+    }
+    let mut parts = vec![];
+    let mut text = String::new();
+    let mut chars = value.chars().peekable();
+    while let Some(char) = chars.next() {
+        match char {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                text.push('{');
+            },
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                text.push('}');
+            },
+            '{' => {
+                let mut placeholder = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(char) => placeholder.push(char),
+                        None => return Err(format!("unclosed {{ in {:?}, write {{{{ for a literal one", value)),
+                    }
+                }
+                let (field, case) = placeholder.split_once(':').unwrap_or((&placeholder, ""));
+                let field = match field {
+                    "name" => Field::Name,
+                    "stem" => Field::Stem,
+                    "ext" => Field::Ext,
+                    "mtime" => Field::Mtime,
+                    "index" => Field::Index,
+                    _ => return Err(format!("unknown placeholder {{{}}}, expected name, stem, ext, mtime or index", placeholder)),
+                };
+                let case = match case {
+                    "" => Case::Keep,
+                    "lower" => Case::Lower,
+                    "upper" => Case::Upper,
+                    _ => return Err(format!("unknown case {:?}, expected lower or upper", case)),
+                };
+                if !text.is_empty() {
+                    parts.push(Part::Text(std::mem::take(&mut text)));
+                }
+                parts.push(Part::Field(field, case));
+            },
+            '}' => return Err(format!("unmatched }} in {:?}, write }}}} for a literal one", value)),
+            char => text.push(char),
+        }
+    }
+    if !text.is_empty() {
+        parts.push(Part::Text(text));
+    }
+    if !parts.iter().any(|part| matches!(part, Part::Field(..))) {
+        return Err(format!("the rename pattern has no placeholder, every file would get the name {:?}", value));
+    }
+    Ok(RenamePattern::Template(parts))
+}
+
+/// `s/find/replace/`, None when the value is not one
+fn parse_replace(value: &str) -> Option<Result<RenamePattern, String>> {
+    let rest = value.strip_prefix('s')?;
+    let delimiter = rest.chars().next().filter(|char| !char.is_alphanumeric() && *char != '{')?;
+    let fields: Vec<&str> = rest[delimiter.len_utf8()..].split(delimiter).collect();
+    Some(match fields.as_slice() {
+        [find, replace, ""] if !find.is_empty() => Ok(RenamePattern::Replace { find: find.to_string(), replace: replace.to_string() }),
+        [_, _, ""] => Err("the text to find cannot be empty".to_owned()),
+        _ => Err(format!("expected s{0}find{0}replace{0}: {1:?}", delimiter, value)),
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::filter::parse_time;
+    use super::parse_rename_pattern;
+
+    #[test]
+    fn prefix() {
+        let pattern = parse_rename_pattern("imported_{name}").unwrap();
+        assert_eq!(pattern.apply("photo.JPG", None, 1).unwrap(), "imported_photo.JPG");
+        assert!(!pattern.needs_metadata());
+    }
+
+    #[test]
+    fn spaces_to_underscores() {
+        let pattern = parse_rename_pattern("s/ /_/").unwrap();
+        assert_eq!(pattern.apply("my holiday photo.jpg", None, 1).unwrap(), "my_holiday_photo.jpg");
+        assert_eq!(pattern.apply("plain.jpg", None, 1).unwrap(), "plain.jpg");
+        let pattern = parse_rename_pattern("s|.jpeg|.jpg|").unwrap();
+        assert_eq!(pattern.apply("a.jpeg", None, 1).unwrap(), "a.jpg");
+    }
+
+    #[test]
+    fn placeholders() {
+        let pattern = parse_rename_pattern("{index}-{stem:lower}.{ext:lower}").unwrap();
+        assert_eq!(pattern.apply("Photo.JPG", None, 7).unwrap(), "7-photo.jpg");
+        // No extension, no dot
+        assert_eq!(pattern.apply("README", None, 1).unwrap(), "1-readme");
+        assert_eq!(parse_rename_pattern("{{{name}}}").unwrap().apply("a", None, 1).unwrap(), "{a}");
+
+        let pattern = parse_rename_pattern("{stem}_{mtime}.{ext}").unwrap();
+        assert!(pattern.needs_metadata());
+        let mtime = parse_time("2024-05-01 13:00:09").unwrap();
+        assert_eq!(pattern.apply("log.txt", Some(mtime), 1).unwrap(), "log_20240501-130009.txt");
+        assert!(pattern.apply("log.txt", None, 1).is_err());
+    }
+
+    #[test]
+    fn invalid_patterns() {
+        assert!(parse_rename_pattern("{size}").is_err());
+        assert!(parse_rename_pattern("{name:title}").is_err());
+        assert!(parse_rename_pattern("fixed.txt").is_err());
+        assert!(parse_rename_pattern("a}{name}").is_err());
+        assert!(parse_rename_pattern("{name").is_err());
+        assert!(parse_rename_pattern("s//x/").is_err());
+        assert!(parse_rename_pattern("s/a/b").is_err());
+        // Not a replacement, a template
+        assert!(parse_rename_pattern("sub_{name}").is_ok());
+        assert!(parse_rename_pattern("s/a/b/").unwrap().apply("a", None, 1).is_ok());
+        assert!(parse_rename_pattern("s/a//").unwrap().apply("a", None, 1).is_err());
+        assert!(parse_rename_pattern("s/a/x/y/").is_err());
+    }
+}
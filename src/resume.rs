@@ -0,0 +1,281 @@
+//! `--resume`: the big files are written into `<name>.part` next to their destination, with a sidecar
+//! `<name>.part.offset` that records the bytes already flushed. A copy that is interrupted (a failure, a timeout, a
+//! killed run) leaves both behind and the next copy of the file goes on from that offset, as long as the source still
+//! has the same size and modification time. The complete file is checked against the source before taking its name
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use anyhow::{anyhow, Context, Result};
+use log::{debug, info};
+use crate::checksum::{self, ChecksumAlgorithm};
+use crate::checksum_cache;
+use crate::copy::{self, BufferSize, Copied};
+use crate::limit::Bandwidth;
+use crate::tempdir::TempDir;
+use crate::CopyOptions;
+
+/// Bytes written between two checkpoints of the offset
+const CHECKPOINT_BYTES: u64 = if cfg!(test) { 64 * 1024 } else { 64 << 20 };
+
+/// What the sidecar records: the source that was being copied and how far
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Checkpoint {
+    len: u64,
+    /// Nanoseconds since the epoch, 0 when the platform has no modification time
+    modified: u128,
+    /// Bytes of the `.part` flushed to the disk
+    offset: u64,
+}
+
+impl Checkpoint {
+    fn parse(text: &str) -> Option<Self> {
+        let mut fields = text.split_whitespace().map(str::parse::<u128>);
+        let (len, modified, offset) = (fields.next()?.ok()?, fields.next()?.ok()?, fields.next()?.ok()?);
+        Some(Self { len: len.try_into().ok()?, modified, offset: offset.try_into().ok()? })
+    }
+
+    fn format(&self) -> String {
+        format!("{} {} {}\n", self.len, self.modified, self.offset)
+    }
+}
+
+/// The `.part` and the sidecar of the destination `to`, next to it or in the `--tempdir`
+async fn partial_paths(to: &Path, tempdir: Option<&TempDir>) -> Result<(PathBuf, PathBuf)> {
+    let name = to.file_name().with_context(|| format!("Invalid destination: {:?}", to))?;
+    let mut part = name.to_owned();
+    part.push(".part");
+    let part = match tempdir {
+        Some(tempdir) => tempdir.place(to, &part).await,
+        None => to.with_file_name(part),
+    };
+    let mut sidecar = part.clone().into_os_string();
+    sidecar.push(".offset");
+    Ok((part, sidecar.into()))
+}
+
+/// Where the copy of a file started and how many bytes it wrote
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Written {
+    start: u64,
+    bytes: u64,
+}
+
+/// Copy the file `from` of `len` bytes to `to` through its `.part`, resuming an interrupted copy of the same source
+pub async fn copy_file(from: &Path, to: &Path, len: u64, options: &CopyOptions) -> Result<Copied> {
+    let (part, sidecar) = partial_paths(to, options.tempdir.as_deref()).await?;
+    let source = tokio::fs::File::open(from).await?;
+    let metadata = source.metadata().await?;
+    let source = source.into_std().await;
+    let modified = metadata.modified().ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |modified| modified.as_nanos());
+    let expected = Checkpoint { len, modified, offset: 0 };
+    let (buffer_size, bandwidth) = (options.buffer_size, options.bandwidth.clone());
+    let (part_path, sidecar_path) = (part.clone(), sidecar.clone());
+    let written = tokio::task::spawn_blocking(move || {
+        copy_from_checkpoint(&source, &part_path, &sidecar_path, expected, buffer_size, bandwidth.as_deref())
+    }).await??;
+    if written.start > 0 {
+        info!("Resumed {:?} at {} of {} bytes", to, written.start, len);
+    }
+
+    // The resumed bytes were written by another run, the whole file is checked
+    let digest = checksum_cache::hash_source(from, options.checksum_algorithm, options.checksum_cache.as_deref()).await
+        .with_context(|| format!("Cannot hash file: {:?}", from))?;
+    if !same_content(&part, &digest, options.checksum_algorithm).await? {
+        // The next copy starts over
+        let _ = tokio::fs::remove_file(&sidecar).await;
+        let _ = tokio::fs::remove_file(&part).await;
+        return Err(anyhow!("The checksum of the resumed copy does not match the source, it is discarded"));
+    }
+    tokio::fs::set_permissions(&part, metadata.permissions()).await?;
+    tokio::fs::rename(&part, to).await?;
+    tokio::fs::remove_file(&sidecar).await
+        .with_context(|| format!("Cannot remove file: {:?}", sidecar))?;
+    Ok(Copied { bytes: len, digest: options.needs_digest().then_some(digest), cloned: false })
+}
+
+/// Whether the content of `part` has the `digest` of the source
+async fn same_content(part: &Path, digest: &str, algorithm: ChecksumAlgorithm) -> Result<bool> {
+    let copied = checksum::hash_file(part, algorithm).await
+        .with_context(|| format!("Cannot hash file: {:?}", part))?;
+    Ok(copied == digest)
+}
+
+/// The offset to resume from: the one of the sidecar when it was written for the same source and the `.part` has
+/// those bytes, 0 otherwise
+fn resume_offset(part: &File, sidecar: &Path, expected: Checkpoint) -> u64 {
+    let Ok(text) = std::fs::read_to_string(sidecar) else { return 0 };
+    match Checkpoint::parse(&text) {
+        Some(checkpoint) if Checkpoint { offset: 0, ..checkpoint } == expected => {
+            let on_disk = part.metadata().map_or(0, |metadata| metadata.len());
+            if checkpoint.offset <= on_disk.min(expected.len) {
+                checkpoint.offset
+            } else {
+                0
+            }
+        },
+        _ => {
+            debug!("The source changed since {:?} was written, the copy starts over", sidecar);
+            0
+        },
+    }
+}
+
+/// The positional copy into the `.part`, flushed and checkpointed every CHECKPOINT_BYTES
+fn copy_from_checkpoint(
+    source: &File,
+    part: &Path,
+    sidecar: &Path,
+    expected: Checkpoint,
+    buffer_size: BufferSize,
+    bandwidth: Option<&Bandwidth>,
+) -> io::Result<Written> {
+    let dest = OpenOptions::new().write(true).create(true).truncate(false).open(part)?;
+    let start = resume_offset(&dest, sidecar, expected);
+    // What is after the checkpoint may not have been flushed
+    dest.set_len(start)?;
+    let mut buffer = vec![0u8; buffer_size.0.min(expected.len.max(1) as usize)];
+    let (mut offset, mut checkpoint) = (start, start);
+    while offset < expected.len {
+        let want = (expected.len - offset).min(buffer.len() as u64) as usize;
+        let copied = copy::read_at(source, &mut buffer[..want], offset).and_then(|read| match read {
+            0 => Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("source ended at offset {}", offset))),
+            read => {
+                if let Some(bandwidth) = bandwidth {
+                    bandwidth.acquire_blocking(read);
+                }
+                copy::write_all_at(&dest, &buffer[..read], offset).map(|()| read)
+            },
+        });
+        match copied {
+            Ok(read) => offset += read as u64,
+            Err(error) => {
+                // Keep what was written for the next attempt, if the destination still takes it
+                if offset > checkpoint {
+                    let _ = write_checkpoint(&dest, sidecar, Checkpoint { offset, ..expected });
+                }
+                return Err(error);
+            },
+        }
+        if offset - checkpoint >= CHECKPOINT_BYTES {
+            write_checkpoint(&dest, sidecar, Checkpoint { offset, ..expected })?;
+            checkpoint = offset;
+        }
+    }
+    dest.sync_all()?;
+    Ok(Written { start, bytes: offset - start })
+}
+
+/// Flush the `.part` and then record the offset, so the sidecar never promises bytes that are not on the disk.
+/// The sidecar is replaced with a rename, a crash leaves the old checkpoint or the new one
+fn write_checkpoint(dest: &File, sidecar: &Path, checkpoint: Checkpoint) -> io::Result<()> {
+    dest.sync_data()?;
+    let mut next = sidecar.as_os_str().to_owned();
+    next.push(".next");
+    std::fs::write(&next, checkpoint.format())?;
+    std::fs::rename(&next, sidecar)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::time::UNIX_EPOCH;
+    use crate::tempdir::TempDir;
+    use crate::test_support::init;
+    use crate::CopyOptions;
+    use super::{copy_file, copy_from_checkpoint, partial_paths, Checkpoint, Written, CHECKPOINT_BYTES};
+
+    /// The sidecar that a copy of `source` interrupted at `offset` leaves
+    fn checkpoint(source: &Path, offset: u64) -> String {
+        let metadata = std::fs::metadata(source).unwrap();
+        let modified = metadata.modified().unwrap().duration_since(UNIX_EPOCH).unwrap().as_nanos();
+        Checkpoint { len: metadata.len(), modified, offset }.format()
+    }
+
+    #[tokio::test]
+    async fn resumes_a_truncated_part() {
+        let base_dir = init("resumes_a_truncated_part").await;
+
+        let source = base_dir.join("source");
+        let dest = base_dir.join("dest");
+        let content: Vec<u8> = (0..10 * CHECKPOINT_BYTES + 123).map(|byte| (byte % 251) as u8).collect();
+        tokio::fs::write(&source, &content).await.unwrap();
+        let (part, sidecar) = partial_paths(&dest, None).await.unwrap();
+        assert_eq!(part, base_dir.join("dest.part"));
+        assert_eq!(sidecar, base_dir.join("dest.part.offset"));
+
+        // Interrupted after 3 checkpoints and half of the next one, which was not recorded
+        let offset = 3 * CHECKPOINT_BYTES;
+        tokio::fs::write(&part, &content[..(offset + CHECKPOINT_BYTES / 2) as usize]).await.unwrap();
+        tokio::fs::write(&sidecar, checkpoint(&source, offset)).await.unwrap();
+        let expected = Checkpoint::parse(&checkpoint(&source, 0)).unwrap();
+        let file = std::fs::File::open(&source).unwrap();
+        let options = CopyOptions::default();
+        let written = copy_from_checkpoint(&file, &part, &sidecar, expected, options.buffer_size, None).unwrap();
+        assert_eq!(written, Written { start: offset, bytes: content.len() as u64 - offset });
+
+        // The whole copy checks and renames the part
+        tokio::fs::write(&part, &content[..offset as usize]).await.unwrap();
+        tokio::fs::write(&sidecar, checkpoint(&source, offset)).await.unwrap();
+        let copied = copy_file(&source, &dest, content.len() as u64, &options).await.unwrap();
+        assert_eq!(copied.bytes, content.len() as u64);
+        assert_eq!(tokio::fs::read(&dest).await.unwrap(), content);
+        assert!(!part.exists() && !sidecar.exists());
+    }
+
+    #[tokio::test]
+    async fn discards_a_wrong_part() {
+        let base_dir = init("discards_a_wrong_part").await;
+
+        let source = base_dir.join("source");
+        let dest = base_dir.join("dest");
+        let content = vec![7u8; 4 * CHECKPOINT_BYTES as usize];
+        tokio::fs::write(&source, &content).await.unwrap();
+        let (part, sidecar) = partial_paths(&dest, None).await.unwrap();
+        let options = CopyOptions::default();
+
+        // Bytes that the source does not have are only found by the final checksum
+        tokio::fs::write(&part, vec![0u8; 2 * CHECKPOINT_BYTES as usize]).await.unwrap();
+        tokio::fs::write(&sidecar, checkpoint(&source, 2 * CHECKPOINT_BYTES)).await.unwrap();
+        assert!(copy_file(&source, &dest, content.len() as u64, &options).await.is_err());
+        assert!(!part.exists() && !sidecar.exists() && !dest.exists());
+
+        // The sidecar of another source is ignored and the copy starts over
+        tokio::fs::write(&part, vec![0u8; CHECKPOINT_BYTES as usize]).await.unwrap();
+        tokio::fs::write(&sidecar, "1 2 3\n").await.unwrap();
+        copy_file(&source, &dest, content.len() as u64, &options).await.unwrap();
+        assert_eq!(tokio::fs::read(&dest).await.unwrap(), content);
+    }
+
+    #[tokio::test]
+    async fn resumes_in_a_tempdir() {
+        let base_dir = init("resumes_in_a_tempdir").await;
+
+        let source = base_dir.join("source");
+        let dest = base_dir.join("dest/file");
+        tokio::fs::create_dir_all(base_dir.join("dest")).await.unwrap();
+        let content: Vec<u8> = (0..5 * CHECKPOINT_BYTES).map(|byte| (byte % 13) as u8).collect();
+        tokio::fs::write(&source, &content).await.unwrap();
+        let tempdir = Arc::new(TempDir::create(&base_dir.join("tmp")).await.unwrap());
+        let (part, sidecar) = partial_paths(&dest, Some(&tempdir)).await.unwrap();
+        assert_eq!(part.parent(), Some(base_dir.join("tmp").as_path()));
+        assert_eq!(sidecar.file_name().unwrap().to_str().unwrap(), format!("{}.offset", part.file_name().unwrap().to_str().unwrap()));
+
+        // An interrupted copy only left its part in the temporary directory, the destination appears once complete
+        let offset = 2 * CHECKPOINT_BYTES;
+        tokio::fs::write(&part, &content[..offset as usize]).await.unwrap();
+        tokio::fs::write(&sidecar, checkpoint(&source, offset)).await.unwrap();
+        assert!(!dest.exists());
+        let options = CopyOptions { tempdir: Some(tempdir), ..Default::default() };
+        let copied = copy_file(&source, &dest, content.len() as u64, &options).await.unwrap();
+        assert_eq!(copied.bytes, content.len() as u64);
+        assert_eq!(tokio::fs::read(&dest).await.unwrap(), content);
+        assert_eq!(std::fs::read_dir(base_dir.join("tmp")).unwrap().count(), 0);
+        assert_eq!(std::fs::read_dir(base_dir.join("dest")).unwrap().count(), 1);
+    }
+}
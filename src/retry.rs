@@ -0,0 +1,86 @@
+//! `--retries`: the copies that fail with a transient error (a flaky NFS server, a busy file) are tried again after
+//! a delay that doubles every attempt, with some jitter so the copies that failed together do not retry together
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::time::Duration;
+
+/// Longest delay between two attempts, unless --retry-delay is longer
+const MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// How the failed copies are retried
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retry {
+    /// Attempts after the first one
+    pub retries: u32,
+    /// Delay before the first retry
+    pub delay: Duration,
+}
+
+impl Retry {
+    /// The delay before the `attempt`th retry (from 1): --retry-delay doubled every attempt, then a random part of
+    /// its second half is dropped
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let backoff = self.delay.saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)));
+        let backoff = backoff.min(MAX_DELAY.max(self.delay));
+        backoff / 2 + backoff.mul_f64(random_fraction() / 2.0)
+    }
+}
+
+/// A number in [0, 1) from the random keys of the standard library, enough for a jitter
+fn random_fraction() -> f64 {
+    let random = RandomState::new().build_hasher().finish();
+    (random >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Whether the copy failed with an error that may go away by itself: an I/O error, a stale NFS handle, a resource
+/// that is busy or asks to try again, a timeout. Missing files, permissions and the like fail for good
+pub fn is_transient(error: &anyhow::Error) -> bool {
+    error.chain().filter_map(|cause| cause.downcast_ref::<io::Error>()).any(|error| {
+        #[cfg(unix)]
+        if matches!(error.raw_os_error(), Some(libc::EIO | libc::ESTALE | libc::EAGAIN | libc::ETIMEDOUT | libc::EBUSY)) {
+            return true;
+        }
+        matches!(
+            error.kind(),
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock | io::ErrorKind::ResourceBusy | io::ErrorKind::StaleNetworkFileHandle
+        )
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::time::Duration;
+    use super::{is_transient, Retry};
+
+    #[test]
+    fn exponential_delays() {
+        let retry = Retry { retries: 10, delay: Duration::from_secs(1) };
+        for (attempt, backoff) in [(1, 1), (2, 2), (3, 4), (4, 8), (8, 60), (30, 60)] {
+            let delay = retry.delay(attempt);
+            let backoff = Duration::from_secs(backoff);
+            assert!(delay >= backoff / 2 && delay <= backoff, "{:?} for the attempt {}", delay, attempt);
+        }
+        // A delay longer than the cap is kept
+        let retry = Retry { retries: 1, delay: Duration::from_secs(120) };
+        assert!(retry.delay(5) >= Duration::from_secs(60));
+    }
+
+    #[test]
+    fn transient_errors() {
+        let error = |kind: io::ErrorKind| anyhow::Error::new(io::Error::from(kind)).context("Cannot copy file");
+        assert!(is_transient(&error(io::ErrorKind::TimedOut)));
+        assert!(is_transient(&error(io::ErrorKind::ResourceBusy)));
+        assert!(!is_transient(&error(io::ErrorKind::NotFound)));
+        assert!(!is_transient(&error(io::ErrorKind::PermissionDenied)));
+        #[cfg(unix)]
+        {
+            assert!(is_transient(&anyhow::Error::new(io::Error::from_raw_os_error(libc::EIO))));
+            assert!(is_transient(&anyhow::Error::new(io::Error::from_raw_os_error(libc::ESTALE))));
+            assert!(!is_transient(&anyhow::Error::new(io::Error::from_raw_os_error(libc::EACCES))));
+        }
+    }
+}
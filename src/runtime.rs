@@ -0,0 +1,97 @@
+//! The tokio runtime of the program, built from the arguments instead of `#[tokio::main]`: `--runtime-threads`
+//! workers run the tasks and up to `--blocking-threads` threads do the blocking I/O (`tokio::fs`, the kernel
+//! copies, the batches of small files). Every thread gets the `--io-nice` and `--nice` priorities when it starts
+
+use anyhow::Result;
+use log::{debug, info, warn};
+use tokio::runtime::Runtime;
+use crate::priority::Priority;
+
+/// The threads of the runtime
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Threads {
+    /// Workers that run the asynchronous tasks
+    pub workers: usize,
+    /// Most threads of the blocking pool
+    pub blocking: usize,
+}
+
+impl Threads {
+    /// The defaults for `copies` copies in flight, of `ranges` ranges each, while `listings` directories are listed:
+    /// a worker per core up to the copies, which spend their time waiting for the disks, and a blocking thread for
+    /// everything that can be reading or writing at the same time
+    pub fn for_copies(copies: usize, ranges: usize, listings: usize) -> Self {
+        let cores = std::thread::available_parallelism().map_or(1, |cores| cores.get());
+        Self {
+            workers: cores.min(copies).max(1),
+            blocking: copies.saturating_mul(ranges.max(1)).saturating_add(listings).max(1),
+        }
+    }
+}
+
+/// Build the runtime with `threads` whose threads run with `priority`
+pub fn build(threads: Threads, priority: Priority) -> Result<Runtime> {
+    if threads.workers == 0 || threads.blocking == 0 {
+        return Err(anyhow::anyhow!("--runtime-threads and --blocking-threads must be at least 1"));
+    }
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    runtime.enable_all().worker_threads(threads.workers).max_blocking_threads(threads.blocking);
+    for flag in priority.unsupported() {
+        warn!("The priorities cannot be changed on this platform, ignoring {}", flag);
+    }
+    if !priority.is_default() {
+        // The threads of the runtime, including its blocking pool, get the priority when they start
+        match priority.apply_to_current_thread() {
+            Ok(()) => {
+                info!("The copy runs with a lower priority: {:?}", priority);
+                runtime.on_thread_start(move || {
+                    if let Err(error) = priority.apply_to_current_thread() {
+                        debug!("Cannot set the priority of a thread: {}", error);
+                    }
+                });
+            },
+            Err(error) => warn!("Cannot set the priority, it is ignored: {}", error),
+        }
+    }
+    info!("The runtime runs {} worker threads and up to {} blocking threads", threads.workers, threads.blocking);
+    Ok(runtime.build()?)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use crate::priority::Priority;
+    use super::{build, Threads};
+
+    #[test]
+    fn honors_the_threads() {
+        let runtime = build(Threads { workers: 3, blocking: 1 }, Priority::default()).unwrap();
+        assert_eq!(runtime.metrics().num_workers(), 3);
+
+        // A single blocking thread runs the blocking tasks one after the other
+        let (first, second) = runtime.block_on(async {
+            let run = || tokio::task::spawn_blocking(|| {
+                let started = Instant::now();
+                std::thread::sleep(Duration::from_millis(50));
+                (started, Instant::now())
+            });
+            let (first, second) = (run(), run());
+            (first.await.unwrap(), second.await.unwrap())
+        });
+        let ((first_started, first_ended), (second_started, second_ended)) = (first, second);
+        assert!(second_started >= first_ended || first_started >= second_ended);
+
+        assert!(build(Threads { workers: 0, blocking: 1 }, Priority::default()).is_err());
+        assert!(build(Threads { workers: 1, blocking: 0 }, Priority::default()).is_err());
+    }
+
+    #[test]
+    fn defaults_follow_the_concurrency() {
+        let threads = Threads::for_copies(1, 1, 2);
+        assert_eq!(threads, Threads { workers: 1, blocking: 3 });
+        let threads = Threads::for_copies(10, 4, 20);
+        assert!((1..=10).contains(&threads.workers));
+        assert_eq!(threads.blocking, 60);
+    }
+}
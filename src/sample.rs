@@ -0,0 +1,100 @@
+//! `--skip-unchanged sample`: a destination file is taken as unchanged when it has the size of the source and the
+//! same checksum of three sampled blocks, the head, the middle and the tail. The files too small for three blocks
+//! are hashed entirely. Changes between the samples go unnoticed, the skips are counted apart for that reason
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+use anyhow::{Context, Result};
+use crate::checksum::{ChecksumAlgorithm, Hasher};
+
+/// Bytes of every sampled block
+const SAMPLE_BLOCK: u64 = 64 * 1024;
+
+/// The (offset, length) of the regions hashed for a file of `len` bytes. They only depend on the length, so the
+/// source and the destination are sampled at the same offsets
+fn regions(len: u64) -> Vec<(u64, u64)> {
+    if len < 3 * SAMPLE_BLOCK {
+        return (0..len).step_by(SAMPLE_BLOCK as usize)
+            .map(|offset| (offset, SAMPLE_BLOCK.min(len - offset)))
+            .collect();
+    }
+    vec![(0, SAMPLE_BLOCK), ((len - SAMPLE_BLOCK) / 2, SAMPLE_BLOCK), (len - SAMPLE_BLOCK, SAMPLE_BLOCK)]
+}
+
+/// The checksum of the regions of `path`, whose length is `len`
+fn sample_digest(path: &Path, len: u64, algorithm: ChecksumAlgorithm) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut buffer = vec![0u8; SAMPLE_BLOCK as usize];
+    let mut hasher = Hasher::new(algorithm);
+    for (offset, length) in regions(len) {
+        let block = &mut buffer[..length as usize];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(block)?;
+        hasher.update(block);
+    }
+    Ok(hasher.finish())
+}
+
+/// Whether the destination `to` is a file with the size and the sampled checksum of the source `from`
+pub async fn unchanged(from: &Path, to: &Path, algorithm: ChecksumAlgorithm) -> Result<bool> {
+    let (from, to) = (from.to_owned(), to.to_owned());
+    tokio::task::spawn_blocking(move || {
+        let len = std::fs::metadata(&from).with_context(|| format!("Cannot read metadata: {:?}", from))?.len();
+        match std::fs::metadata(&to) {
+            Ok(dest) if dest.is_file() && dest.len() == len => {},
+            _ => return Ok(false),
+        }
+        let source = sample_digest(&from, len, algorithm).with_context(|| format!("Cannot sample file: {:?}", from))?;
+        let dest = sample_digest(&to, len, algorithm).with_context(|| format!("Cannot sample file: {:?}", to))?;
+        Ok(source == dest)
+    }).await?
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::checksum::ChecksumAlgorithm;
+    use crate::test_support::init;
+    use super::{regions, unchanged, SAMPLE_BLOCK};
+
+    #[test]
+    fn sampled_regions() {
+        let block = SAMPLE_BLOCK;
+        assert_eq!(regions(0), []);
+        assert_eq!(regions(10), [(0, 10)]);
+        assert_eq!(regions(block + 1), [(0, block), (block, 1)]);
+        assert_eq!(regions(3 * block), [(0, block), (block, block), (2 * block, block)]);
+        assert_eq!(regions(10 * block + 1), [(0, block), (4 * block + block / 2, block), (9 * block + 1, block)]);
+    }
+
+    #[tokio::test]
+    async fn sampled_comparison() {
+        let base_dir = init("sampled_comparison").await;
+
+        let (source, dest) = (base_dir.join("source"), base_dir.join("dest"));
+        let content: Vec<u8> = (0..10 * SAMPLE_BLOCK).map(|byte| byte as u8).collect();
+        let check = |mut changed: Vec<u8>, offset: u64| {
+            let (source, dest, content) = (source.clone(), dest.clone(), content.clone());
+            async move {
+                if let Some(byte) = changed.get_mut(offset as usize) {
+                    *byte ^= 0xff;
+                }
+                tokio::fs::write(&source, &content).await.unwrap();
+                tokio::fs::write(&dest, &changed).await.unwrap();
+                unchanged(&source, &dest, ChecksumAlgorithm::Blake3).await.unwrap()
+            }
+        };
+        assert!(check(content.clone(), u64::MAX).await);
+        // The head, the middle and the tail are compared
+        assert!(!check(content.clone(), 10).await);
+        assert!(!check(content.clone(), 5 * SAMPLE_BLOCK).await);
+        assert!(!check(content.clone(), 10 * SAMPLE_BLOCK - 1).await);
+        // But not what is between them
+        assert!(check(content.clone(), 2 * SAMPLE_BLOCK).await);
+        // Another size or no destination at all
+        assert!(!check(content[1..].to_vec(), u64::MAX).await);
+        tokio::fs::remove_file(&dest).await.unwrap();
+        assert!(!unchanged(&source, &dest, ChecksumAlgorithm::Blake3).await.unwrap());
+    }
+}
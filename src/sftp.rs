@@ -0,0 +1,485 @@
+//! `sftp://[user@]host[:port]/path` destinations. The files are uploaded with the OpenSSH client: a master `ssh`
+//! connection is opened once and every operation runs an `sftp` batch over it, so the concurrent copies are concurrent
+//! SFTP channels of a single authenticated connection (Unix only, it needs the control socket of the master).
+//! This runs the OpenSSH client as a fallback for an in-process SSH library, which is not among the dependencies: it
+//! costs a process per upload and per directory created, which shows on trees of many small files. The hosts, keys,
+//! agent and `known_hosts` of the user's OpenSSH configuration apply as they do to `sftp` itself; the tests fake the
+//! client with a script
+
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+use anyhow::{anyhow, Context, Result};
+use log::{debug, warn};
+use tokio::io::AsyncWriteExt;
+use tokio::process::{Child, Command};
+use crate::backend::{Backend, BackendFuture, DirEntries, FileInfo};
+use crate::{CopyOptions, IfExists};
+
+/// Time between the checks of the master connection while it authenticates
+const CONNECT_POLL: Duration = Duration::from_millis(100);
+
+/// Suffix of the uploads in progress, they are renamed to their name once complete
+const PARTIAL_SUFFIX: &str = ".rs-copier-part";
+
+/// The parts of an `sftp://` destination
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SftpUrl {
+    /// `user@host` or `host`, as ssh takes it
+    pub host: String,
+    pub port: Option<u16>,
+    /// Remote directory, relative to the home of the user when the URL has no path
+    pub path: PathBuf,
+}
+
+impl SftpUrl {
+    pub fn parse(url: &str) -> Result<Self> {
+        let rest = url.strip_prefix("sftp://").ok_or_else(|| anyhow!("Not an sftp:// URL: {:?}", url))?;
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => (&rest[..slash], &rest[slash..]),
+            None => (rest, "."),
+        };
+        let (user, host_port) = match authority.rsplit_once('@') {
+            Some((user, host_port)) => (Some(user), host_port),
+            None => (None, authority),
+        };
+        // [::1]:22 for IPv6 addresses
+        let (host, port) = match host_port.strip_prefix('[') {
+            Some(bracketed) => {
+                let (host, after) = bracketed.split_once(']').ok_or_else(|| anyhow!("Unclosed bracket in {:?}", url))?;
+                (host, after.strip_prefix(':'))
+            },
+            None => match host_port.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (host_port, None),
+            },
+        };
+        if host.is_empty() {
+            return Err(anyhow!("The sftp:// URL has no host: {:?}", url));
+        }
+        let port = port.map(|port| port.parse().with_context(|| format!("Invalid port in {:?}", url))).transpose()?;
+        let host = match user {
+            Some(user) => format!("{user}@{host}"),
+            None => host.to_owned(),
+        };
+        Ok(Self { host, port, path: PathBuf::from(path) })
+    }
+}
+
+/// An SFTP server reached through a master ssh connection
+#[derive(Debug)]
+pub struct Sftp {
+    url: SftpUrl,
+    /// Control socket of the master connection, in a private directory
+    socket: PathBuf,
+    socket_dir: PathBuf,
+    /// The sftp client, a fake one in the tests
+    client: PathBuf,
+    /// Killed when the backend is dropped
+    _master: Child,
+}
+
+impl Sftp {
+    /// Open the master connection and wait until it is authenticated
+    pub async fn connect(url: &SftpUrl) -> Result<Self> {
+        let socket_dir = private_dir()?;
+        let socket = socket_dir.join("control.sock");
+        let mut master = Command::new("ssh");
+        master.args(["-M", "-N", "-o", "ControlPersist=no", "-o", "ServerAliveInterval=30", "-S"]).arg(&socket);
+        if let Some(port) = url.port {
+            master.arg("-p").arg(port.to_string());
+        }
+        let mut master = master.arg(&url.host)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .context("Cannot run ssh, SFTP destinations need the OpenSSH client")?;
+        loop {
+            if let Some(status) = master.try_wait()? {
+                return Err(anyhow!("Cannot connect to {}: ssh exited with {}", url.host, status));
+            }
+            let check = Command::new("ssh").args(["-O", "check", "-S"]).arg(&socket).arg(&url.host)
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status().await?;
+            if check.success() {
+                break;
+            }
+            tokio::time::sleep(CONNECT_POLL).await;
+        }
+        Ok(Self { url: url.clone(), socket, socket_dir, client: PathBuf::from("sftp"), _master: master })
+    }
+
+    /// Run the commands in a new SFTP channel, their output. Any failed command fails the batch, unless it starts
+    /// with `-`
+    async fn run(&self, commands: &str) -> Result<String> {
+        debug!("sftp {}: {}", self.url.host, commands.trim_end());
+        let mut sftp = Command::new(&self.client);
+        sftp.args(["-q", "-b", "-", "-o", "ControlMaster=no", "-o"]).arg(format!("ControlPath={}", self.socket.display()));
+        if let Some(port) = self.url.port {
+            sftp.arg("-P").arg(port.to_string());
+        }
+        let mut sftp = sftp.arg(&self.url.host)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .context("Cannot run sftp, SFTP destinations need the OpenSSH client")?;
+        let mut stdin = sftp.stdin.take().expect("stdin is piped");
+        stdin.write_all(commands.as_bytes()).await?;
+        drop(stdin);
+        let output = sftp.wait_with_output().await?;
+        if !output.status.success() {
+            return Err(anyhow!("sftp failed ({}): {}", output.status, String::from_utf8_lossy(&output.stderr).trim()));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+}
+
+/// A new directory that only the user can enter (mkdtemp gives it the mode 0700) for the control socket. A name known
+/// in advance in the shared temporary directory could be taken, or replaced, by another user
+#[cfg(unix)]
+fn private_dir() -> Result<PathBuf> {
+    use std::os::unix::ffi::{OsStrExt, OsStringExt};
+    let template = std::env::temp_dir().join("rs-copier-XXXXXX");
+    let mut bytes = std::ffi::CString::new(template.as_os_str().as_bytes())?.into_bytes_with_nul();
+    // SAFETY: the template is a NUL terminated string that mkdtemp changes in place
+    if unsafe { libc::mkdtemp(bytes.as_mut_ptr().cast()) }.is_null() {
+        return Err(std::io::Error::last_os_error()).context("Cannot create the directory of the ssh control socket");
+    }
+    bytes.pop();
+    Ok(PathBuf::from(std::ffi::OsString::from_vec(bytes)))
+}
+
+#[cfg(not(unix))]
+fn private_dir() -> Result<PathBuf> {
+    Err(anyhow!("SFTP destinations are only supported on Unix"))
+}
+
+/// The size in the `ls -l` line of a regular file (`-rw-r--r-- 1 1000 1000 1234 Jan 1 00:00 name`). The batch echoes
+/// its commands after `sftp>`
+fn listed_size(listing: &str) -> Option<u64> {
+    listing.lines()
+        .filter(|line| line.starts_with('-'))
+        .find_map(|line| line.split_whitespace().nth(4)?.parse().ok())
+}
+
+impl Drop for Sftp {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.socket);
+        let _ = std::fs::remove_dir(&self.socket_dir);
+    }
+}
+
+impl Backend for Sftp {
+    fn read_dir<'a>(&'a self, _dir: &'a Path) -> BackendFuture<'a, Box<dyn DirEntries>> {
+        Box::pin(async { Err(anyhow!("SFTP can only be the destination of a copy")) })
+    }
+
+    fn metadata<'a>(&'a self, _path: &'a Path) -> BackendFuture<'a, FileInfo> {
+        Box::pin(async { Err(anyhow!("SFTP can only be the destination of a copy")) })
+    }
+
+    /// The file is uploaded next to its destination with a temporary name and renamed once complete, so an
+    /// interrupted upload never replaces the destination. With options.fsync the server flushes it before the rename
+    fn copy<'a>(&'a self, from: &'a Path, to: &'a Path, options: &'a CopyOptions) -> BackendFuture<'a, Option<PathBuf>> {
+        Box::pin(async move {
+            // Copies to the same destination must not overlap
+            let _claim = match &options.claims {
+                Some(claims) => {
+                    let (claimed, guard) = claims.claim(to).await;
+                    if claimed {
+                        warn!("{:?} is overwritten by {:?}, several sources are copied to it", to, from);
+                    }
+                    Some(guard)
+                },
+                None => None,
+            };
+            let bytes = tokio::fs::metadata(from).await
+                .with_context(|| format!("Cannot read metadata: {:?}", from))?.len();
+            let mut partial = to.as_os_str().to_owned();
+            partial.push(PARTIAL_SUFFIX);
+            let partial = PathBuf::from(partial);
+            let put = if options.fsync { "put -f" } else { "put" };
+            let mut commands = format!("{put} {} {}\nrename {} {}\n", quote(from)?, quote(&partial)?, quote(&partial)?, quote(to)?);
+            if options.remove_source {
+                // Its size confirms the upload, in the same batch
+                commands.push_str(&format!("ls -ln {}\n", quote(to)?));
+            }
+            let listing = match self.run(&commands).await {
+                Ok(listing) => listing,
+                Err(error) => {
+                    if let Err(error) = self.remove_file(&partial).await {
+                        debug!("Cannot remove the partial upload {:?}: {:#}", partial, error);
+                    }
+                    return Err(error.context(format!("Cannot upload file: {:?}", from)));
+                },
+            };
+            options.stats.copied(bytes, false);
+            if options.remove_source {
+                // Like crate::copy::confirm_copy, with the size the server gives
+                let uploaded = listed_size(&listing)
+                    .ok_or_else(|| anyhow!("No file in the listing of {}:{:?}: {:?}", self.url.host, to, listing.trim()))
+                    .with_context(|| format!("The upload of {:?} is not confirmed, its source is kept", from))?;
+                if uploaded != bytes {
+                    return Err(anyhow!("The upload of {:?} has {} bytes instead of the {} of its source, its source is kept", from, uploaded, bytes));
+                }
+                if options.defers_removal() {
+                    return Ok(Some(from.to_owned()));
+                }
+                crate::remove_source(from, options).await?;
+            }
+            Ok(None)
+        })
+    }
+
+    /// SFTP has no `mkdir -p`: every ancestor is created ignoring the ones that exist and then the directory is checked
+    fn create_dir_all<'a>(&'a self, dir: &'a Path) -> BackendFuture<'a, ()> {
+        Box::pin(async move {
+            let mut commands = String::new();
+            let mut ancestors: Vec<&Path> = dir.ancestors().filter(|ancestor| !matches!(ancestor.to_str(), Some("" | "/" | "."))).collect();
+            ancestors.reverse();
+            for ancestor in ancestors {
+                commands.push_str(&format!("-mkdir {}\n", quote(ancestor)?));
+            }
+            commands.push_str(&format!("cd {}\n", quote(dir)?));
+            self.run(&commands).await.with_context(|| format!("Cannot create directory: {}:{:?}", self.url.host, dir))?;
+            Ok(())
+        })
+    }
+
+    /// An uploaded file is already flushed by the server (with options.fsync), SFTP cannot flush directories
+    fn sync_directory<'a>(&'a self, _dir: &'a Path) -> BackendFuture<'a, ()> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a Path) -> BackendFuture<'a, ()> {
+        Box::pin(async move {
+            self.run(&format!("rm {}\n", quote(path)?)).await
+                .with_context(|| format!("Cannot remove file: {}:{:?}", self.url.host, path))?;
+            Ok(())
+        })
+    }
+
+    fn remove_dir_all<'a>(&'a self, dir: &'a Path) -> BackendFuture<'a, ()> {
+        // The sftp client cannot remove a tree, --on-type-conflict is rejected
+        Box::pin(async move { Err(anyhow!("Cannot remove directory, SFTP only removes files: {}:{:?}", self.url.host, dir)) })
+    }
+}
+
+/// Quote a path for an sftp command. The quotes, backslashes and glob characters are escaped because put and rm expand
+/// globs even inside quotes
+fn quote(path: &Path) -> Result<String> {
+    let path = path.to_str().ok_or_else(|| anyhow!("Only UTF-8 paths can be copied through SFTP: {:?}", path))?;
+    let mut quoted = String::with_capacity(path.len() + 2);
+    quoted.push('"');
+    for c in path.chars() {
+        if matches!(c, '"' | '\\' | '*' | '?' | '[') {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    Ok(quoted)
+}
+
+/// The options a copy through SFTP cannot honour, the first one set is reported
+pub fn unsupported_option(options: &CopyOptions) -> Option<&'static str> {
+    [
+        (options.manifest.is_some(), "--manifest"),
+        (options.verify, "--verify"),
+        (options.dedupe.is_some(), "--dedupe-dest"),
+        (options.if_exists != IfExists::Overwrite, "--if-exists"),
+        (options.on_type_conflict != crate::OnTypeConflict::Error, "--on-type-conflict"),
+        (options.skip_unchanged.is_some(), "--skip-unchanged"),
+        (options.reflink != crate::Reflink::Never, "--reflink"),
+        (options.copy_engine != crate::Engine::Auto, "--copy-engine"),
+        (options.chunk_parallelism > 1, "--chunk-parallelism"),
+        (options.resume, "--resume"),
+        (options.atomic, "--atomic"),
+        (options.tempdir.is_some(), "--tempdir"),
+        (options.bandwidth.is_some(), "--bwlimit"),
+        (options.chmod.is_some() || options.chmod_dirs.is_some(), "--chmod"),
+        (options.preserve.timestamps, "--preserve timestamps"),
+        (!options.preserve.mode, "--preserve without mode"),
+        (options.preserve.ownership, "--preserve ownership"),
+        (options.preserve.xattrs, "--preserve xattrs"),
+        (options.preserve.acls, "--preserve acls"),
+        (options.follow_top_level_links, "--follow-top-level-links"),
+        (options.preserve.links, "--preserve links"),
+        (options.dangling_links == crate::DanglingLinks::Preserve, "--dangling-links preserve"),
+        (options.preserve.attributes, "--preserve attributes"),
+        (options.preserve.crtime, "--preserve crtime"),
+        (options.preallocate, "--preallocate"),
+        (options.direct_io, "--direct-io"),
+        (options.min_free_space.is_some(), "--min-free-space"),
+        (options.case_folds.is_some(), "--case-fold-merge"),
+        (options.hooks.is_some(), "--post-copy-hook"),
+    ].into_iter().find_map(|(set, flag)| set.then_some(flag))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use super::{listed_size, quote, Sftp, SftpUrl};
+
+    /// A backend whose sftp client is a script running the batch on the local filesystem, like a server would. `put`
+    /// is the command that uploads `$2` into `$3`
+    #[cfg(unix)]
+    fn fake_remote(base_dir: &Path, put: &str) -> Sftp {
+        use std::os::unix::fs::PermissionsExt;
+        let client = base_dir.join("sftp");
+        let script = format!(r#"#!/bin/sh
+while IFS= read -r line; do
+    echo "sftp> $line"
+    eval "set -- $line"
+    case "$1" in
+        put) if [ "$2" = -f ]; then shift; fi; {put} ;;
+        rename) mv "$2" "$3" ;;
+        ls) ls -ln "$3" ;;
+        rm) rm "$2" ;;
+        -mkdir) mkdir "$2" 2>/dev/null || true ;;
+        cd) cd "$2" ;;
+        *) false ;;
+    esac || exit 1
+done
+"#);
+        std::fs::write(&client, script).unwrap();
+        std::fs::set_permissions(&client, std::fs::Permissions::from_mode(0o755)).unwrap();
+        // Stands for the master connection
+        let master = tokio::process::Command::new("true").spawn().unwrap();
+        let url = SftpUrl { host: "fake".into(), port: None, path: base_dir.join("dest") };
+        Sftp { url, socket: base_dir.join("control/socket"), socket_dir: base_dir.join("control"), client, _master: master }
+    }
+
+    #[test]
+    fn urls() {
+        let url = SftpUrl::parse("sftp://backup@example.com:2222/srv/data").unwrap();
+        assert_eq!(url, SftpUrl { host: "backup@example.com".into(), port: Some(2222), path: PathBuf::from("/srv/data") });
+        let url = SftpUrl::parse("sftp://example.com").unwrap();
+        assert_eq!(url, SftpUrl { host: "example.com".into(), port: None, path: PathBuf::from(".") });
+        let url = SftpUrl::parse("sftp://me@[::1]:22/tmp").unwrap();
+        assert_eq!((url.host.as_str(), url.port), ("me@::1", Some(22)));
+        assert!(SftpUrl::parse("sftp:///path").is_err());
+        assert!(SftpUrl::parse("sftp://host:port/path").is_err());
+        assert!(SftpUrl::parse("/local/path").is_err());
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(quote(Path::new("/a b/c")).unwrap(), r#""/a b/c""#);
+        assert_eq!(quote(Path::new(r#"x"y\*?[z]"#)).unwrap(), r#""x\"y\\\*\?\[z]""#);
+    }
+
+    #[test]
+    fn listings() {
+        let listing = "sftp> ls -ln \"/srv/a b\"\n-rw-r--r--    1 1000     1000         1234 Jan  1 00:00 /srv/a b\n";
+        assert_eq!(listed_size(listing), Some(1234));
+        assert_eq!(listed_size("sftp> ls -ln \"/srv\"\ndrwxr-xr-x    2 0 0 4096 Jan  1 00:00 /srv\n"), None);
+        assert_eq!(listed_size(""), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn private_socket_dirs() {
+        use std::os::unix::fs::PermissionsExt;
+        let (first, second) = (super::private_dir().unwrap(), super::private_dir().unwrap());
+        assert_ne!(first, second);
+        assert_eq!(first.parent(), Some(std::env::temp_dir().as_path()));
+        assert_eq!(std::fs::metadata(&first).unwrap().permissions().mode() & 0o7777, 0o700);
+        std::fs::remove_dir(first).unwrap();
+        std::fs::remove_dir(second).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn confirmed_uploads() {
+        use crate::backend::Backend;
+        use crate::test_support::init;
+        use crate::CopyOptions;
+
+        let base_dir = init("confirmed_uploads").await;
+        tokio::fs::create_dir_all(base_dir.join("dest")).await.unwrap();
+        let options = CopyOptions { remove_source: true, ..Default::default() };
+        let (from, to) = (base_dir.join("file one"), base_dir.join("dest/file one"));
+
+        // The size on the server is the one of the source
+        tokio::fs::write(&from, "hello").await.unwrap();
+        fake_remote(&base_dir, r#"cp "$2" "$3""#).copy(&from, &to, &options).await.unwrap();
+        assert_eq!(tokio::fs::read_to_string(&to).await.unwrap(), "hello");
+        assert!(!from.exists());
+
+        // A short upload keeps its source
+        tokio::fs::write(&from, "hello").await.unwrap();
+        let short = fake_remote(&base_dir, r#"head -c 2 "$2" > "$3""#);
+        let error = short.copy(&from, &to, &options).await.unwrap_err();
+        assert!(error.to_string().contains("has 2 bytes instead of the 5 of its source"), "{:#}", error);
+        assert_eq!(tokio::fs::read_to_string(&from).await.unwrap(), "hello");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn fake_server_uploads() {
+        use std::sync::Arc;
+        use crate::backend::Backend;
+        use crate::test_support::init;
+        use crate::{copy_tree, CopyOptions, CopyPermits};
+
+        let base_dir = init("fake_server_uploads").await;
+        let source = base_dir.join("source");
+        tokio::fs::create_dir_all(source.join("a/b")).await.unwrap();
+        tokio::fs::write(source.join("top"), "top").await.unwrap();
+        tokio::fs::write(source.join("a/b/nested file"), "nested").await.unwrap();
+
+        // The directories are created and every file is renamed once uploaded
+        let sftp = fake_remote(&base_dir, r#"cp "$2" "$3""#);
+        let dest = sftp.url.path.clone();
+        let options = Arc::new(CopyOptions { copy_permits: CopyPermits::new(4), destination: Some(Arc::new(sftp)), ..Default::default() });
+        copy_tree(&source, &dest, options.clone(), 4).await.unwrap();
+        assert_eq!(options.stats.summary(), "2 files copied, 0 files cloned, 0 files renamed, 9 bytes");
+        assert_eq!(tokio::fs::read_to_string(dest.join("top")).await.unwrap(), "top");
+        assert_eq!(tokio::fs::read_to_string(dest.join("a/b/nested file")).await.unwrap(), "nested");
+        assert_eq!(std::fs::read_dir(dest.join("a/b")).unwrap().count(), 1);
+
+        // A failed upload removes its partial file
+        let broken = fake_remote(&base_dir, r#"head -c 2 "$2" > "$3"; false"#);
+        let error = broken.copy(&source.join("top"), &dest.join("again"), &CopyOptions::default()).await.unwrap_err();
+        assert!(format!("{:#}", error).starts_with("Cannot upload file"), "{:#}", error);
+        assert_eq!(std::fs::read_dir(&dest).unwrap().count(), 2);
+        broken.remove_file(&dest.join("top")).await.unwrap();
+        assert!(!dest.join("top").exists());
+    }
+
+    /// Needs an SSH server that accepts the key of the current user without a password, the URL of the destination
+    /// is taken from RS_COPIER_SFTP_URL and defaults to localhost. `cargo test --features sftp-tests`
+    #[cfg(feature = "sftp-tests")]
+    #[tokio::test]
+    async fn sftp_copy_tree() {
+        use std::sync::Arc;
+        use crate::test_support::init;
+        use crate::{copy_tree, CopyOptions, CopyPermits};
+
+        let base_dir = init("sftp_copy_tree").await;
+        let source = base_dir.join("source");
+        tokio::fs::create_dir_all(source.join("a/b")).await.unwrap();
+        tokio::fs::write(source.join("top"), "top").await.unwrap();
+        tokio::fs::write(source.join("a/b/nested file"), "nested").await.unwrap();
+
+        let default_url = format!("sftp://localhost{}", base_dir.join("dest").display());
+        let url = SftpUrl::parse(&std::env::var("RS_COPIER_SFTP_URL").unwrap_or(default_url)).unwrap();
+        let sftp = Sftp::connect(&url).await.unwrap();
+        let options = Arc::new(CopyOptions { copy_permits: CopyPermits::new(4), destination: Some(Arc::new(sftp)), ..Default::default() });
+        copy_tree(&source, &url.path, options.clone(), 4).await.unwrap();
+        assert_eq!(options.stats.summary(), "2 files copied, 0 files cloned, 0 files renamed, 9 bytes");
+
+        // A local server writes where the test can check it
+        if url.host == "localhost" {
+            assert_eq!(tokio::fs::read_to_string(url.path.join("top")).await.unwrap(), "top");
+            assert_eq!(tokio::fs::read_to_string(url.path.join("a/b/nested file")).await.unwrap(), "nested");
+        }
+    }
+}
@@ -0,0 +1,117 @@
+//! Human readable sizes and durations
+
+use std::time::Duration;
+
+/// Parse a size like `512`, `64K`, `1MiB`, `1.5G` or `2TB`. The multiples are powers of 1024
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let split = value.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let multiplier: u64 = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        "T" | "TB" | "TIB" => 1 << 40,
+        _ => return Err(format!("unknown size unit: {:?}", unit)),
+    };
+    if let Ok(number) = number.parse::<u64>() {
+        return number.checked_mul(multiplier).ok_or_else(|| format!("size too big: {:?}", value));
+    }
+    match number.parse::<f64>() {
+        Ok(number) if number.is_finite() && number >= 0.0 => Ok((number * multiplier as f64) as u64),
+        _ => Err(format!("invalid size: {:?}", value)),
+    }
+}
+
+/// Format a size like `1023 B`, `1.5 KiB` or `10.0 MiB`, with one decimal and the powers of 1024 of `parse_size`
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 6] = ["KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    // A value that rounds up to 1024.0 is shown in the next unit
+    while value >= 1023.95 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+/// Parse a rate in bytes per second like `50MB`, `50MB/s` or `512K`
+pub fn parse_rate(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    parse_size(value.strip_suffix("/s").unwrap_or(value))
+}
+
+/// Parse a duration like `90` (seconds), `500ms`, `90s`, `15m`, `2h`, `7d` or `1w`
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let split = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: u64 = number.parse().map_err(|_| format!("invalid duration: {:?}", value))?;
+    let milliseconds: u64 = match unit {
+        "ms" => 1,
+        "" | "s" => 1000,
+        "m" => 60 * 1000,
+        "h" => 60 * 60 * 1000,
+        "d" => 24 * 60 * 60 * 1000,
+        "w" => 7 * 24 * 60 * 60 * 1000,
+        _ => return Err(format!("unknown duration unit: {:?}", unit)),
+    };
+    number.checked_mul(milliseconds)
+        .map(Duration::from_millis)
+        .ok_or_else(|| format!("duration too big: {:?}", value))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::{format_size, parse_duration, parse_rate, parse_size};
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("0"), Ok(0));
+        assert_eq!(parse_size("1023"), Ok(1023));
+        assert_eq!(parse_size("64K"), Ok(64 * 1024));
+        assert_eq!(parse_size("1MiB"), Ok(1024 * 1024));
+        assert_eq!(parse_size("1.5G"), Ok(3 * 512 * 1024 * 1024));
+        assert_eq!(parse_size("2 tb"), Ok(2 << 40));
+        assert!(parse_size("1X").is_err());
+        assert!(parse_size("MB").is_err());
+        assert!(parse_size("99999999999T").is_err());
+    }
+
+    #[test]
+    fn formatted_sizes() {
+        assert_eq!(format_size(0), "0 B");
+        assert_eq!(format_size(1023), "1023 B");
+        assert_eq!(format_size(1024), "1.0 KiB");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(1024 * 1024 - 1), "1.0 MiB");
+        assert_eq!(format_size(10 * 1024 * 1024), "10.0 MiB");
+        assert_eq!(format_size(3 * 512 * 1024 * 1024), "1.5 GiB");
+        assert_eq!(format_size(2 << 40), "2.0 TiB");
+        assert_eq!(format_size(u64::MAX), "16.0 EiB");
+    }
+
+    #[test]
+    fn rates() {
+        assert_eq!(parse_rate("50MB/s"), Ok(50 << 20));
+        assert_eq!(parse_rate("512K"), Ok(512 << 10));
+        assert!(parse_rate("/s").is_err());
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_duration("15m"), Ok(Duration::from_secs(15 * 60)));
+        assert_eq!(parse_duration("1w"), Ok(Duration::from_secs(7 * 24 * 3600)));
+        assert!(parse_duration("5x").is_err());
+        assert!(parse_duration("m").is_err());
+    }
+}
@@ -0,0 +1,96 @@
+//! The source given in the command line is checked before anything else is done: a source that does not exist, that
+//! is neither a directory nor a regular file (a device, a socket) or that cannot be read stops the run with a message
+//! and an exit code of its own, instead of the error of the first listing of the walk
+
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use crate::failures::{EXIT_SOURCE_MISSING, EXIT_SOURCE_NOT_DIRECTORY, EXIT_SOURCE_UNREADABLE};
+
+/// Why the source cannot be copied
+#[derive(Debug)]
+pub enum InvalidSource {
+    Missing(PathBuf),
+    /// A device, a pipe or a socket
+    NotADirectory(PathBuf),
+    Unreadable(PathBuf, io::Error),
+}
+
+impl InvalidSource {
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Self::Missing(_) => EXIT_SOURCE_MISSING,
+            Self::NotADirectory(_) => EXIT_SOURCE_NOT_DIRECTORY,
+            Self::Unreadable(..) => EXIT_SOURCE_UNREADABLE,
+        }
+    }
+}
+
+impl fmt::Display for InvalidSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Missing(path) => write!(f, "The source does not exist: {:?}", path),
+            Self::NotADirectory(path) => write!(f, "The source is neither a directory nor a regular file: {:?}", path),
+            Self::Unreadable(path, error) => write!(f, "The source cannot be read: {:?}: {}", path, error),
+        }
+    }
+}
+
+impl std::error::Error for InvalidSource {}
+
+/// Check that `path` is a directory that can be listed, or a file (an archive or one copied on its own) that can be
+/// opened
+pub async fn check(path: &Path) -> Result<(), InvalidSource> {
+    let metadata = match tokio::fs::metadata(path).await {
+        Ok(metadata) => metadata,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Err(InvalidSource::Missing(path.to_owned())),
+        Err(error) => return Err(InvalidSource::Unreadable(path.to_owned(), error)),
+    };
+    let opened = if metadata.is_dir() {
+        tokio::fs::read_dir(path).await.map(drop)
+    } else if metadata.is_file() {
+        tokio::fs::File::open(path).await.map(drop)
+    } else {
+        return Err(InvalidSource::NotADirectory(path.to_owned()));
+    };
+    opened.map_err(|error| InvalidSource::Unreadable(path.to_owned(), error))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::test_support::init;
+    use super::{check, InvalidSource};
+
+    #[tokio::test]
+    async fn invalid_sources() {
+        let base_dir = init("invalid_sources").await;
+
+        tokio::fs::write(base_dir.join("file.txt"), "text").await.unwrap();
+        tokio::fs::write(base_dir.join("archive.tar"), "").await.unwrap();
+        assert!(check(&base_dir).await.is_ok());
+        assert!(check(&base_dir.join("archive.tar")).await.is_ok());
+        assert!(check(&base_dir.join("file.txt")).await.is_ok());
+        let missing = check(&base_dir.join("missing")).await.unwrap_err();
+        assert!(matches!(&missing, InvalidSource::Missing(path) if path == &base_dir.join("missing")));
+        #[cfg(unix)]
+        {
+            let device = check(std::path::Path::new("/dev/null")).await.unwrap_err();
+            assert!(matches!(&device, InvalidSource::NotADirectory(_)));
+            assert_ne!(missing.exit_code(), device.exit_code());
+        }
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let locked = base_dir.join("locked");
+            tokio::fs::create_dir(&locked).await.unwrap();
+            std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o000)).unwrap();
+            // The permissions do not stop root
+            if std::fs::read_dir(&locked).is_err() {
+                assert!(matches!(check(&locked).await.unwrap_err(), InvalidSource::Unreadable(..)));
+            }
+            std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o755)).unwrap();
+        }
+    }
+}
@@ -0,0 +1,217 @@
+//! The free space of the destination checked against the bytes found by the pre-scan, before copying anything,
+//! so a big move does not run the destination out of space half way. `--min-free-space` also watches it during the
+//! copy and stops the run when it drops below, instead of failing every file that follows with ENOSPC
+
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use anyhow::Result;
+use log::{info, warn};
+
+/// Time between two measures of the free space during the copy
+const MONITOR_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How the bytes of the pre-scan compare to what the copy takes at the destination
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Estimate {
+    /// The destination files take the bytes of the sources
+    Exact,
+    /// The destination may take less: compressed archives, clones that share the blocks of the sources
+    UpperBound,
+}
+
+/// Compare the `needed` bytes with the free space of `dest`, measured with `free_space`, that must also leave
+/// `reserve` bytes free (`--min-free-space`). Not enough space is an error unless `force` or the estimate is only an
+/// upper bound, then it is a warning. A destination whose free space cannot be read is not checked
+pub fn check(
+    dest: &Path,
+    needed: u64,
+    reserve: u64,
+    estimate: Estimate,
+    force: bool,
+    free_space: impl Fn(&Path) -> io::Result<u64>,
+) -> Result<()> {
+    let measured = existing_ancestor(dest);
+    let available = match free_space(measured) {
+        Ok(available) if reserve > 0 => {
+            info!("{} bytes free at the destination, {} of them are kept free (--min-free-space)", available, reserve.min(available));
+            available.saturating_sub(reserve)
+        },
+        Ok(available) => available,
+        Err(error) => {
+            warn!("Cannot read the free space of {:?}, it is not checked: {}", measured, error);
+            return Ok(());
+        }
+    };
+    if needed <= available {
+        info!("{} bytes to copy, {} bytes free at the destination", needed, available);
+        return Ok(());
+    }
+    match (estimate, force) {
+        (Estimate::UpperBound, _) => warn!(
+            "Up to {} bytes to copy and only {} bytes free at the destination {:?}, the copy may not fit",
+            needed, available, measured,
+        ),
+        (Estimate::Exact, true) => warn!(
+            "{} bytes to copy and only {} bytes free at the destination {:?}, copying anyway (--force)",
+            needed, available, measured,
+        ),
+        (Estimate::Exact, false) => return Err(anyhow::anyhow!(
+            "Not enough space at the destination {:?}: {} bytes to copy and {} bytes free (--force to copy anyway, --no-space-check to skip the check)",
+            measured, needed, available,
+        )),
+    }
+    Ok(())
+}
+
+/// `--min-free-space`: the free space of the destination, watched while the files are copied
+#[derive(Debug, Clone)]
+pub struct Monitor {
+    dest: PathBuf,
+    min: u64,
+}
+
+impl Monitor {
+    pub fn new(dest: &Path, min: u64) -> Self {
+        Self { dest: dest.to_owned(), min }
+    }
+
+    /// Wait until the free space of the destination is below the minimum. A destination whose free space cannot be
+    /// read is not watched, it never returns then
+    pub async fn low(&self) -> OutOfSpace {
+        let mut interval = tokio::time::interval(MONITOR_INTERVAL);
+        loop {
+            interval.tick().await;
+            let measured = existing_ancestor(&self.dest).to_owned();
+            let measured = tokio::task::spawn_blocking(move || free_space(&measured)).await
+                .unwrap_or_else(|error| Err(io::Error::other(error)));
+            match measured {
+                Ok(available) if available < self.min => {
+                    return OutOfSpace { dest: self.dest.clone(), available, min: self.min };
+                },
+                Ok(_) => {},
+                Err(error) => {
+                    warn!("Cannot read the free space of {:?}, it is not watched: {}", self.dest, error);
+                    return std::future::pending().await;
+                },
+            }
+        }
+    }
+}
+
+/// The error of a run stopped by `--min-free-space`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutOfSpace {
+    pub dest: PathBuf,
+    pub available: u64,
+    pub min: u64,
+}
+
+impl fmt::Display for OutOfSpace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f, "The destination {:?} has {} bytes free, less than --min-free-space {}, the copy was stopped",
+            self.dest, self.available, self.min,
+        )
+    }
+}
+
+impl std::error::Error for OutOfSpace {}
+
+/// The destination or, when it is not created yet, its nearest parent that exists. The last parent of a relative
+/// path is the current directory
+pub fn existing_ancestor(path: &Path) -> &Path {
+    path.ancestors()
+        .map(|ancestor| if ancestor.as_os_str().is_empty() { Path::new(".") } else { ancestor })
+        .find(|ancestor| ancestor.exists())
+        .unwrap_or(path)
+}
+
+/// Whether both paths (or their nearest existing parents) are in the same filesystem, then a move only renames.
+/// Unknown outside Unix
+pub fn same_filesystem(source: &Path, dest: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let device = |path: &Path| std::fs::metadata(existing_ancestor(path)).map(|metadata| metadata.dev());
+        matches!((device(source), device(dest)), (Ok(source), Ok(dest)) if source == dest)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (source, dest);
+        false
+    }
+}
+
+/// The bytes available to the user in the filesystem of `path`
+#[cfg(unix)]
+pub fn free_space(path: &Path) -> io::Result<u64> {
+    use std::os::unix::ffi::OsStrExt;
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    let mut stats = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: the path is a valid C string and statvfs fills the struct when it returns 0
+    if unsafe { libc::statvfs(path.as_ptr(), stats.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: initialized by the successful call
+    let stats = unsafe { stats.assume_init() };
+    #[allow(clippy::unnecessary_cast)] // The types of the fields depend on the platform
+    Ok(stats.f_bavail as u64 * stats.f_frsize as u64)
+}
+
+/// The bytes available to the user in the volume of `path`
+#[cfg(windows)]
+pub fn free_space(path: &Path) -> io::Result<u64> {
+    use std::os::windows::ffi::OsStrExt;
+    use windows_sys::Win32::Storage::FileSystem::GetDiskFreeSpaceExW;
+    let path: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+    let mut available = 0;
+    // SAFETY: the path is null terminated and the other outputs are optional
+    if unsafe { GetDiskFreeSpaceExW(path.as_ptr(), &mut available, std::ptr::null_mut(), std::ptr::null_mut()) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(available)
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn free_space(_path: &Path) -> io::Result<u64> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::path::Path;
+    use super::{check, free_space, Estimate};
+
+    #[test]
+    fn oversized_copies() {
+        let dest = Path::new("/nonexistent/dest");
+        let free = |path: &Path| {
+            // The nearest parent that exists is measured
+            assert_eq!(path, Path::new("/"));
+            Ok(1000)
+        };
+        assert!(check(dest, 1000, 0, Estimate::Exact, false, free).is_ok());
+        let error = check(dest, 1001, 0, Estimate::Exact, false, free).unwrap_err();
+        assert!(error.to_string().contains("Not enough space"), "{}", error);
+        assert!(check(dest, 1001, 0, Estimate::Exact, true, free).is_ok());
+        assert!(check(dest, 1001, 0, Estimate::UpperBound, false, free).is_ok());
+        assert!(check(Path::new("nonexistent/dest"), 1, 0, Estimate::Exact, false, |path| {
+            assert_eq!(path, Path::new("."));
+            Ok(1)
+        }).is_ok());
+        // The reserve of --min-free-space is not available to the copy
+        assert!(check(dest, 900, 100, Estimate::Exact, false, free).is_ok());
+        assert!(check(dest, 901, 100, Estimate::Exact, false, free).is_err());
+        // Not checked
+        assert!(check(dest, 1001, 0, Estimate::Exact, false, |_| Err(io::ErrorKind::Unsupported.into())).is_ok());
+    }
+
+    #[test]
+    fn real_free_space() {
+        assert!(free_space(&std::env::temp_dir()).unwrap() > 0);
+    }
+}
@@ -0,0 +1,497 @@
+//! Counters of the whole run, shared by all the copy tasks
+
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use anyhow::{Context, Result};
+use log::info;
+use crate::paths;
+use crate::prescan::Totals;
+use crate::size;
+
+#[derive(Debug, Default)]
+pub struct CopyStats {
+    /// Files whose bytes were copied
+    pub files_copied: AtomicU64,
+    /// Files cloned with a reflink
+    pub files_cloned: AtomicU64,
+    /// Files moved with a rename
+    pub files_renamed: AtomicU64,
+    /// Bytes of the copied and cloned files
+    pub bytes_copied: AtomicU64,
+    /// Files copied only after a failed attempt (--retries)
+    pub files_retried: AtomicU64,
+    /// Files not copied because the destination exists (--if-exists skip)
+    pub files_skipped: AtomicU64,
+    /// Files not copied because the destination matches their sampled checksum (--skip-unchanged sample)
+    pub files_skipped_by_sample: AtomicU64,
+    /// Files done in the journal of a previous run (--journal)
+    pub files_skipped_by_journal: AtomicU64,
+    /// Files that changed while they were copied and were left out (--volatile skip)
+    pub files_skipped_volatile: AtomicU64,
+    /// Files written as hard links to a copy with the same content (--dedupe-dest)
+    pub files_linked: AtomicU64,
+    /// Bytes of the linked files, which were not written
+    pub bytes_linked: AtomicU64,
+    /// Files read back and matching their source (--verify)
+    pub files_verified: AtomicU64,
+    /// Bytes of the verified files
+    pub bytes_verified: AtomicU64,
+    /// Copies that did not match their source, including the ones that matched once copied again
+    pub verify_mismatches: AtomicU64,
+    /// Files whose copy failed, and the directories that could not be listed
+    pub files_failed: AtomicU64,
+    /// Directories that could not be listed, nothing below them was copied
+    pub directories_failed: AtomicU64,
+    /// Most directories waiting in the queue of copy_tree at the same time
+    pub max_queued_directories: AtomicUsize,
+    /// Time spent by all the workers of the pipeline on their items, in nanoseconds
+    pub worker_busy_nanos: AtomicU64,
+    /// The sizes of the reports are formatted like `1.5 GiB` instead of a number of bytes (--human-readable)
+    pub human_readable: bool,
+    /// The source of the last copy started, for --progress-json
+    pub current_file: Mutex<Option<PathBuf>>,
+    /// The lines of --checkpoint-every
+    pub checkpoints: Option<Checkpoints>,
+}
+
+/// `--checkpoint-every`: a summary of the run so far is logged every `every` files done
+#[derive(Debug)]
+pub struct Checkpoints {
+    every: u64,
+    started: Instant,
+    /// The files done, counted on their own so that every multiple of `every` is seen by exactly one of the tasks
+    done: AtomicU64,
+}
+
+impl Checkpoints {
+    pub fn new(every: u64) -> Self {
+        Self { every: every.max(1), started: Instant::now(), done: AtomicU64::new(0) }
+    }
+}
+
+impl CopyStats {
+    /// Account a file copied (`cloned` = false) or cloned
+    pub fn copied(&self, bytes: u64, cloned: bool) {
+        let files = if cloned { &self.files_cloned } else { &self.files_copied };
+        files.fetch_add(1, Ordering::Relaxed);
+        self.bytes_copied.fetch_add(bytes, Ordering::Relaxed);
+        self.done();
+    }
+
+    pub fn renamed(&self) {
+        self.files_renamed.fetch_add(1, Ordering::Relaxed);
+        self.done();
+    }
+
+    /// Account a file copied after a retry, it is accounted as copied too
+    pub fn retried(&self) {
+        self.files_retried.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn skipped(&self) {
+        self.files_skipped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Account a file kept because of its samples, not counted in files_skipped since the match is not certain
+    pub fn skipped_by_sample(&self) {
+        self.files_skipped_by_sample.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Account a file the journal has as done, not counted in files_skipped since its destination is not checked
+    pub fn skipped_by_journal(&self) {
+        self.files_skipped_by_journal.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Account a file left out because it changed while it was copied, it is not done
+    pub fn skipped_volatile(&self) {
+        self.files_skipped_volatile.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Account a file of `bytes` bytes linked to an identical copy
+    pub fn linked(&self, bytes: u64) {
+        self.files_linked.fetch_add(1, Ordering::Relaxed);
+        self.bytes_linked.fetch_add(bytes, Ordering::Relaxed);
+        self.done();
+    }
+
+    /// Count a file done for the checkpoints, and log one when it is its turn
+    fn done(&self) {
+        let Some(checkpoints) = &self.checkpoints else { return };
+        let done = checkpoints.done.fetch_add(1, Ordering::Relaxed) + 1;
+        if done % checkpoints.every == 0 {
+            info!("{}", self.checkpoint(done, checkpoints.started.elapsed()));
+        }
+    }
+
+    /// The line of a checkpoint once `done` files are done, `elapsed` after the start
+    fn checkpoint(&self, done: u64, elapsed: Duration) -> String {
+        let bytes = self.bytes_copied.load(Ordering::Relaxed) + self.bytes_linked.load(Ordering::Relaxed);
+        format!(
+            "Checkpoint: {} files done, {}, {} failed, elapsed {:.0} s",
+            done, self.size(bytes), self.files_failed.load(Ordering::Relaxed), elapsed.as_secs_f64(),
+        )
+    }
+
+    /// Account a copy of `bytes` bytes that matched its source
+    pub fn verified(&self, bytes: u64) {
+        self.files_verified.fetch_add(1, Ordering::Relaxed);
+        self.bytes_verified.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn verify_mismatch(&self) {
+        self.verify_mismatches.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn failed(&self) {
+        self.files_failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Account a directory left out, it is accounted as failed too
+    pub fn directory_failed(&self) {
+        self.directories_failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record the source of a copy that starts
+    pub fn started(&self, from: &Path) {
+        *self.current_file.lock().unwrap() = Some(from.to_owned());
+    }
+
+    /// Account the number of directories in the queue
+    pub fn queued_directories(&self, queued: usize) {
+        self.max_queued_directories.fetch_max(queued, Ordering::Relaxed);
+    }
+
+    /// Account the time a worker spent on an item
+    pub fn worker_busy(&self, busy: Duration) {
+        self.worker_busy_nanos.fetch_add(busy.as_nanos().try_into().unwrap_or(u64::MAX), Ordering::Relaxed);
+    }
+
+    /// Percentage of `elapsed` that `workers` spent copying. 100 means that none of them waited for work
+    pub fn utilization(&self, elapsed: Duration, workers: usize) -> f64 {
+        let busy = Duration::from_nanos(self.worker_busy_nanos.load(Ordering::Relaxed));
+        let available = elapsed.as_secs_f64() * workers as f64;
+        (busy.as_secs_f64() * 100.0 / available.max(f64::EPSILON)).min(100.0)
+    }
+
+    /// Bytes copied per second over `elapsed`
+    pub fn throughput(&self, elapsed: Duration) -> u64 {
+        let bytes = self.bytes_copied.load(Ordering::Relaxed);
+        (bytes as f64 / elapsed.as_secs_f64().max(f64::EPSILON)) as u64
+    }
+
+    /// Files copied, cloned, renamed or linked
+    pub fn files_done(&self) -> u64 {
+        self.files_copied.load(Ordering::Relaxed)
+            + self.files_cloned.load(Ordering::Relaxed)
+            + self.files_renamed.load(Ordering::Relaxed)
+            + self.files_linked.load(Ordering::Relaxed)
+    }
+
+    /// Files copied, cloned or renamed per second over `elapsed`
+    pub fn files_per_second(&self, elapsed: Duration) -> f64 {
+        self.files_done() as f64 / elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+
+    /// A size of the reports and the logs: `10485760 bytes`, or `10.0 MiB` with --human-readable
+    pub fn size(&self, bytes: u64) -> String {
+        match self.human_readable {
+            true => size::format_size(bytes),
+            false => format!("{} bytes", bytes),
+        }
+    }
+
+    /// One line summary for the end of the run. The files that needed a retry, the ones kept by their samples, the
+    /// verified ones and the linked ones (with the bytes of the tree against the bytes written) are only told when
+    /// there are some
+    pub fn summary(&self) -> String {
+        let summary = format!(
+            "{} files copied, {} files cloned, {} files renamed, {}",
+            self.files_copied.load(Ordering::Relaxed),
+            self.files_cloned.load(Ordering::Relaxed),
+            self.files_renamed.load(Ordering::Relaxed),
+            self.size(self.bytes_copied.load(Ordering::Relaxed)),
+        );
+        let summary = match self.files_retried.load(Ordering::Relaxed) {
+            0 => summary,
+            retried => format!("{} ({} only after a retry)", summary, retried),
+        };
+        let summary = match self.files_skipped_by_sample.load(Ordering::Relaxed) {
+            0 => summary,
+            sampled => format!("{}, {} files unchanged by sample", summary, sampled),
+        };
+        let summary = match self.files_skipped_by_journal.load(Ordering::Relaxed) {
+            0 => summary,
+            journaled => format!("{}, {} files done in the journal", summary, journaled),
+        };
+        let summary = match self.files_skipped_volatile.load(Ordering::Relaxed) {
+            0 => summary,
+            volatile => format!("{}, {} files skipped as they changed while copied", summary, volatile),
+        };
+        let summary = match self.directories_failed.load(Ordering::Relaxed) {
+            0 => summary,
+            failed => format!("{}, {} directories could not be listed", summary, failed),
+        };
+        let summary = match (self.files_verified.load(Ordering::Relaxed), self.verify_mismatches.load(Ordering::Relaxed)) {
+            (0, 0) => summary,
+            (verified, mismatches) => format!(
+                "{}, {} files verified ({}, {} mismatches)",
+                summary, verified, self.size(self.bytes_verified.load(Ordering::Relaxed)), mismatches,
+            ),
+        };
+        match self.files_linked.load(Ordering::Relaxed) {
+            0 => summary,
+            linked => {
+                let written = self.bytes_copied.load(Ordering::Relaxed);
+                let logical = written + self.bytes_linked.load(Ordering::Relaxed);
+                let saved = self.bytes_linked.load(Ordering::Relaxed);
+                match self.human_readable {
+                    true => format!(
+                        "{}, {} files deduplicated ({} logical, {} written, {} saved)",
+                        summary, linked, size::format_size(logical), size::format_size(written), size::format_size(saved),
+                    ),
+                    false => format!("{}, {} files deduplicated ({} logical bytes, {} written, {} saved)", summary, linked, logical, written, saved),
+                }
+            },
+        }
+    }
+
+    /// A progress report. With the totals of the pre-scan it tells the percentage of the bytes and what is left
+    pub fn progress(&self, totals: Option<&Totals>) -> String {
+        let files = self.files_done();
+        let bytes = self.bytes_copied.load(Ordering::Relaxed) + self.bytes_linked.load(Ordering::Relaxed);
+        match totals {
+            Some(totals) => {
+                // The renames move no bytes, only the files tell their progress
+                let percentage = if totals.bytes > 0 && self.files_renamed.load(Ordering::Relaxed) == 0 {
+                    bytes as f64 * 100.0 / totals.bytes as f64
+                } else {
+                    files as f64 * 100.0 / totals.files.max(1) as f64
+                };
+                let done = match self.human_readable {
+                    true => format!("{} of {}", size::format_size(bytes), size::format_size(totals.bytes)),
+                    false => format!("{} of {} bytes", bytes, totals.bytes),
+                };
+                format!(
+                    "{} of {} files, {} ({:.1}%), {} remaining",
+                    files, totals.files, done, percentage.min(100.0), self.size(totals.bytes.saturating_sub(bytes)),
+                )
+            },
+            None => format!("{} files, {}", files, self.size(bytes)),
+        }
+    }
+
+    /// A progress snapshot of --progress-json, a JSON object on a single line. The totals are null without the
+    /// pre-scan
+    pub fn progress_json(&self, totals: Option<&Totals>) -> String {
+        let total = |value: Option<u64>| value.map_or_else(|| "null".to_owned(), |value| value.to_string());
+        let current = self.current_file.lock().unwrap().as_ref()
+            .map_or_else(|| "null".to_owned(), |path| json_string(&paths::escape(path)));
+        format!(
+            "{{\"files_done\":{},\"files_total\":{},\"bytes_done\":{},\"bytes_total\":{},\"current_file\":{}}}",
+            self.files_done(),
+            total(totals.map(|totals| totals.files)),
+            self.bytes_copied.load(Ordering::Relaxed) + self.bytes_linked.load(Ordering::Relaxed),
+            total(totals.map(|totals| totals.bytes)),
+            current,
+        )
+    }
+
+    /// The summary with the elapsed time and the average throughput of the bytes copied. The renames move no bytes,
+    /// so a run that only renamed reports the files per second instead
+    pub fn report(&self, elapsed: Duration) -> String {
+        let copied = self.files_copied.load(Ordering::Relaxed) + self.files_cloned.load(Ordering::Relaxed);
+        let renamed = self.files_renamed.load(Ordering::Relaxed);
+        let rate = if copied == 0 && renamed > 0 {
+            format!("{:.1} files/s renamed", self.files_per_second(elapsed))
+        } else {
+            format!("{:.2} MiB/s", self.throughput(elapsed) as f64 / (1024.0 * 1024.0))
+        };
+        format!("{}, elapsed {:.3} s, {}", self.summary(), elapsed.as_secs_f64(), rate)
+    }
+
+    /// The counters as a JSON object for --stats-file. `error` is what stopped the run, null when it finished
+    pub fn to_json(&self, elapsed: Duration, error: Option<&str>) -> String {
+        let counters = [
+            ("files_copied", self.files_copied.load(Ordering::Relaxed)),
+            ("files_cloned", self.files_cloned.load(Ordering::Relaxed)),
+            ("files_renamed", self.files_renamed.load(Ordering::Relaxed)),
+            ("files_skipped", self.files_skipped.load(Ordering::Relaxed)),
+            ("files_skipped_by_sample", self.files_skipped_by_sample.load(Ordering::Relaxed)),
+            ("files_skipped_by_journal", self.files_skipped_by_journal.load(Ordering::Relaxed)),
+            ("files_skipped_volatile", self.files_skipped_volatile.load(Ordering::Relaxed)),
+            ("files_linked", self.files_linked.load(Ordering::Relaxed)),
+            ("bytes_linked", self.bytes_linked.load(Ordering::Relaxed)),
+            ("files_verified", self.files_verified.load(Ordering::Relaxed)),
+            ("bytes_verified", self.bytes_verified.load(Ordering::Relaxed)),
+            ("verify_mismatches", self.verify_mismatches.load(Ordering::Relaxed)),
+            ("files_failed", self.files_failed.load(Ordering::Relaxed)),
+            ("directories_failed", self.directories_failed.load(Ordering::Relaxed)),
+            ("files_retried", self.files_retried.load(Ordering::Relaxed)),
+            ("bytes_copied", self.bytes_copied.load(Ordering::Relaxed)),
+            ("throughput_bytes_per_second", self.throughput(elapsed)),
+        ];
+        let mut json = String::from("{\n");
+        for (name, value) in counters {
+            let _ = writeln!(json, "  \"{}\": {},", name, value);
+        }
+        let _ = writeln!(json, "  \"elapsed_seconds\": {:.3},", elapsed.as_secs_f64());
+        let _ = writeln!(json, "  \"completed\": {},", error.is_none());
+        let _ = writeln!(json, "  \"error\": {}", error.map_or_else(|| "null".to_owned(), json_string));
+        json.push_str("}\n");
+        json
+    }
+
+    /// Write `to_json` into `path`
+    pub async fn write_file(&self, path: &Path, elapsed: Duration, error: Option<&str>) -> Result<()> {
+        write_replacing(path, self.to_json(elapsed, error), "stats file").await
+    }
+
+    /// The counters in the text format of Prometheus for --metrics-file, with their HELP and TYPE lines. The counters
+    /// are the totals of the run, for the textfile collector of the node exporter
+    pub fn to_prometheus(&self, elapsed: Duration, completed: bool) -> String {
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+        let count = |counter: &AtomicU64| counter.load(Ordering::Relaxed).to_string();
+        let metrics = [
+            ("files_copied_total", "counter", "Files copied, cloned, renamed or hard linked", self.files_done().to_string()),
+            ("bytes_copied_total", "counter", "Bytes of the copied and cloned files", count(&self.bytes_copied)),
+            ("files_skipped_total", "counter", "Files not copied because the destination existed", count(&self.files_skipped)),
+            ("errors_total", "counter", "Files that could not be copied", count(&self.files_failed)),
+            ("duration_seconds", "gauge", "Duration of the run", format!("{:.3}", elapsed.as_secs_f64())),
+            ("completed", "gauge", "1 when the run finished, 0 when an error stopped it", u8::from(completed).to_string()),
+            ("last_run_timestamp_seconds", "gauge", "When the run ended, in seconds since the epoch", now.as_secs().to_string()),
+        ];
+        let mut text = String::new();
+        for (name, kind, help, value) in metrics {
+            let _ = writeln!(text, "# HELP rscopier_{} {}\n# TYPE rscopier_{} {}\nrscopier_{} {}", name, help, name, kind, name, value);
+        }
+        text
+    }
+
+    /// Write `to_prometheus` into `path`
+    pub async fn write_metrics(&self, path: &Path, elapsed: Duration, completed: bool) -> Result<()> {
+        write_replacing(path, self.to_prometheus(elapsed, completed), "metrics file").await
+    }
+}
+
+/// Write `content` into `path`. It is written next to it first and renamed, so a reader never sees half of it
+pub async fn write_replacing(path: &Path, content: String, what: &str) -> Result<()> {
+    let name = path.file_name().with_context(|| format!("Invalid {}: {:?}", what, path))?;
+    let mut partial = name.to_owned();
+    partial.push(".part");
+    let partial = path.with_file_name(partial);
+    tokio::fs::write(&partial, content).await
+        .with_context(|| format!("Cannot write the {}: {:?}", what, partial))?;
+    tokio::fs::rename(&partial, path).await
+        .with_context(|| format!("Cannot write the {}: {:?}", what, path))
+}
+
+/// A JSON string with the quotes, backslashes and control characters of `text` escaped
+fn json_string(text: &str) -> String {
+    let mut json = String::from("\"");
+    for char in text.chars() {
+        match char {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            char if char.is_control() => {
+                let _ = write!(json, "\\u{:04x}", char as u32);
+            },
+            char => json.push(char),
+        }
+    }
+    json.push('"');
+    json
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+    use super::{Checkpoints, CopyStats};
+    use crate::prescan::Totals;
+
+    #[test]
+    fn reports() {
+        let stats = CopyStats::default();
+        stats.copied(3 * 1024 * 1024, false);
+        stats.renamed();
+        assert_eq!(
+            stats.report(Duration::from_secs(2)),
+            "1 files copied, 0 files cloned, 1 files renamed, 3145728 bytes, elapsed 2.000 s, 1.50 MiB/s",
+        );
+
+        let stats = CopyStats::default();
+        for _ in 0..5 {
+            stats.renamed();
+        }
+        assert!(stats.report(Duration::from_secs(2)).ends_with("elapsed 2.000 s, 2.5 files/s renamed"));
+    }
+
+    #[test]
+    fn worker_utilization() {
+        let stats = CopyStats::default();
+        stats.worker_busy(Duration::from_secs(3));
+        stats.worker_busy(Duration::from_secs(1));
+        assert_eq!(stats.utilization(Duration::from_secs(2), 4), 50.0);
+        assert_eq!(stats.utilization(Duration::from_secs(1), 2), 100.0);
+        assert_eq!(CopyStats::default().utilization(Duration::ZERO, 1), 0.0);
+    }
+
+    #[test]
+    fn progress() {
+        let stats = CopyStats::default();
+        stats.copied(250, false);
+        assert_eq!(stats.progress(None), "1 files, 250 bytes");
+        let totals = Totals { files: 4, bytes: 1000 };
+        assert_eq!(stats.progress(Some(&totals)), "1 of 4 files, 250 of 1000 bytes (25.0%), 750 bytes remaining");
+
+        let stats = CopyStats { human_readable: true, ..Default::default() };
+        stats.copied(1536, false);
+        assert_eq!(stats.progress(None), "1 files, 1.5 KiB");
+        let totals = Totals { files: 2, bytes: 3 << 20 };
+        assert_eq!(stats.progress(Some(&totals)), "1 of 2 files, 1.5 KiB of 3.0 MiB (0.0%), 3.0 MiB remaining");
+        assert!(stats.summary().ends_with(", 1.5 KiB"), "{}", stats.summary());
+    }
+
+    #[test]
+    fn progress_snapshots() {
+        let stats = CopyStats::default();
+        assert_eq!(stats.progress_json(None), r#"{"files_done":0,"files_total":null,"bytes_done":0,"bytes_total":null,"current_file":null}"#);
+        stats.started(Path::new("dir/\"quoted\""));
+        stats.copied(250, false);
+        let totals = Totals { files: 4, bytes: 1000 };
+        assert_eq!(
+            stats.progress_json(Some(&totals)),
+            r#"{"files_done":1,"files_total":4,"bytes_done":250,"bytes_total":1000,"current_file":"dir/\"quoted\""}"#,
+        );
+    }
+
+    #[test]
+    fn checkpoints() {
+        let stats = CopyStats { checkpoints: Some(Checkpoints::new(2)), ..Default::default() };
+        stats.copied(1000, false);
+        stats.failed();
+        stats.linked(500);
+        assert_eq!(stats.checkpoint(2, Duration::from_secs(75)), "Checkpoint: 2 files done, 1500 bytes, 1 failed, elapsed 75 s");
+        assert_eq!(stats.checkpoints.as_ref().unwrap().done.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn json() {
+        let stats = CopyStats::default();
+        stats.copied(2000, false);
+        stats.failed();
+        let json = stats.to_json(Duration::from_secs(2), Some("Cannot copy \"a\"\n\tb\\c"));
+        assert!(json.contains("\"files_copied\": 1,\n"), "{}", json);
+        assert!(json.contains("\"files_failed\": 1,\n"), "{}", json);
+        assert!(json.contains("\"throughput_bytes_per_second\": 1000,\n"), "{}", json);
+        assert!(json.contains("\"completed\": false,\n"), "{}", json);
+        assert!(json.contains(r#""error": "Cannot copy \"a\"\n\u0009b\\c""#), "{}", json);
+        assert!(stats.to_json(Duration::ZERO, None).ends_with("\"completed\": true,\n  \"error\": null\n}\n"));
+    }
+}
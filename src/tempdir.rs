@@ -0,0 +1,92 @@
+//! `--tempdir`: the temporary files of the copies (the `.part` of `--resume`) are written in another directory and
+//! renamed to their destination once complete, so a small or slow destination filesystem does not hold them. A
+//! rename cannot move a file to another filesystem: the destinations on another one than the temporary directory
+//! keep their temporary file next to them, with a warning the first time
+
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use anyhow::{Context, Result};
+use log::warn;
+
+/// Hex digits of the hash of the destination that start the names in the directory
+const HASH_DIGITS: usize = 16;
+
+#[derive(Debug)]
+pub struct TempDir {
+    path: PathBuf,
+    /// The fallback to the directory of the destination was warned about
+    warned: AtomicBool,
+}
+
+impl TempDir {
+    /// The temporary directory `path`, created when missing
+    pub async fn create(path: &Path) -> Result<Self> {
+        tokio::fs::create_dir_all(path).await
+            .with_context(|| format!("Cannot create the temporary directory: {:?}", path))?;
+        Ok(Self { path: path.to_owned(), warned: AtomicBool::new(false) })
+    }
+
+    /// Where the temporary file `name` of the destination `to` is written: in this directory when a rename can move it
+    /// to `to`, next to `to` otherwise. The names here start with a hash of the destination, so the destinations with
+    /// the same name do not share their files and the next run finds them again
+    pub async fn place(&self, to: &Path, name: &OsStr) -> PathBuf {
+        let dir = to.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        if !same_filesystem(&self.path, dir).await {
+            if !self.warned.swap(true, Ordering::Relaxed) {
+                warn!("{:?} is in another filesystem than --tempdir {:?}, its temporary files stay next to it", dir, self.path);
+            }
+            return to.with_file_name(name);
+        }
+        let absolute = std::path::absolute(to).unwrap_or_else(|_| to.to_owned());
+        let hash = blake3::hash(absolute.as_os_str().as_encoded_bytes()).to_hex();
+        let mut unique = std::ffi::OsString::from(format!("{}-", &hash[..HASH_DIGITS]));
+        unique.push(name);
+        self.path.join(unique)
+    }
+}
+
+/// Whether both existing directories are in the same filesystem, by their device
+#[cfg(unix)]
+async fn same_filesystem(first: &Path, second: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (tokio::fs::metadata(first).await, tokio::fs::metadata(second).await) {
+        (Ok(first), Ok(second)) => first.dev() == second.dev(),
+        _ => false,
+    }
+}
+
+/// Whether both directories are in the same volume, by the drive or share of their path
+#[cfg(not(unix))]
+async fn same_filesystem(first: &Path, second: &Path) -> bool {
+    let prefix = |path: &Path| std::path::absolute(path).ok()
+        .and_then(|path| path.components().next().map(|component| component.as_os_str().to_ascii_lowercase()));
+    prefix(first).is_some() && prefix(first) == prefix(second)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+    use crate::test_support::init;
+    use super::TempDir;
+
+    #[tokio::test]
+    async fn placed_files() {
+        let base_dir = init("placed_files").await;
+
+        let tempdir = TempDir::create(&base_dir.join("tmp")).await.unwrap();
+        tokio::fs::create_dir_all(base_dir.join("a")).await.unwrap();
+        tokio::fs::create_dir_all(base_dir.join("b")).await.unwrap();
+        let first = tempdir.place(&base_dir.join("a/file"), OsStr::new("file.part")).await;
+        let second = tempdir.place(&base_dir.join("b/file"), OsStr::new("file.part")).await;
+        assert_eq!(first.parent(), Some(base_dir.join("tmp").as_path()));
+        assert!(first.to_str().unwrap().ends_with("-file.part"));
+        assert_ne!(first, second);
+        // The same place in the next run
+        assert_eq!(tempdir.place(&base_dir.join("a/file"), OsStr::new("file.part")).await, first);
+        // A missing directory cannot tell its filesystem, the file stays next to it
+        let missing = base_dir.join("missing/file");
+        assert_eq!(tempdir.place(&missing, OsStr::new("file.part")).await, base_dir.join("missing/file.part"));
+    }
+}
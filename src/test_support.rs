@@ -0,0 +1,187 @@
+//! Helpers shared by the tests of all the modules
+
+use std::collections::BTreeMap;
+use std::io;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+use crate::backend::{Backend, BackendFuture, DirEntries, DirEntry, FileInfo};
+use crate::CopyOptions;
+
+const BASE_DIR: &str = "/tmp/test";
+
+/// Logger of the tests that keep what is logged
+struct CapturedLogs(Mutex<Vec<String>>);
+
+impl log::Log for CapturedLogs {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        self.0.lock().unwrap().push(record.args().to_string());
+    }
+
+    fn flush(&self) {}
+}
+
+static CAPTURED_LOGS: CapturedLogs = CapturedLogs(Mutex::new(Vec::new()));
+
+/// Keep the messages logged from now on, of every test running. The messages kept so far
+pub fn captured_logs() -> Vec<String> {
+    // Only the first call installs it
+    if log::set_logger(&CAPTURED_LOGS).is_ok() {
+        log::set_max_level(log::LevelFilter::Info);
+    }
+    CAPTURED_LOGS.0.lock().unwrap().clone()
+}
+
+/// Create an empty working directory for the test `name`
+pub async fn init(name: &str) -> PathBuf {
+    let base_dir = PathBuf::from(BASE_DIR).join(name);
+    
+    if base_dir.exists() {
+        tokio::fs::remove_dir_all(&base_dir).await.unwrap();
+    }
+    tokio::fs::create_dir_all(&base_dir).await.unwrap();
+    
+    base_dir
+}
+
+/// A tree kept in memory, shared by the source and the destination of a copy so the walk and the copy can be tested
+/// without touching the disk. The paths are absolute
+#[derive(Debug, Default)]
+pub struct MemoryBackend(Mutex<BTreeMap<PathBuf, Node>>);
+
+#[derive(Debug, Clone)]
+enum Node {
+    Directory,
+    File { content: Vec<u8>, modified: SystemTime },
+}
+
+impl MemoryBackend {
+    /// Add a file and its missing parents
+    pub fn add_file(&self, path: &str, content: &str, modified: SystemTime) {
+        let path = Path::new(path);
+        self.add_directory(path.parent().unwrap().to_str().unwrap());
+        self.0.lock().unwrap().insert(path.to_owned(), Node::File { content: content.into(), modified });
+    }
+
+    /// Add a directory and its missing parents
+    pub fn add_directory(&self, path: &str) {
+        let mut nodes = self.0.lock().unwrap();
+        for ancestor in Path::new(path).ancestors() {
+            nodes.entry(ancestor.to_owned()).or_insert(Node::Directory);
+        }
+    }
+
+    /// The content of a file, None when it does not exist
+    pub fn read(&self, path: &str) -> Option<String> {
+        match self.0.lock().unwrap().get(Path::new(path)) {
+            Some(Node::File { content, .. }) => Some(String::from_utf8(content.clone()).unwrap()),
+            _ => None,
+        }
+    }
+
+    pub fn is_dir(&self, path: &str) -> bool {
+        matches!(self.0.lock().unwrap().get(Path::new(path)), Some(Node::Directory))
+    }
+
+    fn not_found(path: &Path) -> anyhow::Error {
+        anyhow::Error::new(io::Error::from(io::ErrorKind::NotFound)).context(format!("{:?}", path))
+    }
+}
+
+struct MemoryEntries(std::vec::IntoIter<DirEntry>);
+
+impl DirEntries for MemoryEntries {
+    fn next_entry(&mut self) -> BackendFuture<'_, Option<DirEntry>> {
+        let entry = self.0.next();
+        Box::pin(async move { Ok(entry) })
+    }
+}
+
+impl Backend for MemoryBackend {
+    fn read_dir<'a>(&'a self, dir: &'a Path) -> BackendFuture<'a, Box<dyn DirEntries>> {
+        Box::pin(async move {
+            let nodes = self.0.lock().unwrap();
+            if !matches!(nodes.get(dir), Some(Node::Directory)) {
+                return Err(Self::not_found(dir));
+            }
+            // The descendants of a directory follow it in the map
+            let entries: Vec<_> = nodes.range::<Path, _>((Bound::Excluded(dir), Bound::Unbounded))
+                .take_while(|(path, _)| path.starts_with(dir))
+                .filter(|(path, _)| path.parent() == Some(dir))
+                .map(|(path, node)| DirEntry { path: path.clone(), is_file: matches!(node, Node::File { .. }), is_symlink: false })
+                .collect();
+            Ok(Box::new(MemoryEntries(entries.into_iter())) as Box<dyn DirEntries>)
+        })
+    }
+
+    fn metadata<'a>(&'a self, path: &'a Path) -> BackendFuture<'a, FileInfo> {
+        Box::pin(async move {
+            match self.0.lock().unwrap().get(path) {
+                Some(Node::File { content, modified }) => Ok(FileInfo { len: content.len() as u64, is_dir: false, modified: Some(*modified) }),
+                Some(Node::Directory) => Ok(FileInfo { len: 0, is_dir: true, modified: None }),
+                None => Err(Self::not_found(path)),
+            }
+        })
+    }
+
+    fn create_dir_all<'a>(&'a self, dir: &'a Path) -> BackendFuture<'a, ()> {
+        Box::pin(async move {
+            self.add_directory(dir.to_str().unwrap());
+            Ok(())
+        })
+    }
+
+    fn sync_directory<'a>(&'a self, _dir: &'a Path) -> BackendFuture<'a, ()> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn copy<'a>(&'a self, from: &'a Path, to: &'a Path, options: &'a CopyOptions) -> BackendFuture<'a, Option<PathBuf>> {
+        Box::pin(async move {
+            let bytes = {
+                let mut nodes = self.0.lock().unwrap();
+                let Some(Node::File { content, modified }) = nodes.get(from).cloned() else {
+                    return Err(Self::not_found(from));
+                };
+                if !matches!(to.parent().and_then(|parent| nodes.get(parent)), Some(Node::Directory)) {
+                    return Err(Self::not_found(to));
+                }
+                let bytes = content.len() as u64;
+                nodes.insert(to.to_owned(), Node::File { content, modified });
+                bytes
+            };
+            options.stats.copied(bytes, false);
+            if options.remove_source {
+                if options.defers_removal() {
+                    return Ok(Some(from.to_owned()));
+                }
+                crate::remove_source(from, options).await?;
+            }
+            Ok(None)
+        })
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a Path) -> BackendFuture<'a, ()> {
+        Box::pin(async move {
+            match self.0.lock().unwrap().remove(path) {
+                Some(_) => Ok(()),
+                None => Err(Self::not_found(path)),
+            }
+        })
+    }
+
+    fn remove_dir_all<'a>(&'a self, dir: &'a Path) -> BackendFuture<'a, ()> {
+        Box::pin(async move {
+            let mut nodes = self.0.lock().unwrap();
+            if !matches!(nodes.get(dir), Some(Node::Directory)) {
+                return Err(Self::not_found(dir));
+            }
+            nodes.retain(|path, _| !path.starts_with(dir));
+            Ok(())
+        })
+    }
+}
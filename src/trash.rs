@@ -0,0 +1,101 @@
+//! `--trash`: the sources removed by `--delete-source` are moved into a directory instead of being deleted, so a
+//! mistaken run can be recovered. They keep their path relative to the source and a name already taken in the trash
+//! gets a counter (`file.1`, `file.2`, ...)
+
+use std::io;
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, Context, Result};
+use crate::copy;
+
+#[derive(Debug, Clone)]
+pub struct Trash {
+    /// The source of the copy, the paths in the trash are relative to it
+    source: PathBuf,
+    dir: PathBuf,
+}
+
+impl Trash {
+    /// Create the trash directory `dir` for the sources under `source`. It cannot be inside the source, it would be copied
+    pub async fn create(source: &Path, dir: &Path) -> Result<Self> {
+        tokio::fs::create_dir_all(dir).await
+            .with_context(|| format!("Cannot create the trash: {:?}", dir))?;
+        // A missing source is reported later
+        if let (Ok(source), Ok(dir)) = (tokio::fs::canonicalize(source).await, tokio::fs::canonicalize(dir).await) {
+            if dir.starts_with(&source) {
+                return Err(anyhow!("The trash {:?} cannot be inside the source {:?}", dir, source));
+            }
+        }
+        Ok(Self { source: source.to_owned(), dir: dir.to_owned() })
+    }
+
+    /// Move a source file into the trash
+    pub async fn move_file(&self, from: &Path) -> Result<()> {
+        let to = self.destination(from);
+        let from = from.to_owned();
+        tokio::task::spawn_blocking(move || move_into(&from, &to)).await?
+    }
+
+    /// Path of a source in the trash. The source itself (a single file) keeps its name
+    fn destination(&self, from: &Path) -> PathBuf {
+        match from.strip_prefix(&self.source) {
+            Ok(relative) if !relative.as_os_str().is_empty() => self.dir.join(relative),
+            _ => self.dir.join(from.file_name().unwrap_or(from.as_os_str())),
+        }
+    }
+}
+
+/// Move the file `from` to `to`, or to the first free `to.N` when `to` is taken
+fn move_into(from: &Path, to: &Path) -> Result<()> {
+    if let Some(parent) = to.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let to = free_name(to);
+    let moved = match std::fs::rename(from, &to) {
+        // The trash is in another filesystem
+        Err(error) if copy::is_cross_device(&error) => std::fs::copy(from, &to).and_then(|_| std::fs::remove_file(from)),
+        moved => moved,
+    };
+    moved.with_context(|| format!("Cannot move {:?} to the trash", from))
+}
+
+fn free_name(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default();
+    std::iter::once(path.to_owned())
+        .chain((1..).map(|counter| {
+            let mut numbered = name.to_owned();
+            numbered.push(format!(".{counter}"));
+            path.with_file_name(numbered)
+        }))
+        .find(|candidate| matches!(std::fs::symlink_metadata(candidate), Err(error) if error.kind() == io::ErrorKind::NotFound))
+        .expect("there is always a free counter")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::Trash;
+    use crate::test_support::init;
+
+    #[tokio::test]
+    async fn trash_keeps_relative_paths() {
+        let base_dir = init("trash_keeps_relative_paths").await;
+
+        let source = base_dir.join("source");
+        tokio::fs::create_dir_all(source.join("dir")).await.unwrap();
+        tokio::fs::write(source.join("dir/file"), "new").await.unwrap();
+        tokio::fs::write(source.join("dir/left"), "left").await.unwrap();
+        // Left by a previous run
+        let trash_dir = base_dir.join("trash");
+        tokio::fs::create_dir_all(trash_dir.join("dir")).await.unwrap();
+        tokio::fs::write(trash_dir.join("dir/file"), "old").await.unwrap();
+
+        let trash = Trash::create(&source, &trash_dir).await.unwrap();
+        trash.move_file(&source.join("dir/file")).await.unwrap();
+        trash.move_file(&source.join("dir/left")).await.unwrap();
+
+        assert_eq!(tokio::fs::read_to_string(trash_dir.join("dir/file")).await.unwrap(), "old");
+        assert_eq!(tokio::fs::read_to_string(trash_dir.join("dir/file.1")).await.unwrap(), "new");
+        assert_eq!(tokio::fs::read_to_string(trash_dir.join("dir/left")).await.unwrap(), "left");
+        assert!(Trash::create(&base_dir, &trash_dir).await.is_err());
+    }
+}
@@ -0,0 +1,529 @@
+//! `--copy-engine uring` (cargo feature `uring`, Linux only): the bytes are copied through an io_uring.
+//! Every blocking thread keeps a ring with registered buffers. A file is copied in batches of chunks: the reads of
+//! a batch are submitted together with the writes of the previous one, so every batch is a single system call and
+//! the reads overlap with the writes
+
+use std::cell::RefCell;
+use std::fs::{File, OpenOptions};
+use std::future::Future;
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::pin::Pin;
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Once};
+use anyhow::{anyhow, Context, Result};
+use log::{debug, warn};
+use crate::checksum::Hasher;
+use crate::copy::{self, BufferSize, CopyEngine, Copied, Standard};
+use crate::limit::Bandwidth;
+use crate::CopyOptions;
+
+/// Chunks read (and then written) by every submission
+const BATCH: usize = 4;
+
+/// The lack of io_uring support is only reported once
+static URING_UNSUPPORTED: Once = Once::new();
+
+thread_local! {
+    /// Ring of the blocking thread, created by its first copy
+    static RING: RefCell<Option<Ring>> = const { RefCell::new(None) };
+}
+
+/// Copies the files through an io_uring. When the kernel does not provide one (too old or disabled) the files are
+/// copied with the standard engine
+pub struct Uring;
+
+impl CopyEngine for Uring {
+    fn copy<'a>(&'a self, from: &'a Path, to: &'a Path, options: &'a CopyOptions) -> Pin<Box<dyn Future<Output = Result<Copied>> + Send + 'a>> {
+        Box::pin(async move {
+            let settings = Settings {
+                buffer_size: options.buffer_size,
+                bandwidth: options.bandwidth.clone(),
+                fsync: options.fsync,
+                preallocate: options.preallocate,
+                hasher: options.needs_digest().then(|| Hasher::new(options.checksum_algorithm)),
+            };
+            let (source, dest) = (from.to_owned(), to.to_owned());
+            match tokio::task::spawn_blocking(move || copy(&source, &dest, settings)).await?? {
+                Outcome::Copied { bytes, digest } => {
+                    debug!("Copied with io_uring: {:?} to {:?}", from, to);
+                    Ok(Copied { bytes, digest, cloned: false })
+                },
+                Outcome::Unsupported(error) => {
+                    URING_UNSUPPORTED.call_once(|| {
+                        warn!("io_uring is not available, the files are copied with the standard engine: {}. Only the first occurrence is reported", error);
+                    });
+                    Standard.copy(from, to, options).await
+                },
+            }
+        })
+    }
+}
+
+/// What a ring copy needs from the options, it runs in the blocking pool
+struct Settings {
+    buffer_size: BufferSize,
+    bandwidth: Option<Arc<Bandwidth>>,
+    fsync: bool,
+    preallocate: bool,
+    hasher: Option<Hasher>,
+}
+
+enum Outcome {
+    Copied { bytes: u64, digest: Option<String> },
+    /// The kernel cannot set up a ring, nothing was copied
+    Unsupported(io::Error),
+}
+
+/// Copy the file with the ring of this thread, creating it when needed
+fn copy(from: &Path, to: &Path, mut settings: Settings) -> Result<Outcome> {
+    let source = File::open(from)?;
+    let metadata = source.metadata()?;
+    RING.with(|ring| {
+        let mut ring = ring.borrow_mut();
+        if ring.as_ref().is_none_or(|ring| ring.buffer_size != settings.buffer_size.0) {
+            *ring = None;
+            match Ring::new(settings.buffer_size.0) {
+                Ok(created) => *ring = Some(created),
+                Err(error) => return Ok(Outcome::Unsupported(error)),
+            }
+        }
+        let dest = OpenOptions::new().write(true).create(true).truncate(true).open(to)?;
+        if settings.preallocate {
+            match copy::preallocate(&dest, metadata.len()) {
+                Ok(()) => {},
+                Err(error) if copy::is_preallocation_unsupported(&error) => {
+                    debug!("Cannot preallocate, copy anyway: {:?}: {}", to, error);
+                },
+                Err(error) => return Err(anyhow::Error::new(error).context("Cannot preallocate file")),
+            }
+        }
+        let copied = match ring.as_mut().map(|ring| ring.copy(&source, &dest, metadata.len(), &mut settings)) {
+            Some(Ok(copied)) => copied,
+            Some(Err(Failure::File(error))) => return Err(error),
+            Some(Err(Failure::Ring(error))) => {
+                // The kernel may still own the buffers, so the ring is leaked instead of freeing them
+                mem::forget(ring.take());
+                return Err(anyhow::Error::new(error).context("io_uring failure"));
+            },
+            None => unreachable!("the ring was just created"),
+        };
+        if settings.fsync {
+            dest.sync_all().context("Cannot sync file")?;
+        }
+        std::fs::set_permissions(to, metadata.permissions())?;
+        Ok(Outcome::Copied { bytes: copied, digest: settings.hasher.take().map(Hasher::finish) })
+    })
+}
+
+/// Why a ring copy failed
+enum Failure {
+    /// A read or a write of the file failed, the ring can be used again
+    File(anyhow::Error),
+    /// The ring itself failed, there may be operations in flight
+    Ring(io::Error),
+}
+
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_CQ_RING: libc::off_t = 0x8000000;
+const IORING_OFF_SQES: libc::off_t = 0x10000000;
+const IORING_FEAT_SINGLE_MMAP: u32 = 1;
+const IORING_ENTER_GETEVENTS: libc::c_uint = 1;
+const IORING_REGISTER_BUFFERS: libc::c_uint = 0;
+const IORING_OP_READ_FIXED: u8 = 4;
+const IORING_OP_WRITE_FIXED: u8 = 5;
+
+/// `struct io_sqring_offsets`
+#[repr(C)]
+#[derive(Default)]
+struct SqOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+/// `struct io_cqring_offsets`
+#[repr(C)]
+#[derive(Default)]
+struct CqOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+/// `struct io_uring_params`
+#[repr(C)]
+#[derive(Default)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqOffsets,
+    cq_off: CqOffsets,
+}
+
+/// `struct io_uring_sqe`, only the fields of the fixed reads and writes
+#[repr(C)]
+#[derive(Default)]
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    rw_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    splice_fd_in: i32,
+    addr3: u64,
+    pad: u64,
+}
+
+/// `struct io_uring_cqe`
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+/// A memory map of the ring, unmapped on drop
+struct Mmap {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+impl Mmap {
+    fn new(fd: RawFd, offset: libc::off_t, len: usize) -> io::Result<Self> {
+        // SAFETY: a new shared map of the ring; the kernel checks the descriptor, the offset and the length
+        let ptr = unsafe {
+            libc::mmap(ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED | libc::MAP_POPULATE, fd, offset)
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { ptr, len })
+    }
+
+    /// Pointer to the field at `offset` bytes from the start of the map
+    fn at<T>(&self, offset: u32) -> *mut T {
+        debug_assert!((offset as usize) < self.len);
+        // SAFETY: the kernel reported the offset as part of this map
+        unsafe { self.ptr.cast::<u8>().add(offset as usize).cast() }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        // SAFETY: the map is not used after this
+        unsafe { libc::munmap(self.ptr, self.len) };
+    }
+}
+
+/// An io_uring with `2 * BATCH` registered buffers: one set is being read while the other is being written
+struct Ring {
+    // The maps are released before the descriptor is closed
+    _sq: Mmap,
+    _cq: Option<Mmap>,
+    _sqes: Mmap,
+    fd: OwnedFd,
+    sq_head: *const AtomicU32,
+    sq_tail: *const AtomicU32,
+    sq_mask: u32,
+    sq_array: *mut u32,
+    sqes: *mut Sqe,
+    cq_head: *const AtomicU32,
+    cq_tail: *const AtomicU32,
+    cq_mask: u32,
+    cqes: *const Cqe,
+    /// Entries queued but not submitted yet
+    pending: u32,
+    buffers: Vec<Vec<u8>>,
+    buffer_size: usize,
+}
+
+/// What the `user_data` of an entry identifies
+const WRITE: u64 = 1 << 32;
+
+impl Ring {
+    fn new(buffer_size: usize) -> io::Result<Self> {
+        let mut params = Params::default();
+        let entries = 2 * BATCH as u32;
+        // SAFETY: params outlives the call
+        let fd = unsafe { libc::syscall(libc::SYS_io_uring_setup, entries, &mut params as *mut Params) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: the descriptor was just created and nothing else owns it
+        let fd = unsafe { OwnedFd::from_raw_fd(fd as RawFd) };
+        let sq_len = params.sq_off.array as usize + params.sq_entries as usize * mem::size_of::<u32>();
+        let cq_len = params.cq_off.cqes as usize + params.cq_entries as usize * mem::size_of::<Cqe>();
+        let single = params.features & IORING_FEAT_SINGLE_MMAP != 0;
+        let sq = Mmap::new(fd.as_raw_fd(), IORING_OFF_SQ_RING, if single { sq_len.max(cq_len) } else { sq_len })?;
+        let cq = if single { None } else { Some(Mmap::new(fd.as_raw_fd(), IORING_OFF_CQ_RING, cq_len)?) };
+        let sqes = Mmap::new(fd.as_raw_fd(), IORING_OFF_SQES, params.sq_entries as usize * mem::size_of::<Sqe>())?;
+
+        let mut buffers: Vec<Vec<u8>> = (0..2 * BATCH).map(|_| vec![0u8; buffer_size]).collect();
+        let iovecs: Vec<libc::iovec> = buffers.iter_mut()
+            .map(|buffer| libc::iovec { iov_base: buffer.as_mut_ptr().cast(), iov_len: buffer.len() })
+            .collect();
+        // SAFETY: the iovecs outlive the call and the buffers live (and never move) as long as the ring
+        let registered = unsafe {
+            libc::syscall(libc::SYS_io_uring_register, fd.as_raw_fd(), IORING_REGISTER_BUFFERS, iovecs.as_ptr(), iovecs.len() as libc::c_uint)
+        };
+        if registered < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let cq_map = cq.as_ref().unwrap_or(&sq);
+        let (sq_off, cq_off) = (&params.sq_off, &params.cq_off);
+        // SAFETY: the masks are in the maps at the offsets reported by the kernel
+        let (sq_mask, cq_mask) = unsafe { (*sq.at::<u32>(sq_off.ring_mask), *cq_map.at::<u32>(cq_off.ring_mask)) };
+        Ok(Self {
+            sq_head: sq.at(sq_off.head),
+            sq_tail: sq.at(sq_off.tail),
+            sq_mask,
+            sq_array: sq.at(sq_off.array),
+            sqes: sqes.at(0),
+            cq_head: cq_map.at(cq_off.head),
+            cq_tail: cq_map.at(cq_off.tail),
+            cq_mask,
+            cqes: cq_map.at(cq_off.cqes),
+            _sq: sq,
+            _cq: cq,
+            _sqes: sqes,
+            fd,
+            pending: 0,
+            buffers,
+            buffer_size,
+        })
+    }
+
+    /// Queue a fixed read or write of `len` bytes at `offset` with the buffer `index`
+    fn queue(&mut self, opcode: u8, file: &File, index: usize, len: usize, offset: u64, user_data: u64) {
+        let sqe = Sqe {
+            opcode,
+            fd: file.as_raw_fd(),
+            off: offset,
+            addr: self.buffers[index].as_mut_ptr() as u64,
+            len: len as u32,
+            user_data,
+            buf_index: index as u16,
+            ..Default::default()
+        };
+        // SAFETY: the pointers are in the maps of the ring. Every step submits and completes all its entries and
+        // they never exceed the ring size, so the slot at the tail is free
+        unsafe {
+            debug_assert!((*self.sq_tail).load(Ordering::Relaxed).wrapping_sub((*self.sq_head).load(Ordering::Acquire)) < 2 * BATCH as u32);
+            let tail = (*self.sq_tail).load(Ordering::Relaxed);
+            let slot = tail & self.sq_mask;
+            self.sqes.add(slot as usize).write(sqe);
+            self.sq_array.add(slot as usize).write(slot);
+            (*self.sq_tail).store(tail.wrapping_add(1), Ordering::Release);
+        }
+        self.pending += 1;
+    }
+
+    /// Submit the queued entries and wait until `wait` of them complete
+    fn submit(&mut self, wait: u32) -> io::Result<()> {
+        while self.pending > 0 {
+            // SAFETY: plain system call on the ring descriptor, there is no signal mask
+            let submitted = unsafe {
+                libc::syscall(libc::SYS_io_uring_enter, self.fd.as_raw_fd(), self.pending, wait, IORING_ENTER_GETEVENTS, ptr::null::<libc::c_void>(), 0usize)
+            };
+            if submitted < 0 {
+                let error = io::Error::last_os_error();
+                if error.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(error);
+            }
+            self.pending -= submitted as u32;
+        }
+        Ok(())
+    }
+
+    /// Take the completed entries as (user data, result)
+    fn completions(&mut self) -> Vec<(u64, i32)> {
+        let mut completed = vec![];
+        // SAFETY: the pointers are in the maps of the ring and the entries between head and tail are written
+        unsafe {
+            let mut head = (*self.cq_head).load(Ordering::Relaxed);
+            let tail = (*self.cq_tail).load(Ordering::Acquire);
+            while head != tail {
+                let cqe = *self.cqes.add((head & self.cq_mask) as usize);
+                completed.push((cqe.user_data, cqe.res));
+                head = head.wrapping_add(1);
+            }
+            (*self.cq_head).store(head, Ordering::Release);
+        }
+        completed
+    }
+
+    /// Copy the first `len` bytes of `source` into `dest`. The chunks are read in batches of `BATCH` buffers and the
+    /// reads of a batch are submitted with the writes of the previous one. Every step waits for all its entries, so
+    /// nothing is in flight between steps
+    fn copy(&mut self, source: &File, dest: &File, len: u64, settings: &mut Settings) -> Result<u64, Failure> {
+        let chunk = self.buffer_size as u64;
+        let chunks = len.div_ceil(chunk) as usize;
+        let batches = chunks.div_ceil(BATCH);
+        // Bytes of every chunk of the batch being written
+        let mut written_batch: Vec<(usize, u64, usize)> = vec![];
+        for batch in 0..=batches {
+            let first_buffer = (batch % 2) * BATCH;
+            let mut reads = vec![];
+            if batch < batches {
+                for index in batch * BATCH..chunks.min((batch + 1) * BATCH) {
+                    let offset = index as u64 * chunk;
+                    let size = chunk.min(len - offset) as usize;
+                    let buffer = first_buffer + index % BATCH;
+                    self.queue(IORING_OP_READ_FIXED, source, buffer, size, offset, buffer as u64);
+                    reads.push((buffer, offset, size));
+                }
+            }
+            for &(buffer, offset, size) in &written_batch {
+                self.queue(IORING_OP_WRITE_FIXED, dest, buffer, size, offset, WRITE | buffer as u64);
+            }
+            let entries = (reads.len() + written_batch.len()) as u32;
+            if entries == 0 {
+                break;
+            }
+            self.submit(entries).map_err(Failure::Ring)?;
+            let mut completed = self.completions();
+            while completed.len() < entries as usize {
+                self.submit_wait().map_err(Failure::Ring)?;
+                completed.extend(self.completions());
+            }
+
+            let mut results = [0i32; 2 * BATCH];
+            let mut write_results = [0i32; 2 * BATCH];
+            for (user_data, result) in completed {
+                let buffer = (user_data & 0xffff_ffff) as usize;
+                if user_data & WRITE != 0 {
+                    write_results[buffer] = result;
+                } else {
+                    results[buffer] = result;
+                }
+            }
+            for &(buffer, offset, size) in &written_batch {
+                let result = write_results[buffer];
+                if result < 0 {
+                    let error = io::Error::from_raw_os_error(-result);
+                    return Err(Failure::File(anyhow::Error::new(error).context(format!("Write error at offset {}", offset))));
+                }
+                // Short writes are finished without the ring
+                let done = result as usize;
+                if done < size {
+                    dest.write_all_at(&self.buffers[buffer][done..size], offset + done as u64)
+                        .with_context(|| format!("Write error at offset {}", offset + done as u64))
+                        .map_err(Failure::File)?;
+                }
+            }
+            for &(buffer, offset, size) in &reads {
+                let result = results[buffer];
+                if result < 0 {
+                    let error = io::Error::from_raw_os_error(-result);
+                    return Err(Failure::File(anyhow::Error::new(error).context(format!("Read error at offset {}", offset))));
+                }
+                // Short reads are finished without the ring, the file shrank if it ends before
+                let done = result as usize;
+                if done < size {
+                    source.read_exact_at(&mut self.buffers[buffer][done..size], offset + done as u64)
+                        .map_err(|_| Failure::File(anyhow!("Short read at offset {}", offset + done as u64)))?;
+                }
+                if let Some(hasher) = settings.hasher.as_mut() {
+                    hasher.update(&self.buffers[buffer][..size]);
+                }
+            }
+            if let Some(bandwidth) = &settings.bandwidth {
+                bandwidth.acquire_blocking(reads.iter().map(|(_, _, size)| size).sum());
+            }
+            written_batch = reads;
+        }
+        Ok(len)
+    }
+
+    /// Wait for at least one more completion
+    fn submit_wait(&mut self) -> io::Result<()> {
+        loop {
+            // SAFETY: plain system call on the ring descriptor, there is no signal mask
+            let result = unsafe {
+                libc::syscall(libc::SYS_io_uring_enter, self.fd.as_raw_fd(), 0, 1, IORING_ENTER_GETEVENTS, ptr::null::<libc::c_void>(), 0usize)
+            };
+            if result >= 0 {
+                return Ok(());
+            }
+            let error = io::Error::last_os_error();
+            if error.kind() != io::ErrorKind::Interrupted {
+                return Err(error);
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{copy, Outcome, Settings};
+    use crate::checksum::{self, ChecksumAlgorithm, Hasher};
+    use crate::copy::BufferSize;
+    use crate::test_support::init;
+
+    #[tokio::test]
+    async fn uring_copy() {
+        let base_dir = init("uring_copy").await;
+
+        let source = base_dir.join("source");
+        let dest = base_dir.join("dest");
+        // Several batches and a partial chunk at the end
+        let content: Vec<u8> = (0..100_003u32).map(|i| (i % 251) as u8).collect();
+        tokio::fs::write(&source, &content).await.unwrap();
+        // The ring of a thread is reused by the next copies, also with another buffer size
+        for buffer_size in [4096, 4096, 1000] {
+            let settings = Settings {
+                buffer_size: BufferSize(buffer_size),
+                bandwidth: None,
+                fsync: true,
+                preallocate: true,
+                hasher: Some(Hasher::new(ChecksumAlgorithm::Sha256)),
+            };
+            match copy(&source, &dest, settings).unwrap() {
+                Outcome::Copied { bytes, digest } => {
+                    assert_eq!(bytes, content.len() as u64);
+                    assert_eq!(digest.unwrap(), checksum::hash_file(&source, ChecksumAlgorithm::Sha256).await.unwrap());
+                },
+                // Kernels without io_uring (or with it disabled) fall back to the standard engine
+                Outcome::Unsupported(error) => {
+                    eprintln!("io_uring is not available: {}", error);
+                    return;
+                },
+            }
+            assert_eq!(tokio::fs::read(&dest).await.unwrap(), content);
+        }
+    }
+}
@@ -0,0 +1,231 @@
+//! `--verify-after`: once the copy is done, the whole destination tree is read again and compared with the source,
+//! or with the manifest of the run. The copies checked right after they are written can still be corrupted later
+//! (a bad disk, a cache that never reached it); this pass reads them back from the destination filesystem.
+//! The files are told apart as missing, mismatched (size or checksum) and extra: the files of the destination that
+//! are not in the source, which are only reported since the destination may have had them before.
+//! `--verify` checks every file instead, as soon as it is copied: the copy is read back and compared with the checksum
+//! of the source computed while copying. The read counts against the bandwidth limit, and it holds the copy permit of
+//! the file. A copy that does not match is written once more, in case the error was in the transfer
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use anyhow::{anyhow, Context, Result};
+use log::{error, info, warn};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use crate::checksum::{self, ChecksumAlgorithm};
+use crate::checksum_cache::{self, ChecksumCache};
+use crate::copy::{self, Copied};
+use crate::filter::Filters;
+use crate::listing;
+use crate::manifest::{self, Mismatch};
+use crate::CopyOptions;
+
+/// The result of the pass
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Verification {
+    /// Files checked
+    pub checked: usize,
+    /// The missing and mismatched files
+    pub mismatches: Vec<Mismatch>,
+    /// Files of the destination that are not in the source
+    pub extra: Vec<PathBuf>,
+}
+
+impl Verification {
+    /// Log every discrepancy and fail when a file is missing or does not match
+    pub fn check(&self) -> Result<()> {
+        for mismatch in &self.mismatches {
+            match mismatch {
+                Mismatch::Missing(path) => error!("Verification: {:?} is missing in the destination", path),
+                Mismatch::Size { path, expected, actual } => error!("Verification: {:?} has {} bytes instead of {}", path, actual, expected),
+                Mismatch::Digest { path, .. } => error!("Verification: {:?} does not have the content of its source", path),
+            }
+        }
+        for path in &self.extra {
+            warn!("Verification: {:?} is in the destination but not in the source", path);
+        }
+        let missing = self.mismatches.iter().filter(|mismatch| matches!(mismatch, Mismatch::Missing(_))).count();
+        if !self.mismatches.is_empty() {
+            return Err(anyhow!(
+                "The verification after the copy found {} missing and {} mismatched files",
+                missing, self.mismatches.len() - missing,
+            ));
+        }
+        info!("Verified {} files, {} extra files in the destination", self.checked, self.extra.len());
+        Ok(())
+    }
+}
+
+/// Compare the files of `source` selected by `filters` with the ones under `dest`, reading both. Up to `concurrency`
+/// files are hashed at the same time, the sources through the checksum cache when there is one
+pub async fn against_source(
+    source: &Path,
+    dest: &Path,
+    filters: &Filters,
+    algorithm: ChecksumAlgorithm,
+    cache: Option<Arc<ChecksumCache>>,
+    concurrency: usize,
+) -> Result<Verification> {
+    let sources = listing::list_tree(source, filters, true).await?;
+    let mut copies: HashMap<PathBuf, u64> = listing::list_tree(dest, &Filters::default(), false).await?
+        .into_iter()
+        .map(|listed| (listed.path, listed.size))
+        .collect();
+    let mut verification = Verification { checked: sources.len(), ..Default::default() };
+    let permits = Arc::new(Semaphore::new(concurrency.max(1)));
+    let mut hashes = JoinSet::new();
+    for listed in sources {
+        match copies.remove(&listed.path) {
+            None => verification.mismatches.push(Mismatch::Missing(listed.path)),
+            Some(size) if size != listed.size => {
+                verification.mismatches.push(Mismatch::Size { path: listed.path, expected: listed.size, actual: size });
+            },
+            Some(_) => {
+                let permit = permits.clone().acquire_owned().await?;
+                let (from, to, cache) = (source.join(&listed.path), dest.join(&listed.path), cache.clone());
+                hashes.spawn(async move {
+                    let _permit = permit;
+                    let expected = checksum_cache::hash_source(&from, algorithm, cache.as_deref()).await?;
+                    let actual = checksum::hash_file(&to, algorithm).await?;
+                    anyhow::Ok((expected != actual).then_some(Mismatch::Digest { path: listed.path, expected, actual }))
+                });
+            },
+        }
+    }
+    while let Some(hashed) = hashes.join_next().await {
+        verification.mismatches.extend(hashed??);
+    }
+    verification.mismatches.sort_by(|first, second| mismatch_path(first).cmp(mismatch_path(second)));
+    verification.extra = copies.into_keys().collect();
+    verification.extra.sort();
+    Ok(verification)
+}
+
+/// Compare the files under `dest` with the manifest written by the copy
+pub async fn against_manifest(manifest: &Path, dest: &Path, algorithm: ChecksumAlgorithm) -> Result<Verification> {
+    let checked = manifest::read(manifest).await?.entries.len();
+    let mismatches = manifest::verify(manifest, dest, algorithm).await?;
+    Ok(Verification { checked, mismatches, extra: vec![] })
+}
+
+/// `--verify`: compare the copy `from` just written into `to` with its source. A copy that does not match is copied
+/// once more, and the file fails when the second one does not match either
+pub async fn check_copy(from: &Path, to: &Path, copied: Copied, options: &CopyOptions) -> Result<Copied> {
+    if matches_source(to, &copied, options).await? {
+        return Ok(copied);
+    }
+    warn!("The copy of {:?} does not match its source, it is copied again", from);
+    let copied = copy::copy_file(from, to, options).await
+        .with_context(|| format!("Cannot copy file: {:?}", from))?;
+    if matches_source(to, &copied, options).await? {
+        return Ok(copied);
+    }
+    Err(anyhow!("The copy does not match its source, also copied again: {:?}", to))
+}
+
+/// Whether the content of `to` has the digest of the source of `copied`, accounted in the stats
+async fn matches_source(to: &Path, copied: &Copied, options: &CopyOptions) -> Result<bool> {
+    let expected = copied.digest.as_deref().context("The copy did not hash its source")?;
+    let file = tokio::fs::File::open(to).await
+        .with_context(|| format!("Cannot read the copy back: {:?}", to))?;
+    drop_cached(&file);
+    let actual = checksum::hash_reader(file, options.checksum_algorithm, options.bandwidth.as_deref()).await
+        .with_context(|| format!("Cannot read the copy back: {:?}", to))?;
+    if actual == expected {
+        options.stats.verified(copied.bytes);
+        return Ok(true);
+    }
+    options.stats.verify_mismatch();
+    Ok(false)
+}
+
+/// Drop the pages of `file` from the page cache, so it is read from the disk. The pages not flushed yet (without
+/// --fsync) stay, and those are read from the cache
+#[cfg(target_os = "linux")]
+fn drop_cached(file: &tokio::fs::File) {
+    use std::os::fd::AsRawFd;
+    // Only a hint, the file is read either way
+    unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) };
+}
+
+#[cfg(not(target_os = "linux"))]
+fn drop_cached(_file: &tokio::fs::File) {}
+
+fn mismatch_path(mismatch: &Mismatch) -> &Path {
+    match mismatch {
+        Mismatch::Missing(path) | Mismatch::Size { path, .. } | Mismatch::Digest { path, .. } => path,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::sync::atomic::Ordering;
+    use crate::checksum::{self, ChecksumAlgorithm};
+    use crate::copy::Copied;
+    use crate::filter::Filters;
+    use crate::manifest::Mismatch;
+    use crate::test_support::init;
+    use crate::{copy_tree, CopyOptions};
+    use super::{against_source, check_copy};
+
+    #[tokio::test]
+    async fn corrupted_copies() {
+        let base_dir = init("corrupted_copies").await;
+
+        let source = base_dir.join("source");
+        let dest = base_dir.join("dest");
+        tokio::fs::create_dir_all(source.join("nested")).await.unwrap();
+        for name in ["fine", "nested/corrupted", "truncated", "removed"] {
+            tokio::fs::write(source.join(name), "some text").await.unwrap();
+        }
+        copy_tree(&source, &dest, Arc::new(CopyOptions::default()), 1).await.unwrap();
+        let filters = Filters::default();
+        let verify = || against_source(&source, &dest, &filters, ChecksumAlgorithm::Blake3, None, 2);
+        let verification = verify().await.unwrap();
+        assert!(verification.mismatches.is_empty() && verification.extra.is_empty());
+        assert!(verification.check().is_ok());
+
+        // Damaged after the copy, the same size
+        tokio::fs::write(dest.join("nested/corrupted"), "some test").await.unwrap();
+        tokio::fs::write(dest.join("truncated"), "some").await.unwrap();
+        tokio::fs::remove_file(dest.join("removed")).await.unwrap();
+        tokio::fs::write(dest.join("unrelated"), "text").await.unwrap();
+        let verification = verify().await.unwrap();
+        assert_eq!(verification.checked, 4);
+        assert!(matches!(&verification.mismatches[..], [
+            Mismatch::Digest { path: corrupted, .. },
+            Mismatch::Missing(removed),
+            Mismatch::Size { path: truncated, expected: 9, actual: 4 },
+        ] if corrupted == &PathBuf::from("nested/corrupted") && removed == &PathBuf::from("removed") && truncated == &PathBuf::from("truncated")));
+        assert_eq!(verification.extra, [PathBuf::from("unrelated")]);
+        let error = verification.check().unwrap_err();
+        assert_eq!(error.to_string(), "The verification after the copy found 1 missing and 2 mismatched files");
+    }
+
+    #[tokio::test]
+    async fn copied_again() {
+        let base_dir = init("copied_again").await;
+
+        let source = base_dir.join("source");
+        let dest = base_dir.join("dest");
+        tokio::fs::write(&source, "some text").await.unwrap();
+        let options = CopyOptions { verify: true, checksum_algorithm: ChecksumAlgorithm::Xxh3, ..Default::default() };
+        let digest = checksum::hash_file(&source, ChecksumAlgorithm::Xxh3).await.unwrap();
+        let copied = Copied { bytes: 9, digest: Some(digest), cloned: false };
+
+        // Damaged on its way to the disk
+        tokio::fs::write(&dest, "some test").await.unwrap();
+        let copied = check_copy(&source, &dest, copied, &options).await.unwrap();
+        assert_eq!(tokio::fs::read_to_string(&dest).await.unwrap(), "some text");
+        assert_eq!(copied.bytes, 9);
+        assert_eq!(options.stats.verify_mismatches.load(Ordering::Relaxed), 1);
+        assert_eq!(options.stats.files_verified.load(Ordering::Relaxed), 1);
+        assert_eq!(options.stats.bytes_verified.load(Ordering::Relaxed), 9);
+        assert!(options.stats.summary().ends_with(", 1 files verified (9 bytes, 1 mismatches)"), "{}", options.stats.summary());
+    }
+}
@@ -0,0 +1,135 @@
+//! Sources modified while they are copied. The size and modification time of every source are taken before its copy
+//! and compared once it is written: a copy of a file that changed in between can be torn, half old and half new.
+//! `--volatile` (or `--on-change`) chooses what happens then: `retry` copies it again (up to `--retries` times, then it
+//! fails), `skip` leaves it out, `fail` fails it at once and `warn` keeps the copy as it is. The destination is removed
+//! unless it is kept with `warn`, and the source is never deleted. `--no-detect-changes` skips the check. The files
+//! that stayed changed are listed at the end of the run
+
+use std::fmt;
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+use anyhow::{Context, Result};
+use log::warn;
+
+/// The files that changed during their copy listed at the end of the run, the rest are only counted
+const LOGGED_FILES: usize = 20;
+
+/// The size and modification time of a source before it is copied
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Snapshot {
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+impl Snapshot {
+    pub fn of(metadata: &Metadata) -> Self {
+        Self { len: metadata.len(), modified: metadata.modified().ok() }
+    }
+
+    /// Whether the source `from` is not the one of the snapshot any more
+    pub async fn changed(&self, from: &Path) -> Result<bool> {
+        let now = tokio::fs::metadata(from).await
+            .with_context(|| format!("Cannot read metadata: {:?}", from))?;
+        Ok(Self::of(&now) != *self)
+    }
+}
+
+/// The error of a copy whose source changed while it was copied
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Changed(pub PathBuf);
+
+impl fmt::Display for Changed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The source changed while it was copied: {:?}", self.0)
+    }
+}
+
+impl std::error::Error for Changed {}
+
+/// Whether a copy failed because its source changed
+pub fn is_changed(error: &anyhow::Error) -> bool {
+    error.downcast_ref::<Changed>().is_some()
+}
+
+/// What became of a file that changed while it was copied
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Outcome {
+    /// Its copy was kept, it can be torn (--volatile warn)
+    Copied,
+    Skipped,
+    Failed,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Copied => "copied anyway",
+            Self::Skipped => "skipped",
+            Self::Failed => "failed",
+        })
+    }
+}
+
+/// The sources that were still changing when their copy gave up: kept anyway, skipped or failed
+#[derive(Debug, Default)]
+pub struct VolatileFiles(Mutex<Vec<(PathBuf, Outcome)>>);
+
+impl VolatileFiles {
+    pub fn record(&self, path: &Path, outcome: Outcome) {
+        self.0.lock().unwrap().push((path.to_owned(), outcome));
+    }
+
+    pub fn list(&self) -> Vec<(PathBuf, Outcome)> {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// Log the files for the operators to copy them once they are stable
+    pub fn log(&self) {
+        let mut list = self.list();
+        if list.is_empty() {
+            return;
+        }
+        list.sort();
+        warn!("{} files changed while they were copied:", list.len());
+        for (path, outcome) in list.iter().take(LOGGED_FILES) {
+            warn!("  {:?} ({})", path, outcome);
+        }
+        if list.len() > LOGGED_FILES {
+            warn!("  ... and {} more", list.len() - LOGGED_FILES);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+    use crate::test_support::init;
+    use super::Snapshot;
+
+    #[tokio::test]
+    async fn changed_sources() {
+        let base_dir = init("changed_sources").await;
+
+        let source = base_dir.join("source");
+        tokio::fs::write(&source, "some text").await.unwrap();
+        let before = Snapshot::of(&tokio::fs::metadata(&source).await.unwrap());
+        assert!(!before.changed(&source).await.unwrap());
+
+        // The same size, a new time
+        let file = std::fs::File::options().write(true).open(&source).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(5)).unwrap();
+        assert!(before.changed(&source).await.unwrap());
+
+        let before = Snapshot::of(&tokio::fs::metadata(&source).await.unwrap());
+        let modified = tokio::fs::metadata(&source).await.unwrap().modified().unwrap();
+        // A size change with the time the clock gave it (a coarse one can give the same)
+        tokio::fs::write(&source, "some more text").await.unwrap();
+        let file = std::fs::File::options().write(true).open(&source).unwrap();
+        file.set_modified(modified).unwrap();
+        assert!(before.changed(&source).await.unwrap());
+        assert!(before.changed(&base_dir.join("missing")).await.is_err());
+    }
+}
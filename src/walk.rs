@@ -0,0 +1,109 @@
+//! Reading the entries of a source directory, shared by the copy and the pre-scan so both select the same files
+
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, Result};
+use log::{debug, warn};
+use crate::backend::{Backend, DirEntries, FileInfo};
+use crate::filter::Filters;
+use crate::DanglingLinks;
+
+/// An entry of a source directory selected by the filters
+pub enum Entry {
+    /// A regular file. The metadata is only read when it was requested or the filters need it
+    File { path: PathBuf, metadata: Option<FileInfo> },
+    /// A symbolic link copied as a link, where `Links` preserves it or it is dangling and `--dangling-links preserve`
+    Link(PathBuf),
+    /// Anything else, it is walked as a directory
+    Directory(PathBuf),
+}
+
+/// How the symbolic links of the source are copied
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Links {
+    pub mode: LinkMode,
+    /// What becomes of the followed links whose target does not exist
+    pub dangling: DanglingLinks,
+}
+
+/// Which symbolic links of the source are copied as links instead of followed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LinkMode {
+    /// Every link is followed, a file for a link to a file and a directory for a link to a directory
+    #[default]
+    Followed,
+    /// Every link is copied as a link (`--preserve links`)
+    Preserved,
+    /// The links at the top of the source are followed and the deeper ones copied as links (`--follow-top-level-links`)
+    FollowedAtTopLevel,
+}
+
+impl Links {
+    /// Whether the links of a directory `depth` levels below the source (0 for the source itself) are kept as links
+    pub fn preserved(self, depth: usize) -> bool {
+        match self.mode {
+            LinkMode::Followed => false,
+            LinkMode::Preserved => true,
+            LinkMode::FollowedAtTopLevel => depth > 0,
+        }
+    }
+}
+
+/// The target of the link `path` when it does not exist, None for the rest of links and for the other entries
+pub async fn dangling_target(path: &Path) -> Option<PathBuf> {
+    if tokio::fs::try_exists(path).await.unwrap_or(true) {
+        return None;
+    }
+    tokio::fs::read_link(path).await.ok()
+}
+
+/// The error of the dangling link `path` with `--dangling-links error`
+pub fn dangling_error(path: &Path, target: &Path) -> anyhow::Error {
+    anyhow!("Cannot follow dangling symlink {:?}, its target does not exist: {:?}", path, target)
+}
+
+/// The next entry of the directory that the filters select, None at the end of the directory.
+/// `depth` is the one of the directory below the source: the links are returned as they are where `links` preserves
+/// them, otherwise they are followed. A followed link to a file is a file, anything else is walked like a directory.
+/// A followed link whose target does not exist is skipped, copied as a link or failed as `links.dangling` tells.
+/// A failed entry is returned as an error and the next call goes on with the rest of the entries
+pub async fn next_entry(backend: &dyn Backend, entries: &mut dyn DirEntries, filters: &Filters, with_metadata: bool, links: Links, depth: usize) -> Option<Result<Entry>> {
+    loop {
+        let mut entry = match entries.next_entry().await {
+            Ok(Some(entry)) => entry,
+            Ok(None) => return None,
+            Err(error) => return Some(Err(error)),
+        };
+        if entry.is_symlink {
+            if links.preserved(depth) {
+                return Some(Ok(Entry::Link(entry.path)));
+            }
+            if let Some(target) = dangling_target(&entry.path).await {
+                match links.dangling {
+                    DanglingLinks::Skip => {
+                        warn!("Skip dangling symlink {:?}, its target does not exist: {:?}", entry.path, target);
+                        continue;
+                    },
+                    DanglingLinks::Preserve => return Some(Ok(Entry::Link(entry.path))),
+                    DanglingLinks::Error => return Some(Err(dangling_error(&entry.path, &target))),
+                }
+            }
+            entry.is_file = backend.metadata(&entry.path).await.is_ok_and(|metadata| !metadata.is_dir);
+        }
+        if !entry.is_file {
+            return Some(Ok(Entry::Directory(entry.path)));
+        }
+        let metadata = if with_metadata || filters.needs_metadata() {
+            match backend.metadata(&entry.path).await {
+                Ok(metadata) => Some(metadata),
+                Err(error) => return Some(Err(error.context(format!("Cannot read metadata: {:?}", entry.path)))),
+            }
+        } else {
+            None
+        };
+        if metadata.is_some_and(|metadata| !filters.selects(metadata.modified)) {
+            debug!("Filtered out: {:?}", entry.path);
+            continue;
+        }
+        return Some(Ok(Entry::File { path: entry.path, metadata }));
+    }
+}
@@ -0,0 +1,172 @@
+//! Paths on Windows. The Win32 functions reject the paths longer than 260 characters unless they are in their
+//! extended form (`\\?\C:\dir`, `\\?\UNC\server\share\dir`), which the filesystem takes as they are: the source and
+//! the destination roots are turned into it, so every path built from them has it. That form skips the checks of
+//! the names too, so the names that Win32 does not allow (a trailing dot or space, a reserved character or the name
+//! of a device) are checked here: their copy fails on Windows, and `--sanitize-names` renames them instead, also on
+//! other systems for a destination that is read from Windows
+
+use std::borrow::Cow;
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+use anyhow::Result;
+
+/// Whether the names of the destination must be valid for Win32 even without --sanitize-names
+pub const CHECKS_NAMES: bool = cfg!(windows);
+
+#[cfg_attr(not(windows), allow(dead_code))]
+const EXTENDED_PREFIX: &str = r"\\?\";
+
+/// The characters that a Win32 name cannot have, besides the control characters
+const RESERVED_CHARACTERS: &[char] = &['<', '>', ':', '"', '/', '\\', '|', '?', '*'];
+
+/// The names of devices, also with an extension (`nul.txt`)
+const DEVICES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// The extended form of `path`, made absolute first (and with its `.` and `..` resolved, the extended form does not
+/// resolve them). Elsewhere the path is kept as it is
+#[cfg(windows)]
+pub fn extended(path: &Path) -> Result<PathBuf> {
+    use anyhow::Context;
+    let absolute = std::path::absolute(path).with_context(|| format!("Cannot resolve the path: {:?}", path))?;
+    // Only the lone surrogates of an ill-formed name are not text, Win32 keeps those paths as they are
+    Ok(absolute.to_str().map_or(absolute.clone(), |text| PathBuf::from(extended_form(text))))
+}
+
+#[cfg(not(windows))]
+pub fn extended(path: &Path) -> Result<PathBuf> {
+    Ok(path.to_owned())
+}
+
+/// The extended form of the absolute Windows path `path`, with its forward slashes turned into separators
+#[cfg_attr(not(windows), allow(dead_code))]
+fn extended_form(path: &str) -> String {
+    if path.starts_with(EXTENDED_PREFIX) {
+        return path.to_owned();
+    }
+    let path = path.replace('/', r"\");
+    match path.strip_prefix(r"\\") {
+        Some(share) => format!(r"{}UNC\{}", EXTENDED_PREFIX, share),
+        None => format!("{}{}", EXTENDED_PREFIX, path),
+    }
+}
+
+/// Why Win32 does not allow the file name `name`, None when it does
+fn invalid(name: &OsStr) -> Option<&'static str> {
+    let Some(name) = name.to_str() else {
+        return Some("it is not Unicode");
+    };
+    if name.ends_with(['.', ' ']) {
+        return Some("it ends with a dot or a space");
+    }
+    if name.chars().any(|char| char.is_control() || RESERVED_CHARACTERS.contains(&char)) {
+        return Some("it has a character reserved on Windows");
+    }
+    if is_device(name) {
+        return Some("it is the name of a device on Windows");
+    }
+    None
+}
+
+fn is_device(name: &str) -> bool {
+    let stem = name.split('.').next().unwrap_or(name).trim_end();
+    DEVICES.iter().any(|device| device.eq_ignore_ascii_case(stem))
+}
+
+/// A name that Win32 allows instead of `name`: the reserved characters become `_`, the trailing dots and spaces
+/// are removed and a `_` follows the name of a device
+fn sanitized(name: &OsStr) -> OsString {
+    let name = name.to_string_lossy();
+    let mut sanitized: String = name.trim_end_matches(['.', ' '])
+        .chars()
+        .map(|char| if char.is_control() || RESERVED_CHARACTERS.contains(&char) { '_' } else { char })
+        .collect();
+    if sanitized.is_empty() {
+        sanitized.push('_');
+    }
+    if is_device(&sanitized) {
+        let stem = sanitized.find('.').unwrap_or(sanitized.len());
+        sanitized.insert(stem, '_');
+    }
+    sanitized.into()
+}
+
+/// The name of the destination of an entry called `name`: renamed with `sanitize`, an error when it is not valid
+/// on Windows and the run is on Windows
+pub fn destination_name(name: &OsStr, sanitize: bool) -> Result<Cow<'_, OsStr>> {
+    if !CHECKS_NAMES && !sanitize {
+        return Ok(Cow::Borrowed(name));
+    }
+    match invalid(name) {
+        None => Ok(Cow::Borrowed(name)),
+        Some(_) if sanitize => Ok(Cow::Owned(sanitized(name))),
+        Some(reason) => Err(anyhow::anyhow!("Invalid file name on Windows, {}: {:?} (--sanitize-names renames it)", reason, name)),
+    }
+}
+
+/// `relative` with every component renamed like `destination_name`
+pub fn destination_path(relative: &Path, sanitize: bool) -> Result<Cow<'_, Path>> {
+    if !CHECKS_NAMES && !sanitize {
+        return Ok(Cow::Borrowed(relative));
+    }
+    let mut renamed = PathBuf::new();
+    let mut changed = false;
+    for component in relative.iter() {
+        let name = destination_name(component, sanitize)?;
+        changed |= matches!(name, Cow::Owned(_));
+        renamed.push(name);
+    }
+    Ok(if changed { Cow::Owned(renamed) } else { Cow::Borrowed(relative) })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+    use std::ffi::OsStr;
+    use std::path::Path;
+    use super::{destination_name, destination_path, extended_form, invalid, sanitized};
+
+    #[test]
+    fn extended_forms() {
+        assert_eq!(extended_form(r"C:\data\dir"), r"\\?\C:\data\dir");
+        assert_eq!(extended_form("C:/data/dir"), r"\\?\C:\data\dir");
+        assert_eq!(extended_form(r"\\server\share\dir"), r"\\?\UNC\server\share\dir");
+        assert_eq!(extended_form(r"\\?\C:\data"), r"\\?\C:\data");
+    }
+
+    #[test]
+    fn windows_names() {
+        for name in ["file.txt", "dir", "console", "COM10", ".hidden", "naïve"] {
+            assert_eq!(invalid(OsStr::new(name)), None, "{}", name);
+        }
+        for (name, renamed) in [
+            ("file.", "file"),
+            ("dir . ", "dir"),
+            ("a:b?c", "a_b_c"),
+            ("back\\slash", "back_slash"),
+            ("tab\there", "tab_here"),
+            ("nul", "nul_"),
+            ("Con.txt", "Con_.txt"),
+            ("...", "_"),
+        ] {
+            assert!(invalid(OsStr::new(name)).is_some(), "{}", name);
+            assert_eq!(sanitized(OsStr::new(name)), renamed);
+            assert_eq!(invalid(&sanitized(OsStr::new(name))), None, "{}", renamed);
+        }
+
+        assert!(matches!(destination_name(OsStr::new("file."), true).unwrap(), Cow::Owned(name) if name == "file"));
+        assert!(matches!(destination_name(OsStr::new("file"), true).unwrap(), Cow::Borrowed(_)));
+        let path = destination_path(Path::new("dir./nested/a|b"), true).unwrap();
+        assert_eq!(path, Path::new("dir/nested/a_b"));
+        if cfg!(windows) {
+            let error = destination_name(OsStr::new("file."), false).unwrap_err();
+            assert_eq!(error.to_string(), "Invalid file name on Windows, it ends with a dot or a space: \"file.\" (--sanitize-names renames it)");
+        } else {
+            // Only checked when asked
+            assert_eq!(destination_name(OsStr::new("file."), false).unwrap(), OsStr::new("file."));
+        }
+    }
+}
@@ -0,0 +1,256 @@
+//! XXH3, the 64 bits variant with the default secret and no seed, as `xxhsum -H3` computes it. It is not a
+//! cryptographic hash but it reads much faster than one, enough to tell a copy from its source.
+//! Inputs up to 240 bytes are hashed at once; the longer ones go through 64 bytes stripes, and the last stripe is
+//! hashed with its own part of the secret, so the state keeps the last bytes until the end
+
+/// The default secret
+const SECRET: [u8; 192] = [
+    0xb8, 0xfe, 0x6c, 0x39, 0x23, 0xa4, 0x4b, 0xbe, 0x7c, 0x01, 0x81, 0x2c, 0xf7, 0x21, 0xad, 0x1c,
+    0xde, 0xd4, 0x6d, 0xe9, 0x83, 0x90, 0x97, 0xdb, 0x72, 0x40, 0xa4, 0xa4, 0xb7, 0xb3, 0x67, 0x1f,
+    0xcb, 0x79, 0xe6, 0x4e, 0xcc, 0xc0, 0xe5, 0x78, 0x82, 0x5a, 0xd0, 0x7d, 0xcc, 0xff, 0x72, 0x21,
+    0xb8, 0x08, 0x46, 0x74, 0xf7, 0x43, 0x24, 0x8e, 0xe0, 0x35, 0x90, 0xe6, 0x81, 0x3a, 0x26, 0x4c,
+    0x3c, 0x28, 0x52, 0xbb, 0x91, 0xc3, 0x00, 0xcb, 0x88, 0xd0, 0x65, 0x8b, 0x1b, 0x53, 0x2e, 0xa3,
+    0x71, 0x64, 0x48, 0x97, 0xa2, 0x0d, 0xf9, 0x4e, 0x38, 0x19, 0xef, 0x46, 0xa9, 0xde, 0xac, 0xd8,
+    0xa8, 0xfa, 0x76, 0x3f, 0xe3, 0x9c, 0x34, 0x3f, 0xf9, 0xdc, 0xbb, 0xc7, 0xc7, 0x0b, 0x4f, 0x1d,
+    0x8a, 0x51, 0xe0, 0x4b, 0xcd, 0xb4, 0x59, 0x31, 0xc8, 0x9f, 0x7e, 0xc9, 0xd9, 0x78, 0x73, 0x64,
+    0xea, 0xc5, 0xac, 0x83, 0x34, 0xd3, 0xeb, 0xc3, 0xc5, 0x81, 0xa0, 0xff, 0xfa, 0x13, 0x63, 0xeb,
+    0x17, 0x0d, 0xdd, 0x51, 0xb7, 0xf0, 0xda, 0x49, 0xd3, 0x16, 0x55, 0x26, 0x29, 0xd4, 0x68, 0x9e,
+    0x2b, 0x16, 0xbe, 0x58, 0x7d, 0x47, 0xa1, 0xfc, 0x8f, 0xf8, 0xb8, 0xd1, 0x7a, 0xd0, 0x31, 0xce,
+    0x45, 0xcb, 0x3a, 0x8f, 0x95, 0x16, 0x04, 0x28, 0xaf, 0xd7, 0xfb, 0xca, 0xbb, 0x4b, 0x40, 0x7e,
+];
+
+const PRIME32_1: u64 = 0x9e37_79b1;
+const PRIME32_2: u64 = 0x85eb_ca77;
+const PRIME32_3: u64 = 0xc2b2_ae3d;
+const PRIME64_1: u64 = 0x9e37_79b1_85eb_ca87;
+const PRIME64_2: u64 = 0xc2b2_ae3d_27d4_eb4f;
+const PRIME64_3: u64 = 0x1656_67b1_9e37_79f9;
+const PRIME64_4: u64 = 0x85eb_ca77_c2b2_ae63;
+const PRIME64_5: u64 = 0x27d4_eb2f_1656_67c5;
+const PRIME_MX1: u64 = 0x1656_6791_9e37_79f9;
+const PRIME_MX2: u64 = 0x9fb2_1c65_1e98_df25;
+
+const STRIPE_LEN: usize = 64;
+/// Bytes of the secret between the stripes of a block
+const SECRET_CONSUME_RATE: usize = 8;
+const STRIPES_PER_BLOCK: usize = (SECRET.len() - STRIPE_LEN) / SECRET_CONSUME_RATE;
+/// The longest input hashed at once
+const MIDSIZE_MAX: usize = 240;
+/// Bytes buffered before their stripes are accumulated
+const BUFFER_LEN: usize = 1024;
+
+fn read32(bytes: &[u8], at: usize) -> u64 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap()).into()
+}
+
+fn read64(bytes: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap())
+}
+
+/// The 128 bits product of `a` and `b`, folded
+fn mul128_fold64(a: u64, b: u64) -> u64 {
+    let product = u128::from(a) * u128::from(b);
+    (product as u64) ^ ((product >> 64) as u64)
+}
+
+fn avalanche(mut hash: u64) -> u64 {
+    hash ^= hash >> 37;
+    hash = hash.wrapping_mul(PRIME_MX1);
+    hash ^ (hash >> 32)
+}
+
+/// The final mix of XXH64
+fn xxh64_avalanche(mut hash: u64) -> u64 {
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(PRIME64_2);
+    hash ^= hash >> 29;
+    hash = hash.wrapping_mul(PRIME64_3);
+    hash ^ (hash >> 32)
+}
+
+fn rrmxmx(mut hash: u64, len: u64) -> u64 {
+    hash ^= hash.rotate_left(49) ^ hash.rotate_left(24);
+    hash = hash.wrapping_mul(PRIME_MX2);
+    hash ^= (hash >> 35).wrapping_add(len);
+    hash = hash.wrapping_mul(PRIME_MX2);
+    hash ^ (hash >> 28)
+}
+
+fn mix16(input: &[u8], at: usize, secret: usize) -> u64 {
+    mul128_fold64(read64(input, at) ^ read64(&SECRET, secret), read64(input, at + 8) ^ read64(&SECRET, secret + 8))
+}
+
+/// The hash of the inputs up to MIDSIZE_MAX bytes
+fn hash_short(input: &[u8]) -> u64 {
+    let len = input.len();
+    match len {
+        0 => xxh64_avalanche(read64(&SECRET, 56) ^ read64(&SECRET, 64)),
+        1..=3 => {
+            let combined = (u64::from(input[0]) << 16) | (u64::from(input[len >> 1]) << 24) | u64::from(input[len - 1]) | ((len as u64) << 8);
+            xxh64_avalanche(combined ^ (read32(&SECRET, 0) ^ read32(&SECRET, 4)))
+        },
+        4..=8 => {
+            let combined = read32(input, len - 4).wrapping_add(read32(input, 0) << 32);
+            rrmxmx(combined ^ (read64(&SECRET, 8) ^ read64(&SECRET, 16)), len as u64)
+        },
+        9..=16 => {
+            let low = read64(input, 0) ^ (read64(&SECRET, 24) ^ read64(&SECRET, 32));
+            let high = read64(input, len - 8) ^ (read64(&SECRET, 40) ^ read64(&SECRET, 48));
+            avalanche((len as u64).wrapping_add(low.swap_bytes()).wrapping_add(high).wrapping_add(mul128_fold64(low, high)))
+        },
+        17..=128 => {
+            let mut acc = (len as u64).wrapping_mul(PRIME64_1);
+            let pairs = (len - 1) / 32;
+            for pair in (0..=pairs).rev() {
+                acc = acc.wrapping_add(mix16(input, 16 * pair, 32 * pair));
+                acc = acc.wrapping_add(mix16(input, len - 16 * (pair + 1), 32 * pair + 16));
+            }
+            avalanche(acc)
+        },
+        _ => {
+            let mut acc = (len as u64).wrapping_mul(PRIME64_1);
+            for round in 0..8 {
+                acc = acc.wrapping_add(mix16(input, 16 * round, 16 * round));
+            }
+            acc = avalanche(acc);
+            for round in 8..len / 16 {
+                acc = acc.wrapping_add(mix16(input, 16 * round, 16 * (round - 8) + 3));
+            }
+            avalanche(acc.wrapping_add(mix16(input, len - 16, 136 - 17)))
+        },
+    }
+}
+
+fn accumulate(acc: &mut [u64; 8], stripe: &[u8], secret: usize) {
+    for lane in 0..8 {
+        let value = read64(stripe, 8 * lane);
+        let key = value ^ read64(&SECRET, secret + 8 * lane);
+        acc[lane ^ 1] = acc[lane ^ 1].wrapping_add(value);
+        acc[lane] = acc[lane].wrapping_add((key & 0xffff_ffff).wrapping_mul(key >> 32));
+    }
+}
+
+fn scramble(acc: &mut [u64; 8]) {
+    let secret = SECRET.len() - STRIPE_LEN;
+    for (lane, value) in acc.iter_mut().enumerate() {
+        *value = ((*value ^ (*value >> 47)) ^ read64(&SECRET, secret + 8 * lane)).wrapping_mul(PRIME32_1);
+    }
+}
+
+/// Incremental XXH3
+#[derive(Clone)]
+pub struct Xxh3 {
+    acc: [u64; 8],
+    /// Stripes accumulated in the current block
+    stripes: usize,
+    /// The bytes not accumulated yet, with the start of the input until it is longer than MIDSIZE_MAX
+    pending: Vec<u8>,
+    /// The last stripe accumulated, part of the last stripe of the input when `pending` is shorter
+    previous: [u8; STRIPE_LEN],
+    len: u64,
+}
+
+impl Default for Xxh3 {
+    fn default() -> Self {
+        Self {
+            acc: [PRIME32_3, PRIME64_1, PRIME64_2, PRIME64_3, PRIME64_4, PRIME32_2, PRIME64_5, PRIME32_1],
+            stripes: 0,
+            pending: Vec::with_capacity(BUFFER_LEN),
+            previous: [0; STRIPE_LEN],
+            len: 0,
+        }
+    }
+}
+
+impl Xxh3 {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.len += data.len() as u64;
+        if self.len <= MIDSIZE_MAX as u64 {
+            self.pending.extend_from_slice(data);
+            return;
+        }
+        // The stripes of `pending` and then of `data` are accumulated as long as a byte follows them
+        while !data.is_empty() {
+            let take = data.len().min(BUFFER_LEN - self.pending.len());
+            self.pending.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            let mut start = 0;
+            while self.pending.len() - start > STRIPE_LEN {
+                let stripe: [u8; STRIPE_LEN] = self.pending[start..start + STRIPE_LEN].try_into().unwrap();
+                self.accumulate_stripe(&stripe);
+                start += STRIPE_LEN;
+            }
+            self.pending.drain(..start);
+        }
+    }
+
+    fn accumulate_stripe(&mut self, stripe: &[u8; STRIPE_LEN]) {
+        accumulate(&mut self.acc, stripe, self.stripes * SECRET_CONSUME_RATE);
+        self.previous = *stripe;
+        self.stripes += 1;
+        if self.stripes == STRIPES_PER_BLOCK {
+            scramble(&mut self.acc);
+            self.stripes = 0;
+        }
+    }
+
+    pub fn digest(&self) -> u64 {
+        if self.len <= MIDSIZE_MAX as u64 {
+            return hash_short(&self.pending);
+        }
+        let mut last = [0; STRIPE_LEN];
+        let pending = self.pending.len();
+        last[..STRIPE_LEN - pending].copy_from_slice(&self.previous[pending..]);
+        last[STRIPE_LEN - pending..].copy_from_slice(&self.pending);
+        let mut acc = self.acc;
+        accumulate(&mut acc, &last, SECRET.len() - STRIPE_LEN - 7);
+        let mut result = self.len.wrapping_mul(PRIME64_1);
+        for pair in 0..4 {
+            let secret = 11 + 16 * pair;
+            result = result.wrapping_add(mul128_fold64(
+                acc[2 * pair] ^ read64(&SECRET, secret),
+                acc[2 * pair + 1] ^ read64(&SECRET, secret + 8),
+            ));
+        }
+        avalanche(result)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::Xxh3;
+
+    #[test]
+    fn known_vectors() {
+        // From xxhsum -H3, over the bytes `i * 7 + 3` (wrapping): every path of the short inputs, and the long ones
+        // with their last stripe cut and several blocks
+        let input: Vec<u8> = (0..3000u32).map(|i| (i * 7 + 3) as u8).collect();
+        let vectors = [
+            (0, 0x2d06800538d394c2u64),
+            (2, 0x1c9074b93943b86c),
+            (5, 0x998620e10e3a4b37),
+            (12, 0x6829454be0cc3199),
+            (100, 0xb5937857f0d78c9f),
+            (200, 0x746cd0025327bf5b),
+            (241, 0x8beadd3a8874fe17),
+            (1025, 0x806c2072ed713576),
+            (3000, 0xc89178bb873c6b3d),
+        ];
+        for (len, expected) in vectors {
+            let mut hasher = Xxh3::new();
+            hasher.update(&input[..len]);
+            assert_eq!(hasher.digest(), expected, "{} bytes", len);
+            // The same in pieces of every size
+            for piece in [1, 63, 64, 65, 240, 1000] {
+                let mut hasher = Xxh3::new();
+                input[..len].chunks(piece).for_each(|chunk| hasher.update(chunk));
+                assert_eq!(hasher.digest(), expected, "{} bytes in pieces of {}", len, piece);
+            }
+        }
+    }
+}
@@ -0,0 +1,650 @@
+//! Zip archives as source (`--source backup.zip`): the entries are extracted into the destination.
+//! The central directory at the end of the archive lists the entries, so the files are extracted concurrently, each
+//! with a copy permit and its own handle of the archive. Entries are stored or deflated, zip64 archives are read too.
+//! The modes stored by Unix zippers and the modification times (the UTC time of the extended timestamp when there is
+//! one, the local time of the entry otherwise) are kept, and the time window of the filters applies to the entry times.
+//! Encrypted entries and other compression methods fail like a file that cannot be copied.
+//! The format is read here rather than with a zip crate: only the central directory and the local headers are
+//! parsed, the deflated entries go through flate2 like the tar archives, and every entry is checked against its CRC.
+//! The layouts the zippers write (data descriptors, zip64 records and extra fields) are tested with archives from
+//! Info-ZIP and Python in `testdata/zip`
+
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use anyhow::{anyhow, Context, Result};
+use chrono::{Local, NaiveDate, TimeZone};
+use flate2::read::DeflateDecoder;
+use flate2::Crc;
+use log::{debug, info, warn};
+use tokio::task::JoinSet;
+use crate::{extracts_over, report_file_error, report_file_result, CopyOptions};
+
+const LOCAL_HEADER: u32 = 0x0403_4b50;
+const CENTRAL_HEADER: u32 = 0x0201_4b50;
+const END_OF_DIRECTORY: u32 = 0x0605_4b50;
+const ZIP64_END_OF_DIRECTORY: u32 = 0x0606_4b50;
+const ZIP64_LOCATOR: u32 = 0x0706_4b50;
+/// The end of the central directory, without its comment
+const END_LEN: usize = 22;
+const ZIP64_LOCATOR_LEN: usize = 20;
+/// The extra fields with the 64 bits sizes and the UTC times
+const ZIP64_EXTRA: u16 = 0x0001;
+const TIMESTAMP_EXTRA: u16 = 0x5455;
+/// The host of the entries written on Unix, whose attributes have a mode
+const UNIX_HOST: u16 = 3;
+
+const STORED: u16 = 0;
+const DEFLATED: u16 = 8;
+
+/// Whether the path is a zip archive, based on its extension
+pub fn is_zip(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("zip"))
+}
+
+/// An entry of the central directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Entry {
+    /// The path in the archive, checked to stay in the destination
+    path: PathBuf,
+    is_dir: bool,
+    encrypted: bool,
+    method: u16,
+    crc: u32,
+    compressed: u64,
+    size: u64,
+    /// Where its local header starts
+    offset: u64,
+    mode: Option<u32>,
+    modified: Option<SystemTime>,
+}
+
+impl Entry {
+    /// A symbolic link, whose content is its target. Only Unix creates them, elsewhere they are files
+    fn is_link(&self) -> bool {
+        cfg!(unix) && self.mode.is_some_and(|mode| mode & 0o170_000 == 0o120_000)
+    }
+}
+
+/// Extract the zip `archive` into `dest`, the directories first, then the files and the links last: no entry is
+/// written through a link of the archive
+pub async fn extract(archive: &Path, dest: &Path, options: &Arc<CopyOptions>) -> Result<()> {
+    info!("Extracting {:?} into {:?}", archive, dest);
+    let path = archive.to_owned();
+    let entries = tokio::task::spawn_blocking(move || read_directory(&mut BufReader::new(File::open(&path)?))).await?
+        .with_context(|| format!("Cannot read the zip archive: {:?}", archive))?;
+    let (dirs, files): (Vec<_>, Vec<_>) = entries.into_iter()
+        .filter(|entry| options.filters.selects(entry.modified) || entry.is_dir)
+        .partition(|entry| entry.is_dir);
+    tokio::fs::create_dir_all(dest).await
+        .with_context(|| format!("Cannot create directory: {:?}", dest))?;
+    for dir in &dirs {
+        if let Err(error) = tokio::fs::create_dir_all(dest.join(&dir.path)).await {
+            report_file_error(anyhow::Error::new(error).context(format!("Cannot create directory: {:?}", dest.join(&dir.path))), options)?;
+        }
+    }
+
+    let (links, files): (Vec<_>, Vec<_>) = files.into_iter().partition(Entry::is_link);
+    extract_files(archive, dest, files, options).await?;
+    extract_files(archive, dest, links, options).await?;
+    // Once their files are written, which changes them
+    for dir in dirs.iter().rev() {
+        if let Some(modified) = dir.modified {
+            let set = File::open(dest.join(&dir.path)).and_then(|dir| dir.set_modified(modified));
+            if let Err(error) = set {
+                debug!("Cannot set the time of directory {:?}: {}", dest.join(&dir.path), error);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Extract the file entries concurrently, each with a copy permit
+async fn extract_files(archive: &Path, dest: &Path, entries: Vec<Entry>, options: &Arc<CopyOptions>) -> Result<()> {
+    let mut extracting = JoinSet::new();
+    for entry in entries {
+        let permit = options.copy_permits.acquire().await;
+        let (archive, to, shared) = (archive.to_owned(), dest.join(&entry.path), options.clone());
+        extracting.spawn_blocking(move || {
+            let _permit = permit;
+            debug!("Extract: {:?} to {:?}", entry.path, to);
+            let extracted = extract_file(&archive, &entry, &to, &shared)
+                .with_context(|| format!("Cannot extract {:?} from {:?}", entry.path, archive))?;
+            if extracted {
+                shared.stats.copied(entry.size, false);
+            }
+            Ok(())
+        });
+        while let Some(result) = extracting.try_join_next() {
+            report_file_result(result, options)?;
+        }
+    }
+    while let Some(result) = extracting.join_next().await {
+        report_file_result(result, options)?;
+    }
+    Ok(())
+}
+
+/// Write the content of `entry` into `to` and set its mode and time, false when `--if-exists` keeps what `to` has.
+/// A partial file is removed
+fn extract_file(archive: &Path, entry: &Entry, to: &Path, options: &CopyOptions) -> Result<bool> {
+    if entry.encrypted {
+        return Err(anyhow!("The entry is encrypted"));
+    }
+    if !extracts_over(to, entry.modified, options)? {
+        return Ok(false);
+    }
+    if let Some(parent) = to.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut reader = BufReader::new(File::open(archive)?);
+    reader.seek(SeekFrom::Start(entry.offset))?;
+    let mut header = [0; 30];
+    reader.read_exact(&mut header)?;
+    if u32_at(&header, 0) != LOCAL_HEADER {
+        return Err(anyhow!("Invalid local header at {}", entry.offset));
+    }
+    let skipped = u64::from(u16_at(&header, 26)) + u64::from(u16_at(&header, 28));
+    reader.seek(SeekFrom::Current(skipped as i64))?;
+    let compressed = reader.take(entry.compressed);
+    let decoded: Box<dyn Read> = match entry.method {
+        STORED => Box::new(compressed),
+        DEFLATED => Box::new(DeflateDecoder::new(compressed)),
+        method => return Err(anyhow!("Unsupported compression method {}", method)),
+    };
+    // A byte more than the entry has tells that it is bigger, before a deflate bomb fills the disk
+    let mut content = decoded.take(entry.size.saturating_add(1));
+
+    #[cfg(unix)]
+    if entry.is_link() {
+        let mut target = Vec::new();
+        content.read_to_end(&mut target)?;
+        let target = PathBuf::from(String::from_utf8_lossy(&target).into_owned());
+        if !stays_inside(&entry.path, &target) {
+            return Err(anyhow!("The link points outside of the destination: {:?}", target));
+        }
+        let _ = std::fs::remove_file(to);
+        std::os::unix::fs::symlink(target, to)?;
+        return Ok(true);
+    }
+    let written = File::create(to).map_err(anyhow::Error::from).and_then(|mut file| {
+        let mut crc = Crc::new();
+        let mut buffer = vec![0; 256 * 1024];
+        let mut size = 0;
+        loop {
+            let read = content.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            size += read as u64;
+            if size > entry.size {
+                return Err(anyhow!("The entry is bigger than the size the archive gives it"));
+            }
+            crc.update(&buffer[..read]);
+            file.write_all(&buffer[..read])?;
+        }
+        if size != entry.size || crc.sum() != entry.crc {
+            return Err(anyhow!("The entry is corrupted, its content does not match its checksum"));
+        }
+        if let Some(modified) = entry.modified {
+            file.set_modified(modified)?;
+        }
+        Ok(file)
+    });
+    let file = match written {
+        Ok(file) => file,
+        Err(error) => {
+            let _ = std::fs::remove_file(to);
+            return Err(error);
+        },
+    };
+    if options.fsync {
+        file.sync_all()?;
+    }
+    set_mode(&file, options.chmod.or(entry.mode.map(|mode| mode & 0o7777)))?;
+    Ok(true)
+}
+
+#[cfg(unix)]
+fn set_mode(file: &File, mode: Option<u32>) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    match mode {
+        Some(mode) => file.set_permissions(std::fs::Permissions::from_mode(mode)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+fn set_mode(_file: &File, _mode: Option<u32>) -> io::Result<()> {
+    Ok(())
+}
+
+fn u16_at(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([bytes[at], bytes[at + 1]])
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+fn u64_at(bytes: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap())
+}
+
+/// The entries of the central directory of the archive
+fn read_directory<R: Read + Seek>(reader: &mut R) -> Result<Vec<Entry>> {
+    let len = reader.seek(SeekFrom::End(0))?;
+    // The end record is followed by a comment of up to 64KiB
+    let tail_len = len.min((END_LEN + ZIP64_LOCATOR_LEN + usize::from(u16::MAX)) as u64);
+    let mut tail = vec![0; tail_len as usize];
+    reader.seek(SeekFrom::Start(len - tail_len))?;
+    reader.read_exact(&mut tail)?;
+    let end = (0..=tail.len().saturating_sub(END_LEN)).rev()
+        .find(|&at| tail.len() >= END_LEN && u32_at(&tail, at) == END_OF_DIRECTORY)
+        .ok_or_else(|| anyhow!("Not a zip archive"))?;
+    let mut count = u64::from(u16_at(&tail, end + 10));
+    let mut directory_len = u64::from(u32_at(&tail, end + 12));
+    let mut directory = u64::from(u32_at(&tail, end + 16));
+    if end >= ZIP64_LOCATOR_LEN && u32_at(&tail, end - ZIP64_LOCATOR_LEN) == ZIP64_LOCATOR {
+        let mut record = [0; 56];
+        reader.seek(SeekFrom::Start(u64_at(&tail, end - ZIP64_LOCATOR_LEN + 8)))?;
+        reader.read_exact(&mut record)?;
+        if u32_at(&record, 0) != ZIP64_END_OF_DIRECTORY {
+            return Err(anyhow!("Invalid zip64 end of central directory"));
+        }
+        count = u64_at(&record, 32);
+        directory_len = u64_at(&record, 40);
+        directory = u64_at(&record, 48);
+    }
+    // Before anything is allocated: a corrupt end record can give any size
+    if directory.checked_add(directory_len).is_none_or(|end| end > len) {
+        return Err(anyhow!("Invalid central directory, it does not fit in the archive"));
+    }
+    if count.checked_mul(46).is_none_or(|least| least > directory_len) {
+        return Err(anyhow!("Invalid central directory, it is too small for its {} entries", count));
+    }
+
+    let mut content = vec![0; usize::try_from(directory_len)?];
+    reader.seek(SeekFrom::Start(directory))?;
+    reader.read_exact(&mut content)?;
+    let mut entries = Vec::new();
+    let mut at = 0;
+    for _ in 0..count {
+        let (entry, len) = parse_entry(&content[at..])?;
+        entries.push(entry);
+        at += len;
+    }
+    Ok(entries)
+}
+
+/// The entry of the central directory at the start of `record`, and the length of its record
+fn parse_entry(record: &[u8]) -> Result<(Entry, usize)> {
+    if record.len() < 46 || u32_at(record, 0) != CENTRAL_HEADER {
+        return Err(anyhow!("Invalid central directory"));
+    }
+    let (name_len, extra_len, comment_len) = (usize::from(u16_at(record, 28)), usize::from(u16_at(record, 30)), usize::from(u16_at(record, 32)));
+    let len = 46 + name_len + extra_len + comment_len;
+    if record.len() < len {
+        return Err(anyhow!("Invalid central directory"));
+    }
+    let name = String::from_utf8_lossy(&record[46..46 + name_len]).into_owned();
+    let mut entry = Entry {
+        path: safe_path(&name)?,
+        is_dir: name.ends_with('/'),
+        encrypted: u16_at(record, 8) & 1 != 0,
+        method: u16_at(record, 10),
+        crc: u32_at(record, 16),
+        compressed: u32_at(record, 20).into(),
+        size: u32_at(record, 24).into(),
+        offset: u32_at(record, 42).into(),
+        mode: (u16_at(record, 4) >> 8 == UNIX_HOST).then(|| u32_at(record, 38) >> 16).filter(|mode| *mode != 0),
+        modified: dos_time(u16_at(record, 14), u16_at(record, 12)),
+    };
+    let mut extra = &record[46 + name_len..46 + name_len + extra_len];
+    while extra.len() >= 4 {
+        let (id, field_len) = (u16_at(extra, 0), usize::from(u16_at(extra, 2)));
+        let field = extra.get(4..4 + field_len).ok_or_else(|| anyhow!("Invalid extra field of {:?}", name))?;
+        match id {
+            // Only the values that do not fit in 32 bits, in this order
+            ZIP64_EXTRA => {
+                let mut values = field.chunks_exact(8).map(|value| u64_at(value, 0));
+                for value in [&mut entry.size, &mut entry.compressed, &mut entry.offset] {
+                    if *value == u64::from(u32::MAX) {
+                        *value = values.next().ok_or_else(|| anyhow!("Invalid zip64 field of {:?}", name))?;
+                    }
+                }
+            },
+            TIMESTAMP_EXTRA if field.len() >= 5 && field[0] & 1 != 0 => {
+                let seconds = i32::from_le_bytes(field[1..5].try_into().unwrap());
+                entry.modified = Some(match u64::try_from(seconds) {
+                    Ok(seconds) => UNIX_EPOCH + Duration::from_secs(seconds),
+                    Err(_) => UNIX_EPOCH - Duration::from_secs(seconds.unsigned_abs().into()),
+                });
+            },
+            _ => {},
+        }
+        extra = &extra[4 + field_len..];
+    }
+    Ok((entry, len))
+}
+
+/// The path of an entry in the destination. The absolute ones and the ones going up would write outside of it
+fn safe_path(name: &str) -> Result<PathBuf> {
+    let mut path = PathBuf::new();
+    for component in Path::new(&name.replace('\\', "/")).components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::CurDir => {},
+            _ => return Err(anyhow!("The entry {:?} points outside of the destination", name)),
+        }
+    }
+    if path.as_os_str().is_empty() {
+        return Err(anyhow!("Invalid entry name {:?}", name));
+    }
+    Ok(path)
+}
+
+/// Whether the `target` of the link entry `link` stays in the destination once its `..` are resolved: it is relative
+/// and does not go above the top of the archive. The archive paths have no `..` themselves (`safe_path`)
+#[cfg_attr(not(unix), allow(dead_code))]
+fn stays_inside(link: &Path, target: &Path) -> bool {
+    let mut depth = link.components().count().saturating_sub(1);
+    for component in target.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {},
+            Component::ParentDir if depth > 0 => depth -= 1,
+            _ => return false,
+        }
+    }
+    true
+}
+
+/// The local time of the MS-DOS `date` and `time` of an entry
+fn dos_time(date: u16, time: u16) -> Option<SystemTime> {
+    let day = NaiveDate::from_ymd_opt(1980 + i32::from(date >> 9), u32::from((date >> 5) & 0xf), u32::from(date & 0x1f))?;
+    let time = day.and_hms_opt(u32::from(time >> 11), u32::from((time >> 5) & 0x3f), u32::from(time & 0x1f) * 2)?;
+    match Local.from_local_datetime(&time).earliest() {
+        Some(time) => Some(time.into()),
+        None => {
+            warn!("Invalid local time in the zip archive: {}", time);
+            None
+        },
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use flate2::write::DeflateEncoder;
+    use flate2::{Compression, Crc};
+    use crate::filter::{Filters, TimeWindow};
+    use crate::test_support::init;
+    use crate::{CopyOptions, IfExists};
+    use super::{extract, is_zip, read_directory, stays_inside};
+
+    /// A zip with the `(name, content, deflated)` entries, all of them modified at `modified` and with the mode 0o640
+    fn zip(entries: &[(&str, &[u8], bool)], modified: u32) -> Vec<u8> {
+        let entries: Vec<_> = entries.iter().map(|&(name, content, deflated)| (name, content, deflated, 0o100_640)).collect();
+        zip_with_modes(&entries, modified)
+    }
+
+    /// A zip with the `(name, content, deflated, mode)` entries, all of them modified at `modified`
+    fn zip_with_modes(entries: &[(&str, &[u8], bool, u32)], modified: u32) -> Vec<u8> {
+        let (mut archive, mut directory) = (Vec::new(), Vec::new());
+        for &(name, content, deflated, mode) in entries {
+            let data = match deflated {
+                true => {
+                    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+                    encoder.write_all(content).unwrap();
+                    encoder.finish().unwrap()
+                },
+                false => content.to_vec(),
+            };
+            let mut crc = Crc::new();
+            crc.update(content);
+            let mut extra = vec![0x55, 0x54, 5, 0, 1];
+            extra.extend(modified.to_le_bytes());
+            let mut common = Vec::new();
+            common.extend(20u16.to_le_bytes());
+            common.extend(0u16.to_le_bytes());
+            common.extend(if deflated { 8u16 } else { 0 }.to_le_bytes());
+            // 1 January 1980 at midnight, replaced by the extended timestamp
+            common.extend([0, 0, 0x21, 0]);
+            common.extend(crc.sum().to_le_bytes());
+            common.extend((data.len() as u32).to_le_bytes());
+            common.extend((content.len() as u32).to_le_bytes());
+            common.extend((name.len() as u16).to_le_bytes());
+            common.extend((extra.len() as u16).to_le_bytes());
+
+            directory.extend(0x0201_4b50u32.to_le_bytes());
+            directory.extend([20, 3]);
+            directory.extend(&common);
+            directory.extend([0; 6]);
+            directory.extend((mode << 16).to_le_bytes());
+            directory.extend((archive.len() as u32).to_le_bytes());
+            directory.extend(name.as_bytes());
+            directory.extend(&extra);
+            archive.extend(0x0403_4b50u32.to_le_bytes());
+            archive.extend(&common);
+            archive.extend(name.as_bytes());
+            archive.extend(&extra);
+            archive.extend(&data);
+        }
+        let offset = archive.len() as u32;
+        archive.extend(&directory);
+        archive.extend(0x0605_4b50u32.to_le_bytes());
+        archive.extend([0; 4]);
+        archive.extend((entries.len() as u16).to_le_bytes());
+        archive.extend((entries.len() as u16).to_le_bytes());
+        archive.extend((directory.len() as u32).to_le_bytes());
+        archive.extend(offset.to_le_bytes());
+        archive.extend(0u16.to_le_bytes());
+        archive
+    }
+
+    #[test]
+    fn entry_names() {
+        assert!(is_zip("backup.ZIP".as_ref()));
+        assert!(!is_zip("backup.tar".as_ref()));
+        for name in ["../escape", "/etc/passwd", "nested/../../escape", "..\\escape"] {
+            let archive = zip(&[(name, b"text", false)], 0);
+            assert!(read_directory(&mut Cursor::new(archive)).is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn link_targets() {
+        assert!(stays_inside(Path::new("dir/link"), Path::new("../file")));
+        assert!(stays_inside(Path::new("dir/link"), Path::new("./sub/../other")));
+        assert!(!stays_inside(Path::new("link"), Path::new("../file")));
+        assert!(!stays_inside(Path::new("dir/link"), Path::new("sub/../../../file")));
+        assert!(!stays_inside(Path::new("dir/link"), Path::new("/etc")));
+    }
+
+    /// The offset of the first central directory record of `archive`
+    fn central_directory(archive: &[u8]) -> usize {
+        archive.windows(4).position(|bytes| bytes == 0x0201_4b50u32.to_le_bytes()).unwrap()
+    }
+
+    #[test]
+    fn corrupt_directories() {
+        let archive = zip(&[("file", b"text", false)], 0);
+        let end = archive.len() - 22;
+        // Sizes and entry counts that do not fit, nothing is allocated for them
+        let mut huge = archive.clone();
+        huge[end + 12..end + 16].copy_from_slice(&0xffff_0000u32.to_le_bytes());
+        assert!(read_directory(&mut Cursor::new(huge)).unwrap_err().to_string().contains("does not fit"));
+        let mut outside = archive.clone();
+        outside[end + 16..end + 20].copy_from_slice(&(archive.len() as u32).to_le_bytes());
+        assert!(read_directory(&mut Cursor::new(outside)).is_err());
+        let mut crowded = archive.clone();
+        crowded[end + 10..end + 12].copy_from_slice(&u16::MAX.to_le_bytes());
+        assert!(read_directory(&mut Cursor::new(crowded)).unwrap_err().to_string().contains("too small for its 65535 entries"));
+        assert_eq!(read_directory(&mut Cursor::new(archive)).unwrap().len(), 1);
+    }
+
+    /// An entry that inflates to much more than its size
+    #[tokio::test]
+    async fn deflate_bomb() {
+        let base_dir = init("deflate_bomb").await;
+
+        let mut archive = zip(&[("bomb", &vec![0; 8 * 1024 * 1024], true), ("fine", b"text", false)], 0);
+        let directory = central_directory(&archive);
+        archive[directory + 24..directory + 28].copy_from_slice(&10u32.to_le_bytes());
+        let path = base_dir.join("bomb.zip");
+        tokio::fs::write(&path, archive).await.unwrap();
+        let dest = base_dir.join("dest");
+        let options = Arc::new(CopyOptions::default());
+        extract(&path, &dest, &options).await.unwrap();
+
+        assert!(!dest.join("bomb").exists());
+        assert_eq!(tokio::fs::read_to_string(dest.join("fine")).await.unwrap(), "text");
+        let failures = options.failures.list();
+        assert_eq!(failures.len(), 1);
+        assert!(failures[0].message.contains("bigger than the size the archive gives it"), "{:?}", failures);
+    }
+
+    #[tokio::test]
+    async fn existing_destinations() {
+        let base_dir = init("existing_destinations").await;
+
+        let archive = base_dir.join("archive.zip");
+        let modified = 1_600_000_000;
+        tokio::fs::write(&archive, zip(&[("file", b"archived", false)], modified)).await.unwrap();
+        let dest = base_dir.join("dest");
+        tokio::fs::create_dir(&dest).await.unwrap();
+        let extract_with = |if_exists| {
+            let (archive, dest) = (archive.clone(), dest.clone());
+            async move {
+                let options = Arc::new(CopyOptions { if_exists, ..Default::default() });
+                extract(&archive, &dest, &options).await.unwrap();
+                options
+            }
+        };
+        // Newer than the entry
+        tokio::fs::write(dest.join("file"), "kept").await.unwrap();
+
+        for if_exists in [IfExists::Skip, IfExists::Newer] {
+            let options = extract_with(if_exists).await;
+            assert_eq!(options.stats.files_skipped.load(std::sync::atomic::Ordering::Relaxed), 1);
+        }
+        let options = extract_with(IfExists::Error).await;
+        assert!(options.failures.list()[0].message.contains("Destination already exists"));
+        assert_eq!(tokio::fs::read_to_string(dest.join("file")).await.unwrap(), "kept");
+
+        // Older than the entry
+        std::fs::File::options().write(true).open(dest.join("file")).unwrap()
+            .set_modified(UNIX_EPOCH + Duration::from_secs(modified as u64 - 60)).unwrap();
+        extract_with(IfExists::Newer).await;
+        assert_eq!(tokio::fs::read_to_string(dest.join("file")).await.unwrap(), "archived");
+    }
+
+    /// A link with a target outside of the destination and an entry written through it
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn links_out_of_the_destination() {
+        let base_dir = init("links_out_of_the_destination").await;
+
+        let outside = base_dir.join("outside");
+        tokio::fs::create_dir(&outside).await.unwrap();
+        let archive = base_dir.join("evil.zip");
+        tokio::fs::write(&archive, zip_with_modes(&[
+            ("absolute", outside.to_str().unwrap().as_bytes(), false, 0o120_777),
+            ("absolute/x", b"evil", false, 0o100_640),
+            ("nested/relative", b"../../outside", false, 0o120_777),
+            ("nested/relative/y", b"evil", false, 0o100_640),
+            ("nested/fine", b"../absolute/x", false, 0o120_777),
+        ], 0)).await.unwrap();
+        let dest = base_dir.join("dest");
+        let options = Arc::new(CopyOptions::default());
+        extract(&archive, &dest, &options).await.unwrap();
+
+        assert_eq!(std::fs::read_dir(&outside).unwrap().count(), 0);
+        // The files are written before the links, into real directories
+        assert!(tokio::fs::symlink_metadata(dest.join("absolute")).await.unwrap().is_dir());
+        assert_eq!(tokio::fs::read_to_string(dest.join("nested/relative/y")).await.unwrap(), "evil");
+        assert_eq!(tokio::fs::read_link(dest.join("nested/fine")).await.unwrap(), Path::new("../absolute/x"));
+        let failures = options.failures.list();
+        assert_eq!(failures.len(), 2, "{:?}", failures);
+        assert!(failures.iter().all(|failure| failure.message.contains("The link points outside of the destination")), "{:?}", failures);
+    }
+
+    /// Archives written by Info-ZIP and Python, in testdata/zip: streamed to a pipe (every entry has a data descriptor
+    /// after its content, and the sizes of its local header are 0), forced to zip64 (64 bits sizes in the extra
+    /// fields and a zip64 end of central directory) and with the `UT` and `ux` extra fields and an archive comment
+    #[tokio::test]
+    async fn real_world_archives() {
+        let base_dir = init("real_world_archives").await;
+
+        let deflated: String = (1..=3000).map(|line| format!("{line}\n")).collect();
+        let archived = SystemTime::UNIX_EPOCH + Duration::from_secs(1_714_564_800);
+        for name in ["infozip-streamed.zip", "infozip-zip64.zip"] {
+            let archive = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/zip").join(name);
+            let dest = base_dir.join(name);
+            extract(&archive, &dest, &Arc::default()).await.unwrap();
+            assert_eq!(tokio::fs::read_to_string(dest.join("nested/deflated")).await.unwrap(), deflated, "{}", name);
+            assert_eq!(tokio::fs::read_to_string(dest.join("nested/stored.bin")).await.unwrap(), "stored", "{}", name);
+            let metadata = tokio::fs::metadata(dest.join("nested/deflated")).await.unwrap();
+            assert_eq!(metadata.modified().unwrap(), archived, "{}", name);
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                assert_eq!(metadata.permissions().mode() & 0o777, 0o644, "{}", name);
+            }
+        }
+        let dest = base_dir.join("infozip-streamed.zip");
+        assert_eq!(tokio::fs::read_to_string(dest.join("top")).await.unwrap(), "top text\n");
+        // Read from the standard input
+        let dest = base_dir.join("infozip-zip64.zip");
+        assert_eq!(tokio::fs::read_to_string(dest.join("-")).await.unwrap(), "from stdin\n");
+
+        // A zip64 entry with a data descriptor, no extra field in the central directory
+        let archive = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/zip/python-streamed.zip");
+        let dest = base_dir.join("python-streamed.zip");
+        extract(&archive, &dest, &Arc::default()).await.unwrap();
+        assert_eq!(tokio::fs::read_to_string(dest.join("stream/forced64.txt")).await.unwrap(), "zip64 and a data descriptor\n".repeat(100));
+        assert_eq!(tokio::fs::read_to_string(dest.join("stream/plain.txt")).await.unwrap(), "plain");
+    }
+
+    #[tokio::test]
+    async fn extract_zip_archive() {
+        let base_dir = init("extract_zip_archive").await;
+
+        let archive = base_dir.join("archive.zip");
+        let long: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        let modified = 1_600_000_000;
+        tokio::fs::write(&archive, zip(&[
+            ("nested/", b"", false),
+            ("nested/stored", b"stored text", false),
+            ("nested/deeper/deflated", &long, true),
+            ("top", b"top text", true),
+        ], modified)).await.unwrap();
+        let dest = base_dir.join("dest");
+        extract(&archive, &dest, &Arc::default()).await.unwrap();
+
+        assert_eq!(tokio::fs::read_to_string(dest.join("nested/stored")).await.unwrap(), "stored text");
+        assert_eq!(tokio::fs::read(dest.join("nested/deeper/deflated")).await.unwrap(), long);
+        assert_eq!(tokio::fs::read_to_string(dest.join("top")).await.unwrap(), "top text");
+        let metadata = tokio::fs::metadata(dest.join("top")).await.unwrap();
+        assert_eq!(metadata.modified().unwrap(), UNIX_EPOCH + Duration::from_secs(modified.into()));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(metadata.permissions().mode() & 0o777, 0o640);
+        }
+
+        // Only the entries in the time window
+        let filters = Filters { time_window: TimeWindow { oldest: Some(SystemTime::now() - Duration::from_secs(60)), newest: None } };
+        let dest = base_dir.join("filtered");
+        extract(&archive, &dest, &Arc::new(CopyOptions { filters, ..Default::default() })).await.unwrap();
+        assert!(dest.join("nested").is_dir());
+        assert!(!dest.join("top").exists());
+    }
+}